@@ -36,12 +36,16 @@ async fn main() {
         // let remote_address = "[fdf5:c816:9a31:0:14de:f8f3:b5aa:f677]:5541"
         .parse::<std::net::SocketAddr>()
         .unwrap();
-    matter_controller::controller::commission_with_pin(
+    if let Err(e) = matter_controller::controller::commission_with_pin(
         &mut controller,
         remote_address,
         250,
         123456,
     )
-    .await;
+    .await
+    {
+        println!("Unable to commission {remote_address}: {e}");
+        return;
+    }
     println!("Commissioning PASE step completed");
 }
@@ -1,3 +1,5 @@
+use std::{collections::HashMap, sync::Arc};
+
 use bytes::BytesMut;
 use matter_controller::{
     cluster::utility::basic_information::DeviceInformation,
@@ -5,23 +7,20 @@ use matter_controller::{
         device::{Endpoint, Node},
         device_type::{root_node::DEVICE_TYPE_ROOT_NODE, DEVICE_TYPE_EXTENDED_COLOR_LIGHT},
         endpoint::{extended_color_light_endpoint, root_endpoint},
-        handler::{AttrDataEncoder, Handler},
+        handler::Handler,
     },
     end_device::EndDevice,
-    exchange::ExchangeMessageAction,
-    interaction_model::{
-        transaction::Transaction, InteractionModelProtocolOpCode, ReadRequestMessage,
-        ReportDataMessage,
-    },
-    message::{Message, ProtocolID, SessionType},
-    tlv::Encoder,
+    exchange::{ExchangeEvent, ExchangeKey, ExchangeManager, ExchangeMessageAction, MrpParameters},
+    interaction_model::transaction::InteractionModelHandler,
+    message::{Message, ProtocolID, UDP_MESSAGE_LIMIT},
+    secure_channel::SecureChannelManager,
     transport::{
         mdns::{DnsServiceMode, MdnsHandler},
         udp::UdpInterface,
         Packet,
     },
+    util::time::{Instant, Timer, TokioClock},
 };
-use num::FromPrimitive;
 use thingbuf::mpsc::StaticChannel;
 
 static MESSAGE_CHANNEL: StaticChannel<Packet, 16> = StaticChannel::new();
@@ -58,6 +57,24 @@ async fn main() {
     let device_handler = handler(&device_info_clone);
     let (message_sender, message_receiver) = MESSAGE_CHANNEL.split();
     let mut end_device = EndDevice::new(&node, device_handler, message_sender.clone()).await;
+    let clock = Arc::new(TokioClock::new());
+    // The device sleeps while it's idle, so peers wait longer for its acks
+    let mrp_parameters = MrpParameters {
+        idle_retrans_timeout: std::time::Duration::from_millis(10000),
+        active_retrans_timeout: std::time::Duration::from_millis(500),
+        ..Default::default()
+    };
+    end_device.exchange_manager = ExchangeManager::with_clock(clock.clone());
+    end_device.exchange_manager.register_handler(
+        0,
+        ProtocolID::SecureChannel as u16,
+        SecureChannelManager::with_mrp_parameters(mrp_parameters),
+    );
+    end_device.exchange_manager.register_handler(
+        0,
+        ProtocolID::InteractionModel as u16,
+        InteractionModelHandler::new(handler(&device_info)),
+    );
 
     // let local_address: std::net::SocketAddr = "192.168.86.197:5541".parse().unwrap();
     let local_address: std::net::SocketAddr = "[::]:5541".parse().unwrap();
@@ -96,6 +113,7 @@ async fn main() {
                 "304763D1FA4BA463",
                 DnsServiceMode::Commissionable(1),
                 &device_info_clone,
+                &mrp_parameters,
             );
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             i += 1;
@@ -117,140 +135,120 @@ async fn main() {
         }
     });
     let recv_future = tokio::task::spawn(async move {
+        // Where to send retransmissions and acks of each exchange. Unsecured
+        // exchanges all have session 0, so sessions can't tell peers apart.
+        let mut peers: HashMap<ExchangeKey, std::net::SocketAddr> = HashMap::new();
         loop {
-            // TODO: use a buffer that we can allocate once
-            let mut buf = [0u8; 1024];
-            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
-            println!("Received message {:?}", hex::encode(&buf[..len]));
-            let mut message = Message::decode(&buf[..len]);
-            // println!("Decoded message: {:?}", message);
-            // The message could be encrypted, it could be a new exchange etc.
-            // Send it to the exchange manager to take action on it
-            let action = end_device.exchange_manager.receive_message(&mut message);
-            match action {
-                ExchangeMessageAction::Drop => {
-                    // Ignore message
-                    println!(
-                        "Ignoring duplicate message {}",
-                        message.message_header.message_counter
-                    );
-                    continue;
-                }
-                ExchangeMessageAction::AckAndDrop => {
-                    if let Some(ack) = message.next_ack() {
-                        let exchange_id = message.payload_header.as_ref().unwrap().exchange_id;
-                        let next_message_counter = {
-                            let exchange = end_device.exchange_manager.find_exchange(exchange_id);
-                            exchange.next_message_counter()
-                        };
-                        let ack_message = message.standalone_ack(ack, next_message_counter);
-                        {
-                            // Send a message by writing it directly to the channel buffer
-                            let mut sender = end_device.message_sender.send_ref().await.unwrap();
-                            println!("Sender bytes: {}", hex::encode(&sender.bytes));
-                            sender.recipient = Some(peer);
-                            let encryption_key = if message.message_header.session_type
-                                == SessionType::UnsecuredSession
-                            {
-                                None
-                            } else {
-                                end_device
+            // The message keeps the buffer, and is decrypted in it
+            let mut buf = BytesMut::zeroed(UDP_MESSAGE_LIMIT);
+            // Wait for a message, or for an exchange timer to expire
+            let next_timeout = end_device.exchange_manager.next_timeout();
+            let received = tokio::select! {
+                received = socket.recv_from(&mut buf) => received,
+                _ = exchange_timeout(&*clock, next_timeout) => {
+                    for event in end_device.exchange_manager.poll() {
+                        match event {
+                            ExchangeEvent::Send { exchange, packet } => {
+                                let Some(peer) = peers.get(&exchange) else {
+                                    continue;
+                                };
+                                let mut sender = end_device.message_sender.send_ref().await.unwrap();
+                                sender.recipient = Some(*peer);
+                                sender.bytes.extend_from_slice(&packet);
+                            }
+                            ExchangeEvent::Failed { exchange, message_counter } => {
+                                println!("Exchange {} failed, message {message_counter} was not acknowledged", exchange.exchange_id);
+                            }
+                            ExchangeEvent::Expired { exchange } => {
+                                println!("Exchange {} expired", exchange.exchange_id);
+                            }
+                            ExchangeEvent::SessionExpired { session_id } => {
+                                println!("Session {session_id} ran out of message counters");
+                                peers.retain(|key, _| key.session_id != session_id);
+                            }
+                            ExchangeEvent::SessionEvicted { session_id } => {
+                                println!("Session {session_id} was evicted");
+                                peers.retain(|key, _| key.session_id != session_id);
+                            }
+                            ExchangeEvent::Received { message } => {
+                                let mut response = BytesMut::new();
+                                if let Err(e) = end_device
                                     .exchange_manager
-                                    .session_context(message.message_header.session_id)
-                                    .map(|s| s.encryption_key())
-                                    .flatten()
-                            };
-                            ack_message.encode(&mut sender.bytes, encryption_key);
+                                    .dispatch(&message, &mut response)
+                                {
+                                    println!("Not sending response: {e}");
+                                }
+                                let exchange = ExchangeKey::received(&message);
+                                let (Some(peer), false) = (peers.get(&exchange), response.is_empty()) else {
+                                    continue;
+                                };
+                                let mut sender = end_device.message_sender.send_ref().await.unwrap();
+                                sender.recipient = Some(*peer);
+                                sender.bytes.extend_from_slice(&response);
+                            }
+                            ExchangeEvent::SyncFailed { peer } => {
+                                println!("Group peer {:x} didn't synchronize its counter", peer.source_node_id);
+                            }
                         }
                     }
+                    // Forget the peers of exchanges that were closed
+                    peers.retain(|key, _| end_device.exchange_manager.find_exchange(key).is_some());
+                    continue;
                 }
-                ExchangeMessageAction::Process => {}
-            }
-            let payload_header = message.payload_header.as_ref().unwrap();
-            let protocol_id: ProtocolID = ProtocolID::from_u16(payload_header.protocol_id).unwrap();
-            let next_ack = message.next_ack();
-            // TODO: update our ack counter
-            let exchange_id = payload_header.exchange_id;
-            let next_message_counter = {
-                if exchange_id == 0 {
-                    111111
-                } else {
-                    let exchange = end_device.exchange_manager.find_exchange(exchange_id);
-                    exchange.next_message_counter()
+            };
+            let (len, peer) = received.unwrap();
+            buf.truncate(len);
+            println!("Received message {:?}", hex::encode(&buf));
+            let mut message = match Message::decode(buf) {
+                Ok(message) => message,
+                Err(e) => {
+                    println!("Dropping message: {e}");
+                    continue;
                 }
             };
-            let mut response_message = match protocol_id {
-                ProtocolID::SecureChannel => {
-                    // Send the message to the secure channel manager
-                    let session_context = end_device
+            // println!("Decoded message: {:?}", message);
+            // The message could be encrypted, it could be a new exchange etc.
+            // Send it to the exchange manager to take action on it
+            let action = end_device.exchange_manager.receive_message(&mut message);
+            // Only accepted messages tell where the peer of their exchange is
+            match action {
+                // The exchange manager logs why the message was dropped
+                ExchangeMessageAction::Drop => continue,
+                ExchangeMessageAction::AckAndDrop => {
+                    peers.insert(ExchangeKey::received(&message), peer);
+                    let mut ack = BytesMut::new();
+                    if let Err(e) = end_device
                         .exchange_manager
-                        .session_context_mut(message.message_header.session_id);
-                    let (response_message, maybe_session) = end_device
-                        .secure_channel
-                        .on_message(session_context, &message);
-
-                    // If no message, don't do anything further
-                    let Some(response_message) = response_message else {
-                        continue;
-                    };
-
-                    if let Some(session) = maybe_session {
-                        end_device.exchange_manager.add_session(
-                            matter_controller::session_context::SessionContext::Secure(session),
-                        );
+                        .encode_standalone_ack(&message, &mut ack)
+                    {
+                        println!("Not sending acknowledgement: {e}");
                     }
-
-                    response_message
-                }
-                ProtocolID::InteractionModel => {
-                    // dbg!((&message.message_header, &payload_header));
-                    // The Matter common vendor ID
-                    // assert_eq!(payload_header.protocol_vendor_id, 0x0000);
-                    let session_context = end_device
-                        .exchange_manager
-                        .session_context_mut(message.message_header.session_id);
-                    let mut transaction = Transaction {};
-                    let response_message =
-                        transaction.on_message(session_context, &handler(&device_info), &message);
-
-                    // If no message, don't do anything further
-                    let Some(response_message) = response_message else {
-                        continue;
-                    };
-
-                    response_message
-                }
-                ProtocolID::UserDirectedComm => {
-                    todo!()
-                }
-                ProtocolID::BDX => {
-                    todo!()
+                    if !ack.is_empty() {
+                        // Send a message by writing it directly to the channel buffer
+                        let mut sender = end_device.message_sender.send_ref().await.unwrap();
+                        sender.recipient = Some(peer);
+                        sender.bytes.extend_from_slice(&ack);
+                    }
+                    continue;
                 }
-                ProtocolID::ForTesting => {
-                    todo!()
+                ExchangeMessageAction::Process => {
+                    peers.insert(ExchangeKey::received(&message), peer);
                 }
-            };
-
-            // Add acknowledgement
-            response_message.with_ack(next_ack);
-            response_message.message_header.message_counter = next_message_counter;
-
+            }
+            // The handler of the message's protocol answers it on its exchange,
+            // piggybacking the pending ack on the response
+            let mut response = BytesMut::new();
+            if let Err(e) = end_device
+                .exchange_manager
+                .dispatch(&message, &mut response)
             {
+                println!("Not sending response: {e}");
+            }
+            if !response.is_empty() {
                 // Send a message by writing it directly to the channel buffer
                 let mut sender = end_device.message_sender.send_ref().await.unwrap();
                 sender.recipient = Some(peer);
-                // TODO: re-enable encryption once it's clear when it's used & when not
-                let encryption_key =
-                    if message.message_header.session_type == SessionType::UnsecuredSession {
-                        None
-                    } else {
-                        end_device
-                            .exchange_manager
-                            .session_context(message.message_header.session_id)
-                            .map(|s| s.encryption_key())
-                            .flatten()
-                    };
-                response_message.encode(&mut sender.bytes, encryption_key);
+                sender.bytes.extend_from_slice(&response);
             }
         }
     });
@@ -260,7 +258,15 @@ async fn main() {
     recv.unwrap();
 }
 
-fn handler<'a>(device_info: &'a DeviceInformation<'a>) -> impl Handler + 'a {
+/// Wait until an exchange has to retransmit, acknowledge or expire, if ever
+async fn exchange_timeout(clock: &impl Timer, at: Option<Instant>) {
+    match at {
+        Some(at) => clock.sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn handler<'a>(device_info: &DeviceInformation<'a>) -> impl Handler + 'a {
     root_endpoint::handler(0, device_info.clone()).chain(
         1,
         0,
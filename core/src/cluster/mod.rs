@@ -3,7 +3,7 @@ use num::FromPrimitive;
 use crate::{
     data_model::Attribute,
     interaction_model::{AttributeDataIB, AttributePathIB, CommandRequest},
-    tlv::Encoder,
+    tlv::{Encoder, RawTlv, TagControl},
 };
 
 use self::{level::LevelCluster, on_off::OnOffCluster};
@@ -45,22 +45,20 @@ impl<'a> Cluster<'a> {
         let mut encoder = Encoder::default();
         match path {
             GlobalAttributes::ClusterRevision => todo!(),
-            GlobalAttributes::FeatureMap => encoder.write(
-                crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte4),
-                crate::tlv::TagControl::ContextSpecific(0),
-                crate::tlv::TagLengthValue::Unsigned32(self.features),
-            ),
+            GlobalAttributes::FeatureMap => {
+                encoder.u32(TagControl::ContextSpecific(0), self.features)
+            }
             GlobalAttributes::AttributeList => todo!(),
             GlobalAttributes::EventList => todo!(),
             GlobalAttributes::AcceptedCommandList => todo!(),
             GlobalAttributes::GeneratedCommandList => todo!(),
             GlobalAttributes::FabricIndex => todo!(),
         }
+        .expect("attribute value should fit in an empty encoder");
         AttributeDataIB {
             data_version: 1,
             path: attribute.clone(),
-            data: encoder.inner(),
-            interaction_model_revision: self.revision,
+            data: RawTlv(encoder.inner()),
         }
     }
 }
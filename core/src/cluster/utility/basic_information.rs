@@ -7,7 +7,7 @@ use crate::{
         Attribute,
     },
     interaction_model::{AttributeDataIB, AttributePathIB},
-    tlv::Encoder,
+    tlv::{Encoder, RawTlv, TagControl},
 };
 
 pub const CLUSTER_ID: u16 = 0x0028;
@@ -138,28 +138,15 @@ impl<'a> BasicInformationCluster<'a> {
             match path {
                 Attributes::DataModelRevision => todo!(),
                 Attributes::VendorName => todo!(),
-                Attributes::VendorID => encoder.write(
-                    crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte2),
-                    crate::tlv::TagControl::ContextSpecific(0),
-                    crate::tlv::TagLengthValue::Unsigned16(self.info.vendor_id),
-                ),
+                Attributes::VendorID => {
+                    encoder.u16(TagControl::ContextSpecific(0), self.info.vendor_id)
+                }
                 Attributes::ProductName => {
-                    let value =
-                        heapless::Vec::from_slice(self.info.product_name.as_bytes()).unwrap();
-                    encoder.write(
-                        crate::tlv::TlvType::String(
-                            crate::tlv::ElementSize::Byte1,
-                            self.info.product_name.len(),
-                        ),
-                        crate::tlv::TagControl::ContextSpecific(0),
-                        crate::tlv::TagLengthValue::String(value),
-                    )
+                    encoder.str(TagControl::ContextSpecific(0), self.info.product_name)
+                }
+                Attributes::ProductID => {
+                    encoder.u16(TagControl::ContextSpecific(0), self.info.product_id)
                 }
-                Attributes::ProductID => encoder.write(
-                    crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte2),
-                    crate::tlv::TagControl::ContextSpecific(0),
-                    crate::tlv::TagLengthValue::Unsigned16(self.info.product_id),
-                ),
                 Attributes::NodeLabel => todo!(),
                 Attributes::Location => todo!(),
                 Attributes::HardwareVersion => todo!(),
@@ -167,12 +154,12 @@ impl<'a> BasicInformationCluster<'a> {
                 Attributes::SoftwareVersion => todo!(),
                 Attributes::SoftwareVersionString => todo!(),
                 Attributes::CapabilityMinima => todo!(),
-            };
+            }
+            .expect("attribute value should fit in an empty encoder");
             AttributeDataIB {
                 data_version: self.data_version,
                 path: attribute.clone(),
-                data: encoder.inner(),
-                interaction_model_revision: 1,
+                data: RawTlv(encoder.inner()),
             }
         } else {
             panic!()
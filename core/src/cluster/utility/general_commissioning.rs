@@ -5,7 +5,7 @@ use crate::data_model::handler::{AttrDataEncoder, CmdDetails, Handler};
 use crate::interaction_model::transaction::Transaction;
 use crate::interaction_model::{AttributeDataIB, AttributePathIB};
 use crate::session_context::SecureSessionContext;
-use crate::tlv::Encoder;
+use crate::tlv::{Encoder, FromTlv, RawTlv, TagControl, ToTlv};
 use crate::{
     cluster::ClusterClassification,
     data_model::{Attribute, AttributeValue},
@@ -77,66 +77,25 @@ impl GeneralCommissioningCluster {
             let mut encoder = Encoder::default();
             match path {
                 Attributes::Breadcrumb => {
-                    encoder.write(
-                        crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte8),
-                        crate::tlv::TagControl::ContextSpecific(0),
-                        crate::tlv::TagLengthValue::Unsigned64(0), // TODO: shouldn't be hardcoded
-                    )
+                    // TODO: shouldn't be hardcoded
+                    encoder.u64(TagControl::ContextSpecific(0), 0)
                 }
                 Attributes::BasicCommissioningInfo => {
-                    // TODO: implement to_tlv
-                    encoder.write(
-                        crate::tlv::TlvType::Structure,
-                        crate::tlv::TagControl::Anonymous,
-                        crate::tlv::TagLengthValue::Container,
-                    );
-                    encoder.write(
-                        crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte2),
-                        crate::tlv::TagControl::ContextSpecific(0),
-                        crate::tlv::TagLengthValue::Unsigned16(
-                            self.fail_safe.fail_safe_expiry_len_seconds,
-                        ),
-                    );
-                    encoder.write(
-                        crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte2),
-                        crate::tlv::TagControl::ContextSpecific(1),
-                        crate::tlv::TagLengthValue::Unsigned16(
-                            self.fail_safe.max_cum_fail_safe_seconds,
-                        ),
-                    );
-                    encoder.write(
-                        crate::tlv::TlvType::EndOfContainer,
-                        crate::tlv::TagControl::Anonymous,
-                        crate::tlv::TagLengthValue::EndOfContainer,
-                    );
+                    self.fail_safe.to_tlv(&mut encoder, TagControl::Anonymous)
                 }
                 Attributes::RegulatoryConfig => {
-                    // TODO: should be enum8
-                    encoder.write(
-                        crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte1),
-                        crate::tlv::TagControl::ContextSpecific(0),
-                        crate::tlv::TagLengthValue::Unsigned8(
-                            0, // indoor
-                        ),
-                    );
+                    RegulatoryLocationType::Indoor.to_tlv(&mut encoder, TagControl::Anonymous)
                 }
                 Attributes::LocationCapability => {
-                    // TODO: should be enum8
-                    encoder.write(
-                        crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte1),
-                        crate::tlv::TagControl::ContextSpecific(0),
-                        crate::tlv::TagLengthValue::Unsigned8(
-                            0, // indoor
-                        ),
-                    );
+                    RegulatoryLocationType::Indoor.to_tlv(&mut encoder, TagControl::Anonymous)
                 }
                 Attributes::SupportsConcurrentConnection => todo!(),
-            };
+            }
+            .expect("attribute value should fit in an empty encoder");
             AttributeDataIB {
                 data_version: self.data_version,
                 path: attr.clone(),
-                data: encoder.inner(),
-                interaction_model_revision: 1,
+                data: RawTlv(encoder.inner()),
             }
         } else {
             panic!()
@@ -242,11 +201,16 @@ pub enum AttributeCommissioningError {
 }
 
 /// (11.9.5.2
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct AttributeBasicCommissioningInfo {
+    #[tlv(tag = 0)]
     pub fail_safe_expiry_len_seconds: u16,
+    #[tlv(tag = 1)]
     pub max_cum_fail_safe_seconds: u16,
 }
 
+#[repr(u8)]
+#[derive(Debug, ToTlv, FromTlv)]
 pub enum RegulatoryLocationType {
     Indoor = 0,
     Outdoor = 1,
@@ -7,7 +7,7 @@ use crate::{
         Attribute, AttributeValue,
     },
     interaction_model::{AttributeDataIB, AttributePathIB},
-    tlv::Encoder,
+    tlv::{Encoder, RawTlv, TagControl},
 };
 
 use crate::cluster::Cluster;
@@ -132,20 +132,16 @@ impl NetworkCommissioningCluster {
                 Attributes::Networks => todo!(),
                 Attributes::ScanMaxtimeSeconds => todo!(),
                 Attributes::ConnectMaxTimeSeconds => {
-                    encoder.write(
-                        crate::tlv::TlvType::UnsignedInt(crate::tlv::ElementSize::Byte1),
-                        crate::tlv::TagControl::ContextSpecific(0),
-                        crate::tlv::TagLengthValue::Unsigned8(120),
-                    );
+                    encoder.u8(TagControl::ContextSpecific(0), 120)
                 }
                 Attributes::InterfaceEnabled => todo!(),
                 Attributes::LastNetworkingStatus => todo!(),
-            };
+            }
+            .expect("attribute value should fit in an empty encoder");
             AttributeDataIB {
                 data_version: self.data_version,
                 path: attribute.clone(),
-                data: encoder.inner(),
-                interaction_model_revision: 1,
+                data: RawTlv(encoder.inner()),
             }
         } else {
             panic!()
@@ -12,6 +12,13 @@ pub const SPAKE2P_CONTEXT_PREFIX: [u8; 26] = *b"CHIP PAKE V1 Commissioning";
 pub const SPAKE2P_KEY_CONFIRM_INFO: [u8; 16] = *b"ConfirmationKeys";
 pub const SESSION_KEYS_INFO: [u8; 11] = *b"SessionKeys";
 pub const SESSION_RESUMPTION_KEYS_INFO: [u8; 21] = *b"SessionResumptionKeys";
+pub const SIGMA1_RESUME_INFO: [u8; 13] = *b"Sigma1_Resume";
+pub const SIGMA2_RESUME_INFO: [u8; 13] = *b"Sigma2_Resume";
+pub const SIGMA1_RESUME_NONCE: [u8; 13] = *b"NCASE_SigmaS1";
+pub const SIGMA2_RESUME_NONCE: [u8; 13] = *b"NCASE_SigmaS2";
+pub const PRIVACY_KEY_INFO: [u8; 10] = *b"PrivacyKey";
+pub const GROUP_KEY_INFO: [u8; 13] = *b"GroupKey v1.0";
+pub const GROUP_KEY_HASH_INFO: [u8; 12] = *b"GroupKeyHash";
 
 pub const CRYPTO_SYMMETRIC_KEY_LENGTH_BITS: usize = 128;
 pub const CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES: usize = CRYPTO_SYMMETRIC_KEY_LENGTH_BITS / 8;
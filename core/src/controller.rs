@@ -8,7 +8,7 @@ use bytes::BytesMut;
 use tokio::{
     sync::{
         mpsc::{Receiver, Sender},
-        RwLock,
+        Notify, RwLock,
     },
     task::JoinHandle,
 };
@@ -23,15 +23,19 @@ use crate::{
         device_type::root_node::DEVICE_TYPE_ROOT_NODE,
         endpoint::root_endpoint,
     },
-    exchange::ExchangeManager,
+    exchange::{
+        ExchangeError, ExchangeEvent, ExchangeKey, ExchangeManager, ExchangeMessageAction,
+        MrpParameters,
+    },
     message::status_report::{GeneralCode, StatusReport},
-    message::{Message, SessionType},
+    message::{Message, SessionType, UDP_MESSAGE_LIMIT},
     secure_channel::pake::{PASEManager, Pake2},
     session_context::{
         SecureChannelProtocolCode, SecureChannelProtocolOpCode, SecureSessionContext,
         SessionContext, SessionManager,
     },
     transport::{udp::UdpInterface, SocketAddr},
+    util::time::{Instant, Timer, TokioClock},
 };
 
 pub type TlvAnyData = heapless::Vec<u8, 1024>;
@@ -43,7 +47,13 @@ pub struct Controller<'a, H> {
     pase_session_ids: HashSet<u16>,
     // TODO: Requires a different strategy for no_std
     exchange_manager: Arc<RwLock<ExchangeManager>>,
-    message_sender: Sender<(Message, SocketAddr)>,
+    clock: Arc<TokioClock>,
+    /// Wakes the receiving task up when a sent message starts an exchange
+    /// timer, which can be earlier than the one it's waiting for
+    exchange_timer: Arc<Notify>,
+    /// Where to send retransmissions and acks of each exchange
+    peers: Arc<RwLock<HashMap<ExchangeKey, SocketAddr>>>,
+    message_sender: Sender<(Vec<u8>, SocketAddr)>,
     udp: UdpInterface,
     // TODO: This should probably be in the exchange manager
     message_store: Arc<RwLock<HashMap<(u16, SessionType), Message>>>,
@@ -56,7 +66,7 @@ impl<'a, H> Controller<'a, H> {
         to the exchange, and then polls at its level, sending messages appropriately?
         That isolates running the loop in one place, here.
          */
-        let (sender, receiver) = tokio::sync::mpsc::channel::<(Message, SocketAddr)>(32);
+        let (sender, receiver) = tokio::sync::mpsc::channel::<(Vec<u8>, SocketAddr)>(32);
         let local_address: SocketAddr = "0.0.0.0:5541".parse().unwrap();
         let udp = UdpInterface::new(local_address).await;
         // Temporary
@@ -65,12 +75,16 @@ impl<'a, H> Controller<'a, H> {
             .await
             .unwrap();
         let device = Device::new(node, handler);
+        let clock = Arc::new(TokioClock::new());
         let mut controller = Controller {
             fabric: (),
             device,
             last_node_id: 0,
             pase_session_ids: HashSet::with_capacity(32),
-            exchange_manager: Arc::new(RwLock::new(ExchangeManager::new())),
+            exchange_manager: Arc::new(RwLock::new(ExchangeManager::with_clock(clock.clone()))),
+            clock,
+            exchange_timer: Arc::new(Notify::new()),
+            peers: Arc::new(RwLock::new(HashMap::new())),
             message_sender: sender,
             udp,
             message_store: Arc::new(RwLock::new(HashMap::with_capacity(64))),
@@ -81,7 +95,7 @@ impl<'a, H> Controller<'a, H> {
         controller
     }
 
-    pub async fn start(&self, receiver: Receiver<(Message, SocketAddr)>) -> JoinHandle<()> {
+    pub async fn start(&self, receiver: Receiver<(Vec<u8>, SocketAddr)>) -> JoinHandle<()> {
         let recv_socket = self.udp.socket();
         let message_store = self.message_store.clone();
 
@@ -89,28 +103,112 @@ impl<'a, H> Controller<'a, H> {
 
         tokio::spawn(async move {
             let mut receiver = receiver;
-            while let Some((message, recipient)) = receiver.recv().await {
-                let mut buf = BytesMut::with_capacity(1024);
-                // TODO: encrypt message
-                message.encode(&mut buf, None);
-                let buf = buf.to_vec();
+            while let Some((buf, recipient)) = receiver.recv().await {
                 // println!("Sending message to {recipient}");
                 udp.send_to(&buf, recipient).await;
             }
             // println!("Receiver stopped receiving messages")
         });
 
-        // Spawn a task to receive messages and process them
+        // Spawn a task to receive messages and process them, and to
+        // retransmit and acknowledge messages when exchange timers expire
         let exchange_manager = self.exchange_manager.clone();
+        let clock = self.clock.clone();
+        let exchange_timer = self.exchange_timer.clone();
+        let peers = self.peers.clone();
+        let message_sender = self.message_sender.clone();
         tokio::spawn(async move {
             loop {
-                // TODO: reset and reuse
-                let mut buf = [0u8; 1024];
-                let (len, peer) = recv_socket.recv_from(&mut buf).await.unwrap();
+                // The message keeps the buffer, and is decrypted in it
+                let mut buf = BytesMut::zeroed(UDP_MESSAGE_LIMIT);
+                let next_timeout = exchange_manager.read().await.next_timeout();
+                let received = tokio::select! {
+                    received = recv_socket.recv_from(&mut buf) => received,
+                    _ = exchange_timer.notified() => continue,
+                    _ = exchange_timeout(&*clock, next_timeout) => {
+                        let events = exchange_manager.write().await.poll();
+                        let mut peers = peers.write().await;
+                        for event in events {
+                            match event {
+                                ExchangeEvent::Send { exchange, packet } => {
+                                    let Some(peer) = peers.get(&exchange) else {
+                                        continue;
+                                    };
+                                    message_sender.send((packet.to_vec(), *peer)).await.unwrap();
+                                }
+                                ExchangeEvent::Failed { exchange, message_counter } => {
+                                    println!("Exchange {} failed, message {message_counter} was not acknowledged", exchange.exchange_id);
+                                }
+                                ExchangeEvent::Expired { exchange } => {
+                                    println!("Exchange {} expired", exchange.exchange_id);
+                                }
+                                ExchangeEvent::SessionExpired { session_id } => {
+                                    println!("Session {session_id} ran out of message counters");
+                                    peers.retain(|key, _| key.session_id != session_id);
+                                }
+                                ExchangeEvent::SessionEvicted { session_id } => {
+                                    println!("Session {session_id} was evicted");
+                                    peers.retain(|key, _| key.session_id != session_id);
+                                }
+                                ExchangeEvent::Received { message } => {
+                                    let key = (
+                                        message.message_header.session_id,
+                                        message.message_header.session_type,
+                                    );
+                                    message_store.write().await.insert(key, message);
+                                }
+                                ExchangeEvent::SyncFailed { peer } => {
+                                    println!("Group peer {:x} didn't synchronize its counter", peer.source_node_id);
+                                }
+                            }
+                        }
+                        // Forget the peers of exchanges that were closed
+                        let mut exchange_manager = exchange_manager.write().await;
+                        peers.retain(|key, _| exchange_manager.find_exchange(key).is_some());
+                        continue;
+                    }
+                };
+                let (len, peer) = received.unwrap();
+                buf.truncate(len);
                 // Decode the message but not decrypt it
-                // println!("Received from {peer} {}", hex::encode(&buf[..len]));
-                let mut message = Message::decode(&buf[..len]);
-                exchange_manager.write().await.receive_message(&mut message);
+                // println!("Received from {peer} {}", hex::encode(&buf));
+                let mut message = match Message::decode(buf) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        println!("Dropping message from {peer}: {e}");
+                        continue;
+                    }
+                };
+                let action = exchange_manager.write().await.receive_message(&mut message);
+                // Only accepted messages tell where the peer of their exchange is
+                match action {
+                    // The exchange manager logs why the message was dropped
+                    ExchangeMessageAction::Drop => continue,
+                    ExchangeMessageAction::AckAndDrop => {
+                        peers
+                            .write()
+                            .await
+                            .insert(ExchangeKey::received(&message), peer);
+                        let mut ack = BytesMut::new();
+                        if let Err(e) = exchange_manager
+                            .write()
+                            .await
+                            .encode_standalone_ack(&message, &mut ack)
+                        {
+                            println!("Not sending acknowledgement: {e}");
+                        }
+                        if !ack.is_empty() {
+                            message_sender.send((ack.to_vec(), peer)).await.unwrap();
+                        }
+                        continue;
+                    }
+                    ExchangeMessageAction::Process => {
+                        peers
+                            .write()
+                            .await
+                            .insert(ExchangeKey::received(&message), peer);
+                    }
+                }
                 let key = (
                     message.message_header.session_id,
                     message.message_header.session_type,
@@ -126,13 +224,14 @@ impl<'a, H> Controller<'a, H> {
     }
 
     /// Commission a device with a PIN, creating a new session and interacting with
-    /// the remote node until the process is completed or fails
+    /// the remote node until the process is completed or fails.
+    /// Fails if no exchange can be opened with the node.
     pub async fn commission_with_pin(
         &mut self,
         remote_address: SocketAddr,
         discriminator: u8,
         pin: u32,
-    ) {
+    ) -> Result<(), ExchangeError> {
         /*
         How do we send and receive simultaneously? We want MRP built in, so for each stage of commissioning,
         we would want to be able to retry sending until we get an ack. Then after processing, move to the
@@ -153,7 +252,7 @@ impl<'a, H> Controller<'a, H> {
             .exchange_manager
             .write()
             .await
-            .new_initiator_exchange_unsecured();
+            .new_initiator_exchange_unsecured(node_id)?;
         let mut pake_interaction =
             PASEManager::initiator(pin, session_id, exchange_id, message_counter, node_id);
 
@@ -167,34 +266,34 @@ impl<'a, H> Controller<'a, H> {
             }
         };
         self.send_message(request_message, remote_address.clone())
-            .await;
+            .await?;
         let response_message = self.wait_for_message(0, session_type).await;
         let next_ack = response_message.next_ack();
 
         let peer_session_id = response_message.message_header.session_id;
         pake_interaction.set_pbkdf_param_response(
-            heapless::Vec::from_slice(response_message.payload.as_slice()).unwrap(),
+            heapless::Vec::from_slice(&response_message.payload).unwrap(),
         );
         let mut pake1_message = {
             let mut writer = self.exchange_manager.write().await;
             let session = writer.session_context_mut(session_id);
             match session {
-                SessionContext::Unsecured(session) => pake_interaction.pake1(session),
+                SessionContext::Unsecured(session) => pake_interaction.pake1(session).unwrap(),
                 _ => todo!(),
             }
         };
         // Acknowledge previous response
         pake1_message.with_ack(next_ack);
         self.send_message(pake1_message, remote_address.clone())
-            .await;
+            .await?;
         let mut pake2_message = self.wait_for_message(0, session_type).await;
         // pake2_message.decrypt(None);
         let next_ack = pake2_message.next_ack();
-        let pake2 = Pake2::from_tlv(&pake2_message.payload);
+        let pake2 = Pake2::from_tlv(&pake2_message.payload).unwrap();
         let mut pake3_message = pake_interaction.pake3(&pake2);
         pake3_message.with_ack(next_ack);
         self.send_message(pake3_message, remote_address.clone())
-            .await;
+            .await?;
         let pake_finished_message = self.wait_for_message(0, session_type).await;
         let next_ack = pake_finished_message.next_ack();
 
@@ -204,12 +303,8 @@ impl<'a, H> Controller<'a, H> {
             payload_header.protocol_opcode,
             SecureChannelProtocolOpCode::StatusReport as u8
         );
-        let status_report = StatusReport::from_payload(&pake_finished_message.payload);
-        assert_eq!(status_report.general_code, GeneralCode::Success);
-        assert_eq!(
-            status_report.protocol_code,
-            SecureChannelProtocolCode::SessionEstablishmentSuccess as u16
-        );
+        let status_report = StatusReport::from_payload(&pake_finished_message.payload).unwrap();
+        assert_eq!(status_report, StatusReport::session_establishment_success());
 
         // Get secrets
         let (k_e, c_a, c_b) = pake_interaction.get_secrets();
@@ -217,6 +312,8 @@ impl<'a, H> Controller<'a, H> {
         // Create a new secured session
         let mut secured_session =
             SecureSessionContext::new_pase(true, false, session_id, peer_session_id, k_e, &[]);
+        secured_session.peer_mrp_parameters =
+            MrpParameters::from_session_parameters(pake_interaction.peer_session_parameters());
         drop(pake_interaction);
 
         // Send standalone ack for now, then work on the interaction client
@@ -226,7 +323,8 @@ impl<'a, H> Controller<'a, H> {
         self.exchange_manager
             .write()
             .await
-            .add_session(SessionContext::Secure(secured_session));
+            .add_session(SessionContext::Secure(secured_session))
+            .unwrap();
 
         /*
         How do we know when we've received a message that's moved the processing forward?
@@ -244,6 +342,7 @@ impl<'a, H> Controller<'a, H> {
         // - arm failsafe
         // - regulatory info
         // OperationalCredentialsClusterClient
+        Ok(())
     }
 
     /// Wait for a message, decrypting it when found (if part of an encrypted session)
@@ -261,14 +360,20 @@ impl<'a, H> Controller<'a, H> {
             if has_key {
                 let mut writer = self.message_store.write().await;
                 let mut message = writer.remove(&lookup_key).unwrap();
-                // Decrypt the message if it is secure
+                // Decrypt the message if it is secure, and the exchange manager
+                // could not decrypt it when it was received
                 match session_type {
-                    SessionType::SecureUnicast(_) | SessionType::SecureGroup(_) => {
+                    SessionType::SecureUnicast(_) | SessionType::SecureGroup(_)
+                        if message.payload_header.is_none() =>
+                    {
                         let mut writer = self.exchange_manager.write().await;
                         let SessionContext::Secure(session) = writer.session_context(session_id).unwrap() else {
                             panic!("Session in context not a SecureSession");
                         };
-                        message.decrypt(Some(&session.decryption_key));
+                        if let Err(e) = message.decrypt(Some(&session.decryption_key)) {
+                            println!("Dropping message: {e}");
+                            continue;
+                        }
                     }
                     _ => {}
                 }
@@ -278,21 +383,39 @@ impl<'a, H> Controller<'a, H> {
         }
     }
 
-    /// Send a message, encrypting it if required
-    async fn send_message(&mut self, mut message: Message, peer: SocketAddr) {
-        // let session_context = self
-        //     .exchange_manager
-        //     .session_context(message.message_header.session_id);
-        // match &session_context {
-        //     SessionContext::MCSP => {}
-        //     SessionContext::Secure(session) => {
-        //         message.encrypt(&session.encryption_key);
-        //     }
-        //     SessionContext::Unsecured(_) => todo!(),
-        // }
+    /// Send a message, encrypting it if required. The exchange manager keeps
+    /// reliable messages until they're acknowledged, to retransmit them.
+    async fn send_message(
+        &mut self,
+        mut message: Message,
+        peer: SocketAddr,
+    ) -> Result<(), ExchangeError> {
+        let mut buf = BytesMut::with_capacity(UDP_MESSAGE_LIMIT);
+        self.exchange_manager
+            .write()
+            .await
+            .encode_message(&mut message, &mut buf)?;
+        self.peers
+            .write()
+            .await
+            .insert(ExchangeKey::sent(&message), peer);
+        // The message may have started an earlier retransmission timer
+        self.exchange_timer.notify_one();
 
         // Send message
-        self.message_sender.send((message, peer)).await.unwrap();
+        self.message_sender
+            .send((buf.to_vec(), peer))
+            .await
+            .unwrap();
+        Ok(())
+    }
+}
+
+/// Wait until an exchange has to retransmit, acknowledge or expire, if ever
+async fn exchange_timeout(clock: &impl Timer, at: Option<Instant>) {
+    match at {
+        Some(at) => clock.sleep_until(at).await,
+        None => std::future::pending().await,
     }
 }
 
@@ -303,7 +426,7 @@ pub async fn commission_with_pin<'a>(
     remote_address: SocketAddr,
     discriminator: u8,
     pin: u32,
-) -> () {
+) -> Result<(), ExchangeError> {
     /*
     The flaw here is that we're not using the inner layers to send responses, we want to do that.
     How do we get there?
@@ -322,7 +445,7 @@ pub async fn commission_with_pin<'a>(
         .exchange_manager
         .write()
         .await
-        .new_initiator_exchange_unsecured();
+        .new_initiator_exchange_unsecured(node_id)?;
     let mut pake_interaction =
         PASEManager::initiator(pin, session_id, exchange_id, message_counter, node_id);
 
@@ -337,19 +460,19 @@ pub async fn commission_with_pin<'a>(
     };
     controller
         .send_message(request_message, remote_address.clone())
-        .await;
+        .await?;
     let response_message = controller.wait_for_message(0, session_type).await;
     let next_ack = response_message.next_ack();
 
     let peer_session_id = response_message.message_header.session_id;
     pake_interaction.set_pbkdf_param_response(
-        heapless::Vec::from_slice(response_message.payload.as_slice()).unwrap(),
+        heapless::Vec::from_slice(&response_message.payload).unwrap(),
     );
     let mut pake1_message = {
         let mut writer = controller.exchange_manager.write().await;
         let session = writer.session_context_mut(session_id);
         match session {
-            SessionContext::Unsecured(session) => pake_interaction.pake1(session),
+            SessionContext::Unsecured(session) => pake_interaction.pake1(session).unwrap(),
             _ => todo!(),
         }
     };
@@ -357,16 +480,16 @@ pub async fn commission_with_pin<'a>(
     pake1_message.with_ack(next_ack);
     controller
         .send_message(pake1_message, remote_address.clone())
-        .await;
+        .await?;
     let mut pake2_message = controller.wait_for_message(0, session_type).await;
     // pake2_message.decrypt(None);
     let next_ack = pake2_message.next_ack();
-    let pake2 = Pake2::from_tlv(&pake2_message.payload);
+    let pake2 = Pake2::from_tlv(&pake2_message.payload).unwrap();
     let mut pake3_message = pake_interaction.pake3(&pake2);
     pake3_message.with_ack(next_ack);
     controller
         .send_message(pake3_message, remote_address.clone())
-        .await;
+        .await?;
     let pake_finished_message = controller.wait_for_message(0, session_type).await;
     let next_ack = pake_finished_message.next_ack();
 
@@ -376,12 +499,8 @@ pub async fn commission_with_pin<'a>(
         payload_header.protocol_opcode,
         SecureChannelProtocolOpCode::StatusReport as u8
     );
-    let status_report = StatusReport::from_payload(&pake_finished_message.payload);
-    assert_eq!(status_report.general_code, GeneralCode::Success);
-    assert_eq!(
-        status_report.protocol_code,
-        SecureChannelProtocolCode::SessionEstablishmentSuccess as u16
-    );
+    let status_report = StatusReport::from_payload(&pake_finished_message.payload).unwrap();
+    assert_eq!(status_report, StatusReport::session_establishment_success());
 
     // Get secrets
     let (k_e, c_a, c_b) = pake_interaction.get_secrets();
@@ -389,6 +508,8 @@ pub async fn commission_with_pin<'a>(
     // Create a new secured session
     let mut secured_session =
         SecureSessionContext::new_pase(true, false, session_id, peer_session_id, k_e, &[]);
+    secured_session.peer_mrp_parameters =
+        MrpParameters::from_session_parameters(pake_interaction.peer_session_parameters());
     drop(pake_interaction);
 
     // Send standalone ack for now, then work on the interaction client
@@ -399,7 +520,8 @@ pub async fn commission_with_pin<'a>(
         .exchange_manager
         .write()
         .await
-        .add_session(SessionContext::Secure(secured_session));
+        .add_session(SessionContext::Secure(secured_session))
+        .unwrap();
 
     /*
     How do we know when we've received a message that's moved the processing forward?
@@ -425,4 +547,5 @@ pub async fn commission_with_pin<'a>(
     // - arm failsafe
     // - regulatory info
     // OperationalCredentialsClusterClient
+    Ok(())
 }
@@ -141,7 +141,7 @@ impl KeyPair {
 
         a
     }
-    fn get_public_key(&self, pub_key: &mut [u8]) -> usize {
+    pub fn get_public_key(&self, pub_key: &mut [u8]) -> usize {
         let point = self.public_key_point().to_encoded_point(false);
         let bytes = point.as_bytes();
         let len = bytes.len();
@@ -6,6 +6,10 @@ use ccm::{
 };
 use sha2::Sha256;
 
+use crate::constants::{
+    CRYPTO_AEAD_NONCE_LENGTH_BYTES, CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES, PRIVACY_KEY_INFO,
+};
+
 pub(crate) mod keypair;
 pub(crate) mod sha256;
 pub(crate) mod spake2p;
@@ -15,8 +19,9 @@ type AesCcm = Ccm<Aes128, U16, U13>;
 // Random bytes generator
 // TODO make it a drng
 pub fn fill_random(out: &mut [u8]) {
-    let mut rng = rfc6979::HmacDrbg::<Sha256>::new(&[], &[], &[]);
-    rng.fill_bytes(out);
+    // A DRBG that's seeded with the same input on every call repeats its
+    // output, so the OS generator is used until there's a seeded one
+    rand::RngCore::fill_bytes(&mut rand::thread_rng(), out);
 }
 
 #[inline(always)]
@@ -50,12 +55,14 @@ pub fn encrypt_in_place(
     buffer.len()
 }
 
+/// Decrypt `data` in place, returning the length of the plain text, or an
+/// error if the data fails its integrity check.
 pub fn decrypt_in_place(
     key: &[u8],
     nonce: &[u8],
     associated_data: &[u8],
     data: &mut [u8],
-) -> usize {
+) -> Result<usize, ccm::Error> {
     use ccm::{AeadInPlace, KeyInit};
 
     let key = GenericArray::from_slice(key);
@@ -63,10 +70,37 @@ pub fn decrypt_in_place(
     let cipher = AesCcm::new(key);
 
     let mut buffer = SliceBuffer::new(data, data.len());
-    cipher
-        .decrypt_in_place(nonce, associated_data, &mut buffer)
-        .unwrap();
-    buffer.len()
+    cipher.decrypt_in_place(nonce, associated_data, &mut buffer)?;
+    Ok(buffer.len())
+}
+
+/// Derive the privacy key from an operational or group encryption key (4.8.1)
+pub fn privacy_key(encryption_key: &[u8]) -> [u8; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES] {
+    let mut key = [0u8; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES];
+    hkdf_sha256(&[], encryption_key, &PRIVACY_KEY_INFO, &mut key);
+    key
+}
+
+/// Obfuscate or deobfuscate `data` in place with AES-CTR (Crypto_Privacy_Encrypt),
+/// where `data` starts at `offset` bytes into the obfuscated text.
+///
+/// The counter blocks are those that AES-CCM uses for its payload, so the
+/// first block is `flags || nonce || 1`.
+pub fn privacy_crypt_in_place(privacy_key: &[u8], nonce: &[u8], offset: usize, data: &mut [u8]) {
+    use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+
+    let mut counter = [0u8; 16];
+    // Flags hold L - 1, where L is the width of the counter
+    counter[0] = (15 - CRYPTO_AEAD_NONCE_LENGTH_BYTES - 1) as u8;
+    counter[1..1 + CRYPTO_AEAD_NONCE_LENGTH_BYTES].copy_from_slice(nonce);
+    counter[15] = 1;
+
+    let mut cipher = ctr::Ctr128BE::<Aes128>::new(
+        GenericArray::from_slice(privacy_key),
+        GenericArray::from_slice(&counter),
+    );
+    cipher.seek(offset);
+    cipher.apply_keystream(data);
 }
 
 #[derive(Debug)]
@@ -139,8 +173,12 @@ mod tests {
 
         let mut payload = encrypted.clone();
 
-        let dec1 = decrypt_in_place(&dec_key, &nonce, &header_bytes, &mut payload);
+        let dec1 = decrypt_in_place(&dec_key, &nonce, &header_bytes, &mut payload).unwrap();
         assert_eq!(bytes.len(), dec1);
         assert_eq!(&bytes[..], &payload[..dec1]);
+
+        let mut payload = encrypted.clone();
+        payload[0] ^= 1;
+        assert!(decrypt_in_place(&dec_key, &nonce, &header_bytes, &mut payload).is_err());
     }
 }
@@ -1,20 +1,56 @@
-use std::collections::HashMap;
+use core::{fmt, time::Duration};
+use std::{collections::HashMap, sync::Arc};
 
+use bytes::BytesMut;
 use tokio::sync::mpsc::Receiver;
 
 use crate::{
+    constants::CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES,
     crypto::fill_random,
-    message::{ExchangeFlags, Message, MessageFlags, SecurityFlags, SessionID, SessionType},
-    secure_channel::MSG_COUNTER_WINDOW_SIZE,
+    message::{
+        ExchangeFlags, Message, MessageError, MessageFlags, MessageHeader, NodeID, ProtocolHeader,
+        ProtocolID, SecurityFlags, SessionID, SessionType,
+    },
+    secure_channel::{pake::SessionParameters, MSG_COUNTER_WINDOW_SIZE},
     session_context::{
-        SecureSessionContext, SecureSessionType, SessionContext, SessionManager, SessionRole,
-        UnsecuredSessionContext,
+        CounterStore, GroupCounterCheck, GroupKeyStore, GroupPeer, MsgCounterSyncRequest,
+        MsgCounterSyncResponse, PendingRequest, ResumptionStore, ResumptionTable,
+        SecureChannelProtocolOpCode, SecureSessionContext, SecureSessionType, SessionContext,
+        SessionError, SessionManager, SessionRole, UnsecuredSessionContext,
     },
+    util::time::{Clock, Instant, StdClock},
 };
 
+/// The most times a reliable message is sent, including the first time (4.12.8)
+pub const MRP_MAX_TRANSMISSIONS: u8 = 5;
+/// The base of the exponential backoff between retransmissions
+pub const MRP_BACKOFF_BASE: f64 = 1.6;
+/// The most that jitter lengthens a backoff by, as a fraction of it
+pub const MRP_BACKOFF_JITTER: f64 = 0.25;
+/// The longest idle or active interval that a peer can ask for (4.12.8)
+pub const MRP_MAX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Allows for the time that the peer takes to process a message
+pub const MRP_BACKOFF_MARGIN: f64 = 1.1;
+/// The retransmissions that use the base interval, before backing off
+pub const MRP_BACKOFF_THRESHOLD: u8 = 1;
+/// How long an ack waits to be piggybacked before it is sent on its own
+pub const MRP_STANDALONE_ACK_TIMEOUT: Duration = Duration::from_millis(200);
+/// The most exchanges that can be open at a time
+pub const EXCHANGE_LIMIT: usize = 32;
+/// How long an exchange stays open without sending or receiving a message
+pub const EXCHANGE_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct ExchangeManager {
-    exchanges: HashMap<u16, Exchange>,
+    exchanges: HashMap<ExchangeKey, Exchange>,
+    /// The ID of the next exchange that this node initiates
+    next_exchange_id: u16,
+    /// The handlers of protocols, by vendor ID and protocol ID
+    handlers: HashMap<(u16, u16), Box<dyn ProtocolHandler + Send + Sync>>,
     session_manager: SessionManager,
+    clock: Arc<dyn Clock + Send + Sync>,
+    /// Events of received messages and evicted sessions, which
+    /// [ExchangeManager::poll] returns
+    received_events: Vec<ExchangeEvent>,
 }
 
 #[derive(Debug)]
@@ -22,19 +58,384 @@ pub struct Exchange {
     exchange_id: u16,
     exchange_role: ExchangeRole,
     session_id: u16,
-    /// Message Counters (4.5.5)
-    message_counter: u32,
-    peer_message_counter: MessageCounter,
-    acknowledgements: (),
-    retransmissions: (),
+    /// The node that initiated the exchange, in sessions that peers share
+    initiator_node_id: Option<u64>,
+    /// Received messages that haven't been acknowledged yet. Only the latest
+    /// can be piggybacked, the others are due straight away.
+    acknowledgements: Vec<AcknowledgementTable>,
+    /// A sent message that hasn't been acknowledged yet
+    retransmission: Option<RetransmissionTable>,
+    /// When a message was last sent or received on the exchange
+    active_at: Instant,
+    /// The exchange was closed, and is removed once its last reliable
+    /// message is acknowledged
+    closing: bool,
+}
+
+/// Why a message can't be sent on an exchange
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExchangeError {
+    /// An exchange has one reliable message in flight at a time, and the
+    /// previous one hasn't been acknowledged (4.12.5.1)
+    RetransmissionPending { message_counter: u32 },
+    /// The exchange table is full, and every exchange in it has a message
+    /// in flight
+    Busy,
+    /// There's no open exchange with the key
+    UnknownExchange(ExchangeKey),
+    /// No handler is registered for the protocol of a message
+    NoHandler { vendor_id: u16, protocol_id: u16 },
+    /// The session ran out of message counters, and was removed (4.5.1.2)
+    SessionExpired(SessionID),
+    /// There's no current operational key of the group session that a
+    /// group message is sent in (4.16.3)
+    NoGroupKey(SessionID),
+}
+
+impl fmt::Display for ExchangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExchangeError::RetransmissionPending { message_counter } => write!(
+                f,
+                "message {message_counter} is waiting for an acknowledgement"
+            ),
+            ExchangeError::Busy => write!(f, "too many exchanges are open"),
+            ExchangeError::UnknownExchange(key) => write!(
+                f,
+                "exchange {} of session {} is not open",
+                key.exchange_id, key.session_id
+            ),
+            ExchangeError::NoHandler {
+                vendor_id,
+                protocol_id,
+            } => write!(
+                f,
+                "no handler for protocol {vendor_id:04x}:{protocol_id:04x}"
+            ),
+            ExchangeError::SessionExpired(session_id) => {
+                write!(f, "session {session_id} ran out of message counters")
+            }
+            ExchangeError::NoGroupKey(session_id) => {
+                write!(f, "no current key for group session {session_id}")
+            }
+        }
+    }
+}
+
+/// Something that the exchange owner has to do when exchange timers expire
+#[derive(Debug)]
+pub enum ExchangeEvent {
+    /// Send a retransmission or a standalone acknowledgement to the peer
+    Send {
+        exchange: ExchangeKey,
+        packet: BytesMut,
+    },
+    /// A reliable message was not acknowledged after [MRP_MAX_TRANSMISSIONS],
+    /// and the exchange has failed (4.12.5.1)
+    Failed {
+        exchange: ExchangeKey,
+        message_counter: u32,
+    },
+    /// The exchange was idle for [EXCHANGE_IDLE_TIMEOUT], and was closed
+    Expired { exchange: ExchangeKey },
+    /// The session ran out of message counters while sending an ack, and
+    /// was removed with its exchanges (4.5.1.2)
+    SessionExpired { session_id: SessionID },
+    /// The session was the least recently active when another one needed
+    /// room, and was removed with its exchanges (4.13.2.1)
+    SessionEvicted { session_id: SessionID },
+    /// A group message that waited for its sender's counter to be
+    /// synchronized, and can now be dispatched
+    Received { message: Message },
+    /// A group peer didn't answer MCSP in time, and the messages that
+    /// waited for it were dropped (4.8.1.1)
+    SyncFailed { peer: GroupPeer },
+}
+
+/// A protocol that the messages of exchanges are dispatched to. Handlers are
+/// registered on an [ExchangeManager] by vendor ID and protocol ID.
+pub trait ProtocolHandler {
+    /// Handle a message, returning the response to send on its exchange
+    fn on_message(
+        &mut self,
+        context: &mut ProtocolContext<'_>,
+        message: &Message,
+    ) -> Option<Message>;
+
+    /// A session was removed, so the state that belongs to it, such as
+    /// subscriptions, has to be dropped
+    fn on_session_removed(&mut self, session_id: SessionID) {}
+}
+
+impl<F> ProtocolHandler for F
+where
+    F: FnMut(&mut ProtocolContext<'_>, &Message) -> Option<Message>,
+{
+    fn on_message(
+        &mut self,
+        context: &mut ProtocolContext<'_>,
+        message: &Message,
+    ) -> Option<Message> {
+        self(context, message)
+    }
+}
+
+/// The state that a [ProtocolHandler] can use while it handles a message
+pub struct ProtocolContext<'a> {
+    /// The exchange of the message
+    pub exchange: ExchangeKey,
+    session_manager: &'a mut SessionManager,
+}
+
+impl ProtocolContext<'_> {
+    /// The session of the exchange. Unsecured exchanges are in the
+    /// [unsecured session](ProtocolContext::unsecured_session_mut) of their
+    /// initiator, and group messages don't have one.
+    pub fn session_mut(&mut self) -> Option<&mut SessionContext> {
+        let session_id = self.exchange.session_id;
+        self.session_manager.get_session(session_id)?;
+        Some(self.session_manager.get_session_mut(session_id))
+    }
+
+    /// The unsecured session of the exchange's initiator
+    pub fn unsecured_session_mut(&mut self) -> Option<&mut UnsecuredSessionContext> {
+        if self.exchange.session_id != 0 {
+            return None;
+        }
+        self.session_manager
+            .find_unsecured_session_mut(self.exchange.initiator_node_id?)
+    }
+
+    /// Reserve a session ID for a session that the protocol establishes in
+    /// this exchange. The ID is released if the exchange closes first.
+    pub fn new_session(&mut self, role: SessionRole) -> SessionID {
+        self.session_manager.new_session(role, self.exchange)
+    }
+
+    /// Release a session ID reserved with [ProtocolContext::new_session],
+    /// as its session couldn't be established
+    pub fn release_session(&mut self, session_id: SessionID) {
+        self.session_manager.release_session(session_id)
+    }
+
+    /// The CASE sessions that peers can resume
+    pub fn resumption_records(&mut self) -> &mut ResumptionTable {
+        &mut self.session_manager.resumption_records
+    }
+
+    /// Add a session that the protocol established
+    pub fn add_session(
+        &mut self,
+        session_context: SessionContext,
+    ) -> Result<SessionID, SessionError> {
+        self.session_manager.add_session(session_context, false)
+    }
+}
+
+/// The MRP parameters of a peer (4.12.8)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MrpParameters {
+    /// The retransmission interval when the peer is idle
+    pub idle_retrans_timeout: Duration,
+    /// The retransmission interval when the peer is active
+    pub active_retrans_timeout: Duration,
+    /// How long a peer stays active after it sends a message
+    pub active_threshold: Duration,
+}
+
+impl Default for MrpParameters {
+    fn default() -> Self {
+        Self {
+            idle_retrans_timeout: Duration::from_millis(500),
+            active_retrans_timeout: Duration::from_millis(300),
+            active_threshold: Duration::from_millis(4000),
+        }
+    }
+}
+
+impl MrpParameters {
+    /// The interval that retransmissions back off from, which is shorter
+    /// when the peer is active
+    pub fn base_interval(&self, peer_active_at: Option<Instant>, now: Instant) -> Duration {
+        match peer_active_at {
+            Some(at) if now.saturating_duration_since(at) < self.active_threshold => {
+                self.active_retrans_timeout
+            }
+            _ => self.idle_retrans_timeout,
+        }
+    }
+
+    /// The parameters that a peer sent during session establishment, where
+    /// missing values take their defaults
+    pub fn from_session_parameters(parameters: Option<&SessionParameters>) -> Self {
+        let mut mrp = Self::default();
+        let Some(parameters) = parameters else {
+            return mrp;
+        };
+        let interval = |millis: u32| Duration::from_millis(millis as u64).min(MRP_MAX_INTERVAL);
+        if let Some(idle) = parameters.session_idle_interval {
+            mrp.idle_retrans_timeout = interval(idle);
+        }
+        if let Some(active) = parameters.session_active_interval {
+            mrp.active_retrans_timeout = interval(active);
+        }
+        if let Some(threshold) = parameters.session_active_threshold {
+            mrp.active_threshold = Duration::from_millis(threshold as u64);
+        }
+        mrp
+    }
+
+    /// The parameters to send to peers during session establishment
+    pub fn session_parameters(&self) -> SessionParameters {
+        SessionParameters {
+            session_idle_interval: Some(self.idle_retrans_timeout.as_millis() as u32),
+            session_active_interval: Some(self.active_retrans_timeout.as_millis() as u32),
+            session_active_threshold: Some(
+                self.active_threshold.as_millis().min(u16::MAX as u128) as u16
+            ),
+        }
+    }
+}
+
+/// The interval that retransmissions to the peer of an exchange back off
+/// from. The peer's parameters are only known for sessions that were
+/// established with them, and its session remembers when it was last active.
+fn base_interval(session_manager: &SessionManager, key: &ExchangeKey, now: Instant) -> Duration {
+    match session_manager.get_session(key.session_id) {
+        Some(SessionContext::Secure(session)) => session
+            .peer_mrp_parameters
+            .base_interval(session.peer_active_at, now),
+        _ => {
+            let peer_active_at = key
+                .initiator_node_id
+                .and_then(|node_id| session_manager.find_unsecured_session(node_id))
+                .and_then(|session| session.peer_active_at);
+            MrpParameters::default().base_interval(peer_active_at, now)
+        }
+    }
+}
+
+/// Address a message of an unsecured exchange to or from the ephemeral node
+/// ID of its initiator (4.12.1.1), unless the handler that made it already
+/// did
+fn address_unsecured_message(key: &ExchangeKey, message: &mut Message) {
+    let header = &mut message.message_header;
+    let (SessionType::UnsecuredSession, Some(node_id)) =
+        (&header.session_type, key.initiator_node_id)
+    else {
+        return;
+    };
+    match key.role {
+        ExchangeRole::Initiator if header.source_node_id.is_none() => {
+            header.source_node_id = Some(node_id);
+            header.message_flags |= MessageFlags::SOURCE_NODE_ID_PRESENT;
+        }
+        ExchangeRole::Responder if header.dest_node_id.is_none() => {
+            header.dest_node_id = Some(NodeID::Unique(node_id));
+            header.message_flags |= MessageFlags::DSIZ_64_BIT_NODE_ID;
+        }
+        _ => {}
+    }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+/// How long to wait for an ack before sending a message again (4.12.2.1).
+///
+/// `retransmissions` is how many times the message has been sent again, and
+/// `jitter` is a random value in `[0, 1)`.
+pub fn mrp_backoff_time(base_interval: Duration, retransmissions: u8, jitter: f64) -> Duration {
+    let exponent = retransmissions.saturating_sub(MRP_BACKOFF_THRESHOLD) as i32;
+    base_interval.mul_f64(
+        MRP_BACKOFF_MARGIN * MRP_BACKOFF_BASE.powi(exponent) * (1.0 + jitter * MRP_BACKOFF_JITTER),
+    )
+}
+
+fn random_jitter() -> f64 {
+    let mut jitter = [0u8; 4];
+    fill_random(&mut jitter);
+    u32::from_le_bytes(jitter) as f64 / (u32::MAX as f64 + 1.0)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum ExchangeRole {
     Initiator,
     Responder,
 }
 
+/// Identifies an exchange. Exchange IDs are only unique per session and
+/// role, as the initiator of an exchange picks its ID (4.9.5.1).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ExchangeKey {
+    pub session_id: SessionID,
+    pub exchange_id: u16,
+    /// Our role in the exchange
+    pub role: ExchangeRole,
+    /// The node that initiated the exchange, which tells apart the
+    /// exchanges of peers that share a session: the ephemeral initiator
+    /// node ID of the unsecured session, or the source node ID of a group
+    /// session. Secure unicast sessions have a single peer.
+    pub initiator_node_id: Option<u64>,
+}
+
+impl ExchangeKey {
+    /// The exchange that a received message belongs to. We're the responder
+    /// of exchanges that the sender initiated.
+    pub fn received(message: &Message) -> Self {
+        let payload_header = message.payload_header.as_ref().unwrap();
+        let role = if payload_header
+            .exchange_flags
+            .contains(ExchangeFlags::INITIATOR)
+        {
+            ExchangeRole::Responder
+        } else {
+            ExchangeRole::Initiator
+        };
+        Self {
+            session_id: message.message_header.session_id,
+            exchange_id: payload_header.exchange_id,
+            role,
+            initiator_node_id: initiator_node_id(message),
+        }
+    }
+
+    /// The exchange that a message to be sent belongs to
+    pub fn sent(message: &Message) -> Self {
+        let payload_header = message.payload_header.as_ref().unwrap();
+        let role = if payload_header
+            .exchange_flags
+            .contains(ExchangeFlags::INITIATOR)
+        {
+            ExchangeRole::Initiator
+        } else {
+            ExchangeRole::Responder
+        };
+        Self {
+            session_id: message.message_header.session_id,
+            exchange_id: payload_header.exchange_id,
+            role,
+            initiator_node_id: initiator_node_id(message),
+        }
+    }
+}
+
+/// The node ID of the initiator of a message's exchange, in the sessions
+/// that are shared by peers. The initiator sends from its node ID, and the
+/// responder sends to it.
+fn initiator_node_id(message: &Message) -> Option<u64> {
+    let header = &message.message_header;
+    let from_initiator = message
+        .payload_header
+        .as_ref()
+        .unwrap()
+        .exchange_flags
+        .contains(ExchangeFlags::INITIATOR);
+    match (header.session_type, header.dest_node_id) {
+        (SessionType::SecureUnicast(_), _) => None,
+        _ if from_initiator => header.source_node_id,
+        (_, Some(NodeID::Unique(node_id))) => Some(node_id),
+        _ => None,
+    }
+}
+
 /*
 We move encryption and decryption down to another level,
 where messages get ecrypted and decrypted before they're sent up
@@ -52,71 +453,194 @@ Each exchange could handle its messages
 
 impl ExchangeManager {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(StdClock::new()))
+    }
+
+    /// An exchange manager that times MRP with `clock`
+    pub fn with_clock(clock: Arc<dyn Clock + Send + Sync>) -> Self {
+        // Exchange IDs start at a random value (4.4.5)
+        let mut next_exchange_id = [0u8; 2];
+        fill_random(&mut next_exchange_id);
         Self {
-            exchanges: HashMap::with_capacity(32),
+            exchanges: HashMap::with_capacity(EXCHANGE_LIMIT),
+            next_exchange_id: u16::from_le_bytes(next_exchange_id),
+            handlers: HashMap::new(),
             session_manager: SessionManager::new(),
+            clock,
+            received_events: vec![],
         }
     }
 
-    /// Create a new exchange and return its Exchange ID and Session ID
-    pub fn new_initiator_exchange_unsecured(&mut self) -> (u16, SessionID) {
-        let session_id = self.session_manager.next_session_id();
-        let session_context = SessionContext::Unsecured(UnsecuredSessionContext {
-            session_role: SessionRole::Initiator,
-            local_session_id: session_id,
-            peer_session_id: 0,
-            ephemeral_initiator_node_id: 0,
-            message_reception_state: (),
-            // local_message_counter: 10001,
-            // message_reception_state: (),
-            // local_fabric_index: 1,
-            // peer_node_id: 1,
-            // resumption_id: 1,
-            // session_timestamp: 0,
-            // active_timestamp: 0,
-        });
-        self.session_manager.add_session(session_context, false);
-
+    /// Create a new exchange and return its Exchange ID and Session ID.
+    /// Its messages are sent from `ephemeral_initiator_node_id` (4.12.1.1).
+    pub fn new_initiator_exchange_unsecured(
+        &mut self,
+        ephemeral_initiator_node_id: u64,
+    ) -> Result<(u16, SessionID), ExchangeError> {
         // TODO: replace this with a single call to create an initiator exchange
-        let exchange = Exchange::initiator(session_id);
-        let exchange_id = exchange.exchange_id;
-        self.exchanges.insert(exchange_id, exchange);
-        (exchange_id, session_id)
+        // Unsecured messages don't carry our session ID, so their exchanges
+        // are in the unsecured session
+        let initiator_node_id = Some(ephemeral_initiator_node_id);
+        let exchange_id = self.allocate_exchange_id(0, initiator_node_id);
+        let mut exchange = Exchange::initiator(0, exchange_id);
+        exchange.initiator_node_id = initiator_node_id;
+        let key = self.insert_exchange(exchange)?;
+        self.session_manager
+            .unsecured_session_mut(ephemeral_initiator_node_id, SessionRole::Initiator);
+        // The session reserves the ID of the secure session that's established
+        let session_id = self
+            .session_manager
+            .new_session(SessionRole::Initiator, key);
+        self.remove_evicted_sessions();
+        Ok((exchange_id, session_id))
     }
 
-    pub fn new_responder_exchange_unsecured(&mut self, message: &Message) -> (u16, SessionID) {
-        let session_id = message.message_header.session_id;
-        let session_context = SessionContext::Unsecured(UnsecuredSessionContext {
-            session_role: SessionRole::Responder,
-            local_session_id: session_id,
-            peer_session_id: message.message_header.session_id,
-            ephemeral_initiator_node_id: message.message_header.source_node_id.unwrap(),
-            message_reception_state: (),
-            // local_message_counter: 10001,
-            // message_reception_state: (),
-            // local_fabric_index: 1,
-            // peer_node_id: 1,
-            // resumption_id: 1,
-            // session_timestamp: 0,
-            // active_timestamp: 0,
-        });
-        let exchange_id = self.new_responder_exchange(message);
-        self.session_manager.add_session(session_context, false);
+    /// Create an exchange for an unsolicited unsecured message, in the
+    /// unsecured session of its initiator
+    fn new_responder_exchange_unsecured(
+        &mut self,
+        message: &Message,
+        ephemeral_initiator_node_id: u64,
+    ) -> Result<ExchangeKey, ExchangeError> {
+        let key = self.new_responder_exchange(message)?;
+        self.session_manager
+            .unsecured_session_mut(ephemeral_initiator_node_id, SessionRole::Responder);
+        Ok(key)
+    }
 
-        (exchange_id, session_id)
+    /// Create an exchange for an unsolicited group message. Group sessions
+    /// are identified by their keys and not kept by the session manager, so
+    /// the exchange is only keyed by the sender.
+    fn new_group_responder_exchange(
+        &mut self,
+        message: &Message,
+    ) -> Result<ExchangeKey, ExchangeError> {
+        self.new_responder_exchange(message)
     }
 
-    pub fn new_responder_exchange(&mut self, message: &Message) -> u16 {
-        let exchange = Exchange::responder(message);
-        let exchange_id = exchange.exchange_id;
-        // TODO: we use the exchange ID from the intiator, this is unsafe
-        self.exchanges.insert(exchange_id, exchange);
-        exchange_id
+    /// Create an exchange for an unsolicited message, with the ID that its
+    /// initiator picked
+    pub fn new_responder_exchange(
+        &mut self,
+        message: &Message,
+    ) -> Result<ExchangeKey, ExchangeError> {
+        self.insert_exchange(Exchange::responder(message))
     }
 
-    pub fn add_session(&mut self, session_context: SessionContext) {
-        self.session_manager.add_session(session_context, false);
-        self.session_manager.last_session_id += 10;
+    /// Pick the ID of an exchange that we initiate on a session (4.4.5).
+    /// IDs go up by one from a random start, skipping the ones that are
+    /// still open.
+    fn allocate_exchange_id(
+        &mut self,
+        session_id: SessionID,
+        initiator_node_id: Option<u64>,
+    ) -> u16 {
+        loop {
+            let exchange_id = self.next_exchange_id;
+            self.next_exchange_id = exchange_id.wrapping_add(1);
+            let key = ExchangeKey {
+                session_id,
+                exchange_id,
+                role: ExchangeRole::Initiator,
+                initiator_node_id,
+            };
+            if !self.exchanges.contains_key(&key) {
+                return exchange_id;
+            }
+        }
+    }
+
+    /// Add an exchange to the table. When the table is full, the exchange
+    /// that has been idle the longest is evicted, as long as it has no
+    /// messages in flight.
+    fn insert_exchange(&mut self, mut exchange: Exchange) -> Result<ExchangeKey, ExchangeError> {
+        let key = exchange.key();
+        if !self.exchanges.contains_key(&key) && self.exchanges.len() >= EXCHANGE_LIMIT {
+            let evicted = self
+                .exchanges
+                .values()
+                .filter(|exchange| {
+                    !exchange.is_awaiting_ack() && exchange.acknowledgements.is_empty()
+                })
+                .min_by_key(|exchange| exchange.active_at)
+                .map(Exchange::key)
+                .ok_or(ExchangeError::Busy)?;
+            println!("Evicting idle exchange {}", evicted.exchange_id);
+            self.remove_exchange(&evicted);
+        }
+        exchange.active_at = self.clock.now();
+        self.exchanges.insert(key, exchange);
+        Ok(key)
+    }
+
+    /// Close an exchange (4.10.5.3), flushing its pending acknowledgements as
+    /// standalone acks that the caller has to send. An exchange with a
+    /// reliable message in flight stays open until the message is
+    /// acknowledged or MRP gives up on it.
+    pub fn close_exchange(
+        &mut self,
+        key: ExchangeKey,
+    ) -> Result<Vec<ExchangeEvent>, ExchangeError> {
+        let exchange = self
+            .exchanges
+            .get_mut(&key)
+            .ok_or(ExchangeError::UnknownExchange(key))?;
+        exchange.closing = true;
+        let mut events = vec![];
+        for ack in core::mem::take(&mut exchange.acknowledgements) {
+            match standalone_ack_event(&mut self.session_manager, exchange, ack) {
+                Some(event) => events.push(event),
+                None => {
+                    self.remove_session(key.session_id);
+                    return Err(ExchangeError::SessionExpired(key.session_id));
+                }
+            }
+        }
+        if !exchange.is_awaiting_ack() {
+            self.remove_exchange(&key);
+        }
+        Ok(events)
+    }
+
+    /// Remove an exchange, releasing the session IDs it reserved for
+    /// sessions that it didn't establish
+    fn remove_exchange(&mut self, key: &ExchangeKey) {
+        self.exchanges.remove(key);
+        self.session_manager.release_reservations(key);
+    }
+
+    /// Remove a session, and the exchanges and protocol state that use it
+    pub fn remove_session(&mut self, session_id: SessionID) -> Option<SessionContext> {
+        self.exchanges.retain(|key, _| key.session_id != session_id);
+        for handler in self.handlers.values_mut() {
+            handler.on_session_removed(session_id);
+        }
+        self.session_manager.remove_session(session_id)
+    }
+
+    pub fn add_session(
+        &mut self,
+        session_context: SessionContext,
+    ) -> Result<SessionID, SessionError> {
+        let session_id = self.session_manager.add_session(session_context, false)?;
+        self.remove_evicted_sessions();
+        Ok(session_id)
+    }
+
+    /// Change how many sessions are kept before the least recently active
+    /// one is evicted
+    pub fn set_session_limit(&mut self, limit: usize) {
+        self.session_manager.set_limit(limit);
+    }
+
+    /// Remove what belonged to the sessions that the session manager
+    /// evicted, and report them from [ExchangeManager::poll]
+    fn remove_evicted_sessions(&mut self) {
+        for session_id in self.session_manager.take_evicted() {
+            self.remove_session(session_id);
+            self.received_events
+                .push(ExchangeEvent::SessionEvicted { session_id });
+        }
     }
 
     pub fn session_context(&self, session_id: u16) -> Option<&SessionContext> {
@@ -127,51 +651,108 @@ impl ExchangeManager {
         self.session_manager.get_session_mut(session_id)
     }
 
-    /// Find an exchange
-    pub fn find_exchange(&mut self, exchange_id: u16) -> &mut Exchange {
-        println!("Looking for exchange with ID {exchange_id}");
-        self.exchanges.get_mut(&exchange_id).unwrap()
+    /// The keys of the groups this node sends to or is a member of
+    pub fn group_keys_mut(&mut self) -> &mut GroupKeyStore {
+        &mut self.session_manager.group_keys
+    }
+
+    /// Persist the global message counters in `store`, so that they
+    /// continue after a reboot instead of starting again (4.5.1.1)
+    pub fn set_counter_store(&mut self, store: impl CounterStore + Send + Sync + 'static) {
+        self.session_manager.set_counter_store(Box::new(store));
+    }
+
+    /// Persist CASE resumption records in `store`, so that peers can resume
+    /// their sessions after a reboot (4.14.2.2)
+    pub fn set_resumption_store(&mut self, store: impl ResumptionStore + Send + Sync + 'static) {
+        self.session_manager
+            .resumption_records
+            .set_store(Box::new(store));
+    }
+
+    /// The CASE resumption records of this node, one for each peer in a
+    /// fabric
+    pub fn resumption_records(&self) -> &ResumptionTable {
+        &self.session_manager.resumption_records
+    }
+
+    /// Register the handler of a protocol, replacing its previous handler.
+    /// Unsolicited messages of protocols without a handler are dropped.
+    pub fn register_handler(
+        &mut self,
+        vendor_id: u16,
+        protocol_id: u16,
+        handler: impl ProtocolHandler + Send + Sync + 'static,
+    ) {
+        self.handlers
+            .insert((vendor_id, protocol_id), Box::new(handler));
+    }
+
+    /// Pass a message that [ExchangeManager::receive_message] accepted to
+    /// the handler of its protocol, and encode the response on the message's
+    /// exchange into `out`
+    pub fn dispatch(&mut self, message: &Message, out: &mut BytesMut) -> Result<(), ExchangeError> {
+        let key = ExchangeKey::received(message);
+        let payload_header = message.payload_header.as_ref().unwrap();
+        let vendor_id = payload_header.protocol_vendor_id.unwrap_or_default();
+        let protocol_id = payload_header.protocol_id;
+        let handler =
+            self.handlers
+                .get_mut(&(vendor_id, protocol_id))
+                .ok_or(ExchangeError::NoHandler {
+                    vendor_id,
+                    protocol_id,
+                })?;
+        let mut context = ProtocolContext {
+            exchange: key,
+            session_manager: &mut self.session_manager,
+        };
+        let response = handler.on_message(&mut context, message);
+        // The handler may have added a session that evicted another one
+        self.remove_evicted_sessions();
+        let Some(mut response) = response else {
+            return Ok(());
+        };
+        if !self.exchanges.contains_key(&key) {
+            return Err(ExchangeError::UnknownExchange(key));
+        }
+        address_unsecured_message(&key, &mut response);
+        self.encode_exchange_message(key, &mut response, out)
+    }
+
+    /// Find an open exchange
+    pub fn find_exchange(&mut self, key: &ExchangeKey) -> Option<&mut Exchange> {
+        self.exchanges.get_mut(key)
     }
 
     /// Message Reception (4.6.2)
     // TODO: we need to know the type of transport the message came in,
     // as UDP would follow MRP
     pub fn receive_message(&mut self, message: &mut Message) -> ExchangeMessageAction {
-        // TODO: validation checks
-        let header = &message.message_header;
-        if !header.message_flags.contains(MessageFlags::FORMAT_V1) {
-            panic!("Invalid format");
-        }
-        match &header.session_type {
-            SessionType::UnsecuredSession => {}
-            SessionType::SecureUnicast(session_id) => {
-                if header
-                    .message_flags
-                    .contains(MessageFlags::DSIZ_16_BIT_GROUP_ID)
-                {
-                    panic!("Can't have a group ID for a unicast message");
+        // The header was validated when the message was decoded
+        let session_id = message.message_header.session_id;
+        let decrypted = match message.message_header.session_type {
+            // Group sessions are identified by their keys, of which there can be many
+            SessionType::SecureGroup(_) => {
+                let keys = self
+                    .session_manager
+                    .group_keys
+                    .candidates(session_id)
+                    .map(|key| &key.encryption_key[..]);
+                match message.decrypt_with_candidates(keys) {
+                    Ok(i) => return self.receive_group_message(message, i),
+                    Err(e) => Err(e),
                 }
             }
-            SessionType::SecureGroup(session_id) => {
-                // TODO: DSIZ shouldn't be 0 for neither group nor session
-                if !header
-                    .message_flags
-                    .contains(MessageFlags::SOURCE_NODE_ID_PRESENT)
-                {
-                    panic!("Source Node ID should be present");
+            _ => match self.session_manager.get_session(session_id) {
+                Some(SessionContext::Secure(session)) => {
+                    message.decrypt(Some(&session.decryption_key[..]))
                 }
-            }
+                Some(SessionContext::Unsecured(_)) | None => message.decrypt(None),
+            },
         };
-        let session_id = message.message_header.session_id;
-        // TODO: There can be > 1 key for group messages
-        match self.session_manager.get_session(session_id) {
-            Some(SessionContext::MCSP) => todo!("MCSP sessions not supported"),
-            Some(SessionContext::Secure(session)) => {
-                message.decrypt(Some(&session.decryption_key[..]));
-            }
-            Some(SessionContext::Unsecured(_)) | None => {
-                message.decrypt(None);
-            }
+        if let Err(e) = decrypted {
+            return drop_message(message, &e);
         }
 
         // if let Some(session_id) = session_id {
@@ -182,146 +763,699 @@ impl ExchangeManager {
         //     message.decrypt(Some(&session.decryption_key[..]));
         // } else {
         // }
-        // TODO: update session timestamps
+        self.session_manager.mark_active(session_id);
 
         // Message can now be processed by the next layer
-        self.process_message(message)
+        let now = self.clock.now();
+        self.process_message(message, now)
+    }
+
+    /// Reject a decrypted group message if its sender sent it before. `key`
+    /// is the position of the key it was decrypted with in the candidates.
+    fn receive_group_message(&mut self, message: &Message, key: usize) -> ExchangeMessageAction {
+        let session_id = message.message_header.session_id;
+        let Some(source_node_id) = message.message_header.source_node_id else {
+            println!("Dropping group message without a source node");
+            return ExchangeMessageAction::Drop;
+        };
+        let key = self
+            .session_manager
+            .group_keys
+            .candidates(session_id)
+            .nth(key)
+            .unwrap();
+        let peer = GroupPeer {
+            fabric_index: key.fabric_index,
+            key_set_id: key.key_set_id,
+            source_node_id,
+        };
+        let now = self.clock.now();
+
+        // MCSP messages are authenticated by their challenge, not their counter
+        let payload_header = message.payload_header.as_ref().unwrap();
+        if payload_header.protocol_vendor_id.is_none()
+            && payload_header.protocol_id == ProtocolID::SecureChannel as u16
+        {
+            let opcode = payload_header.protocol_opcode;
+            if opcode == SecureChannelProtocolOpCode::MsgCounterSyncReq as u8 {
+                self.answer_counter_sync(peer, message);
+                return ExchangeMessageAction::Drop;
+            } else if opcode == SecureChannelProtocolOpCode::MsgCounterSyncRsp as u8 {
+                self.complete_counter_sync(peer, message, now);
+                return ExchangeMessageAction::Drop;
+            }
+        }
+
+        // Group messages are never acknowledged, so they are only dropped
+        match self.session_manager.group_peers.check(peer, message) {
+            GroupCounterCheck::Accept => self.process_message(message, now),
+            GroupCounterCheck::Duplicate => ExchangeMessageAction::Drop,
+            GroupCounterCheck::Unsynchronized => {
+                // The message is returned by poll once the peer answers
+                self.session_manager
+                    .counter_sync
+                    .queue(peer, message.clone(), now);
+                ExchangeMessageAction::Drop
+            }
+        }
+    }
+
+    /// Answer a peer's MsgCounterSyncReq with our group counter (4.8.2)
+    fn answer_counter_sync(&mut self, peer: GroupPeer, message: &Message) {
+        let session_id = message.message_header.session_id;
+        let Ok(request) = MsgCounterSyncRequest::decode(&message.payload) else {
+            println!("Dropping malformed MsgCounterSyncReq");
+            return;
+        };
+        let group_keys = &self.session_manager.group_keys;
+        let Some(node_id) = group_keys.node_id(peer.fabric_index) else {
+            return;
+        };
+        // Requests are unicast to the node whose counter is asked for
+        if !matches!(message.message_header.dest_node_id, Some(NodeID::Unique(id)) if id == node_id)
+        {
+            return;
+        }
+        let Some(encryption_key) = group_key(group_keys, session_id, &peer) else {
+            return;
+        };
+        let Some(message_counter) = self
+            .session_manager
+            .next_message_counter(session_id, &SessionType::SecureGroup(session_id))
+        else {
+            return;
+        };
+        let mut payload = BytesMut::new();
+        MsgCounterSyncResponse {
+            synchronized_counter: message_counter,
+            response: request.challenge,
+        }
+        .encode(&mut payload);
+        let exchange_id = message.payload_header.as_ref().unwrap().exchange_id;
+        let response = counter_sync_message(
+            session_id,
+            node_id,
+            &peer,
+            message_counter,
+            ProtocolHeader {
+                exchange_id,
+                protocol_opcode: SecureChannelProtocolOpCode::MsgCounterSyncRsp as u8,
+                protocol_id: ProtocolID::SecureChannel as u16,
+                ..Default::default()
+            },
+            payload,
+        );
+        let mut packet = BytesMut::new();
+        response.encode(&mut packet, Some(&encryption_key));
+        self.received_events.push(ExchangeEvent::Send {
+            exchange: ExchangeKey::received(message),
+            packet,
+        });
+    }
+
+    /// Synchronize a peer's counter with its MsgCounterSyncRsp, and process
+    /// the messages that waited for it (4.8.1.2)
+    fn complete_counter_sync(&mut self, peer: GroupPeer, message: &Message, now: Instant) {
+        let Ok(response) = MsgCounterSyncResponse::decode(&message.payload) else {
+            println!("Dropping malformed MsgCounterSyncRsp");
+            return;
+        };
+        let Some(queued) = self
+            .session_manager
+            .counter_sync
+            .on_response(&peer, &response)
+        else {
+            // Not an answer to our challenge
+            return;
+        };
+        self.session_manager
+            .group_peers
+            .synchronize(peer, response.synchronized_counter);
+        for message in queued {
+            if self.session_manager.group_peers.check(peer, &message) != GroupCounterCheck::Accept {
+                continue;
+            }
+            if self.process_message(&message, now) == ExchangeMessageAction::Process {
+                self.received_events
+                    .push(ExchangeEvent::Received { message });
+            }
+        }
+    }
+
+    /// Encode the MsgCounterSyncReq to a peer whose counter isn't known
+    /// (4.8.1.1)
+    fn request_counter_sync(&mut self, request: PendingRequest) -> Option<ExchangeEvent> {
+        let PendingRequest {
+            peer,
+            session_id,
+            request,
+        } = request;
+        let group_keys = &self.session_manager.group_keys;
+        let node_id = group_keys.node_id(peer.fabric_index)?;
+        let encryption_key = group_key(group_keys, session_id, &peer)?;
+        let message_counter = self
+            .session_manager
+            .next_message_counter(session_id, &SessionType::SecureGroup(session_id))?;
+        let exchange_id = self.allocate_exchange_id(session_id, Some(node_id));
+        let mut payload = BytesMut::new();
+        request.encode(&mut payload);
+        let message = counter_sync_message(
+            session_id,
+            node_id,
+            &peer,
+            message_counter,
+            ProtocolHeader {
+                exchange_flags: ExchangeFlags::INITIATOR,
+                exchange_id,
+                protocol_opcode: SecureChannelProtocolOpCode::MsgCounterSyncReq as u8,
+                protocol_id: ProtocolID::SecureChannel as u16,
+                ..Default::default()
+            },
+            payload,
+        );
+        let mut packet = BytesMut::new();
+        message.encode(&mut packet, Some(&encryption_key));
+        Some(ExchangeEvent::Send {
+            exchange: ExchangeKey::sent(&message),
+            packet,
+        })
+    }
+
+    /// Encode a message of an exchange, encrypting it with its session's key.
+    /// The message counter is assigned from the session's counter.
+    ///
+    /// A pending acknowledgement of the exchange is piggybacked on the
+    /// message, and a reliable message is kept until the peer acknowledges
+    /// it, to be retransmitted by [ExchangeManager::poll] (4.12.5.1).
+    pub fn encode_message(
+        &mut self,
+        message: &mut Message,
+        out: &mut BytesMut,
+    ) -> Result<(), ExchangeError> {
+        self.encode_exchange_message(ExchangeKey::sent(message), message, out)
+    }
+
+    fn encode_exchange_message(
+        &mut self,
+        key: ExchangeKey,
+        message: &mut Message,
+        out: &mut BytesMut,
+    ) -> Result<(), ExchangeError> {
+        let now = self.clock.now();
+        let reliable = message
+            .payload_header
+            .as_ref()
+            .unwrap()
+            .exchange_flags
+            .contains(ExchangeFlags::RELIABILITY);
+        let pending = self
+            .exchanges
+            .get(&key)
+            .and_then(|exchange| exchange.retransmission.as_ref());
+        if let (true, Some(pending)) = (reliable, pending) {
+            return Err(ExchangeError::RetransmissionPending {
+                message_counter: pending.message_counter,
+            });
+        }
+        // Group messages are never sent in plaintext, so the key is found
+        // before a counter is used up
+        let group_key = match message.message_header.session_type {
+            SessionType::SecureGroup(_) => Some(group_encryption_key(
+                &self.session_manager.group_keys,
+                &message.message_header,
+                self.clock.timestamp(),
+            )?),
+            _ => None,
+        };
+        message.message_header.message_counter =
+            self.next_message_counter(key.session_id, &message.message_header.session_type)?;
+        self.session_manager.mark_active(key.session_id);
+        let encryption_key = match &group_key {
+            Some(group_key) => Some(&group_key[..]),
+            None => encryption_key(
+                &self.session_manager,
+                key.session_id,
+                &message.message_header.session_type,
+            ),
+        };
+        let Some(exchange) = self.exchanges.get_mut(&key) else {
+            // Messages outside of exchanges, like acks to unknown exchanges,
+            // aren't tracked
+            message.encode(out, encryption_key);
+            return Ok(());
+        };
+
+        if let Some(ack) = exchange.acknowledgements.pop() {
+            message.with_ack(Some(ack.message_counter));
+        }
+        exchange.active_at = now;
+        let start = out.len();
+        message.encode(out, encryption_key);
+        if reliable {
+            let base_interval = base_interval(&self.session_manager, &key, now);
+            exchange.retransmission = Some(RetransmissionTable {
+                message: BytesMut::from(&out[start..]),
+                message_counter: message.message_header.message_counter,
+                send_count: 1,
+                retrans_timeout: now + mrp_backoff_time(base_interval, 0, random_jitter()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Encode the standalone ack of a received message that is dropped, like
+    /// a duplicate or an unsolicited message that isn't handled (4.12.5.2.2)
+    pub fn encode_standalone_ack(
+        &mut self,
+        message: &Message,
+        out: &mut BytesMut,
+    ) -> Result<(), ExchangeError> {
+        let Some(ack) = message.next_ack() else {
+            return Ok(());
+        };
+        let session_id = message.message_header.session_id;
+        let session_type = &message.message_header.session_type;
+        let message_counter = self.next_message_counter(session_id, session_type)?;
+        let encryption_key = encryption_key(&self.session_manager, session_id, session_type);
+        message
+            .standalone_ack(ack, message_counter)
+            .encode(out, encryption_key);
+        Ok(())
+    }
+
+    /// The counter of the next message sent in a session, which removes the
+    /// session if it ran out of counters (4.5.1.2)
+    fn next_message_counter(
+        &mut self,
+        session_id: SessionID,
+        session_type: &SessionType,
+    ) -> Result<u32, ExchangeError> {
+        match self
+            .session_manager
+            .next_message_counter(session_id, session_type)
+        {
+            Some(message_counter) => Ok(message_counter),
+            None => {
+                self.remove_session(session_id);
+                Err(ExchangeError::SessionExpired(session_id))
+            }
+        }
+    }
+
+    /// Retransmit the messages and send the standalone acknowledgements
+    /// whose timers have expired, and close the exchanges that are done
+    pub fn poll(&mut self) -> Vec<ExchangeEvent> {
+        let now = self.clock.now();
+        let mut events = core::mem::take(&mut self.received_events);
+        for request in self.session_manager.counter_sync.due_requests(now) {
+            events.extend(self.request_counter_sync(request));
+        }
+        for peer in self.session_manager.counter_sync.expire(now) {
+            events.push(ExchangeEvent::SyncFailed { peer });
+        }
+        let mut closed = vec![];
+        let mut expired_sessions = vec![];
+        for (&key, exchange) in self.exchanges.iter_mut() {
+            if let Some(retransmission) = &mut exchange.retransmission {
+                if retransmission.retrans_timeout <= now {
+                    if retransmission.send_count >= MRP_MAX_TRANSMISSIONS {
+                        events.push(ExchangeEvent::Failed {
+                            exchange: key,
+                            message_counter: retransmission.message_counter,
+                        });
+                        exchange.retransmission = None;
+                        // The exchange can't continue without the message
+                        closed.push(key);
+                        continue;
+                    } else {
+                        let base_interval = base_interval(&self.session_manager, &key, now);
+                        retransmission.retrans_timeout = now
+                            + mrp_backoff_time(
+                                base_interval,
+                                retransmission.send_count,
+                                random_jitter(),
+                            );
+                        retransmission.send_count += 1;
+                        events.push(ExchangeEvent::Send {
+                            exchange: key,
+                            packet: retransmission.message.clone(),
+                        });
+                    }
+                }
+            }
+
+            let (due, pending) = exchange
+                .acknowledgements
+                .drain(..)
+                .partition(|ack| ack.ack_timeout <= now);
+            exchange.acknowledgements = pending;
+            for ack in due {
+                match standalone_ack_event(&mut self.session_manager, exchange, ack) {
+                    Some(event) => events.push(event),
+                    None => expired_sessions.push(key.session_id),
+                }
+            }
+
+            if exchange.is_awaiting_ack() {
+                continue;
+            }
+            if exchange.closing && exchange.acknowledgements.is_empty() {
+                closed.push(key);
+            } else if exchange.active_at + EXCHANGE_IDLE_TIMEOUT <= now {
+                events.push(ExchangeEvent::Expired { exchange: key });
+                closed.push(key);
+            }
+        }
+        for key in closed {
+            self.remove_exchange(&key);
+        }
+        expired_sessions.sort_unstable();
+        expired_sessions.dedup();
+        for session_id in expired_sessions {
+            self.remove_session(session_id);
+            events.retain(|event| !matches!(event, ExchangeEvent::Send { exchange, .. } if exchange.session_id == session_id));
+            events.push(ExchangeEvent::SessionExpired { session_id });
+        }
+        events
+    }
+
+    /// When [ExchangeManager::poll] has to be called next
+    pub fn next_timeout(&self) -> Option<Instant> {
+        if !self.received_events.is_empty() {
+            return Some(self.clock.now());
+        }
+        let counter_sync = self.session_manager.counter_sync.next_timeout();
+        self.exchanges
+            .values()
+            .flat_map(|exchange| {
+                let retransmission = exchange.retransmission.as_ref().map(|r| r.retrans_timeout);
+                let acks = exchange.acknowledgements.iter().map(|a| a.ack_timeout);
+                // Exchanges with a message in flight don't idle
+                let idle = match exchange.retransmission {
+                    Some(_) => None,
+                    None if exchange.closing => Some(exchange.active_at),
+                    None => Some(exchange.active_at + EXCHANGE_IDLE_TIMEOUT),
+                };
+                retransmission.into_iter().chain(acks).chain(idle)
+            })
+            .chain(counter_sync)
+            .min()
     }
 
     /// Exchange Message PRocessing (4.9.5)
     /// Process a message that has already been decrypted and verified.
     /// Return a boolean indicating whether to contine prucessing or to ignore
     /// the message.
-    fn process_message(&mut self, message: &Message) -> ExchangeMessageAction {
+    fn process_message(&mut self, message: &Message, now: Instant) -> ExchangeMessageAction {
         // Exchange Message Matching (4.9.5.1)
-        /*
-        Attempt to match the messge to an existing message
-        Use the exchange ID of the message
-        Initiator matches opposite of the exchange
-            Else it's an unsolicited message, handle as such
+        // A message matches an exchange of its session with the same ID, where
+        // we have the opposite role of the sender
+        let key = ExchangeKey::received(message);
+        // Duplicates are detected by the session, so that messages of
+        // closed exchanges can't be replayed (4.5.5)
+        let role = match key.role {
+            ExchangeRole::Initiator => SessionRole::Initiator,
+            ExchangeRole::Responder => SessionRole::Responder,
+        };
+        if self
+            .session_manager
+            .is_duplicate(message, key.initiator_node_id, role)
+        {
+            println!(
+                "Ignoring duplicate message {}",
+                message.message_header.message_counter
+            );
+            // Duplicates are acknowledged again, in case the ack was lost (4.12.5.2.2)
+            return if message.next_ack().is_some() {
+                ExchangeMessageAction::AckAndDrop
+            } else {
+                ExchangeMessageAction::Drop
+            };
+        }
+        if let Some(exchange) = self.exchanges.get_mut(&key) {
+            let action = exchange.on_message(message, now);
+            self.session_manager
+                .accept_message(message, key.initiator_node_id, role, now);
+            return action;
+        }
 
-        */
+        // Unsolicited Message Processing (4.9.5.2)
         let payload_header = message.payload_header.as_ref().unwrap();
-        // dbg!((&message.message_header, payload_header));
-        let exchange = self.exchanges.get_mut(&payload_header.exchange_id);
-        match exchange {
-            Some(exchange) => {
-                assert_eq!(exchange.session_id, message.message_header.session_id);
-                let check = payload_header
-                    .exchange_flags
-                    .contains(ExchangeFlags::INITIATOR) as u8
-                    + (exchange.exchange_role != ExchangeRole::Responder) as u8;
-                assert_eq!(check, 1);
-                if exchange.deduplicate_message(message) {
-                    ExchangeMessageAction::Drop
-                } else {
-                    ExchangeMessageAction::Process
-                }
+        let is_standalone_ack = payload_header.protocol_id == ProtocolID::SecureChannel as u16
+            && payload_header.protocol_opcode
+                == SecureChannelProtocolOpCode::MRPStandaloneAck as u8;
+        let is_registered = self.handlers.contains_key(&(
+            payload_header.protocol_vendor_id.unwrap_or_default(),
+            payload_header.protocol_id,
+        ));
+        if key.role == ExchangeRole::Responder && !is_standalone_ack && !is_registered {
+            let error = MessageError::UnsupportedProtocol {
+                vendor_id: payload_header.protocol_vendor_id.unwrap_or_default(),
+                protocol_id: payload_header.protocol_id,
+            };
+            return drop_message(message, &error);
+        }
+        if key.role == ExchangeRole::Initiator || is_standalone_ack {
+            // Only the initiator of an exchange can start it. Other messages
+            // are acknowledged if they have to be, and dropped.
+            println!(
+                "Dropping message of unknown exchange {}",
+                payload_header.exchange_id
+            );
+            return if message.next_ack().is_some() {
+                ExchangeMessageAction::AckAndDrop
+            } else {
+                ExchangeMessageAction::Drop
+            };
+        }
+
+        // A session ID might already exist, find one first
+        // TODO: validate this before creating a new session (e.g. can't hijack existing session)
+        let created = match (
+            &message.message_header.session_type,
+            self.session_context(message.message_header.session_id),
+        ) {
+            (SessionType::SecureGroup(_), _) => self.new_group_responder_exchange(message),
+            // Create a new exchange with the session
+            (_, Some(SessionContext::Secure(_))) => self.new_responder_exchange(message),
+            _ => {
+                let Some(ephemeral_initiator_node_id) = key.initiator_node_id else {
+                    println!("Dropping unsecured message without a source node");
+                    return ExchangeMessageAction::Drop;
+                };
+                self.new_responder_exchange_unsecured(message, ephemeral_initiator_node_id)
             }
-            None if payload_header
-                .exchange_flags
-                .contains(ExchangeFlags::INITIATOR) =>
-            {
-                // Unsolicited message (4.9.5.2)
-                // TODO: Should not have a duplicate counter
-                // TODO: has to have a registered protocol ID
-                // println!("Processing unsolicited message {:#?}", message);
-                assert!(payload_header.protocol_id < 0x0005);
-                // A session ID might already exist, find one first
-                // TODO: validate this before creating a new session (e.g. can't hijack existing session)
-                match self.session_context(message.message_header.session_id) {
-                    Some(session) => {
-                        // Log some data to capture encryption inputs
-                        let SessionContext::Secure(context) = &session else {
-                            todo!();
-                        };
-
-                        // Create a new exchange with the session
-                        self.new_responder_exchange(message);
-                    }
-                    None => {
-                        let (_exchange_id, _session_id) =
-                            self.new_responder_exchange_unsecured(message);
-                    }
-                }
-                // TODO: depends on the above conditions
-                ExchangeMessageAction::Process
+        };
+        match created {
+            Ok(key) => {
+                let action = self
+                    .exchanges
+                    .get_mut(&key)
+                    .unwrap()
+                    .on_message(message, now);
+                self.session_manager
+                    .accept_message(message, key.initiator_node_id, role, now);
+                action
             }
-            None => {
-                // TODO: Create an ephemeral exchange, acknowledge the message and don't process further
-                println!("Did not find exchange ID {}", payload_header.exchange_id);
-                ExchangeMessageAction::AckAndDrop
+            Err(e) => {
+                // The initiator retransmits the message when there's room
+                println!("Dropping unsolicited message: {e}");
+                ExchangeMessageAction::Drop
             }
         }
     }
 }
 
 impl Exchange {
-    pub fn initiator(session_id: u16) -> Self {
-        // TODO: add a number generator util
-        let mut exchange_id = [0u8; 2];
-        fill_random(&mut exchange_id);
-        let exchange_id = u16::from_le_bytes(exchange_id);
-        let mut message_counter = [0u8; 4];
-        fill_random(&mut message_counter);
-        let message_counter = u32::from_le_bytes(message_counter);
-
+    pub fn initiator(session_id: u16, exchange_id: u16) -> Self {
         Self {
             exchange_id,
             exchange_role: ExchangeRole::Initiator,
             session_id,
-            message_counter,
-            peer_message_counter: MessageCounter::new(0),
-            acknowledgements: (),
-            retransmissions: (),
+            initiator_node_id: None,
+            acknowledgements: vec![],
+            retransmission: None,
+            active_at: Instant::default(),
+            closing: false,
             // receiver: todo!(),
         }
     }
 
     pub fn responder(message: &Message) -> Self {
-        let mut message_counter = [0u8; 4];
-        fill_random(&mut message_counter);
-        let message_counter = u32::from_le_bytes(message_counter);
         Self {
             exchange_id: message.payload_header.as_ref().unwrap().exchange_id,
             exchange_role: ExchangeRole::Responder,
             session_id: message.message_header.session_id,
-            message_counter,
-            peer_message_counter: MessageCounter::new(message.message_header.message_counter),
-            acknowledgements: (),
-            retransmissions: (),
+            initiator_node_id: ExchangeKey::received(message).initiator_node_id,
+            acknowledgements: vec![],
+            retransmission: None,
+            active_at: Instant::default(),
+            closing: false,
         }
     }
 
-    /// Generate a message counter
-    // TODO: This is to make progress for now, needs to be spec compliant
-    pub fn next_message_counter(&mut self) -> u32 {
-        self.message_counter += 1;
-        self.message_counter
+    pub fn key(&self) -> ExchangeKey {
+        ExchangeKey {
+            session_id: self.session_id,
+            exchange_id: self.exchange_id,
+            role: self.exchange_role,
+            initiator_node_id: self.initiator_node_id,
+        }
     }
 
-    /// Check for duplicates using the message counter and type of session
-    pub fn deduplicate_message(&mut self, message: &Message) -> bool {
-        self.peer_message_counter.process_message_counter(
-            message.message_header.message_counter,
-            &message.message_header.session_type,
-        )
+    /// Update the MRP state with a new message from the peer (4.12.5.2)
+    fn on_message(&mut self, message: &Message, now: Instant) -> ExchangeMessageAction {
+        self.active_at = now;
+        let payload_header = message.payload_header.as_ref().unwrap();
+        if let Some(ack) = payload_header.ack_message_counter {
+            self.retransmission
+                .take_if(|retransmission| retransmission.message_counter == ack);
+        }
+        if let Some(ack) = message.next_ack() {
+            // Only the newest ack can be piggybacked, so the ones before it
+            // are sent on their own straight away
+            for pending in &mut self.acknowledgements {
+                pending.ack_timeout = now;
+            }
+            self.acknowledgements.push(AcknowledgementTable {
+                message_counter: ack,
+                ack_timeout: now + MRP_STANDALONE_ACK_TIMEOUT,
+                standalone_ack: message.standalone_ack(ack, 0),
+            });
+        }
+
+        // Standalone acks only carry an acknowledgement
+        if payload_header.protocol_id == ProtocolID::SecureChannel as u16
+            && payload_header.protocol_opcode == SecureChannelProtocolOpCode::MRPStandaloneAck as u8
+        {
+            ExchangeMessageAction::Drop
+        } else {
+            ExchangeMessageAction::Process
+        }
+    }
+
+    /// Whether a reliable message of the exchange is waiting for its ack
+    pub fn is_awaiting_ack(&self) -> bool {
+        self.retransmission.is_some()
+    }
+}
+
+/// Encode the standalone ack of a received message, to send it when it
+/// can't be piggybacked. Returns `None` if the session ran out of counters.
+fn standalone_ack_event(
+    session_manager: &mut SessionManager,
+    exchange: &mut Exchange,
+    ack: AcknowledgementTable,
+) -> Option<ExchangeEvent> {
+    let mut message: Message = ack.standalone_ack;
+    message.message_header.message_counter = session_manager
+        .next_message_counter(exchange.session_id, &message.message_header.session_type)?;
+    let encryption_key = encryption_key(
+        session_manager,
+        exchange.session_id,
+        &message.message_header.session_type,
+    );
+    let mut packet = BytesMut::new();
+    message.encode(&mut packet, encryption_key);
+    Some(ExchangeEvent::Send {
+        exchange: exchange.key(),
+        packet,
+    })
+}
+
+/// The key that messages of a session are encrypted with, if they are
+fn encryption_key<'a>(
+    session_manager: &'a SessionManager,
+    session_id: SessionID,
+    session_type: &SessionType,
+) -> Option<&'a [u8]> {
+    if *session_type == SessionType::UnsecuredSession {
+        None
+    } else {
+        session_manager
+            .get_session(session_id)
+            .and_then(|s| s.encryption_key())
     }
 }
 
+/// The key that a group message of this node is encrypted with, which is
+/// the current operational key of the group in the fabric that the source
+/// node ID is ours in (4.16.3)
+fn group_encryption_key(
+    group_keys: &GroupKeyStore,
+    header: &MessageHeader,
+    timestamp: i64,
+) -> Result<[u8; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES], ExchangeError> {
+    let no_key = ExchangeError::NoGroupKey(header.session_id);
+    let (Some(source_node_id), Some(NodeID::Group(group_id))) =
+        (header.source_node_id, header.dest_node_id)
+    else {
+        return Err(no_key);
+    };
+    // Epoch start times are in microseconds
+    let now = timestamp.max(0) as u64 * 1000;
+    group_keys
+        .sending_key(source_node_id, group_id, now)
+        .filter(|key| key.session_id == header.session_id)
+        .map(|key| key.encryption_key)
+        .ok_or(no_key)
+}
+
+/// The encryption key of a group peer's key set, in a group session
+fn group_key(
+    group_keys: &GroupKeyStore,
+    session_id: SessionID,
+    peer: &GroupPeer,
+) -> Option<[u8; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES]> {
+    group_keys
+        .candidates(session_id)
+        .find(|key| key.fabric_index == peer.fabric_index && key.key_set_id == peer.key_set_id)
+        .map(|key| key.encryption_key)
+}
+
+/// An MCSP message, which is unicast to a group peer with the group key
+/// (4.8.1)
+fn counter_sync_message(
+    session_id: SessionID,
+    source_node_id: u64,
+    peer: &GroupPeer,
+    message_counter: u32,
+    payload_header: ProtocolHeader,
+    payload: BytesMut,
+) -> Message {
+    let mut message_header = MessageHeader::group(session_id, source_node_id, 0);
+    message_header.message_flags =
+        MessageFlags::SOURCE_NODE_ID_PRESENT | MessageFlags::DSIZ_64_BIT_NODE_ID;
+    message_header.dest_node_id = Some(NodeID::Unique(peer.source_node_id));
+    message_header.security_flags |= SecurityFlags::CONTROL;
+    message_header.message_counter = message_counter;
+    Message::new(message_header, Some(payload_header), payload)
+}
+
+/// A reliable message that was sent and is waiting for its acknowledgement.
+/// An exchange has at most one of them (4.12.5.1).
+#[derive(Debug)]
 pub struct RetransmissionTable {
-    message: Vec<u8>,
+    /// The encoded message, which is sent again as it is
+    message: BytesMut,
     message_counter: u32,
-    send_count: usize,
-    retrans_timeout_counter: usize,
+    send_count: u8,
+    /// When the message is sent again if it isn't acknowledged
+    retrans_timeout: Instant,
 }
 
+/// A reliable message that was received and is waiting to be acknowledged
+/// (4.12.5.2).
+#[derive(Debug)]
 pub struct AcknowledgementTable {
     message_counter: u32,
-    standalone_ack_sent: bool,
+    /// When the standalone ack is sent if no message piggybacks it
+    ack_timeout: Instant,
+    standalone_ack: Message,
 }
 
-#[derive(Debug)]
+/// Message Reception State (4.5.5), which detects duplicate messages
+#[derive(Debug, Clone)]
 pub struct MessageCounter {
     max_counter: u32,
     bitmap: u32,
@@ -335,36 +1469,55 @@ impl MessageCounter {
         }
     }
 
+    /// The state of a counter that the peer told us with MCSP. Messages
+    /// that it sent before, within the window, are still accepted.
+    pub fn synchronized(max_counter: u32) -> Self {
+        Self {
+            max_counter,
+            bitmap: 0,
+        }
+    }
+
     /// Process this message counter, updating the state and returning whether
-    /// the message is a duplicate.
+    /// the message is a duplicate (4.5.5).
+    ///
+    /// Bit `n` of the bitmap is set if `max_counter - n - 1` was received.
     pub fn process_message_counter(&mut self, counter: u32, session_type: &SessionType) -> bool {
         if self.max_counter == counter {
             // Duplicate, return very early
             return true;
         }
-        let delta = (counter as i64 - self.max_counter as i64).abs() as u32;
-        let delta_bitmap = 1u32 << delta;
-        match (session_type, counter > self.max_counter) {
-            // Secured messages don't allow rolling over, so the max is always higher
-            (SessionType::SecureUnicast(_), false) => {
-                // Check if the bitmap contains the message
-                let is_duplicate = self.contains(delta_bitmap);
-                if !is_duplicate {
-                    self.insert(delta_bitmap);
-                }
-                is_duplicate
-            }
-            (_, true) => {
-                self.max_counter = counter;
-                if delta < MSG_COUNTER_WINDOW_SIZE {
-                    self.bitmap <<= delta;
-                    self.insert(1 << (delta - 1));
+        match session_type {
+            // Group counters roll over, so the half of the counter space
+            // after the max is ahead of it, and the other half behind it
+            SessionType::SecureGroup(_) => {
+                let ahead = counter.wrapping_sub(self.max_counter);
+                if ahead < 1 << 31 {
+                    self.advance(counter, ahead);
+                    false
                 } else {
-                    self.bitmap = u32::MAX;
+                    self.check_behind(self.max_counter.wrapping_sub(counter))
                 }
+            }
+            // Secured messages don't allow rolling over, so the max is always higher
+            SessionType::SecureUnicast(_) if counter < self.max_counter => {
+                self.check_behind(self.max_counter - counter)
+            }
+            SessionType::SecureUnicast(_) => {
+                self.advance(counter, counter - self.max_counter);
+                false
+            }
+            // Unsecured counters restart with the peer, so a counter behind
+            // the window is accepted, and starts the window again
+            SessionType::UnsecuredSession if counter > self.max_counter => {
+                self.advance(counter, counter - self.max_counter);
                 false
             }
-            (SessionType::UnsecuredSession | SessionType::SecureGroup(_), false) => {
+            SessionType::UnsecuredSession => {
+                let delta = self.max_counter - counter;
+                if delta <= MSG_COUNTER_WINDOW_SIZE {
+                    return self.check_behind(delta);
+                }
                 self.max_counter = counter;
                 self.bitmap = u32::MAX;
                 false
@@ -372,6 +1525,31 @@ impl MessageCounter {
         }
     }
 
+    fn advance(&mut self, counter: u32, delta: u32) {
+        self.max_counter = counter;
+        if delta <= MSG_COUNTER_WINDOW_SIZE {
+            // The previous max moves into the window
+            self.bitmap = self.bitmap.checked_shl(delta).unwrap_or(0);
+            self.insert(1 << (delta - 1));
+        } else {
+            self.bitmap = 0;
+        }
+    }
+
+    /// Whether a counter that is `delta` behind the max is a duplicate.
+    /// Counters behind the window are treated as duplicates.
+    fn check_behind(&mut self, delta: u32) -> bool {
+        if delta > MSG_COUNTER_WINDOW_SIZE {
+            return true;
+        }
+        let delta_bitmap = 1 << (delta - 1);
+        let is_duplicate = self.contains(delta_bitmap);
+        if !is_duplicate {
+            self.insert(delta_bitmap);
+        }
+        is_duplicate
+    }
+
     fn contains(&self, delta_bitmap: u32) -> bool {
         self.bitmap & delta_bitmap != 0
     }
@@ -381,6 +1559,17 @@ impl MessageCounter {
     }
 }
 
+/// Drop a message that failed, acknowledging it first if the error
+/// [should be acknowledged](MessageError::should_ack)
+fn drop_message(message: &Message, error: &MessageError) -> ExchangeMessageAction {
+    println!("Dropping message: {error}");
+    if error.should_ack() && message.next_ack().is_some() {
+        ExchangeMessageAction::AckAndDrop
+    } else {
+        ExchangeMessageAction::Drop
+    }
+}
+
 /// Helper that indicates what should be done with a message after exchange processing.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ExchangeMessageAction {
@@ -391,3 +1580,884 @@ pub enum ExchangeMessageAction {
     /// Acknowledge message and drop it
     AckAndDrop,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        secure_channel::MSG_COUNTER_SYNC_REQ_JITTER,
+        session_context::{GroupKey, SessionCounter},
+        util::time::MockClock,
+    };
+
+    use super::*;
+
+    /// An unsecured message, where the initiator is node 0x0102
+    fn message(exchange_id: u16, exchange_flags: ExchangeFlags, message_counter: u32) -> Message {
+        let mut message_header = MessageHeader::new(0);
+        if exchange_flags.contains(ExchangeFlags::INITIATOR) {
+            message_header.message_flags = MessageFlags::SOURCE_NODE_ID_PRESENT;
+            message_header.source_node_id = Some(0x0102);
+        } else {
+            message_header.message_flags = MessageFlags::DSIZ_64_BIT_NODE_ID;
+            message_header.dest_node_id = Some(NodeID::Unique(0x0102));
+        }
+        message_header.message_counter = message_counter;
+        Message::new(
+            message_header,
+            Some(ProtocolHeader {
+                exchange_flags,
+                protocol_opcode: SecureChannelProtocolOpCode::PBKDFParamRequest as _,
+                exchange_id,
+                protocol_id: ProtocolID::SecureChannel as _,
+                ..Default::default()
+            }),
+            BytesMut::new(),
+        )
+    }
+
+    fn initiator_key(exchange_id: u16) -> ExchangeKey {
+        ExchangeKey {
+            session_id: 0,
+            exchange_id,
+            role: ExchangeRole::Initiator,
+            initiator_node_id: Some(0x0102),
+        }
+    }
+
+    fn responder_key(exchange_id: u16) -> ExchangeKey {
+        ExchangeKey {
+            session_id: 0,
+            exchange_id,
+            role: ExchangeRole::Responder,
+            initiator_node_id: Some(0x0102),
+        }
+    }
+
+    /// An exchange manager that accepts secure channel messages
+    fn manager(clock: &MockClock) -> ExchangeManager {
+        fn ignore(_: &mut ProtocolContext<'_>, _: &Message) -> Option<Message> {
+            None
+        }
+        let mut manager = ExchangeManager::with_clock(Arc::new(clock.clone()));
+        manager.register_handler(0, ProtocolID::SecureChannel as u16, ignore);
+        manager
+    }
+
+    /// Encode and decode a message, as if it was received
+    fn receive(manager: &mut ExchangeManager, message: &Message) -> ExchangeMessageAction {
+        let mut out = BytesMut::new();
+        message.encode(&mut out, None);
+        let mut message = Message::decode(out).unwrap();
+        manager.receive_message(&mut message)
+    }
+
+    #[test]
+    fn test_mrp_backoff_time() {
+        let backoff = |retransmissions, jitter| {
+            mrp_backoff_time(Duration::from_millis(300), retransmissions, jitter).as_secs_f64()
+        };
+        let expected = [
+            (0, 0.0, 0.33),
+            (1, 0.0, 0.33),
+            (2, 0.0, 0.528),
+            (3, 0.0, 0.8448),
+        ];
+        for (retransmissions, jitter, seconds) in expected {
+            assert!((backoff(retransmissions, jitter) - seconds).abs() < 1e-6);
+        }
+        // Jitter lengthens the backoff by up to a quarter
+        assert!((backoff(0, 0.5) - 0.37125).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mrp_retransmissions() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        let (exchange_id, _) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+        let flags = ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY;
+        let mut request = message(exchange_id, flags, 0);
+        let mut sent = BytesMut::new();
+        manager.encode_message(&mut request, &mut sent).unwrap();
+        let message_counter = request.message_header.message_counter;
+
+        // An exchange has one reliable message in flight
+        assert_eq!(
+            manager.encode_message(&mut request.clone(), &mut BytesMut::new()),
+            Err(ExchangeError::RetransmissionPending { message_counter })
+        );
+
+        // The first retransmissions wait for the base interval, then they back off
+        let mut intervals = vec![];
+        for _ in 1..MRP_MAX_TRANSMISSIONS {
+            let timeout = manager.next_timeout().unwrap();
+            intervals.push(timeout - clock.now());
+            clock.advance(timeout - clock.now() - Duration::from_millis(1));
+            assert!(manager.poll().is_empty());
+            clock.advance_to(timeout);
+            let events = manager.poll();
+            assert!(matches!(&events[..], [ExchangeEvent::Send { packet, .. }] if packet == &sent));
+        }
+        let idle = MrpParameters::default().idle_retrans_timeout;
+        assert!(intervals[0] >= idle.mul_f64(MRP_BACKOFF_MARGIN));
+        assert!(intervals[3] >= idle.mul_f64(MRP_BACKOFF_MARGIN * MRP_BACKOFF_BASE.powi(2)));
+
+        clock.advance_to(manager.next_timeout().unwrap());
+        assert!(matches!(
+            manager.poll()[..],
+            [ExchangeEvent::Failed { message_counter: failed, .. }] if failed == message_counter
+        ));
+        // The exchange failed and was closed
+        assert!(manager.find_exchange(&initiator_key(exchange_id)).is_none());
+        assert!(manager.next_timeout().is_none());
+
+        // An ack from the peer stops the retransmissions
+        let (exchange_id, _) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+        let mut request = message(exchange_id, flags, 0);
+        manager
+            .encode_message(&mut request, &mut BytesMut::new())
+            .unwrap();
+        let mut response = message(exchange_id, ExchangeFlags::empty(), 1);
+        response.with_ack(Some(request.message_header.message_counter));
+        let exchange = manager.find_exchange(&initiator_key(exchange_id)).unwrap();
+        assert_eq!(
+            exchange.on_message(&response, clock.now()),
+            ExchangeMessageAction::Process
+        );
+        assert!(!exchange.is_awaiting_ack());
+    }
+
+    #[test]
+    fn test_mrp_acknowledgements() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        let flags = ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY;
+        assert_eq!(
+            receive(&mut manager, &message(5, flags, 100)),
+            ExchangeMessageAction::Process
+        );
+        assert_eq!(
+            manager.next_timeout(),
+            Some(clock.now() + MRP_STANDALONE_ACK_TIMEOUT)
+        );
+
+        // The ack is piggybacked on the response
+        let mut response = message(5, ExchangeFlags::empty(), 1);
+        manager
+            .encode_message(&mut response, &mut BytesMut::new())
+            .unwrap();
+        assert_eq!(
+            response
+                .payload_header
+                .as_ref()
+                .unwrap()
+                .ack_message_counter,
+            Some(100)
+        );
+        assert_eq!(
+            manager.next_timeout(),
+            Some(clock.now() + EXCHANGE_IDLE_TIMEOUT)
+        );
+
+        // A duplicate is acknowledged again
+        assert_eq!(
+            receive(&mut manager, &message(5, flags, 100)),
+            ExchangeMessageAction::AckAndDrop
+        );
+
+        // Without a response, a standalone ack is sent when the timer expires
+        let received_at = clock.now();
+        receive(&mut manager, &message(5, flags, 101));
+        clock.advance(MRP_STANDALONE_ACK_TIMEOUT / 2);
+        assert!(manager.poll().is_empty());
+        clock.advance(MRP_STANDALONE_ACK_TIMEOUT / 2);
+        let events = manager.poll();
+        let [ExchangeEvent::Send { packet, .. }] = &events[..] else {
+            panic!("expected a standalone ack, got {events:?}");
+        };
+        let mut ack = Message::decode(packet.clone()).unwrap();
+        ack.decrypt(None).unwrap();
+        let payload_header = ack.payload_header.unwrap();
+        assert_eq!(
+            payload_header.protocol_opcode,
+            SecureChannelProtocolOpCode::MRPStandaloneAck as u8
+        );
+        assert_eq!(payload_header.ack_message_counter, Some(101));
+        assert!(!payload_header
+            .exchange_flags
+            .contains(ExchangeFlags::INITIATOR));
+        assert_eq!(
+            manager.next_timeout(),
+            Some(received_at + EXCHANGE_IDLE_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn test_close_exchange() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        let flags = ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY;
+        receive(&mut manager, &message(5, flags, 100));
+
+        // Closing flushes the pending ack straight away
+        let events = manager.close_exchange(responder_key(5)).unwrap();
+        assert!(matches!(
+            &events[..],
+            [ExchangeEvent::Send { exchange, .. }] if *exchange == responder_key(5)
+        ));
+        assert!(manager.find_exchange(&responder_key(5)).is_none());
+        assert_eq!(
+            manager.close_exchange(responder_key(5)).unwrap_err(),
+            ExchangeError::UnknownExchange(responder_key(5))
+        );
+
+        // An exchange with a message in flight waits for its ack
+        let (exchange_id, _) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+        let mut request = message(exchange_id, flags, 0);
+        manager
+            .encode_message(&mut request, &mut BytesMut::new())
+            .unwrap();
+        let key = initiator_key(exchange_id);
+        assert!(manager.close_exchange(key).unwrap().is_empty());
+        let mut response = message(exchange_id, ExchangeFlags::empty(), 1);
+        response.with_ack(Some(request.message_header.message_counter));
+        assert_eq!(
+            receive(&mut manager, &response),
+            ExchangeMessageAction::Process
+        );
+        assert!(manager.poll().is_empty());
+        assert!(manager.find_exchange(&key).is_none());
+    }
+
+    #[test]
+    fn test_replay_after_close() {
+        let mut manager = manager(&MockClock::new());
+        let flags = ExchangeFlags::INITIATOR;
+
+        // The unsecured session of the peer remembers its messages
+        let first = message(5, flags, 100);
+        assert_eq!(
+            receive(&mut manager, &first),
+            ExchangeMessageAction::Process
+        );
+        assert_eq!(
+            receive(&mut manager, &message(6, flags, 101)),
+            ExchangeMessageAction::Process
+        );
+        manager.close_exchange(responder_key(5)).unwrap();
+        assert_eq!(receive(&mut manager, &first), ExchangeMessageAction::Drop);
+        assert!(manager.find_exchange(&responder_key(5)).is_none());
+
+        // and so does a secure session
+        let session = SecureSessionContext::new_pase(false, false, 7, 8, &[0; 16], &[]);
+        let peer_key = session.decryption_key;
+        manager
+            .add_session(SessionContext::Secure(session))
+            .unwrap();
+        let mut first = message(5, flags, 100);
+        first.message_header.session_id = 7;
+        first.message_header.session_type = SessionType::SecureUnicast(7);
+        let mut packet = BytesMut::new();
+        first.encode(&mut packet, Some(&peer_key));
+        let receive = |manager: &mut ExchangeManager| {
+            let mut message = Message::decode(packet.clone()).unwrap();
+            manager.receive_message(&mut message)
+        };
+        assert_eq!(receive(&mut manager), ExchangeMessageAction::Process);
+        let key = ExchangeKey::received(&first);
+        manager.close_exchange(key).unwrap();
+        assert_eq!(receive(&mut manager), ExchangeMessageAction::Drop);
+        assert!(manager.find_exchange(&key).is_none());
+    }
+
+    #[test]
+    fn test_exchange_idle_timeout() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        let (exchange_id, _) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+        let key = initiator_key(exchange_id);
+
+        // Messages keep the exchange open
+        clock.advance(EXCHANGE_IDLE_TIMEOUT / 2);
+        let mut request = message(exchange_id, ExchangeFlags::INITIATOR, 10);
+        manager
+            .encode_message(&mut request, &mut BytesMut::new())
+            .unwrap();
+        clock.advance(EXCHANGE_IDLE_TIMEOUT / 2);
+        assert!(manager.poll().is_empty());
+
+        clock.advance_to(manager.next_timeout().unwrap());
+        assert!(matches!(
+            manager.poll()[..],
+            [ExchangeEvent::Expired { exchange }] if exchange == key
+        ));
+        assert!(manager.find_exchange(&key).is_none());
+        assert!(manager.next_timeout().is_none());
+    }
+
+    #[test]
+    fn test_exchange_eviction() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        let flags = ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY;
+        for exchange_id in 0..EXCHANGE_LIMIT as u16 {
+            receive(
+                &mut manager,
+                &message(exchange_id, flags, 100 + exchange_id as u32),
+            );
+            clock.advance(Duration::from_millis(1));
+        }
+
+        // Exchanges with pending acks aren't evicted
+        assert_eq!(
+            receive(&mut manager, &message(1000, flags, 200)),
+            ExchangeMessageAction::Drop
+        );
+        assert!(manager.find_exchange(&responder_key(1000)).is_none());
+
+        // Once they are acknowledged, the least recently active one makes room
+        clock.advance(MRP_STANDALONE_ACK_TIMEOUT);
+        assert_eq!(manager.poll().len(), EXCHANGE_LIMIT);
+        // The dropped message wasn't recorded, so its retransmission is processed
+        assert_eq!(
+            receive(&mut manager, &message(1000, flags, 200)),
+            ExchangeMessageAction::Process
+        );
+        assert!(manager.find_exchange(&responder_key(0)).is_none());
+        assert!(manager.find_exchange(&responder_key(1)).is_some());
+        assert!(manager.find_exchange(&responder_key(1000)).is_some());
+    }
+
+    #[test]
+    fn test_exchange_matching() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        assert_eq!(
+            receive(&mut manager, &message(5, ExchangeFlags::INITIATOR, 100)),
+            ExchangeMessageAction::Process
+        );
+
+        // Our exchange can have the same ID as the peer's
+        manager.next_exchange_id = 5;
+        let (exchange_id, _) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+        assert_eq!(exchange_id, 5);
+        // but not the same ID as our other exchanges
+        manager.next_exchange_id = 5;
+        let (exchange_id, _) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+        assert_eq!(exchange_id, 6);
+
+        // A response matches the exchange that we initiated
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            receive(&mut manager, &message(5, ExchangeFlags::empty(), 1)),
+            ExchangeMessageAction::Process
+        );
+        let initiated = manager.find_exchange(&initiator_key(5)).unwrap();
+        assert_eq!(initiated.active_at, clock.now());
+        let responded = manager.find_exchange(&responder_key(5)).unwrap();
+        assert!(responded.active_at < clock.now());
+
+        // Messages that don't initiate an exchange aren't unsolicited, and
+        // are only acknowledged
+        assert_eq!(
+            receive(&mut manager, &message(9, ExchangeFlags::RELIABILITY, 1)),
+            ExchangeMessageAction::AckAndDrop
+        );
+        assert!(manager.find_exchange(&initiator_key(9)).is_none());
+        assert!(manager.find_exchange(&responder_key(9)).is_none());
+
+        // Peers share the unsecured session, and can pick the same exchange ID
+        let mut other_peer = message(5, ExchangeFlags::INITIATOR, 100);
+        other_peer.message_header.source_node_id = Some(0x0304);
+        assert_eq!(
+            receive(&mut manager, &other_peer),
+            ExchangeMessageAction::Process
+        );
+        let other_key = ExchangeKey::received(&other_peer);
+        assert_eq!(other_key.initiator_node_id, Some(0x0304));
+        assert!(manager.find_exchange(&other_key).is_some());
+        assert!(manager.find_exchange(&responder_key(5)).is_some());
+
+        // Without a source node, unsecured messages can't be told apart
+        let mut anonymous = message(7, ExchangeFlags::INITIATOR, 1);
+        anonymous.message_header.message_flags = MessageFlags::empty();
+        anonymous.message_header.source_node_id = None;
+        assert_eq!(
+            receive(&mut manager, &anonymous),
+            ExchangeMessageAction::Drop
+        );
+    }
+
+    #[test]
+    fn test_remove_session() {
+        let mut manager = manager(&MockClock::new());
+        receive(&mut manager, &message(5, ExchangeFlags::INITIATOR, 100));
+        let other = manager.insert_exchange(Exchange::initiator(7, 1)).unwrap();
+
+        // Unsecured sessions don't have an ID, but their exchanges are closed
+        assert!(manager.remove_session(0).is_none());
+        assert!(manager.find_exchange(&responder_key(5)).is_none());
+        // Other sessions keep their exchanges
+        assert!(manager.find_exchange(&other).is_some());
+    }
+
+    #[test]
+    fn test_session_eviction() {
+        let mut manager = manager(&MockClock::new());
+        manager.set_session_limit(2);
+        let session = |id| {
+            SessionContext::Secure(SecureSessionContext::new_pase(
+                false,
+                false,
+                id,
+                1,
+                &[0; 16],
+                &[],
+            ))
+        };
+        for id in [7, 8] {
+            assert_eq!(manager.add_session(session(id)), Ok(id));
+            manager.insert_exchange(Exchange::initiator(id, 1)).unwrap();
+        }
+        assert_eq!(manager.add_session(session(7)), Err(SessionError::InUse(7)));
+        assert_eq!(manager.add_session(session(0)), Err(SessionError::InUse(0)));
+
+        // Sending keeps a session active, so the other one makes room
+        let mut request = message(1, ExchangeFlags::INITIATOR, 0);
+        request.message_header.session_id = 7;
+        request.message_header.session_type = SessionType::SecureUnicast(7);
+        manager
+            .encode_message(&mut request, &mut BytesMut::new())
+            .unwrap();
+        assert_eq!(manager.add_session(session(9)), Ok(9));
+        assert!(manager.session_context(8).is_none());
+        assert!(manager
+            .find_exchange(&ExchangeKey {
+                session_id: 8,
+                exchange_id: 1,
+                role: ExchangeRole::Initiator,
+                initiator_node_id: None,
+            })
+            .is_none());
+        assert!(manager.session_context(7).is_some());
+        assert!(matches!(
+            manager.poll()[..],
+            [ExchangeEvent::SessionEvicted { session_id: 8 }]
+        ));
+
+        // Reserved IDs are random, non-zero and not in use
+        let (_, reserved) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+        assert!(![0, 7, 9].contains(&reserved));
+        assert!(matches!(
+            manager.session_context(reserved),
+            Some(SessionContext::Unsecured(_))
+        ));
+        // The established session replaces the reservation
+        assert_eq!(manager.add_session(session(reserved)), Ok(reserved));
+        assert!(matches!(
+            manager.session_context(reserved),
+            Some(SessionContext::Secure(_))
+        ));
+    }
+
+    #[test]
+    fn test_session_reservations() {
+        let mut manager = manager(&MockClock::new());
+        manager.set_session_limit(2);
+        let session = SecureSessionContext::new_pase(false, false, 7, 1, &[0; 16], &[]);
+        assert_eq!(manager.add_session(SessionContext::Secure(session)), Ok(7));
+
+        // Reservations don't evict established sessions, the oldest
+        // reservation makes room instead
+        let mut reservations = vec![];
+        for node_id in 1..=3 {
+            let (exchange_id, reserved) =
+                manager.new_initiator_exchange_unsecured(node_id).unwrap();
+            reservations.push((node_id, exchange_id, reserved));
+        }
+        assert!(manager.session_context(7).is_some());
+        assert!(manager.poll().is_empty());
+        assert!(manager.session_context(reservations[0].2).is_none());
+
+        // Closing an exchange releases the ID it reserved
+        let (node_id, exchange_id, reserved) = reservations[1];
+        manager
+            .close_exchange(ExchangeKey {
+                session_id: 0,
+                exchange_id,
+                role: ExchangeRole::Initiator,
+                initiator_node_id: Some(node_id),
+            })
+            .unwrap();
+        assert!(manager.session_context(reserved).is_none());
+        assert!(manager.session_context(reservations[2].2).is_some());
+    }
+
+    #[test]
+    fn test_message_counters() {
+        let mut manager = manager(&MockClock::new());
+        // Unsecured messages share the global counter, whatever their exchange
+        let mut counters = vec![];
+        for _ in 0..2 {
+            let (exchange_id, _) = manager.new_initiator_exchange_unsecured(0x0102).unwrap();
+            let mut request = message(exchange_id, ExchangeFlags::INITIATOR, 0);
+            manager
+                .encode_message(&mut request, &mut BytesMut::new())
+                .unwrap();
+            counters.push(request.message_header.message_counter);
+        }
+        assert_eq!(counters[1], counters[0] + 1);
+        let duplicate = message(
+            9,
+            ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY,
+            100,
+        );
+        let mut out = BytesMut::new();
+        manager.encode_standalone_ack(&duplicate, &mut out).unwrap();
+        let ack = Message::decode(out).unwrap();
+        assert_eq!(ack.message_header.message_counter, counters[1] + 1);
+
+        // A secure session expires instead of rolling its counter over
+        let mut session = SecureSessionContext::new_pase(true, false, 7, 8, &[0; 16], &[]);
+        session.local_message_counter = SessionCounter::starting_at(u32::MAX);
+        manager
+            .add_session(SessionContext::Secure(session))
+            .unwrap();
+        let key = manager.insert_exchange(Exchange::initiator(7, 1)).unwrap();
+        let secure_message = || {
+            let mut message = message(1, ExchangeFlags::INITIATOR, 0);
+            message.message_header.session_id = 7;
+            message.message_header.session_type = SessionType::SecureUnicast(7);
+            message
+        };
+        let mut last = secure_message();
+        manager
+            .encode_message(&mut last, &mut BytesMut::new())
+            .unwrap();
+        assert_eq!(last.message_header.message_counter, u32::MAX);
+        assert_eq!(
+            manager.encode_message(&mut secure_message(), &mut BytesMut::new()),
+            Err(ExchangeError::SessionExpired(7))
+        );
+        assert!(manager.session_context(7).is_none());
+        assert!(manager.find_exchange(&key).is_none());
+    }
+
+    #[test]
+    fn test_sleepy_peer() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        // The peer sleeps for up to 10s, and asks for more than the maximum
+        // while it's active
+        let parameters = SessionParameters {
+            session_idle_interval: Some(10_000),
+            session_active_interval: Some(u32::MAX),
+            session_active_threshold: None,
+        };
+        let mrp = MrpParameters::from_session_parameters(Some(&parameters));
+        assert_eq!(mrp.idle_retrans_timeout, Duration::from_secs(10));
+        assert_eq!(mrp.active_retrans_timeout, MRP_MAX_INTERVAL);
+        assert_eq!(
+            mrp.active_threshold,
+            MrpParameters::default().active_threshold
+        );
+        assert_eq!(
+            MrpParameters::from_session_parameters(Some(&mrp.session_parameters())),
+            mrp
+        );
+
+        let mut session = SecureSessionContext::new_pase(true, false, 7, 8, &[0; 16], &[]);
+        session.peer_mrp_parameters = mrp;
+        let peer_key = session.decryption_key;
+        manager
+            .add_session(SessionContext::Secure(session))
+            .unwrap();
+        manager.insert_exchange(Exchange::initiator(7, 1)).unwrap();
+        let mut request = message(1, ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY, 0);
+        request.message_header.session_id = 7;
+        request.message_header.session_type = SessionType::SecureUnicast(7);
+        manager
+            .encode_message(&mut request, &mut BytesMut::new())
+            .unwrap();
+        let interval = manager.next_timeout().unwrap() - clock.now();
+        assert!(interval >= Duration::from_secs(10).mul_f64(MRP_BACKOFF_MARGIN));
+        assert!(interval < MRP_MAX_INTERVAL);
+
+        // Once the peer answers, it's active in the session, and so on the
+        // session's other exchanges too
+        let mut response = message(1, ExchangeFlags::empty(), 0);
+        response.message_header.session_id = 7;
+        response.message_header.session_type = SessionType::SecureUnicast(7);
+        response.with_ack(Some(request.message_header.message_counter));
+        let mut packet = BytesMut::new();
+        response.encode(&mut packet, Some(&peer_key));
+        let mut response = Message::decode(packet).unwrap();
+        assert_eq!(
+            manager.receive_message(&mut response),
+            ExchangeMessageAction::Process
+        );
+        let key = manager.insert_exchange(Exchange::initiator(7, 2)).unwrap();
+        let mut request = message(2, ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY, 0);
+        request.message_header.session_id = 7;
+        request.message_header.session_type = SessionType::SecureUnicast(7);
+        manager
+            .encode_message(&mut request, &mut BytesMut::new())
+            .unwrap();
+        let retransmission = manager.find_exchange(&key).unwrap().retransmission.as_ref();
+        let interval = retransmission.unwrap().retrans_timeout - clock.now();
+        assert!(interval >= MRP_MAX_INTERVAL.mul_f64(MRP_BACKOFF_MARGIN));
+    }
+
+    #[test]
+    fn test_group_replay() {
+        let mut manager = manager(&MockClock::new());
+        let key = GroupKey::new(1, 7, 0, &[3; 16], &[0; 8]);
+        manager.group_keys_mut().add_key(key.clone());
+        // Group messages are never acknowledged, even if the sender asks
+        let mut request = message(3, ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY, 0);
+        request.message_header = MessageHeader::group(key.session_id, 0x0102, 0x0101);
+        request.message_header.message_counter = 11;
+        let mut out = BytesMut::new();
+        request.encode(&mut out, Some(&key.encryption_key));
+
+        let mut received = Message::decode(out.clone()).unwrap();
+        assert_eq!(
+            manager.receive_message(&mut received),
+            ExchangeMessageAction::Process
+        );
+        assert!(manager
+            .close_exchange(ExchangeKey::received(&received))
+            .unwrap()
+            .is_empty());
+
+        // A replayed group command is rejected by the counter of its sender,
+        // even once its exchange is gone
+        let mut replayed = Message::decode(out).unwrap();
+        assert_eq!(
+            manager.receive_message(&mut replayed),
+            ExchangeMessageAction::Drop
+        );
+    }
+
+    #[test]
+    fn test_group_exchange() {
+        let mut manager = manager(&MockClock::new());
+        manager.set_session_limit(1);
+        let session = SecureSessionContext::new_pase(false, false, 7, 1, &[0; 16], &[]);
+        manager
+            .add_session(SessionContext::Secure(session))
+            .unwrap();
+        let key = GroupKey::new(1, 7, 0, &[3; 16], &[0; 8]);
+        manager.group_keys_mut().add_key(key.clone());
+        let mut request = message(3, ExchangeFlags::INITIATOR, 0);
+        request.message_header = MessageHeader::group(key.session_id, 0x0102, 0x0101);
+        request.message_header.message_counter = 11;
+        let mut out = BytesMut::new();
+        request.encode(&mut out, Some(&key.encryption_key));
+        let mut received = Message::decode(out).unwrap();
+        assert_eq!(
+            manager.receive_message(&mut received),
+            ExchangeMessageAction::Process
+        );
+
+        // The exchange is keyed by the sender, and group messages take no
+        // room from the unicast sessions
+        let exchange = ExchangeKey::received(&received);
+        assert_eq!(exchange.initiator_node_id, Some(0x0102));
+        assert!(manager.find_exchange(&exchange).is_some());
+        assert!(manager.session_context(key.session_id).is_none());
+        assert!(manager
+            .session_manager
+            .find_unsecured_session_mut(0x0102)
+            .is_none());
+        assert!(manager.session_context(7).is_some());
+        assert!(manager.poll().is_empty());
+    }
+
+    #[test]
+    fn test_group_encryption() {
+        let mut manager = manager(&MockClock::new());
+        let key = GroupKey::new(1, 7, 0, &[3; 16], &[0; 8]);
+        manager.group_keys_mut().add_key(key.clone());
+        manager.group_keys_mut().set_node_id(1, 0x0102);
+        let command = || {
+            let mut command = message(3, ExchangeFlags::INITIATOR, 0);
+            command.message_header = MessageHeader::group(key.session_id, 0x0102, 0x0101);
+            command.payload = BytesMut::from(&b"group command"[..]);
+            command
+        };
+
+        // A group without a key set isn't sent to
+        assert_eq!(
+            manager.encode_message(&mut command(), &mut BytesMut::new()),
+            Err(ExchangeError::NoGroupKey(key.session_id))
+        );
+
+        // Group messages are encrypted with the group's current key
+        manager.group_keys_mut().map_group(1, 0x0101, 7);
+        let mut out = BytesMut::new();
+        manager.encode_message(&mut command(), &mut out).unwrap();
+        assert!(!out.windows(13).any(|window| window == b"group command"));
+        let mut received = Message::decode(out).unwrap();
+        received.decrypt(Some(&key.encryption_key)).unwrap();
+        assert_eq!(&received.payload[..], b"group command");
+    }
+
+    #[test]
+    fn test_message_counter_sync() {
+        let clock = MockClock::new();
+        let key = GroupKey::new(1, 7, 0, &[3; 16], &[0; 8]);
+        let node = |node_id| {
+            let mut manager = manager(&clock);
+            manager.group_keys_mut().add_key(key.clone());
+            manager.group_keys_mut().set_node_id(1, node_id);
+            manager
+        };
+        let (mut receiver, mut sender) = (node(0x0a), node(0x0b));
+        let group_type = SessionType::SecureGroup(key.session_id);
+        let packets = |events: Vec<ExchangeEvent>| -> Vec<BytesMut> {
+            events
+                .into_iter()
+                .map(|event| match event {
+                    ExchangeEvent::Send { packet, .. } => packet,
+                    event => panic!("expected a packet, got {event:?}"),
+                })
+                .collect()
+        };
+        let receive = |manager: &mut ExchangeManager, packet: &BytesMut| {
+            let mut message = Message::decode(packet.clone()).unwrap();
+            manager.receive_message(&mut message)
+        };
+
+        // A control message from a peer whose counter isn't known waits for it
+        let mut command = message(3, ExchangeFlags::INITIATOR, 0);
+        command.message_header = MessageHeader::group(key.session_id, 0x0b, 0x0101);
+        command.message_header.security_flags |= SecurityFlags::CONTROL;
+        command.message_header.message_counter = sender
+            .session_manager
+            .next_message_counter(key.session_id, &group_type)
+            .unwrap();
+        let mut command_packet = BytesMut::new();
+        command.encode(&mut command_packet, Some(&key.encryption_key));
+        assert_eq!(
+            receive(&mut receiver, &command_packet),
+            ExchangeMessageAction::Drop
+        );
+
+        // The request is sent after a jitter, and the peer answers it
+        let send_at = receiver.next_timeout().unwrap();
+        assert!(send_at - clock.now() <= Duration::from_millis(MSG_COUNTER_SYNC_REQ_JITTER as u64));
+        clock.advance_to(send_at);
+        let [request] = &packets(receiver.poll())[..] else {
+            panic!("expected a MsgCounterSyncReq");
+        };
+        assert_eq!(receive(&mut sender, request), ExchangeMessageAction::Drop);
+        let [response] = &packets(sender.poll())[..] else {
+            panic!("expected a MsgCounterSyncRsp");
+        };
+
+        // A response that isn't for us is ignored, or the queued message is
+        // released with the counter it gives
+        assert_eq!(receive(&mut sender, response), ExchangeMessageAction::Drop);
+        assert_eq!(
+            receive(&mut receiver, response),
+            ExchangeMessageAction::Drop
+        );
+        let events = receiver.poll();
+        let [ExchangeEvent::Received { message }] = &events[..] else {
+            panic!("expected the queued message, got {events:?}");
+        };
+        assert_eq!(message.payload, command.payload);
+        assert!(receiver.poll().is_empty());
+        // The response can't be replayed, and neither can the message
+        assert_eq!(
+            receive(&mut receiver, response),
+            ExchangeMessageAction::Drop
+        );
+        assert!(receiver.poll().is_empty());
+        receiver
+            .close_exchange(ExchangeKey::received(message))
+            .unwrap();
+        assert_eq!(
+            receive(&mut receiver, &command_packet),
+            ExchangeMessageAction::Drop
+        );
+
+        // Peers that don't answer are given up on
+        let mut command = command.clone();
+        command.message_header.source_node_id = Some(0x0c);
+        let mut command_packet = BytesMut::new();
+        command.encode(&mut command_packet, Some(&key.encryption_key));
+        receive(&mut receiver, &command_packet);
+        clock.advance_to(receiver.next_timeout().unwrap());
+        assert_eq!(packets(receiver.poll()).len(), 1);
+        clock.advance_to(receiver.next_timeout().unwrap());
+        let events = receiver.poll();
+        assert!(matches!(
+            &events[..],
+            [ExchangeEvent::SyncFailed { peer }] if peer.source_node_id == 0x0c
+        ));
+    }
+
+    #[test]
+    fn test_protocol_dispatch() {
+        let clock = MockClock::new();
+        let mut manager = manager(&clock);
+        // A vendor's protocol that answers every message
+        manager.register_handler(
+            0xfff1,
+            0x0001,
+            |context: &mut ProtocolContext<'_>, message: &Message| {
+                assert_eq!(context.exchange, responder_key(5));
+                let mut response = message.clone();
+                let payload_header = response.payload_header.as_mut().unwrap();
+                payload_header.exchange_flags = ExchangeFlags::empty();
+                payload_header.protocol_opcode = 0x02;
+                Some(response)
+            },
+        );
+        let vendor_message = |vendor_id| {
+            let mut message = message(
+                5,
+                ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY,
+                100,
+            );
+            let payload_header = message.payload_header.as_mut().unwrap();
+            payload_header.protocol_vendor_id = Some(vendor_id);
+            payload_header.protocol_id = 0x0001;
+            message
+        };
+
+        // Unsolicited messages of protocols without a handler are only acknowledged
+        let unknown = vendor_message(0xfff2);
+        assert_eq!(
+            receive(&mut manager, &unknown),
+            ExchangeMessageAction::AckAndDrop
+        );
+        assert!(manager.find_exchange(&responder_key(5)).is_none());
+        assert_eq!(
+            manager.dispatch(&unknown, &mut BytesMut::new()),
+            Err(ExchangeError::NoHandler {
+                vendor_id: 0xfff2,
+                protocol_id: 0x0001
+            })
+        );
+
+        let request = vendor_message(0xfff1);
+        assert_eq!(
+            receive(&mut manager, &request),
+            ExchangeMessageAction::Process
+        );
+        let mut out = BytesMut::new();
+        manager.dispatch(&request, &mut out).unwrap();
+
+        // The response is sent on the exchange, and acknowledges the request
+        let mut response = Message::decode(out).unwrap();
+        response.decrypt(None).unwrap();
+        let payload_header = response.payload_header.unwrap();
+        assert_eq!(payload_header.protocol_vendor_id, Some(0xfff1));
+        assert_eq!(payload_header.protocol_opcode, 0x02);
+        assert_eq!(payload_header.exchange_id, 5);
+        assert_eq!(payload_header.ack_message_counter, Some(100));
+        assert!(manager.next_timeout() > Some(clock.now() + MRP_STANDALONE_ACK_TIMEOUT));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::tlv::{decode, ElementSize, Encoder, TagControl, TagLengthValue, Tlv, TlvData, TlvType};
+use crate::tlv::{FromTlv, RawTlv, ToTlv};
 
 pub mod action;
 pub mod path;
@@ -28,725 +28,318 @@ pub enum InteractionModelProtocolOpCode {
     TimedRequest,
 }
 
+/// StatusResponseMessage (10.6.1)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct StatusResponseMessage {
-    pub status: u32,
+    #[tlv(tag = 0)]
+    pub status: u8,
+    #[tlv(tag = 255)]
+    pub interaction_model_revision: u8,
 }
 
-#[derive(Debug)]
+/// Interaction model status codes (8.10)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToTlv, FromTlv)]
+pub enum StatusCode {
+    Success = 0x00,
+    Failure = 0x01,
+    InvalidSubscription = 0x7d,
+    UnsupportedAccess = 0x7e,
+    UnsupportedEndpoint = 0x7f,
+    InvalidAction = 0x80,
+    UnsupportedCommand = 0x81,
+    InvalidCommand = 0x85,
+    UnsupportedAttribute = 0x86,
+    ConstraintError = 0x87,
+    UnsupportedWrite = 0x88,
+    ResourceExhausted = 0x89,
+    NotFound = 0x8b,
+    UnreportableAttribute = 0x8c,
+    InvalidDataType = 0x8d,
+    UnsupportedRead = 0x8f,
+    DataVersionMismatch = 0x92,
+    Timeout = 0x94,
+    Busy = 0x9c,
+    UnsupportedCluster = 0xc3,
+    NoUpstreamSubscription = 0xc5,
+    NeedsTimedInteraction = 0xc6,
+    UnsupportedEvent = 0xc7,
+    PathsExhausted = 0xc8,
+    TimedRequestMismatch = 0xc9,
+    FailsafeRequired = 0xca,
+}
+
+/// ReadRequestMessage (10.6.2)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct ReadRequestMessage {
+    #[tlv(tag = 0)]
     pub attribute_requests: Option<Vec<AttributePathIB>>,
+    #[tlv(tag = 1)]
     pub event_requests: Option<Vec<EventPathIB>>,
+    #[tlv(tag = 2)]
     pub event_filters: Option<Vec<EventFilterIB>>,
+    #[tlv(tag = 3)]
     pub fabric_filtered: bool,
+    #[tlv(tag = 4)]
     pub data_version_filters: Option<Vec<DataVersionFilterIB>>,
+    #[tlv(tag = 255)]
     pub interaction_model_revision: u8,
 }
 
-impl ReadRequestMessage {
-    pub fn to_tlv(&self) {}
-    pub fn from_tlv(data: &[u8]) -> Self {
-        let tlv = decode(data);
-
-        let mut attribute_requests: Option<Vec<AttributePathIB>> = None;
-        let mut in_attribute_requests = false;
-        let mut event_requests = None;
-        let mut event_filters = None;
-        let mut fabric_filtered = false;
-        let mut data_version_filters = None;
-        let mut interaction_model_revision = 0;
-
-        let mut element = tlv;
-        loop {
-            println!(
-                "Element {:?}, {:?}, {:?}",
-                element.get_control(),
-                element.get_type(),
-                element.get_value()
-            );
-            match element.get_control() {
-                TagControl::Anonymous => match element.get_type() {
-                    TlvType::Structure => {
-                        // Beginning of struct
-                    }
-                    TlvType::Array => todo!(),
-                    TlvType::List if in_attribute_requests => {
-                        element = element.next_in_container();
-                        let (ib, e) = AttributePathIB::decode_inner(element);
-                        attribute_requests.as_mut().unwrap().push(ib);
-                        element = e;
-                        continue;
-                    }
-                    TlvType::EndOfContainer if in_attribute_requests => {
-                        in_attribute_requests = false;
-                        element = element.next_in_container();
-                    }
-                    TlvType::EndOfContainer => {
-                        // Do nothing?
-                    }
-                    t => todo!("Unsupported anonymous tag {t:?}"),
-                },
-                TagControl::ContextSpecific(0) => {
-                    // TODO: should also be an array
-                    if let TlvType::Array = element.get_type() {
-                        in_attribute_requests = true;
-                        attribute_requests = Some(vec![]);
-                    }
-                }
-                TagControl::ContextSpecific(1) => {
-                    todo!("EventRequests");
-                }
-                TagControl::ContextSpecific(2) => {
-                    todo!("EventFilters");
-                }
-                TagControl::ContextSpecific(3) => {
-                    if let TagLengthValue::Boolean(value) = element.get_value() {
-                        fabric_filtered = value;
-                    } else {
-                        panic!("Invalid value for FabricFiltered");
-                    }
-                }
-                TagControl::ContextSpecific(255) => {
-                    if let TagLengthValue::Unsigned8(value) = element.get_value() {
-                        interaction_model_revision = value;
-                    }
-                }
-                t => println!(
-                    "Encountered tag: {:?} with type: {:?} and value: {:?}",
-                    t,
-                    element.get_type(),
-                    element.get_value()
-                ),
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-        Self {
-            attribute_requests,
-            event_requests,
-            event_filters,
-            fabric_filtered,
-            data_version_filters,
-            interaction_model_revision,
-        }
-    }
-}
-
+/// ReportDataMessage (10.6.3)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct ReportDataMessage {
+    #[tlv(tag = 0)]
     pub subscription_id: Option<u32>,
+    #[tlv(tag = 1)]
     pub attribute_reports: Option<Vec<AttributeReportIB>>,
+    #[tlv(tag = 2)]
     pub event_reports: Option<Vec<EventReportIB>>,
-    pub more_chunked_messages: bool,
-    pub suppressed_response: bool,
+    #[tlv(tag = 3)]
+    pub more_chunked_messages: Option<bool>,
+    #[tlv(tag = 4)]
+    pub suppressed_response: Option<bool>,
+    #[tlv(tag = 255)]
     pub interaction_model_revision: u8,
 }
 
-impl ReportDataMessage {
-    pub fn to_tlv(&self, encoder: &mut Encoder) {
-        encoder.write(
-            TlvType::Structure,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-        if let Some(id) = self.subscription_id {
-            encoder.write(
-                TlvType::UnsignedInt(ElementSize::Byte4),
-                TagControl::ContextSpecific(0),
-                TagLengthValue::Unsigned32(id),
-            );
-        }
-        if let Some(reports) = &self.attribute_reports {
-            encoder.write(
-                TlvType::Array,
-                TagControl::ContextSpecific(1),
-                TagLengthValue::Container,
-            );
-
-            encoder.write(
-                TlvType::Structure,
-                TagControl::Anonymous,
-                TagLengthValue::Container,
-            );
-            for report in reports {
-                report.to_tlv(encoder);
-            }
-            encoder.write(
-                TlvType::EndOfContainer,
-                TagControl::Anonymous,
-                TagLengthValue::EndOfContainer,
-            );
-
-            encoder.write(
-                TlvType::EndOfContainer,
-                TagControl::Anonymous,
-                TagLengthValue::EndOfContainer,
-            );
-        }
-        if let Some(reports) = &self.event_reports {
-            todo!("Event reports not yet supported");
-        }
-        encoder.write(
-            TlvType::Boolean(self.more_chunked_messages),
-            TagControl::ContextSpecific(3),
-            TagLengthValue::Boolean(self.more_chunked_messages),
-        );
-        encoder.write(
-            TlvType::Boolean(self.suppressed_response),
-            TagControl::ContextSpecific(4),
-            TagLengthValue::Boolean(self.suppressed_response),
-        );
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte1),
-            TagControl::ContextSpecific(255),
-            TagLengthValue::Unsigned8(1),
-        );
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::EndOfContainer,
-        );
-    }
-    // pub fn from_tlv(data: &[u8]) -> Self {
-    //     let tlv = decode(data);
-
-    //     let mut attribute_requests: Option<Vec<AttributePathIB>> = None;
-    //     let mut in_attribute_requests = false;
-    //     let mut event_requests = None;
-    //     let mut event_filters = None;
-    //     let mut fabric_filtered = false;
-    //     let mut data_version_filters = None;
-
-    //     let mut element = tlv;
-    //     loop {
-    //         println!(
-    //             "Element {:?}, {:?}, {:?}",
-    //             element.get_control(),
-    //             element.get_type(),
-    //             element.get_value()
-    //         );
-    //         match element.get_control() {
-    //             TagControl::Anonymous => match element.get_type() {
-    //                 TlvType::Structure => {
-    //                     // Beginning of struct
-    //                 }
-    //                 TlvType::Array => todo!(),
-    //                 TlvType::List if in_attribute_requests => {
-    //                     element = element.next_in_container();
-    //                     let (ib, e) = AttributePathIB::decode_inner(element);
-    //                     attribute_requests.as_mut().unwrap().push(ib);
-    //                     element = e;
-    //                     continue;
-    //                 }
-    //                 TlvType::EndOfContainer if in_attribute_requests => {
-    //                     in_attribute_requests = false;
-    //                     element = element.next_in_container();
-    //                 }
-    //                 TlvType::EndOfContainer => {
-    //                     // Do nothing?
-    //                 }
-    //                 t => todo!("Unsupported anonymous tag {t:?}"),
-    //             },
-    //             TagControl::ContextSpecific(0) => {
-    //                 if let TagLengthValue::Container = element.get_value() {
-    //                     in_attribute_requests = true;
-    //                     attribute_requests = Some(vec![]);
-    //                 }
-    //             }
-    //             TagControl::ContextSpecific(1) => {
-    //                 todo!("EventRequests");
-    //             }
-    //             TagControl::ContextSpecific(2) => {
-    //                 todo!("EventFilters");
-    //             }
-    //             TagControl::ContextSpecific(3) => {
-    //                 if let TagLengthValue::Boolean(value) = element.get_value() {
-    //                     fabric_filtered = value;
-    //                 } else {
-    //                     panic!("Invalid value for FabricFiltered");
-    //                 }
-    //             }
-    //             TagControl::ContextSpecific(255) => {
-    //                 println!("Common tag not yet added")
-    //             }
-    //             t => println!(
-    //                 "Encountered tag: {:?} with type: {:?} and value: {:?}",
-    //                 t,
-    //                 element.get_type(),
-    //                 element.get_value()
-    //             ),
-    //         }
-
-    //         if element.is_last() {
-    //             break;
-    //         }
-    //         element = element.next_in_container();
-    //     }
-    //     Self {
-    //         attribute_requests,
-    //         event_requests,
-    //         event_filters,
-    //         fabric_filtered,
-    //         data_version_filters,
-    //     }
-    // }
-}
-
 /// AttributePathIB (10.5.2)
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, ToTlv, FromTlv)]
+#[tlv(list)]
 pub struct AttributePathIB {
+    #[tlv(tag = 0)]
     pub enable_tag_compression: Option<bool>,
+    #[tlv(tag = 1)]
     pub node: Option<u64>,
+    #[tlv(tag = 2)]
     pub endpoint: Option<u16>,
+    #[tlv(tag = 3)]
     pub cluster: Option<u16>,
+    #[tlv(tag = 4)]
     pub attribute: Option<u32>,
-    pub list_index: Option<u16>,
-}
-
-impl AttributePathIB {
-    pub fn to_tlv(&self, encoder: &mut Encoder) {
-        if let Some(value) = self.enable_tag_compression {
-            encoder.write(
-                TlvType::Boolean(value),
-                TagControl::ContextSpecific(0),
-                TagLengthValue::Boolean(value),
-            );
-        }
-        if let Some(value) = self.node {
-            encoder.write(
-                TlvType::UnsignedInt(ElementSize::Byte8),
-                TagControl::ContextSpecific(1),
-                TagLengthValue::Unsigned64(value),
-            );
-        }
-        if let Some(value) = self.endpoint {
-            encoder.write(
-                TlvType::UnsignedInt(ElementSize::Byte2),
-                TagControl::ContextSpecific(2),
-                TagLengthValue::Unsigned16(value),
-            );
-        }
-        if let Some(value) = self.cluster {
-            encoder.write(
-                TlvType::UnsignedInt(ElementSize::Byte2),
-                TagControl::ContextSpecific(3),
-                TagLengthValue::Unsigned16(value),
-            );
-        }
-        if let Some(value) = self.attribute {
-            encoder.write(
-                TlvType::UnsignedInt(ElementSize::Byte4),
-                TagControl::ContextSpecific(4),
-                TagLengthValue::Unsigned32(value),
-            );
-        }
-        if let Some(value) = self.list_index {
-            encoder.write(
-                TlvType::UnsignedInt(ElementSize::Byte2),
-                TagControl::ContextSpecific(5),
-                TagLengthValue::Unsigned16(value),
-            );
-        }
-        // -
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte1),
-            TagControl::ContextSpecific(255),
-            TagLengthValue::Unsigned8(1),
-        );
-    }
-    fn decode_inner(tlv: TlvData) -> (Self, TlvData) {
-        let mut ib = Self {
-            ..Default::default()
-        };
-        let mut element = tlv;
-        loop {
-            println!(
-                "AttributePathIB Element {:?}, {:?}, {:?}",
-                element.get_control(),
-                element.get_type(),
-                element.get_value()
-            );
-            match element.get_control() {
-                TagControl::Anonymous if element.get_type() == TlvType::EndOfContainer => {
-                    element = element.next_in_container();
-                    break;
-                }
-                TagControl::ContextSpecific(0) => {
-                    if let TagLengthValue::Boolean(value) = element.get_value() {
-                        ib.enable_tag_compression = Some(value);
-                    } else {
-                        panic!("Invalid value")
-                    }
-                }
-                TagControl::ContextSpecific(1) => {
-                    if let TagLengthValue::Unsigned64(value) = element.get_value() {
-                        ib.node = Some(value);
-                    } else {
-                        panic!("Invalid value")
-                    }
-                }
-                TagControl::ContextSpecific(2) => {
-                    let value = match element.get_value() {
-                        TagLengthValue::Unsigned8(value) => value as _,
-                        TagLengthValue::Unsigned16(value) => value as _,
-                        t => panic!("Invalid value {t:?}"),
-                    };
-                    ib.endpoint = Some(value);
-                }
-                TagControl::ContextSpecific(3) => {
-                    let value = match element.get_value() {
-                        TagLengthValue::Unsigned8(value) => value as _,
-                        TagLengthValue::Unsigned16(value) => value as _,
-                        TagLengthValue::Unsigned32(value) => value as _,
-                        t => panic!("Invalid value {t:?}"),
-                    };
-                    ib.cluster = Some(value);
-                }
-                TagControl::ContextSpecific(4) => {
-                    let value = match element.get_value() {
-                        TagLengthValue::Unsigned8(value) => value as _,
-                        TagLengthValue::Unsigned16(value) => value as _,
-                        TagLengthValue::Unsigned32(value) => value as _,
-                        t => panic!("Invalid value {t:?}"),
-                    };
-                    ib.attribute = Some(value);
-                }
-                TagControl::ContextSpecific(5) => {
-                    // TODO: this is nullable
-                    let value = match element.get_value() {
-                        TagLengthValue::Unsigned8(value) => value as _,
-                        TagLengthValue::Unsigned16(value) => value as _,
-                        t => panic!("Invalid value {t:?}"),
-                    };
-                    ib.list_index = Some(value);
-                }
-                t => todo!("{t:?} not covered"),
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-        (ib, element)
-    }
+    #[tlv(tag = 5, nullable)]
+    pub list_index: Option<Option<u16>>,
 }
 
-#[derive(Debug)]
+/// DataVersionFilterIB (10.5.3)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct DataVersionFilterIB {
+    #[tlv(tag = 0)]
     pub path: ClusterPathIB,
+    #[tlv(tag = 1)]
     pub data_version: u32,
 }
 
-/// 10.5.4
+/// AttributeDataIB (10.5.4)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct AttributeDataIB {
+    #[tlv(tag = 0)]
     pub data_version: u32,
+    #[tlv(tag = 1)]
     pub path: AttributePathIB,
-    pub data: heapless::Vec<u8, 1024>, // TODO: what's a good representation?
-    pub interaction_model_revision: u8,
-}
-
-impl AttributeDataIB {
-    pub fn to_tlv(&self, encoder: &mut Encoder) {
-        // encoder.write(
-        //     TlvType::Structure,
-        //     TagControl::ContextSpecific(0),
-        //     TagLengthValue::Container,
-        // );
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte4),
-            TagControl::ContextSpecific(0),
-            TagLengthValue::Unsigned32(self.data_version),
-        );
-        encoder.write(
-            TlvType::List,
-            TagControl::ContextSpecific(1),
-            TagLengthValue::Container,
-        );
-        self.path.to_tlv(encoder);
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::ContextSpecific(1),
-            TagLengthValue::EndOfContainer,
-        );
-
-        let data = heapless::Vec::from_slice(self.data.as_slice()).unwrap();
-        encoder.write(
-            // TODO: optimise length
-            TlvType::ByteString(ElementSize::Byte2, self.data.len()),
-            TagControl::ContextSpecific(2),
-            TagLengthValue::ByteString(data),
-        );
-        // --
-        // encoder.write(
-        //     TlvType::UnsignedInt(ElementSize::Byte1),
-        //     TagControl::ContextSpecific(255),
-        //     TagLengthValue::Unsigned8(1),
-        // );
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::EndOfContainer,
-        );
-    }
+    #[tlv(tag = 2)]
+    pub data: RawTlv,
 }
 
+/// AttributeReportIB (10.5.5)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct AttributeReportIB {
-    pub attribute_status: AttributeStatusIB,
-    pub attribute_data: AttributeDataIB,
+    #[tlv(tag = 0)]
+    pub attribute_status: Option<AttributeStatusIB>,
+    #[tlv(tag = 1)]
+    pub attribute_data: Option<AttributeDataIB>,
 }
 
-impl AttributeReportIB {
-    pub fn to_tlv(&self, encoder: &mut Encoder) {
-        // encoder.write(
-        //     TlvType::Structure,
-        //     TagControl::Anonymous,
-        //     TagLengthValue::Container,
-        // );
-        encoder.write(
-            TlvType::Structure,
-            TagControl::ContextSpecific(0),
-            TagLengthValue::Container,
-        );
-        self.attribute_status.to_tlv(encoder);
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::EndOfContainer,
-        );
-
-        encoder.write(
-            TlvType::Structure,
-            TagControl::ContextSpecific(1),
-            TagLengthValue::Container,
-        );
-        self.attribute_data.to_tlv(encoder);
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::EndOfContainer,
-        );
-
-        // ---
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte1),
-            TagControl::ContextSpecific(255),
-            TagLengthValue::Unsigned8(1),
-        );
-
-        // encoder.write(
-        //     TlvType::EndOfContainer,
-        //     TagControl::Anonymous,
-        //     TagLengthValue::EndOfContainer,
-        // );
-    }
-}
-
-#[derive(Debug, Default)]
+/// EventFilterIB (10.5.6)
+#[derive(Debug, Default, ToTlv, FromTlv)]
 pub struct EventFilterIB {
+    #[tlv(tag = 0)]
     pub node: Option<u64>,
+    #[tlv(tag = 1)]
     pub event_min: u64,
 }
 
-impl EventFilterIB {
-    fn decode_inner(tlv: TlvData) -> (Self, TlvData) {
-        let mut ib = Self {
-            ..Default::default()
-        };
-        let mut element = tlv;
-        loop {
-            println!(
-                "EventFilterIB Element {:?}, {:?}, {:?}",
-                element.get_control(),
-                element.get_type(),
-                element.get_value()
-            );
-            match element.get_control() {
-                TagControl::Anonymous if element.get_type() == TlvType::EndOfContainer => {
-                    // element = element.next_in_container();
-                    break;
-                }
-                // TagControl::ContextSpecific(0) => {
-                //     if let TagLengthValue::Boolean(value) = element.get_value() {
-                //         ib.enable_tag_compression = Some(value);
-                //     } else {
-                //         panic!("Invalid value")
-                //     }
-                // }
-                // TagControl::ContextSpecific(1) => {
-                //     if let TagLengthValue::Unsigned64(value) = element.get_value() {
-                //         ib.node = Some(value);
-                //     } else {
-                //         panic!("Invalid value")
-                //     }
-                // }
-                t => todo!("{t:?} not covered"),
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-        (ib, element)
-    }
-}
-
-#[derive(Debug)]
+/// ClusterPathIB (10.5.7)
+#[derive(Debug, ToTlv, FromTlv)]
+#[tlv(list)]
 pub struct ClusterPathIB {
+    #[tlv(tag = 0)]
     pub node: Option<u64>,
+    #[tlv(tag = 1)]
     pub endpoint: u16,
+    #[tlv(tag = 2)]
     pub cluster: u32,
 }
-#[derive(Debug)]
+
+/// EventPathIB (10.5.8)
+#[derive(Debug, ToTlv, FromTlv)]
+#[tlv(list)]
 pub struct EventPathIB {
+    #[tlv(tag = 0)]
     pub node: Option<u64>,
+    #[tlv(tag = 1)]
     pub endpoint: u16,
+    #[tlv(tag = 2)]
     pub cluster: u32,
+    #[tlv(tag = 3)]
     pub event: u32,
-    pub is_urgent: bool,
+    #[tlv(tag = 4)]
+    pub is_urgent: Option<bool>,
 }
 
-#[derive(Debug)]
+/// EventDataIB (10.5.9), which has one of the timestamps
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct EventDataIB {
+    #[tlv(tag = 0)]
     pub path: EventPathIB,
+    #[tlv(tag = 1)]
     pub event_number: u64,
+    #[tlv(tag = 2)]
     pub priority: u8,
-    pub timestamp: EventDataIBTimestamp,
-    pub data: Vec<u8>,
-}
-
-#[derive(Debug)]
-pub enum EventDataIBTimestamp {
-    EpochTimestamp(i64),
-    SystemTimestamp(u64),
-    DeltaEpochTimestamp(u64),  // Optional,
-    DeltaSystemTimestamp(u64), // Optional
+    #[tlv(tag = 3)]
+    pub epoch_timestamp: Option<i64>,
+    #[tlv(tag = 4)]
+    pub system_timestamp: Option<u64>,
+    #[tlv(tag = 5)]
+    pub delta_epoch_timestamp: Option<u64>,
+    #[tlv(tag = 6)]
+    pub delta_system_timestamp: Option<u64>,
+    #[tlv(tag = 7)]
+    pub data: RawTlv,
 }
 
+/// EventReportIB (10.5.10)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct EventReportIB {
-    pub event_status: EventStatusIB,
-    pub event_data: EventDataIB,
+    #[tlv(tag = 0)]
+    pub event_status: Option<EventStatusIB>,
+    #[tlv(tag = 1)]
+    pub event_data: Option<EventDataIB>,
 }
 
+/// CommandPathIB (10.5.11)
+#[derive(Debug, ToTlv, FromTlv)]
+#[tlv(list)]
 pub struct CommandPathIB {
+    #[tlv(tag = 0)]
     pub endpoint: u16,
+    #[tlv(tag = 1)]
     pub cluster: u32,
+    #[tlv(tag = 2)]
     pub command: u32,
 }
 
+/// CommandDataIB (10.5.12)
+#[derive(Debug, ToTlv, FromTlv)]
+pub struct CommandDataIB {
+    #[tlv(tag = 0)]
+    pub command_path: CommandPathIB,
+    #[tlv(tag = 1)]
+    pub command_fields: Option<RawTlv>,
+}
+
+/// InvokeResponseIB (10.5.13)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct InvokeResponseIB {
-    pub command: CommandDataIB,
-    pub status: CommandStatusIB,
+    #[tlv(tag = 0)]
+    pub command: Option<CommandDataIB>,
+    #[tlv(tag = 1)]
+    pub status: Option<CommandStatusIB>,
 }
 
+/// CommandStatusIB (10.5.14)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct CommandStatusIB {
+    #[tlv(tag = 0)]
     pub path: CommandPathIB,
+    #[tlv(tag = 1)]
     pub status: StatusIB,
 }
 
+/// EventStatusIB (10.5.15)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct EventStatusIB {
+    #[tlv(tag = 0)]
     pub path: EventPathIB,
+    #[tlv(tag = 1)]
     pub status: StatusIB,
 }
 
-pub struct CommandDataIB {
-    pub command_path: CommandPathIB,
-    pub command_fields: Vec<u8>,
-}
-
+/// AttributeStatusIB (10.5.16)
+#[derive(Debug, ToTlv, FromTlv)]
 pub struct AttributeStatusIB {
+    #[tlv(tag = 0)]
     pub path: AttributePathIB,
+    #[tlv(tag = 1)]
     pub status: StatusIB,
 }
 
-impl AttributeStatusIB {
-    pub fn to_tlv(&self, encoder: &mut Encoder) {
-        encoder.write(
-            TlvType::List,
-            TagControl::ContextSpecific(0),
-            TagLengthValue::Container,
-        );
-        self.path.to_tlv(encoder);
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::EndOfContainer,
-        );
-
-        encoder.write(
-            TlvType::Structure,
-            TagControl::ContextSpecific(1),
-            TagLengthValue::Container,
-        );
-        self.status.to_tlv(encoder);
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::EndOfContainer,
-        );
-    }
-}
-
-#[derive(Default)]
+/// StatusIB (10.5.17)
+#[derive(Debug, Default, ToTlv, FromTlv)]
 pub struct StatusIB {
+    #[tlv(tag = 0)]
     pub status: u16,
-    pub cluster_status: u16,
+    #[tlv(tag = 1)]
+    pub cluster_status: Option<u16>,
 }
 
-impl StatusIB {
-    pub fn to_tlv(&self, encoder: &mut Encoder) {
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte2),
-            TagControl::ContextSpecific(0),
-            TagLengthValue::Unsigned16(self.status),
-        );
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte2),
-            TagControl::ContextSpecific(1),
-            TagLengthValue::Unsigned16(self.cluster_status),
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tlv;
+
+    #[test]
+    fn decode_read_request() {
+        // Read of the Basic Information cluster's VendorID on endpoint 0
+        let data = hex_literal::hex!("153600172402002403282404021818280324ff0118");
+        let request: ReadRequestMessage = tlv::from_slice(&data).unwrap();
+        let attributes = request.attribute_requests.unwrap();
+        assert_eq!(attributes.len(), 1);
+        assert_eq!(attributes[0].endpoint, Some(0));
+        assert_eq!(attributes[0].cluster, Some(0x28));
+        assert_eq!(attributes[0].attribute, Some(2));
+        assert!(request.event_requests.is_none());
+        assert!(!request.fabric_filtered);
+        assert_eq!(request.interaction_model_revision, 1);
     }
-    fn decode_inner(tlv: TlvData) -> (Self, TlvData) {
-        let mut ib = Self {
-            ..Default::default()
+
+    #[test]
+    fn encode_report_data() {
+        // The VendorID attribute with a value of 0xfff1
+        let report = ReportDataMessage {
+            subscription_id: None,
+            attribute_reports: Some(vec![AttributeReportIB {
+                attribute_status: None,
+                attribute_data: Some(AttributeDataIB {
+                    data_version: 1,
+                    path: AttributePathIB {
+                        endpoint: Some(0),
+                        cluster: Some(0x28),
+                        attribute: Some(2),
+                        ..Default::default()
+                    },
+                    data: RawTlv(tlv::to_encoder(&0xfff1u16).unwrap().inner()),
+                }),
+            }]),
+            event_reports: None,
+            more_chunked_messages: None,
+            suppressed_response: Some(true),
+            interaction_model_revision: 1,
         };
-        let mut element = tlv;
-        loop {
-            println!(
-                "StatusIB Element {:?}, {:?}, {:?}",
-                element.get_control(),
-                element.get_type(),
-                element.get_value()
-            );
-            match element.get_control() {
-                TagControl::Anonymous if element.get_type() == TlvType::EndOfContainer => {
-                    // element = element.next_in_container();
-                    break;
-                }
-                TagControl::ContextSpecific(0) => {
-                    if let TagLengthValue::Unsigned16(value) = element.get_value() {
-                        ib.status = value;
-                    } else {
-                        panic!("Invalid value for status")
-                    }
-                }
-                TagControl::ContextSpecific(1) => {
-                    if let TagLengthValue::Unsigned16(value) = element.get_value() {
-                        ib.cluster_status = value;
-                    } else {
-                        panic!("Invalid value for cluster_status")
-                    }
-                }
-                t => todo!("{t:?} not covered"),
-            }
+        let encoded = tlv::to_encoder(&report).unwrap();
+        assert_eq!(
+            encoded.to_slice(),
+            hex_literal::hex!(
+                "153601153501240001370124020024032824040218" "2502f1ff1818182904" "24ff0118"
+            )
+        );
 
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-        (ib, element)
+        let decoded: ReportDataMessage = tlv::from_slice(encoded.to_slice()).unwrap();
+        let reports = decoded.attribute_reports.unwrap();
+        let data = reports[0].attribute_data.as_ref().unwrap();
+        assert_eq!(data.path.cluster, Some(0x28));
+        assert_eq!(data.data.0.as_slice(), hex_literal::hex!("05f1ff"));
+        assert!(reports[0].attribute_status.is_none());
+        assert_eq!(decoded.suppressed_response, Some(true));
     }
 }
@@ -1,15 +1,21 @@
+use bytes::BytesMut;
 use num::FromPrimitive;
 
 use crate::{
     data_model::handler::{AttrDataEncoder, Handler},
-    exchange::Exchange,
+    exchange::{Exchange, ProtocolContext, ProtocolHandler},
     interaction_model::InteractionModelProtocolOpCode,
-    message::{ExchangeFlags, Message, MessageFlags, MessageHeader, ProtocolHeader, ProtocolID},
-    session_context::SessionContext,
-    tlv::Encoder,
+    message::{
+        ExchangeFlags, Message, MessageFlags, MessageHeader, ProtocolHeader, ProtocolID,
+        SessionType,
+    },
+    session_context::{SecureSessionContext, SessionContext},
+    tlv::{self, Encoder, TagControl, TlvError, ToTlv},
 };
 
-use super::{AttributeStatusIB, ReadRequestMessage, ReportDataMessage};
+use super::{
+    AttributeStatusIB, ReadRequestMessage, ReportDataMessage, StatusCode, StatusResponseMessage,
+};
 
 /*
 How do we know when there is a new transaction?
@@ -29,42 +35,50 @@ pub enum TransactionState {
 impl Transaction {
     pub fn on_message(
         &mut self,
-        session_context: &mut SessionContext,
+        session: &SecureSessionContext,
         handler: &impl Handler,
         message: &Message,
     ) -> Option<Message> {
-        let SessionContext::Secure(session) = session_context else {
-            panic!()
-        };
         let payload_header = message.payload_header.as_ref().unwrap();
         assert_eq!(
             payload_header.protocol_id,
             ProtocolID::InteractionModel as u16
         );
-        let opcode: InteractionModelProtocolOpCode =
-            InteractionModelProtocolOpCode::from_u8(payload_header.protocol_opcode).unwrap();
         let mut response_opcode = InteractionModelProtocolOpCode::StatusResponse;
         // TODO: validate that the correct stage of session establishment is being used.
-        let encoder = match opcode {
-            InteractionModelProtocolOpCode::StatusResponse => {
-                todo!()
-            }
+        let encoder = match InteractionModelProtocolOpCode::from_u8(payload_header.protocol_opcode)
+        {
             // First in a transaction
-            InteractionModelProtocolOpCode::ReadRequest => {
-                response_opcode = InteractionModelProtocolOpCode::ReportData;
-                self.read_request(message, handler)
+            Some(InteractionModelProtocolOpCode::ReadRequest) => {
+                match self.read_request(message, handler) {
+                    Ok(encoder) => {
+                        response_opcode = InteractionModelProtocolOpCode::ReportData;
+                        encoder
+                    }
+                    Err(e) => {
+                        println!("Malformed read request: {e}");
+                        status_response(StatusCode::InvalidAction)
+                    }
+                }
             }
-            InteractionModelProtocolOpCode::SubscribeRequest => todo!(),
-            InteractionModelProtocolOpCode::SubscribeResponse => todo!(),
-            InteractionModelProtocolOpCode::ReportData => todo!(),
-            InteractionModelProtocolOpCode::WriteRequest => todo!(),
-            InteractionModelProtocolOpCode::WriteResponse => todo!(),
-            InteractionModelProtocolOpCode::InvokeRequest => {
-                // got here, next is to support arming fail-safe
-                panic!()
-            }
-            InteractionModelProtocolOpCode::InvokeResponse => todo!(),
-            InteractionModelProtocolOpCode::TimedRequest => todo!(),
+            // TODO: support subscriptions, writes and commands. Invoke is
+            // next, to support arming the fail-safe.
+            Some(
+                InteractionModelProtocolOpCode::SubscribeRequest
+                | InteractionModelProtocolOpCode::WriteRequest
+                | InteractionModelProtocolOpCode::InvokeRequest
+                | InteractionModelProtocolOpCode::TimedRequest,
+            ) => status_response(StatusCode::InvalidAction),
+            // This node does not initiate transactions, so there is nothing
+            // for a response to be part of
+            Some(
+                InteractionModelProtocolOpCode::StatusResponse
+                | InteractionModelProtocolOpCode::SubscribeResponse
+                | InteractionModelProtocolOpCode::ReportData
+                | InteractionModelProtocolOpCode::WriteResponse
+                | InteractionModelProtocolOpCode::InvokeResponse,
+            )
+            | None => status_response(StatusCode::InvalidAction),
         };
 
         let message_header = &message.message_header;
@@ -72,8 +86,8 @@ impl Transaction {
 
         // TODO: some responses can be split into multiple messages,
         // so we shouldn't return only 1 message
-        let response_message = Message {
-            message_header: MessageHeader {
+        let response_message = Message::new(
+            MessageHeader {
                 session_type: message_header.session_type.clone(),
                 // session_type: crate::message::SessionType::SecureUnicast(message_header.session_id),
                 message_flags: message_header.message_flags.clone(),
@@ -82,9 +96,9 @@ impl Transaction {
                 message_counter: 0,
                 source_node_id: Some(0), // TODO
                 dest_node_id: Some(crate::message::NodeID::Unique(session.peer_node_id)),
-                message_extensions: (),
+                message_extensions: None,
             },
-            payload_header: Some(ProtocolHeader {
+            Some(ProtocolHeader {
                 exchange_flags: ExchangeFlags::default(),
                 protocol_opcode: response_opcode as u8,
                 exchange_id: payload_header.exchange_id,
@@ -92,11 +106,10 @@ impl Transaction {
                 protocol_vendor_id: payload_header.protocol_vendor_id,
                 // Not handled here
                 ack_message_counter: None,
-                secured_extensions: (),
+                secured_extensions: None,
             }),
-            payload: encoder.inner(),
-            integrity_check: None,
-        };
+            BytesMut::from(encoder.to_slice()),
+        );
 
         dbg!(&message.message_header);
         dbg!(&response_message.message_header);
@@ -104,8 +117,12 @@ impl Transaction {
         Some(response_message)
     }
 
-    fn read_request(&mut self, message: &Message, handler: &impl Handler) -> Encoder {
-        let read_request_message = ReadRequestMessage::from_tlv(&message.payload);
+    fn read_request(
+        &mut self,
+        message: &Message,
+        handler: &impl Handler,
+    ) -> Result<Encoder, TlvError> {
+        let read_request_message: ReadRequestMessage = tlv::from_slice(&message.payload)?;
         // dbg!(&read_request_message);
         // TODO: how do we handle multiple attribute reads?
         let mut writer = Encoder::default();
@@ -117,8 +134,8 @@ impl Transaction {
             subscription_id: None,
             attribute_reports: None,
             event_reports: None,
-            more_chunked_messages: false,
-            suppressed_response: false,
+            more_chunked_messages: None,
+            suppressed_response: None,
             interaction_model_revision: 1,
         };
         // TODO: Surely we can't send attr twice?
@@ -126,24 +143,56 @@ impl Transaction {
             let mut attribute_reports = vec![];
             for attr in attrs.as_slice() {
                 attribute_reports.push(super::AttributeReportIB {
-                    attribute_status: super::AttributeStatusIB {
-                        path: attr.clone(),
-                        status: super::StatusIB {
-                            status: 0,
-                            cluster_status: 0,
-                        },
-                    },
-                    attribute_data: handler.handle_read2(&attr),
+                    attribute_status: None,
+                    attribute_data: Some(handler.handle_read2(&attr)),
                 });
             }
             response.attribute_reports = Some(attribute_reports);
         }
 
-        response.to_tlv(&mut writer);
-        writer
+        response.to_tlv(&mut writer, TagControl::Anonymous)?;
+        Ok(writer)
+    }
+}
+
+/// Serves the Interaction Model protocol from the data model of a device
+pub struct InteractionModelHandler<H> {
+    handler: H,
+}
+
+impl<H: Handler> InteractionModelHandler<H> {
+    pub fn new(handler: H) -> Self {
+        Self { handler }
+    }
+}
+
+impl<H: Handler> ProtocolHandler for InteractionModelHandler<H> {
+    fn on_message(
+        &mut self,
+        context: &mut ProtocolContext<'_>,
+        message: &Message,
+    ) -> Option<Message> {
+        // Interactions only take place in secure unicast sessions
+        let session = match (&message.message_header.session_type, context.session_mut()) {
+            (SessionType::SecureUnicast(_), Some(SessionContext::Secure(session))) => session,
+            _ => {
+                println!("Dropping Interaction Model message outside of a secure unicast session");
+                return None;
+            }
+        };
+        let mut transaction = Transaction {};
+        transaction.on_message(session, &self.handler, message)
     }
 }
 
+fn status_response(status: StatusCode) -> Encoder {
+    tlv::to_encoder(&StatusResponseMessage {
+        status: status as u8,
+        interaction_model_revision: 1,
+    })
+    .expect("status response should fit in an empty encoder")
+}
+
 /*
 What information do we need in a transaction?
 - its state
@@ -32,6 +32,8 @@ use crate::{
 #[macro_use]
 extern crate num_derive;
 extern crate alloc;
+// Lets the derive macros refer to `::matter_controller` from within this crate
+extern crate self as matter_controller;
 
 /// Cluster definitions, servers and clients
 pub mod cluster;
@@ -1,10 +1,12 @@
+use core::fmt;
+
 use bitflags::bitflags;
-// TODO: ideal to use a version of buf that doesn't panic
 use bytes::{Buf, BufMut, BytesMut};
+use num_traits::FromPrimitive;
 
 use crate::{
     constants::{CRYPTO_AEAD_MIC_LENGTH_BYTES, CRYPTO_AEAD_NONCE_LENGTH_BYTES},
-    crypto::{decrypt_in_place, encrypt_in_place},
+    crypto::{decrypt_in_place, encrypt_in_place, privacy_crypt_in_place, privacy_key},
     session_context::SecureChannelProtocolOpCode,
 };
 
@@ -14,6 +16,102 @@ pub const UDP_MESSAGE_LIMIT: usize = 1280;
 
 pub type SessionID = u16;
 
+/// The most extension data that is kept from a message
+pub const EXTENSIONS_LIMIT: usize = 128;
+
+/// Why a message was dropped while decoding or decrypting it.
+///
+/// Most messages that fail are dropped silently, see [MessageError::should_ack]
+/// for the ones that are acknowledged first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageError {
+    /// The message ended in the middle of a field
+    Truncated,
+    /// The message format version is not supported (4.4.1.1)
+    UnsupportedVersion(u8),
+    /// The destination node ID size is reserved (4.4.1.2)
+    ReservedDestination,
+    /// The session type is reserved (4.4.1.4)
+    ReservedSessionType,
+    /// The header fields contradict each other, e.g. a group ID in a
+    /// unicast message (4.6.2)
+    InvalidHeader(&'static str),
+    /// The message or secured extensions are longer than [EXTENSIONS_LIMIT]
+    ExtensionsTooLong(usize),
+    /// A secure message was decrypted without a key
+    MissingKey,
+    /// The message integrity check failed
+    Integrity,
+    /// The message is valid, but for a protocol that isn't supported
+    UnsupportedProtocol { vendor_id: u16, protocol_id: u16 },
+}
+
+impl MessageError {
+    /// Whether the message should be acknowledged before it is dropped, if
+    /// the sender asked for an acknowledgement (4.10.5.2).
+    ///
+    /// Only messages that were decrypted and authenticated are acknowledged.
+    pub fn should_ack(&self) -> bool {
+        matches!(self, MessageError::UnsupportedProtocol { .. })
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Truncated => f.write_str("message is truncated"),
+            MessageError::UnsupportedVersion(version) => {
+                write!(f, "unsupported message version {version}")
+            }
+            MessageError::ReservedDestination => f.write_str("destination size is reserved"),
+            MessageError::ReservedSessionType => f.write_str("session type is reserved"),
+            MessageError::InvalidHeader(reason) => write!(f, "invalid message header: {reason}"),
+            MessageError::ExtensionsTooLong(len) => {
+                write!(f, "{len} bytes of extensions exceed the limit")
+            }
+            MessageError::MissingKey => f.write_str("no key to decrypt the message"),
+            MessageError::Integrity => f.write_str("message integrity check failed"),
+            MessageError::UnsupportedProtocol {
+                vendor_id,
+                protocol_id,
+            } => write!(f, "unsupported protocol {vendor_id:04x}:{protocol_id:04x}"),
+        }
+    }
+}
+
+/// Check that `len` more bytes can be read from `buf`
+fn ensure(buf: &impl Buf, len: usize) -> Result<(), MessageError> {
+    if buf.remaining() < len {
+        Err(MessageError::Truncated)
+    } else {
+        Ok(())
+    }
+}
+
+/// Message extensions (4.4.1.8) or secured extensions (4.4.3.7).
+///
+/// No extensions are defined yet, and receivers skip the ones they don't
+/// understand, so this is the data after the length field.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extensions(pub heapless::Vec<u8, EXTENSIONS_LIMIT>);
+
+impl Extensions {
+    fn decode(buf: &mut impl Buf) -> Result<Self, MessageError> {
+        ensure(buf, 2)?;
+        let len = buf.get_u16_le() as usize;
+        ensure(buf, len)?;
+        let data = buf.copy_to_bytes(len);
+        heapless::Vec::from_slice(&data)
+            .map(Self)
+            .map_err(|_| MessageError::ExtensionsTooLong(len))
+    }
+
+    fn encode(&self, out: &mut BytesMut) {
+        out.put_u16_le(self.0.len() as u16);
+        out.put_slice(&self.0);
+    }
+}
+
 /// Message format (4.4)
 ///
 /// Message Header
@@ -45,12 +143,33 @@ pub type SessionID = u16;
 /// - vr Application Payload [opt]
 enum X {}
 
+/// Privacy obfuscates the header from the message counter onwards, after the
+/// message flags, session ID and security flags.
+const PRIVACY_HEADER_OFFSET: usize = 4;
+
+/// The session ID followed by the last 11 bytes of the MIC (4.8.2)
+fn privacy_nonce(session_id: SessionID, mic: &[u8]) -> [u8; CRYPTO_AEAD_NONCE_LENGTH_BYTES] {
+    let mut nonce = [0u8; CRYPTO_AEAD_NONCE_LENGTH_BYTES];
+    nonce[..2].copy_from_slice(&session_id.to_be_bytes());
+    nonce[2..].copy_from_slice(&mic[CRYPTO_AEAD_MIC_LENGTH_BYTES - 11..]);
+    nonce
+}
+
+/// A message and the buffer it is held in.
+///
+/// A received message keeps the [Packet](crate::transport::Packet) bytes it
+/// was decoded from. The header and payload are views into that buffer, which
+/// is deobfuscated and decrypted in place.
 #[derive(Debug, Clone, Default)]
 pub struct Message {
     pub message_header: MessageHeader,
     pub payload_header: Option<ProtocolHeader>,
-    pub payload: heapless::Vec<u8, 1024>,
+    /// The application payload, or the encrypted payload and protocol header
+    /// until the message is decrypted
+    pub payload: BytesMut,
     pub integrity_check: Option<heapless::Vec<u8, 16>>,
+    /// The message header as it was received, which authenticates the payload
+    raw_header: BytesMut,
 }
 
 impl Message {
@@ -58,13 +177,14 @@ impl Message {
     pub fn new(
         message_header: MessageHeader,
         payload_header: Option<ProtocolHeader>,
-        payload: heapless::Vec<u8, 1024>,
+        payload: BytesMut,
     ) -> Self {
         Self {
             message_header,
             payload_header,
             payload,
             integrity_check: None,
+            raw_header: BytesMut::new(),
         }
     }
 
@@ -76,155 +196,167 @@ impl Message {
         // If so, it's safe to get our Node ID from the incoming destination.
         // It would be ideal to enforce this outside here so that this doesn't
         // return Result<Self> in future.
-        // TODO: we have used a default source node ID of 1, review it
-        let Some(NodeID::Unique(source_node_id)) = message_header.dest_node_id else {
-            panic!("Trying to create an ack for a group message");
+        // Messages of PASE sessions leave out the node IDs.
+        let source_node_id = match message_header.dest_node_id {
+            Some(NodeID::Unique(node_id)) => Some(node_id),
+            Some(NodeID::Group(_)) => panic!("Trying to create an ack for a group message"),
+            None => None,
         };
+        let dest_node_id = message_header.source_node_id.map(NodeID::Unique);
+        let mut message_flags = MessageFlags::empty();
+        message_flags.set(
+            MessageFlags::SOURCE_NODE_ID_PRESENT,
+            source_node_id.is_some(),
+        );
+        message_flags.set(MessageFlags::DSIZ_64_BIT_NODE_ID, dest_node_id.is_some());
+        // The ack goes the other way on the exchange
+        let mut exchange_flags = ExchangeFlags::ACKNOWLDEGE;
+        exchange_flags.set(
+            ExchangeFlags::INITIATOR,
+            !payload_header
+                .exchange_flags
+                .contains(ExchangeFlags::INITIATOR),
+        );
         Self {
             message_header: MessageHeader {
                 session_type: message_header.session_type.clone(),
-                message_flags: message_header.message_flags.clone(),
+                message_flags,
                 session_id: message_header.session_id,
                 security_flags: message_header.security_flags.clone(),
                 message_counter,
-                source_node_id: Some(source_node_id),
-                dest_node_id: message_header.source_node_id.map(|v| NodeID::Unique(v)),
-                message_extensions: (),
+                source_node_id,
+                dest_node_id,
+                message_extensions: None,
             },
             payload_header: Some(ProtocolHeader {
-                exchange_flags: ExchangeFlags::ACKNOWLDEGE,
+                exchange_flags,
                 protocol_opcode: SecureChannelProtocolOpCode::MRPStandaloneAck as _,
                 exchange_id: payload_header.exchange_id,
                 protocol_id: ProtocolID::SecureChannel as _,
                 protocol_vendor_id: payload_header.protocol_vendor_id,
                 ack_message_counter: Some(ack),
-                secured_extensions: (),
+                secured_extensions: None,
             }),
             payload: Default::default(),
             integrity_check: None,
+            raw_header: BytesMut::new(),
         }
     }
-    pub fn decode(mut buf: &[u8]) -> Self {
+    /// Decode a message from the bytes of a packet, without decrypting it.
+    /// The message takes the buffer, and no bytes are copied.
+    ///
+    /// Messages that fail are dropped, none of them are acknowledged.
+    pub fn decode(mut packet: BytesMut) -> Result<Self, MessageError> {
+        let mut buf = &packet[..];
         // Perform validity checks
-        let message_flags = MessageFlags::from_bits(buf.get_u8()).unwrap();
-        if (message_flags & MessageFlags::FORMAT_INVALID).bits() > 0 {
-            panic!("Invalid version")
+        ensure(&buf, 4)?;
+        let flags = buf.get_u8();
+        // Reserved bits are ignored
+        let message_flags = MessageFlags::from_bits_truncate(flags);
+        let version = flags >> 4;
+        if version != 0 {
+            return Err(MessageError::UnsupportedVersion(version));
         }
 
         let session_id = buf.get_u16_le();
 
-        let security_flags = SecurityFlags::from_bits(buf.get_u8()).unwrap();
-        if security_flags.contains(SecurityFlags::SESSION_UNICAST)
-            && message_flags.contains(MessageFlags::DSIZ_16_BIT_GROUP_ID)
+        let security_flags = SecurityFlags::from_bits_truncate(buf.get_u8());
+        let session_type = SessionType::new(security_flags, session_id)?;
+        // 4.6.2 1.b
+        let dsiz = message_flags & MessageFlags::DSIZ_RESERVED;
+        if !matches!(session_type, SessionType::SecureGroup(_))
+            && dsiz.bits() == MessageFlags::DSIZ_16_BIT_GROUP_ID.bits()
         {
-            panic!("4.6.2 1.b")
+            return Err(MessageError::InvalidHeader("group ID in a unicast message"));
+        }
+        // 4.6.2 1.c
+        if matches!(session_type, SessionType::SecureGroup(_))
+            && !message_flags.contains(MessageFlags::SOURCE_NODE_ID_PRESENT)
+        {
+            return Err(MessageError::InvalidHeader(
+                "group message without a source node ID",
+            ));
+        }
+        // Unsecured messages have no key to obfuscate the header with (4.8.1)
+        if matches!(session_type, SessionType::UnsecuredSession)
+            && security_flags.contains(SecurityFlags::PRIVACY)
+        {
+            return Err(MessageError::InvalidHeader(
+                "privacy in an unsecured message",
+            ));
         }
-        // TODO 1.c
-        // 2. If message is not of unsecured session type
-        let session_type = SessionType::new(security_flags, session_id);
-
-        // Message counter
-        let message_counter = buf.get_u32_le();
-        println!("Received message counter {message_counter}");
-
-        // Source and destination node ID
-        let source_node_id = if message_flags.contains(MessageFlags::SOURCE_NODE_ID_PRESENT) {
-            Some(buf.get_u64_le())
-        } else {
-            None
-        };
 
-        let dest_node_id = if message_flags.contains(MessageFlags::DSIZ_64_BIT_NODE_ID) {
-            Some(NodeID::Unique(buf.get_u64_le()))
-        } else if message_flags.contains(MessageFlags::DSIZ_16_BIT_GROUP_ID) {
-            Some(NodeID::Group(buf.get_u16_le()))
-        } else if message_flags.contains(MessageFlags::DSIZ_RESERVED) {
-            panic!("Should drop message without ack (4.4.1.2)")
-        } else {
-            None
+        let mut message_header = MessageHeader {
+            session_type,
+            message_flags,
+            session_id,
+            security_flags,
+            ..Default::default()
         };
-
-        // Message extensions (4.4.1.8)
-        if security_flags.contains(SecurityFlags::MESSAGE_EXT) {
-            todo!("Message extensions not yet implemented")
+        // The rest of the header can only be read once the privacy key is known
+        if !message_header.is_private() {
+            message_header.decode_obfuscated(&mut buf)?;
         }
+        let header_len = packet.len() - buf.len();
+        let raw_header = packet.split_to(header_len);
 
-        Self {
-            message_header: MessageHeader {
-                session_type,
-                message_flags,
-                session_id,
-                security_flags,
-                message_counter,
-                source_node_id,
-                dest_node_id,
-                message_extensions: (),
-            },
+        Ok(Self {
+            message_header,
             payload_header: None,
-            payload: heapless::Vec::from_slice(&buf).unwrap(),
+            payload: packet,
             integrity_check: None,
-        }
+            raw_header,
+        })
     }
-    pub fn decrypt(&mut self, decryption_key: Option<&[u8]>) {
+
+    /// Decrypt the payload if there's a key, and decode the protocol header.
+    /// The protocol isn't checked, as the exchange layer knows which
+    /// protocols have handlers, and reports
+    /// [MessageError::UnsupportedProtocol] for the others.
+    pub fn decrypt(&mut self, decryption_key: Option<&[u8]>) -> Result<(), MessageError> {
         if let Some(decryption_key) = decryption_key {
-            // Unencrypted header
-            let mut aad: heapless::Vec<u8, 32> = heapless::Vec::new();
-            aad.push(self.message_header.message_flags.bits());
-            aad.extend(self.message_header.session_id.to_le_bytes());
-            aad.push(self.message_header.security_flags.bits());
-            aad.extend(self.message_header.message_counter.to_le_bytes());
-            if let Some(value) = self.message_header.source_node_id {
-                aad.extend(value.to_le_bytes());
+            if self.payload.len() < CRYPTO_AEAD_MIC_LENGTH_BYTES {
+                return Err(MessageError::Truncated);
             }
-            if let Some(value) = self.message_header.dest_node_id {
-                match value {
-                    NodeID::Unique(value) => aad.extend(value.to_le_bytes()),
-                    NodeID::Group(value) => aad.extend(value.to_le_bytes()),
-                }
+            if self.message_header.is_private() {
+                self.deobfuscate(decryption_key)?;
             }
-            // TODO: append message extensions when supported
-
-            let mut nonce: heapless::Vec<u8, CRYPTO_AEAD_NONCE_LENGTH_BYTES> = heapless::Vec::new();
-            nonce.push(self.message_header.security_flags.bits());
-            nonce.extend(self.message_header.message_counter.to_le_bytes());
-            nonce.extend(
-                self.message_header
-                    .source_node_id
-                    .unwrap_or_default()
-                    .to_le_bytes(),
-            );
-            let mut payload = self.payload.as_mut();
-
-            let decrypted_len = decrypt_in_place(
-                decryption_key,
-                nonce.as_slice(),
-                aad.as_slice(),
-                &mut payload,
-            );
-            self.payload.resize(decrypted_len, 0);
+            // The header as received, including message extensions
+            let nonce = self.message_header.nonce();
+            let decrypted_len =
+                decrypt_in_place(decryption_key, &nonce, &self.raw_header, &mut self.payload)
+                    .map_err(|_| MessageError::Integrity)?;
+            self.payload.truncate(decrypted_len);
+        } else if self.message_header.session_type != SessionType::UnsecuredSession {
+            return Err(MessageError::MissingKey);
         }
         // Protocol Header Field Descriptions (4.4.3)
         // Read past where the header was
-        let mut buf = BytesMut::from(&self.payload[..]);
-        let flag = buf.get_u8() & 0b00011111;
-        let exchange_flags = ExchangeFlags::from_bits(flag).unwrap();
+        let mut buf = &self.payload[..];
+        ensure(&buf, 6)?;
+        let exchange_flags = ExchangeFlags::from_bits_truncate(buf.get_u8());
         let protocol_opcode = buf.get_u8();
         // TODO: convert to an enum
         let exchange_id = buf.get_u16_le();
         let protocol_id = buf.get_u16_le();
         let protocol_vendor_id = if exchange_flags.contains(ExchangeFlags::VENDOR) {
+            ensure(&buf, 2)?;
             Some(buf.get_u16_le())
         } else {
             None
         };
         let ack_message_counter = if exchange_flags.contains(ExchangeFlags::ACKNOWLDEGE) {
+            ensure(&buf, 4)?;
             Some(buf.get_u32_le())
         } else {
             None
         };
-        if exchange_flags.contains(ExchangeFlags::SECURED_EXT) {
-            // todo!("Secured extensions not yet implemented");
-        }
+        let secured_extensions = if exchange_flags.contains(ExchangeFlags::SECURED_EXT) {
+            Some(Extensions::decode(&mut buf)?)
+        } else {
+            None
+        };
+        let protocol_header_len = self.payload.len() - buf.len();
 
         self.payload_header = Some(ProtocolHeader {
             exchange_flags,
@@ -233,53 +365,110 @@ impl Message {
             protocol_id,
             protocol_vendor_id,
             ack_message_counter,
-            secured_extensions: (),
+            secured_extensions,
         });
+        self.payload.advance(protocol_header_len);
+        Ok(())
+    }
 
-        self.payload = heapless::Vec::from_slice(&buf).unwrap();
+    /// Decrypt a group message with the first of `keys` that authenticates
+    /// it, returning the position of that key.
+    ///
+    /// Fails with the error of the last key if none of them work.
+    pub fn decrypt_with_candidates<'k>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'k [u8]>,
+    ) -> Result<usize, MessageError> {
+        let mut error = MessageError::MissingKey;
+        let mut keys = keys.into_iter().enumerate().peekable();
+        while let Some((i, key)) = keys.next() {
+            // Decryption is in place and a failed attempt garbles the payload,
+            // so only the last key is tried without a copy
+            let result = if keys.peek().is_some() {
+                let mut message = self.clone();
+                let result = message.decrypt(Some(key));
+                if result.is_ok() {
+                    *self = message;
+                }
+                result
+            } else {
+                self.decrypt(Some(key))
+            };
+            match result {
+                Ok(()) => return Ok(i),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Recover the header fields after the security flags, which are kept
+    /// in the payload by [Message::decode] when privacy is used.
+    fn deobfuscate(&mut self, decryption_key: &[u8]) -> Result<(), MessageError> {
+        let mic_start = self.payload.len() - CRYPTO_AEAD_MIC_LENGTH_BYTES;
+        let nonce = privacy_nonce(self.message_header.session_id, &self.payload[mic_start..]);
+        let privacy_key = privacy_key(decryption_key);
+        // The length of the extensions is obfuscated too, so the fixed fields
+        // are deobfuscated first to find where the header ends.
+        let mut header_len = self.message_header.obfuscated_fixed_len();
+        if self.message_header.has_extensions() {
+            header_len += 2;
+        }
+        if header_len > mic_start {
+            return Err(MessageError::Truncated);
+        }
+        privacy_crypt_in_place(&privacy_key, &nonce, 0, &mut self.payload[..header_len]);
+        if self.message_header.has_extensions() {
+            let len = &self.payload[header_len - 2..header_len];
+            let extensions_end = header_len + u16::from_le_bytes([len[0], len[1]]) as usize;
+            if extensions_end > mic_start {
+                return Err(MessageError::Truncated);
+            }
+            privacy_crypt_in_place(
+                &privacy_key,
+                &nonce,
+                header_len,
+                &mut self.payload[header_len..extensions_end],
+            );
+            header_len = extensions_end;
+        }
+        self.message_header
+            .decode_obfuscated(&mut &self.payload[..header_len])?;
+        // The rest of the header follows the clear fields in the same buffer
+        let header = self.payload.split_to(header_len);
+        self.raw_header.unsplit(header);
+        Ok(())
     }
 
+    /// Encode the message at the end of `out`, encrypting it there if there's a key
     pub fn encode(&self, out: &mut BytesMut, encryption_key: Option<&[u8]>) {
+        let message_header_start = out.len();
         self.message_header.encode(out);
-        let message_header_len = out.len();
-        let mut payload_out = BytesMut::with_capacity(1024);
-        self.payload_header
-            .as_ref()
-            .unwrap()
-            .encode(&mut payload_out);
-        payload_out.put_slice(&self.payload);
-        let payload_len = payload_out.len();
+        let message_header_len = out.len() - message_header_start;
+        self.payload_header.as_ref().unwrap().encode(out);
+        out.put_slice(&self.payload);
 
         // Handle encryption if applicable
         if let Some(encryption_key) = encryption_key {
-            let mut nonce: heapless::Vec<u8, CRYPTO_AEAD_NONCE_LENGTH_BYTES> = heapless::Vec::new();
-            nonce
-                .push(self.message_header.security_flags.bits())
-                .unwrap();
-            nonce.extend(self.message_header.message_counter.to_le_bytes());
-            // Default here makes sense for PASE messages as the Unspecified node ID
-            // is used (2.5.5.6)
-            nonce.extend(
-                self.message_header
-                    .source_node_id
-                    .unwrap_or_default()
-                    .to_le_bytes(),
-            );
+            let nonce = self.message_header.nonce();
+            let payload_len = out.len() - message_header_start - message_header_len;
 
             // Cipher Text
-            // let tag_space = [2u8; CRYPTO_AEAD_MIC_LENGTH_BYTES];
-            payload_out.put_bytes(0, CRYPTO_AEAD_MIC_LENGTH_BYTES);
-            let encrypted_len = encrypt_in_place(
-                encryption_key,
-                nonce.as_slice(),
-                &out,
-                &mut payload_out,
-                payload_len,
-            );
-            payload_out.resize(encrypted_len, 0);
-        }
+            out.put_bytes(0, CRYPTO_AEAD_MIC_LENGTH_BYTES);
+            let (header, payload) = out[message_header_start..].split_at_mut(message_header_len);
+            encrypt_in_place(encryption_key, &nonce, header, payload, payload_len);
 
-        out.extend_from_slice(&payload_out);
+            // Privacy is applied to the header after encryption (4.8.2)
+            if self.message_header.is_private() {
+                let nonce = privacy_nonce(self.message_header.session_id, &payload[payload_len..]);
+                privacy_crypt_in_place(
+                    &privacy_key(encryption_key),
+                    &nonce,
+                    0,
+                    &mut header[PRIVACY_HEADER_OFFSET..],
+                );
+            }
+        }
     }
 
     /// Add an acknowledgement to the message. Useful to add after consruction.
@@ -291,8 +480,12 @@ impl Message {
         payload_header.ack_message_counter = ack;
     }
 
-    /// Get the next acknowledgement counter if it is required by sender
+    /// Get the next acknowledgement counter if it is required by sender.
+    /// Group messages aren't sent reliably, so they are never acknowledged.
     pub fn next_ack(&self) -> Option<u32> {
+        if let SessionType::SecureGroup(_) = self.message_header.session_type {
+            return None;
+        }
         if self
             .payload_header
             .as_ref()
@@ -315,7 +508,7 @@ pub struct MessageHeader {
     pub message_counter: u32,
     pub source_node_id: Option<u64>,
     pub dest_node_id: Option<NodeID>,
-    pub message_extensions: (),
+    pub message_extensions: Option<Extensions>,
 }
 
 impl MessageHeader {
@@ -325,11 +518,119 @@ impl MessageHeader {
             ..Default::default()
         }
     }
+    /// Header of a message to a group, sent by `source_node_id` in the group
+    /// session of an operational group key (4.15.3)
+    pub fn group(session_id: SessionID, source_node_id: u64, group_id: u16) -> Self {
+        Self {
+            session_type: SessionType::SecureGroup(session_id),
+            message_flags: MessageFlags::SOURCE_NODE_ID_PRESENT
+                | MessageFlags::DSIZ_16_BIT_GROUP_ID,
+            session_id,
+            security_flags: SecurityFlags::SESSION_GROUP,
+            source_node_id: Some(source_node_id),
+            dest_node_id: Some(NodeID::Group(group_id)),
+            ..Default::default()
+        }
+    }
+
+    /// The nonce of the message's encryption (4.7.2.1).
+    ///
+    /// Group messages always have the source node ID in their header. Unicast
+    /// messages of PASE sessions leave it out, and the nonce has the
+    /// Unspecified node ID instead (2.5.5.6).
+    fn nonce(&self) -> [u8; CRYPTO_AEAD_NONCE_LENGTH_BYTES] {
+        let mut nonce = [0u8; CRYPTO_AEAD_NONCE_LENGTH_BYTES];
+        nonce[0] = self.wire_security_flags().bits();
+        nonce[1..5].copy_from_slice(&self.message_counter.to_le_bytes());
+        nonce[5..].copy_from_slice(&self.source_node_id.unwrap_or_default().to_le_bytes());
+        nonce
+    }
+
+    /// The security flags, with `MESSAGE_EXT` set if there are message extensions
+    fn wire_security_flags(&self) -> SecurityFlags {
+        let mut flags = self.security_flags;
+        flags.set(
+            SecurityFlags::MESSAGE_EXT,
+            self.message_extensions.is_some(),
+        );
+        flags
+    }
+
+    /// Whether the header fields after the security flags are obfuscated
+    pub fn is_private(&self) -> bool {
+        self.security_flags.contains(SecurityFlags::PRIVACY)
+    }
+
+    fn has_extensions(&self) -> bool {
+        self.security_flags.contains(SecurityFlags::MESSAGE_EXT)
+    }
+
+    /// The length of the message counter and node IDs, which come before the
+    /// message extensions
+    fn obfuscated_fixed_len(&self) -> usize {
+        let source_len = if self
+            .message_flags
+            .contains(MessageFlags::SOURCE_NODE_ID_PRESENT)
+        {
+            8
+        } else {
+            0
+        };
+        let dest_len = match self.message_flags.bits() & MessageFlags::DSIZ_RESERVED.bits() {
+            0b01 => 8,
+            0b10 => 2,
+            _ => 0,
+        };
+        4 + source_len + dest_len
+    }
+
+    /// Decode the fields that privacy obfuscates, from the message counter
+    /// to the message extensions.
+    fn decode_obfuscated(&mut self, buf: &mut &[u8]) -> Result<(), MessageError> {
+        // Message counter
+        ensure(buf, 4)?;
+        self.message_counter = buf.get_u32_le();
+        println!("Received message counter {}", self.message_counter);
+
+        // Source and destination node ID
+        self.source_node_id = if self
+            .message_flags
+            .contains(MessageFlags::SOURCE_NODE_ID_PRESENT)
+        {
+            ensure(buf, 8)?;
+            Some(buf.get_u64_le())
+        } else {
+            None
+        };
+
+        self.dest_node_id = match self.message_flags.bits() & MessageFlags::DSIZ_RESERVED.bits() {
+            0b00 => None,
+            0b01 => {
+                ensure(buf, 8)?;
+                Some(NodeID::Unique(buf.get_u64_le()))
+            }
+            0b10 => {
+                ensure(buf, 2)?;
+                Some(NodeID::Group(buf.get_u16_le()))
+            }
+            // Dropped without an ack (4.4.1.2)
+            _ => return Err(MessageError::ReservedDestination),
+        };
+
+        // Message extensions (4.4.1.8)
+        self.message_extensions = if self.has_extensions() {
+            Some(Extensions::decode(buf)?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
     pub fn encode(&self, target: &mut BytesMut) -> usize {
         // The message length is prepended outside of this function for TCP
         target.put_u8(self.message_flags.bits());
         target.put_u16_le(self.session_id);
-        target.put_u8(self.security_flags.bits());
+        target.put_u8(self.wire_security_flags().bits());
         target.put_u32_le(self.message_counter);
         if let Some(val) = self.source_node_id {
             target.put_u64_le(val);
@@ -339,7 +640,9 @@ impl MessageHeader {
             Some(NodeID::Unique(val)) => target.put_u64_le(val),
             None => {}
         }
-        // Message extensions
+        if let Some(extensions) = &self.message_extensions {
+            extensions.encode(target);
+        }
         target.len()
     }
 }
@@ -352,12 +655,18 @@ pub struct ProtocolHeader {
     pub protocol_id: u16,
     pub protocol_vendor_id: Option<u16>,
     pub ack_message_counter: Option<u32>,
-    pub secured_extensions: (),
+    pub secured_extensions: Option<Extensions>,
 }
 
 impl ProtocolHeader {
     pub fn encode(&self, out: &mut BytesMut) {
-        out.put_u8(self.exchange_flags.bits());
+        let mut exchange_flags = self.exchange_flags;
+        exchange_flags.set(
+            ExchangeFlags::SECURED_EXT,
+            self.secured_extensions.is_some(),
+        );
+        exchange_flags.set(ExchangeFlags::VENDOR, self.protocol_vendor_id.is_some());
+        out.put_u8(exchange_flags.bits());
         out.put_u8(self.protocol_opcode);
         out.put_u16_le(self.exchange_id);
         out.put_u16_le(self.protocol_id);
@@ -367,7 +676,9 @@ impl ProtocolHeader {
         if let Some(ack) = self.ack_message_counter {
             out.put_u32_le(ack);
         }
-        // Secured extensions
+        if let Some(extensions) = &self.secured_extensions {
+            extensions.encode(out);
+        }
     }
 }
 
@@ -387,20 +698,19 @@ impl Default for SessionType {
 }
 
 impl SessionType {
-    const fn new(flag: SecurityFlags, session_id: SessionID) -> Self {
-        // Unicast is 0b00, care should be taken here
+    const fn new(flag: SecurityFlags, session_id: SessionID) -> Result<Self, MessageError> {
+        // Unicast is 0b00, so the session type bits are compared as a whole
         match (
             session_id,
-            flag.contains(SecurityFlags::SESSION_GROUP),
-            flag.contains(SecurityFlags::SESSION_UNICAST),
+            flag.bits() & SecurityFlags::SESSION_RESERVED.bits(),
         ) {
-            (0, true, _) => panic!("Invalid message"),
-            (0, false, true) => SessionType::UnsecuredSession,
-            (_, false, true) => SessionType::SecureUnicast(session_id),
-            (_, true, false) => SessionType::SecureGroup(session_id),
-            (_, true, true) | (_, false, false) => {
-                unreachable!()
-            }
+            (0, 0b00) => Ok(SessionType::UnsecuredSession),
+            (_, 0b00) => Ok(SessionType::SecureUnicast(session_id)),
+            (0, 0b01) => Err(MessageError::InvalidHeader(
+                "group message without a session",
+            )),
+            (_, 0b01) => Ok(SessionType::SecureGroup(session_id)),
+            _ => Err(MessageError::ReservedSessionType),
         }
     }
 
@@ -478,8 +788,8 @@ mod tests {
     #[test]
     fn test_decode() {
         let buf = hex_literal::hex!("01000000e30ba008e8030000000000000621000000000000000015300120c3bf6a81dda5b85c626a582fdaf855cb7085ee308c8976954544afe814cca1a3300220b7b386219f54d57c39273a93b91a16a9232f209c4a0c8b7ff14ccb73434a24bf24030135042501d007300220f84523aa2486f48877a672c8146dafbdf531360ac8d8915d6027da1abc83193c1818");
-        let mut message = Message::decode(&buf);
-        message.decrypt(None);
+        let mut message = Message::decode(BytesMut::from(&buf[..])).unwrap();
+        message.decrypt(None).unwrap();
         dbg!(message);
     }
 
@@ -489,7 +799,7 @@ mod tests {
         let buf = hex_literal::hex!(
             "01000000138ada02e8030000000000000621000000000000000015300120c3bf6a81dda5b85c626a582fdaf855cb7085ee308c8976954544afe814cca1a33002201a6497bc785ac87d2004d9fa61bf8e2adb412ae4b475a7362e07db43ad1b6db324030135042501d007300220ee1c5b288f0abb63f9602e0cd72e256a002e71ead3e4188096e86986b9b5d5c01818"
         );
-        let message = Message::decode(&buf);
+        let message = Message::decode(BytesMut::from(&buf[..])).unwrap();
         dbg!(message);
     }
 
@@ -497,7 +807,7 @@ mod tests {
     fn test_decode_3() {
         // light on payload
         let buf = hex_literal::hex!("00010000ebed3e005cf46a92ce31fbf54a3fb90eef5a8f5549250c84df73619aba1e45648d966af5f8bd9be8aee5b3d660fe2acce629bc73874437");
-        let message = Message::decode(&buf);
+        let message = Message::decode(BytesMut::from(&buf[..])).unwrap();
         dbg!(message);
     }
 
@@ -513,9 +823,9 @@ mod tests {
             129, 225, 147, 31, 234, 13, 93, 71, 143, 97, 235, 17, 249, 114, 189, 255, 134, 153, 29,
             72, 24, 24,
         ];
-        let mut message = Message::decode(&buf);
-        message.decrypt(None);
-        let payload = PBKDFParamResponse::from_tlv(&message.payload.as_slice());
+        let mut message = Message::decode(BytesMut::from(&buf[..])).unwrap();
+        message.decrypt(None).unwrap();
+        let payload = PBKDFParamResponse::from_tlv(&message.payload).unwrap();
         dbg!(message);
         dbg!(payload);
     }
@@ -526,8 +836,8 @@ mod tests {
             4, 0, 0, 0, 11, 57, 253, 93, 0, 221, 54, 23, 63, 11, 59, 187, 3, 16, 19, 17, 0, 0, 198,
             191, 106, 129,
         ];
-        let mut message = Message::decode(&buf);
-        message.decrypt(None);
+        let mut message = Message::decode(BytesMut::from(&buf[..])).unwrap();
+        message.decrypt(None).unwrap();
         // message.decrypt(Some(&[
         //     77, 78, 236, 186, 38, 33, 108, 189, 52, 74, 213, 94, 170, 213, 56, 123,
         // ]));
@@ -540,8 +850,8 @@ mod tests {
             1, 0, 0, 0, 141, 50, 186, 13, 147, 2, 236, 155, 9, 141, 93, 78, 6, 64, 123, 198, 0, 0,
             193, 195, 101, 9, 0, 0, 0, 0, 0, 0, 0, 0,
         ];
-        let mut message = Message::decode(&buf);
-        message.decrypt(None);
+        let mut message = Message::decode(BytesMut::from(&buf[..])).unwrap();
+        message.decrypt(None).unwrap();
         // message.decrypt(Some(&[
         //     77, 78, 236, 186, 38, 33, 108, 189, 52, 74, 213, 94, 170, 213, 56, 123,
         // ]));
@@ -551,11 +861,238 @@ mod tests {
     #[test]
     fn test_decode_7() {
         let buf = hex_literal::hex!("000100009ae8560ff2efc6bac2db99b99331342ed46ad399530cc82b94ee41c869d3bc57b03fbd397064834a310ea9029cf10e98ee235db19234ffb9969b8755e2955f129ad87e7acf48c80c8ff1bbb6d34d8f1878047b81d2f1065a2c050971944f0710037b86f424ad4d74f50e9eab831e98eefbc44b6de4d442");
-        let mut message = Message::decode(&buf);
-        message.decrypt(None);
+        let mut message = Message::decode(BytesMut::from(&buf[..])).unwrap();
+        // A secure session's payload can't be read without its key
+        assert_eq!(message.decrypt(None), Err(MessageError::MissingKey));
         // message.decrypt(Some(&[
         //     77, 78, 236, 186, 38, 33, 108, 189, 52, 74, 213, 94, 170, 213, 56, 123,
         // ]));
         dbg!(message);
     }
+
+    #[test]
+    fn test_extensions_roundtrip() {
+        let message = Message::new(
+            MessageHeader {
+                message_flags: MessageFlags::SOURCE_NODE_ID_PRESENT,
+                session_id: 0x1234,
+                message_counter: 42,
+                source_node_id: Some(1),
+                message_extensions: Some(Extensions(
+                    heapless::Vec::from_slice(&[0xaa, 0xbb]).unwrap(),
+                )),
+                ..Default::default()
+            },
+            Some(ProtocolHeader {
+                exchange_flags: ExchangeFlags::INITIATOR,
+                protocol_opcode: 0x02,
+                exchange_id: 7,
+                protocol_id: ProtocolID::InteractionModel as _,
+                secured_extensions: Some(Extensions(heapless::Vec::from_slice(&[0xcc]).unwrap())),
+                ..Default::default()
+            }),
+            BytesMut::from(&[1, 2, 3][..]),
+        );
+
+        let mut out = BytesMut::new();
+        message.encode(&mut out, None);
+        assert_eq!(
+            out.as_ref(),
+            hex_literal::hex!(
+                "04341220" "2a000000" "0100000000000000" "0200aabb"
+                "090207000100" "0100cc" "010203"
+            )
+        );
+
+        // The message extensions are authenticated along with the rest of the header
+        let key = [7u8; 16];
+        for key in [None, Some(&key[..])] {
+            let mut message = message.clone();
+            if key.is_none() {
+                // Unencrypted messages belong to the unsecured session
+                message.message_header.session_id = 0;
+            }
+            let mut out = BytesMut::new();
+            message.encode(&mut out, key);
+            let mut decoded = Message::decode(out.clone()).unwrap();
+            decoded.decrypt(key).unwrap();
+            assert_eq!(
+                decoded.message_header.message_extensions,
+                message.message_header.message_extensions
+            );
+            let payload_header = decoded.payload_header.unwrap();
+            assert!(payload_header
+                .exchange_flags
+                .contains(ExchangeFlags::SECURED_EXT));
+            assert_eq!(
+                payload_header.secured_extensions,
+                message.payload_header.as_ref().unwrap().secured_extensions
+            );
+            assert_eq!(decoded.payload, message.payload);
+        }
+    }
+
+    #[test]
+    fn test_privacy_roundtrip() {
+        let message = Message::new(
+            MessageHeader {
+                message_flags: MessageFlags::SOURCE_NODE_ID_PRESENT
+                    | MessageFlags::DSIZ_16_BIT_GROUP_ID,
+                session_id: 0xbeef,
+                security_flags: SecurityFlags::PRIVACY | SecurityFlags::SESSION_GROUP,
+                message_counter: 0x01020304,
+                source_node_id: Some(0x1122334455667788),
+                dest_node_id: Some(NodeID::Group(0x0102)),
+                message_extensions: Some(Extensions(heapless::Vec::from_slice(&[0xaa]).unwrap())),
+                ..Default::default()
+            },
+            Some(ProtocolHeader {
+                protocol_opcode: 0x02,
+                exchange_id: 7,
+                protocol_id: ProtocolID::InteractionModel as _,
+                ..Default::default()
+            }),
+            BytesMut::from(&[1, 2, 3][..]),
+        );
+        let key = [7u8; 16];
+        let mut out = BytesMut::new();
+        message.encode(&mut out, Some(&key));
+
+        // Flags, session ID and security flags stay readable, the rest is obfuscated
+        assert_eq!(out[..4], hex_literal::hex!("06efbea1"));
+        let mut plain = BytesMut::new();
+        message.message_header.encode(&mut plain);
+        assert_ne!(out[4..plain.len()], plain[4..]);
+
+        // The message is deobfuscated and decrypted in the packet's buffer
+        let packet = out.as_ptr_range();
+        let mut decoded = Message::decode(out).unwrap();
+        assert_eq!(decoded.message_header.message_counter, 0);
+        decoded.decrypt(Some(&key)).unwrap();
+        assert!(packet.contains(&decoded.payload.as_ptr()));
+        assert!(packet.contains(&decoded.raw_header.as_ptr()));
+        let header = &decoded.message_header;
+        assert_eq!(header.message_counter, 0x01020304);
+        assert_eq!(header.source_node_id, Some(0x1122334455667788));
+        assert!(matches!(header.dest_node_id, Some(NodeID::Group(0x0102))));
+        assert_eq!(
+            header.message_extensions,
+            message.message_header.message_extensions
+        );
+        assert_eq!(decoded.payload_header.unwrap().exchange_id, 7);
+        assert_eq!(decoded.payload, message.payload);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let decode = |buf: &[u8]| Message::decode(BytesMut::from(buf)).err();
+        assert_eq!(decode(&[0x00, 0x00]), Some(MessageError::Truncated));
+        assert_eq!(
+            decode(&hex_literal::hex!("00000000" "010000")),
+            Some(MessageError::Truncated)
+        );
+        assert_eq!(
+            decode(&hex_literal::hex!("10000000" "01000000")),
+            Some(MessageError::UnsupportedVersion(1))
+        );
+        assert_eq!(
+            decode(&hex_literal::hex!("03000000" "01000000")),
+            Some(MessageError::ReservedDestination)
+        );
+        assert_eq!(
+            decode(&hex_literal::hex!("00000003" "01000000")),
+            Some(MessageError::ReservedSessionType)
+        );
+        assert!(matches!(
+            decode(&hex_literal::hex!("02010000" "01000000" "0100")),
+            Some(MessageError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            decode(&hex_literal::hex!("00010001" "01000000")),
+            Some(MessageError::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            decode(&hex_literal::hex!("00000080" "01000000")),
+            Some(MessageError::InvalidHeader(_))
+        ));
+
+        let message = Message::new(
+            MessageHeader::new(0x1234),
+            Some(ProtocolHeader {
+                protocol_id: 0x1000,
+                ..Default::default()
+            }),
+            Default::default(),
+        );
+        let key = [7u8; 16];
+        let mut out = BytesMut::new();
+        message.encode(&mut out, Some(&key));
+
+        // Tampering is reported
+        let mut tampered = out.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let mut decoded = Message::decode(tampered).unwrap();
+        let err = decoded.decrypt(Some(&key)).unwrap_err();
+        assert_eq!(err, MessageError::Integrity);
+        assert!(!err.should_ack());
+
+        // Messages of any protocol are decrypted, and left to their handlers
+        let mut decoded = Message::decode(out.clone()).unwrap();
+        decoded.decrypt(Some(&key)).unwrap();
+        assert_eq!(decoded.payload_header.unwrap().protocol_id, 0x1000);
+    }
+
+    #[test]
+    fn test_group_decrypt_candidates() {
+        use crate::session_context::{GroupKey, GroupKeyStore};
+
+        let key = GroupKey::new(1, 7, 0, &[3; 16], &[0; 8]);
+        // Another group's key that happens to have the same session ID
+        let decoy = GroupKey {
+            encryption_key: [4; 16],
+            ..key.clone()
+        };
+        let mut store = GroupKeyStore::new();
+        store.add_key(decoy);
+        store.add_key(key.clone());
+
+        let mut message_header = MessageHeader::group(key.session_id, 0x0102030405060708, 0x0101);
+        message_header.message_counter = 11;
+        let message = Message::new(
+            message_header,
+            Some(ProtocolHeader {
+                protocol_opcode: 0x08,
+                exchange_id: 3,
+                protocol_id: ProtocolID::InteractionModel as _,
+                ..Default::default()
+            }),
+            BytesMut::from(&[1, 2, 3][..]),
+        );
+        let mut out = BytesMut::new();
+        message.encode(&mut out, Some(&key.encryption_key));
+
+        let mut decoded = Message::decode(out.clone()).unwrap();
+        assert_eq!(
+            decoded.message_header.session_type,
+            SessionType::SecureGroup(key.session_id)
+        );
+        let keys = store
+            .candidates(key.session_id)
+            .map(|key| &key.encryption_key[..]);
+        assert_eq!(decoded.decrypt_with_candidates(keys), Ok(1));
+        assert_eq!(decoded.payload, message.payload);
+
+        // The source node ID is part of the nonce
+        let mut spoofed = out.clone();
+        spoofed[8] ^= 1;
+        let mut decoded = Message::decode(spoofed).unwrap();
+        let keys = store
+            .candidates(key.session_id)
+            .map(|key| &key.encryption_key[..]);
+        assert_eq!(
+            decoded.decrypt_with_candidates(keys),
+            Err(MessageError::Integrity)
+        );
+    }
 }
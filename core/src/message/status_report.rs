@@ -1,52 +1,221 @@
+use core::fmt;
+
 use bytes::{Buf, BufMut};
 use num::FromPrimitive;
 
+use crate::{
+    interaction_model::StatusCode, message::ProtocolID, session_context::SecureChannelProtocolCode,
+};
+
+/// The general code, protocol ID and protocol code
+const HEADER_LEN: usize = 8;
+
 /// Status Report Message (Appendix D)
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct StatusReport {
     pub general_code: GeneralCode,
-    pub protocol_id: u32,
-    pub protocol_code: u16,
+    pub protocol_code: ProtocolCode,
     pub protocol_data: Vec<u8>,
 }
 
+/// Errors from decoding or encoding a status report
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatusReportError {
+    /// The payload is shorter than the fixed fields
+    Truncated,
+    /// The general code is not one of [GeneralCode]
+    InvalidGeneralCode(u16),
+    /// The status report doesn't fit in the payload
+    Overflow,
+}
+
+impl fmt::Display for StatusReportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusReportError::Truncated => f.write_str("status report is truncated"),
+            StatusReportError::InvalidGeneralCode(code) => {
+                write!(f, "invalid general code {code}")
+            }
+            StatusReportError::Overflow => f.write_str("status report doesn't fit the payload"),
+        }
+    }
+}
+
 impl StatusReport {
-    pub fn from_payload(mut payload: &[u8]) -> Self {
-        // TODO: do implementations send 6 bytes?
-        dbg!(payload.len());
-        assert!(payload.len() >= 8);
+    pub fn new(general_code: GeneralCode, protocol_code: ProtocolCode) -> Self {
+        Self {
+            general_code,
+            protocol_code,
+            protocol_data: vec![],
+        }
+    }
+
+    /// Sent by the responder when PASE or CASE completes (4.11.1)
+    pub fn session_establishment_success() -> Self {
+        Self::new(
+            GeneralCode::Success,
+            ProtocolCode::SecureChannel(SecureChannelProtocolCode::SessionEstablishmentSuccess),
+        )
+    }
+
+    /// Sent to a session establishment request when there are no resources
+    /// to handle it, asking the initiator to wait at least `wait_ms` before
+    /// retrying (4.11.1)
+    pub fn busy(wait_ms: u16) -> Self {
+        Self {
+            protocol_data: wait_ms.to_le_bytes().to_vec(),
+            ..Self::new(
+                GeneralCode::Busy,
+                ProtocolCode::SecureChannel(SecureChannelProtocolCode::Busy),
+            )
+        }
+    }
+
+    /// Sent when a session establishment message is invalid or unexpected,
+    /// which ends the session establishment (4.11.1)
+    pub fn invalid_parameter() -> Self {
+        Self::new(
+            GeneralCode::Failure,
+            ProtocolCode::SecureChannel(SecureChannelProtocolCode::InvalidParameter),
+        )
+    }
+
+    /// Sent to close a CASE session (4.11.1)
+    pub fn close_session() -> Self {
+        Self::new(
+            GeneralCode::Success,
+            ProtocolCode::SecureChannel(SecureChannelProtocolCode::CloseSession),
+        )
+    }
+
+    /// The minimum time to wait before retrying, from a busy status report
+    pub fn busy_wait_ms(&self) -> Option<u16> {
+        match (&self.protocol_code, &self.protocol_data[..]) {
+            (ProtocolCode::SecureChannel(SecureChannelProtocolCode::Busy), [a, b]) => {
+                Some(u16::from_le_bytes([*a, *b]))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn from_payload(mut payload: &[u8]) -> Result<Self, StatusReportError> {
+        if payload.len() < HEADER_LEN {
+            return Err(StatusReportError::Truncated);
+        }
 
         let general_code = payload.get_u16_le();
-        let general_code = GeneralCode::from_u16(general_code).unwrap();
+        let general_code = GeneralCode::from_u16(general_code)
+            .ok_or(StatusReportError::InvalidGeneralCode(general_code))?;
         let protocol_id = payload.get_u32_le();
-        let protocol_code = if payload.remaining() >= 2 {
-            payload.get_u16_le()
-        } else {
-            0
-        };
-
-        // TODO: any validation
+        let protocol_code = payload.get_u16_le();
 
-        Self {
+        Ok(Self {
             general_code,
-            protocol_id,
-            protocol_code,
+            protocol_code: ProtocolCode::new(protocol_id, protocol_code),
             protocol_data: payload.to_vec(),
+        })
+    }
+
+    /// The length of the encoded status report
+    pub fn len(&self) -> usize {
+        HEADER_LEN + self.protocol_data.len()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(self.len());
+        self.encode(&mut payload);
+        payload
+    }
+
+    /// Encode as a message payload, failing if it is longer than `N`
+    pub fn to_payload<const N: usize>(&self) -> Result<heapless::Vec<u8, N>, StatusReportError> {
+        if self.len() > N {
+            return Err(StatusReportError::Overflow);
         }
+        let mut payload = heapless::Vec::new();
+        payload.resize_default(self.len()).unwrap();
+        self.encode(&mut &mut payload[..]);
+        Ok(payload)
     }
 
-    pub fn to_payload(&self, mut payload: &mut [u8]) {
+    pub fn encode(&self, payload: &mut impl BufMut) {
         payload.put_u16_le(self.general_code as u16);
-        payload.put_u32_le(self.protocol_id);
-        // TODO: should this be conditional?
-        if self.protocol_code > 0 {
-            payload.put_u16_le(self.protocol_code);
+        payload.put_u32_le(self.protocol_code.protocol_id());
+        payload.put_u16_le(self.protocol_code.code());
+        payload.put_slice(&self.protocol_data);
+    }
+}
+
+/// The protocol-specific status of a status report, which is interpreted
+/// according to its protocol.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolCode {
+    SecureChannel(SecureChannelProtocolCode),
+    Bdx(BdxStatusCode),
+    InteractionModel(StatusCode),
+    /// A code that isn't known, or from another protocol. The protocol ID
+    /// holds the vendor ID in its upper 16 bits.
+    Other {
+        protocol_id: u32,
+        code: u16,
+    },
+}
+
+impl ProtocolCode {
+    pub fn new(protocol_id: u32, code: u16) -> Self {
+        let known = match FromPrimitive::from_u32(protocol_id) {
+            Some(ProtocolID::SecureChannel) => {
+                FromPrimitive::from_u16(code).map(ProtocolCode::SecureChannel)
+            }
+            Some(ProtocolID::BDX) => FromPrimitive::from_u16(code).map(ProtocolCode::Bdx),
+            Some(ProtocolID::InteractionModel) => {
+                FromPrimitive::from_u16(code).map(ProtocolCode::InteractionModel)
+            }
+            _ => None,
+        };
+        known.unwrap_or(ProtocolCode::Other { protocol_id, code })
+    }
+
+    pub fn protocol_id(&self) -> u32 {
+        match self {
+            ProtocolCode::SecureChannel(_) => ProtocolID::SecureChannel as u32,
+            ProtocolCode::Bdx(_) => ProtocolID::BDX as u32,
+            ProtocolCode::InteractionModel(_) => ProtocolID::InteractionModel as u32,
+            ProtocolCode::Other { protocol_id, .. } => *protocol_id,
         }
+    }
 
-        payload.put_slice(&self.protocol_data);
+    pub fn code(&self) -> u16 {
+        match self {
+            ProtocolCode::SecureChannel(code) => *code as u16,
+            ProtocolCode::Bdx(code) => *code as u16,
+            ProtocolCode::InteractionModel(code) => *code as u16,
+            ProtocolCode::Other { code, .. } => *code,
+        }
     }
 }
 
+/// Bulk Data Exchange status codes
+#[repr(u16)]
+#[derive(FromPrimitive, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BdxStatusCode {
+    Overflow = 0x0011,
+    LengthTooLarge = 0x0012,
+    LengthTooShort = 0x0013,
+    LengthMismatch = 0x0014,
+    LengthRequired = 0x0015,
+    BadMessageContents = 0x0016,
+    BadBlockCounter = 0x0017,
+    UnexpectedMessage = 0x0018,
+    ResponderBusy = 0x0019,
+    TransferFailedUnknownError = 0x001f,
+    TransferMethodNotSupported = 0x0050,
+    FileDesignatorUnknown = 0x0051,
+    StartOffsetNotSupported = 0x0052,
+    VersionNotSupported = 0x0053,
+    Unknown = 0x005f,
+}
+
 #[repr(u16)]
 #[derive(FromPrimitive, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum GeneralCode {
@@ -68,3 +237,56 @@ pub enum GeneralCode {
     PermissionDenied,
     DataLoss,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_report_roundtrip() {
+        let report = StatusReport::session_establishment_success();
+        let payload = report.to_payload::<8>().unwrap();
+        assert_eq!(payload[..], hex_literal::hex!("0000" "00000000" "0000"));
+        assert_eq!(StatusReport::from_payload(&payload), Ok(report));
+
+        let busy = StatusReport::busy(500);
+        let payload = busy.to_vec();
+        assert_eq!(payload, hex_literal::hex!("0800" "00000000" "0400" "f401"));
+        let decoded = StatusReport::from_payload(&payload).unwrap();
+        assert_eq!(decoded.busy_wait_ms(), Some(500));
+        assert!(busy.to_payload::<8>().is_err());
+
+        let report =
+            StatusReport::from_payload(&hex_literal::hex!("0100" "02000000" "1900")).unwrap();
+        assert_eq!(
+            report.protocol_code,
+            ProtocolCode::Bdx(BdxStatusCode::ResponderBusy)
+        );
+        let report =
+            StatusReport::from_payload(&hex_literal::hex!("0100" "01000000" "8600")).unwrap();
+        assert_eq!(
+            report.protocol_code,
+            ProtocolCode::InteractionModel(StatusCode::UnsupportedAttribute)
+        );
+        // Vendor protocols are kept as they are
+        let report =
+            StatusReport::from_payload(&hex_literal::hex!("0100" "0100f1ff" "0700")).unwrap();
+        assert_eq!(
+            report.protocol_code,
+            ProtocolCode::Other {
+                protocol_id: 0xfff10001,
+                code: 7
+            }
+        );
+        assert_eq!(report.to_vec(), hex_literal::hex!("0100" "0100f1ff" "0700"));
+
+        assert_eq!(
+            StatusReport::from_payload(&[0, 0, 0, 0, 0, 0]),
+            Err(StatusReportError::Truncated)
+        );
+        assert_eq!(
+            StatusReport::from_payload(&hex_literal::hex!("ff00" "00000000" "0000")),
+            Err(StatusReportError::InvalidGeneralCode(0xff))
+        );
+    }
+}
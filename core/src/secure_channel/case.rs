@@ -1,16 +1,103 @@
+use core::fmt;
+
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    constants::CRYPTO_AEAD_MIC_LENGTH_BYTES,
-    message::{Message, MessageHeader},
-    session_context::SecureSessionContext,
+    constants::{
+        CRYPTO_AEAD_MIC_LENGTH_BYTES, CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES, SIGMA1_RESUME_INFO,
+        SIGMA1_RESUME_NONCE, SIGMA2_RESUME_INFO, SIGMA2_RESUME_NONCE,
+    },
+    crypto::{decrypt_in_place, encrypt_in_place, fill_random, hkdf_sha256, keypair::KeyPair},
+    exchange::MrpParameters,
+    message::{status_report::StatusReport, ExchangeFlags, Message, MessageHeader, ProtocolHeader},
+    session_context::{
+        ResumptionRecord, ResumptionTable, SecureChannelProtocolOpCode, SecureSessionContext,
+        SessionRole,
+    },
+    tlv::{serde_tlv, Encoder},
 };
 
-use super::pake::SedParameters;
+use super::pake::{SessionParameters, CRYPTO_HASH_LEN_BYTES, CRYPTO_PUBLIC_KEY_SIZE_BYTES};
+
+/// The length of the ID of a resumption record (4.14.2.2)
+pub const CASE_RESUMPTION_ID_LENGTH: usize = 16;
+
+/// Why a CASE session can't be established
+#[derive(Debug, Clone, PartialEq)]
+pub enum CASEError {
+    /// Sigma1 has only one of the resumption ID and the resume MIC
+    InvalidParameter,
+    /// There's no resumption record with the ID that the initiator sent
+    UnknownResumptionId,
+    /// The peer doesn't have the shared secret of the resumption record
+    InvalidResumeMic,
+    /// The message isn't expected at this stage of session establishment
+    UnexpectedMessage,
+}
+
+impl fmt::Display for CASEError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CASEError::InvalidParameter => {
+                write!(f, "the resumption ID and resume MIC must be sent together")
+            }
+            CASEError::UnknownResumptionId => write!(f, "no resumption record has the ID"),
+            CASEError::InvalidResumeMic => write!(f, "the resume MIC is invalid"),
+            CASEError::UnexpectedMessage => write!(f, "the message is unexpected"),
+        }
+    }
+}
 
 pub struct CASEManager {
-    session_context: SecureSessionContext,
+    role: SessionRole,
+    local_session_id: u16,
+    peer_session_id: u16,
+    exchange_id: u16,
+    initiator_random: [u8; 32],
+    /// The ephemeral key of the initiator, which the full exchange continues
+    /// with if the responder can't resume the session
+    ephemeral_keypair: Option<KeyPair>,
+    /// The record that the session is resumed from. Once the responder sent
+    /// Sigma2_Resume, this has the ID of the record that replaces it.
+    resumption: Option<ResumptionRecord>,
+    session_parameters: Option<SessionParameters>,
+    peer_session_parameters: Option<SessionParameters>,
 }
 
 impl CASEManager {
+    pub fn initiator(local_session_id: u16, exchange_id: u16) -> Self {
+        Self::new(SessionRole::Initiator, local_session_id, exchange_id)
+    }
+
+    pub fn responder(local_session_id: u16, exchange_id: u16) -> Self {
+        Self::new(SessionRole::Responder, local_session_id, exchange_id)
+    }
+
+    fn new(role: SessionRole, local_session_id: u16, exchange_id: u16) -> Self {
+        Self {
+            role,
+            local_session_id,
+            peer_session_id: 0,
+            exchange_id,
+            initiator_random: [0; 32],
+            ephemeral_keypair: None,
+            resumption: None,
+            session_parameters: None,
+            peer_session_parameters: None,
+        }
+    }
+
+    /// Send our MRP parameters to the peer
+    pub fn set_session_parameters(&mut self, parameters: SessionParameters) {
+        self.session_parameters = Some(parameters);
+    }
+
+    /// The MRP parameters that the peer sent, if any
+    pub fn peer_session_parameters(&self) -> Option<&SessionParameters> {
+        self.peer_session_parameters.as_ref()
+    }
+
     pub fn sigma1(&mut self) -> Message {
         // Generate random number
         // Generate session ID
@@ -19,40 +106,427 @@ impl CASEManager {
         // May encode MRP params
         Message::default()
     }
-    pub fn sigma1_with_resumption(&mut self) -> Message {
-        // Same as above
-        // Generate s1rk
-        // Generate initiator reume NIC using shared secret
-        Message::default()
+
+    /// Ask the responder to resume the session of a resumption record
+    pub fn sigma1_with_resumption(&mut self, record: &ResumptionRecord) -> Message {
+        fill_random(&mut self.initiator_random);
+        let keypair = KeyPair::new();
+        let mut initiator_eph_pubkey = [0; CRYPTO_PUBLIC_KEY_SIZE_BYTES];
+        keypair.get_public_key(&mut initiator_eph_pubkey);
+        self.ephemeral_keypair = Some(keypair);
+        let s1rk = resume_key(
+            &record.shared_secret,
+            &self.initiator_random,
+            &record.resumption_id,
+            &SIGMA1_RESUME_INFO,
+        );
+        let sigma1 = Sigma1 {
+            initiator_random: self.initiator_random,
+            initiator_session_id: self.local_session_id,
+            // TODO: the destination ID needs the fabric's IPK and root public key
+            destination_id: [0; CRYPTO_HASH_LEN_BYTES],
+            initiator_eph_pubkey,
+            initiator_session_params: self.session_parameters.clone(),
+            resumption_id: Some(record.resumption_id),
+            initiator_resume_mic: Some(resume_mic(&s1rk, &SIGMA1_RESUME_NONCE)),
+        };
+        self.resumption = Some(record.clone());
+        self.message(
+            SecureChannelProtocolOpCode::CASESigma1,
+            sigma1.to_tlv().to_slice(),
+        )
     }
+
     pub fn sigma2(&mut self, request: &Sigma1) -> Message {
         // Check if resumption id and initiator resume NIC are both set or both not
-        self.session_context.peer_session_id = request.initiator_session_id;
+        self.peer_session_id = request.initiator_session_id;
+        self.peer_session_parameters = request.initiator_session_params.clone();
         // Search for existing session if resumtion fields present
         // Validate sigma1 destination ID
 
         Message::default()
     }
-    pub fn sigma2_resume(&mut self) -> Message {
-        Message::default()
+
+    /// Resume the session of the record that the initiator asked for, if it
+    /// has the record's shared secret. A new resumption ID replaces the
+    /// record's, so that it can't be resumed twice.
+    pub fn sigma2_resume(
+        &mut self,
+        request: &Sigma1,
+        records: &ResumptionTable,
+    ) -> Result<Message, CASEError> {
+        let (Some(resumption_id), Some(initiator_resume_mic)) =
+            (&request.resumption_id, &request.initiator_resume_mic)
+        else {
+            return Err(CASEError::InvalidParameter);
+        };
+        let record = records
+            .find(resumption_id)
+            .ok_or(CASEError::UnknownResumptionId)?;
+        let s1rk = resume_key(
+            &record.shared_secret,
+            &request.initiator_random,
+            resumption_id,
+            &SIGMA1_RESUME_INFO,
+        );
+        verify_resume_mic(&s1rk, &SIGMA1_RESUME_NONCE, initiator_resume_mic)?;
+        self.initiator_random = request.initiator_random;
+        self.peer_session_id = request.initiator_session_id;
+        self.peer_session_parameters = request.initiator_session_params.clone();
+
+        let mut resumption_id = [0; CASE_RESUMPTION_ID_LENGTH];
+        fill_random(&mut resumption_id);
+        let s2rk = resume_key(
+            &record.shared_secret,
+            &self.initiator_random,
+            &resumption_id,
+            &SIGMA2_RESUME_INFO,
+        );
+        self.resumption = Some(ResumptionRecord {
+            resumption_id,
+            ..record.clone()
+        });
+        let response = Sigma2Resume {
+            resumption_id,
+            sigma2_resume_mic: resume_mic(&s2rk, &SIGMA2_RESUME_NONCE),
+            responder_session_id: self.local_session_id,
+            responder_session_params: self.session_parameters.clone(),
+        };
+        Ok(self.message(
+            SecureChannelProtocolOpCode::CASESigma2Resume,
+            response.to_tlv().to_slice(),
+        ))
     }
+
     pub fn sigma3(&mut self) -> Message {
         Message::default()
     }
-    pub fn sigma_finished(&mut self) -> Message {
-        Message::default()
+
+    /// Verify the responder's Sigma2_Resume, and send SigmaFinished. Returns
+    /// the resumed session, and the record that replaces the one it was
+    /// resumed from.
+    pub fn sigma_finished(
+        &mut self,
+        response: &Sigma2Resume,
+    ) -> Result<(Message, SecureSessionContext, ResumptionRecord), CASEError> {
+        let record = match (&self.role, &self.resumption) {
+            (SessionRole::Initiator, Some(record)) => record,
+            _ => return Err(CASEError::UnexpectedMessage),
+        };
+        let s2rk = resume_key(
+            &record.shared_secret,
+            &self.initiator_random,
+            &response.resumption_id,
+            &SIGMA2_RESUME_INFO,
+        );
+        verify_resume_mic(&s2rk, &SIGMA2_RESUME_NONCE, &response.sigma2_resume_mic)?;
+        self.peer_session_id = response.responder_session_id;
+        self.peer_session_parameters = response.responder_session_params.clone();
+        let record = ResumptionRecord {
+            resumption_id: response.resumption_id,
+            ..record.clone()
+        };
+        self.resumption = None;
+
+        let status_report = StatusReport::session_establishment_success();
+        let mut payload = BytesMut::with_capacity(status_report.len());
+        status_report.encode(&mut payload);
+        let finished = self.message(SecureChannelProtocolOpCode::StatusReport, &payload);
+        Ok((finished, self.session(&record), record))
+    }
+
+    /// Complete the resumption once the initiator sent SigmaFinished
+    pub fn resumed_session(
+        &mut self,
+    ) -> Result<(SecureSessionContext, ResumptionRecord), CASEError> {
+        let record = match (&self.role, self.resumption.take()) {
+            (SessionRole::Responder, Some(record)) => record,
+            _ => return Err(CASEError::UnexpectedMessage),
+        };
+        Ok((self.session(&record), record))
+    }
+
+    /// The session that is resumed from a record, whose keys are derived
+    /// from the record's shared secret and new resumption ID
+    fn session(&self, record: &ResumptionRecord) -> SecureSessionContext {
+        let mut salt = [0; 32 + CASE_RESUMPTION_ID_LENGTH];
+        salt[..32].copy_from_slice(&self.initiator_random);
+        salt[32..].copy_from_slice(&record.resumption_id);
+        let mut session = SecureSessionContext::new_case(
+            self.role == SessionRole::Initiator,
+            true,
+            self.local_session_id,
+            self.peer_session_id,
+            &record.shared_secret,
+            &salt,
+        );
+        session.local_fabric_index = record.fabric_index;
+        session.peer_node_id = record.peer_node_id;
+        session.resumption_id = Some(record.resumption_id);
+        session.peer_mrp_parameters =
+            MrpParameters::from_session_parameters(self.peer_session_parameters.as_ref());
+        session
     }
+
+    fn message(&self, opcode: SecureChannelProtocolOpCode, payload: &[u8]) -> Message {
+        let mut payload_header = ProtocolHeader {
+            exchange_id: self.exchange_id,
+            protocol_opcode: opcode as _,
+            ..Default::default()
+        };
+        // Secure channel by default
+        payload_header.exchange_flags.set(
+            ExchangeFlags::INITIATOR,
+            self.role == SessionRole::Initiator,
+        );
+        payload_header
+            .exchange_flags
+            .set(ExchangeFlags::RELIABILITY, true);
+        Message::new(
+            MessageHeader::new(0),
+            Some(payload_header),
+            BytesMut::from(payload),
+        )
+    }
+}
+
+/// Derive S1RK or S2RK from the shared secret of a resumption record
+/// (4.14.2.2)
+fn resume_key(
+    shared_secret: &[u8],
+    initiator_random: &[u8; 32],
+    resumption_id: &[u8; CASE_RESUMPTION_ID_LENGTH],
+    info: &[u8],
+) -> [u8; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES] {
+    let mut salt = [0; 32 + CASE_RESUMPTION_ID_LENGTH];
+    salt[..32].copy_from_slice(initiator_random);
+    salt[32..].copy_from_slice(resumption_id);
+    let mut key = [0; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES];
+    hkdf_sha256(&salt, shared_secret, info, &mut key);
+    key
 }
 
+/// The MIC of an empty message, which proves that the sender has the resume
+/// key
+fn resume_mic(key: &[u8], nonce: &[u8]) -> [u8; CRYPTO_AEAD_MIC_LENGTH_BYTES] {
+    let mut mic = [0; CRYPTO_AEAD_MIC_LENGTH_BYTES];
+    encrypt_in_place(key, nonce, &[], &mut mic, 0);
+    mic
+}
+
+fn verify_resume_mic(
+    key: &[u8],
+    nonce: &[u8],
+    mic: &[u8; CRYPTO_AEAD_MIC_LENGTH_BYTES],
+) -> Result<(), CASEError> {
+    let mut mic = *mic;
+    decrypt_in_place(key, nonce, &[], &mut mic)
+        .map(|_| ())
+        .map_err(|_| CASEError::InvalidResumeMic)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Sigma1 {
+    #[serde(rename = "1", with = "serde_bytes")]
     pub initiator_random: [u8; 32],
+    #[serde(rename = "2")]
     pub initiator_session_id: u16,
-    pub destination_id: (),
-    pub initiator_eph_pubkey: [u8; 32],
-    pub initiator_sed_params: Option<SedParameters>,
-    pub resumption_id: Option<[u8; 16]>,
+    #[serde(rename = "3", with = "serde_bytes")]
+    pub destination_id: [u8; CRYPTO_HASH_LEN_BYTES],
+    #[serde(rename = "4", with = "serde_bytes")]
+    pub initiator_eph_pubkey: [u8; CRYPTO_PUBLIC_KEY_SIZE_BYTES],
+    #[serde(rename = "5")]
+    pub initiator_session_params: Option<SessionParameters>,
+    #[serde(rename = "6", with = "serde_bytes", default)]
+    pub resumption_id: Option<[u8; CASE_RESUMPTION_ID_LENGTH]>,
+    #[serde(rename = "7", with = "serde_bytes", default)]
     pub initiator_resume_mic: Option<[u8; CRYPTO_AEAD_MIC_LENGTH_BYTES]>,
 }
-pub struct Sigma2 {}
-pub struct Sigma2Resume {}
+
+impl Sigma1 {
+    pub fn to_tlv(&self) -> Encoder {
+        serde_tlv::to_encoder(self).unwrap()
+    }
+
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sigma2 {
+    #[serde(rename = "1", with = "serde_bytes")]
+    pub responder_random: [u8; 32],
+    #[serde(rename = "2")]
+    pub responder_session_id: u16,
+    #[serde(rename = "3", with = "serde_bytes")]
+    pub responder_eph_pubkey: [u8; CRYPTO_PUBLIC_KEY_SIZE_BYTES],
+    #[serde(rename = "4", with = "serde_bytes")]
+    pub encrypted2: Vec<u8>,
+    #[serde(rename = "5")]
+    pub responder_session_params: Option<SessionParameters>,
+}
+
+impl Sigma2 {
+    pub fn to_tlv(&self) -> Encoder {
+        serde_tlv::to_encoder(self).unwrap()
+    }
+
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sigma2Resume {
+    #[serde(rename = "1", with = "serde_bytes")]
+    pub resumption_id: [u8; CASE_RESUMPTION_ID_LENGTH],
+    #[serde(rename = "2", with = "serde_bytes")]
+    pub sigma2_resume_mic: [u8; CRYPTO_AEAD_MIC_LENGTH_BYTES],
+    #[serde(rename = "3")]
+    pub responder_session_id: u16,
+    #[serde(rename = "4")]
+    pub responder_session_params: Option<SessionParameters>,
+}
+
+impl Sigma2Resume {
+    pub fn to_tlv(&self) -> Encoder {
+        serde_tlv::to_encoder(self).unwrap()
+    }
+
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
+    }
+}
+
 pub struct Sigma3 {}
+
+#[cfg(test)]
+mod tests {
+    use crate::secure_channel::pake::CRYPTO_GROUP_SIZE_BYTES;
+
+    use super::*;
+
+    #[test]
+    fn test_sigma1_tlv() {
+        let sigma1 = Sigma1 {
+            initiator_random: [1; 32],
+            initiator_session_id: 0x1234,
+            destination_id: [2; CRYPTO_HASH_LEN_BYTES],
+            initiator_eph_pubkey: [3; CRYPTO_PUBLIC_KEY_SIZE_BYTES],
+            initiator_session_params: Some(SessionParameters {
+                session_idle_interval: Some(10_000),
+                session_active_interval: None,
+                session_active_threshold: Some(2000),
+            }),
+            resumption_id: None,
+            initiator_resume_mic: None,
+        };
+        let encoded = sigma1.to_tlv();
+        let decoded = Sigma1::from_tlv(encoded.to_slice()).unwrap();
+        assert_eq!(decoded.initiator_session_id, 0x1234);
+        assert_eq!(decoded.destination_id, sigma1.destination_id);
+        assert_eq!(decoded.initiator_eph_pubkey, sigma1.initiator_eph_pubkey);
+        assert_eq!(
+            decoded.initiator_session_params,
+            sigma1.initiator_session_params
+        );
+        assert!(decoded.resumption_id.is_none());
+    }
+
+    #[test]
+    fn test_case_resumption() {
+        let record = ResumptionRecord {
+            fabric_index: 1,
+            peer_node_id: 0x0102,
+            resumption_id: [1; CASE_RESUMPTION_ID_LENGTH],
+            shared_secret: [2; CRYPTO_GROUP_SIZE_BYTES],
+        };
+        let mut records = ResumptionTable::new();
+        records.save(ResumptionRecord {
+            peer_node_id: 0x0201,
+            ..record.clone()
+        });
+
+        let mut initiator = CASEManager::initiator(10, 5);
+        initiator.set_session_parameters(SessionParameters {
+            session_idle_interval: Some(10_000),
+            ..Default::default()
+        });
+        let sigma1 = initiator.sigma1_with_resumption(&record);
+        let sigma1 = Sigma1::from_tlv(&sigma1.payload).unwrap();
+        assert_eq!(sigma1.resumption_id, Some(record.resumption_id));
+
+        // The initiator has to know the shared secret
+        let mut forged = Sigma1::from_tlv(sigma1.to_tlv().to_slice()).unwrap();
+        forged.initiator_resume_mic = Some([0; CRYPTO_AEAD_MIC_LENGTH_BYTES]);
+        let mut responder = CASEManager::responder(20, 5);
+        assert_eq!(
+            responder.sigma2_resume(&forged, &records).err(),
+            Some(CASEError::InvalidResumeMic)
+        );
+        forged.initiator_resume_mic = None;
+        assert_eq!(
+            responder.sigma2_resume(&forged, &records).err(),
+            Some(CASEError::InvalidParameter)
+        );
+        assert_eq!(
+            responder
+                .sigma2_resume(&sigma1, &ResumptionTable::new())
+                .err(),
+            Some(CASEError::UnknownResumptionId)
+        );
+
+        let sigma2_resume = responder.sigma2_resume(&sigma1, &records).unwrap();
+        let payload_header = sigma2_resume.payload_header.as_ref().unwrap();
+        assert_eq!(
+            payload_header.protocol_opcode,
+            SecureChannelProtocolOpCode::CASESigma2Resume as u8
+        );
+        let sigma2_resume = Sigma2Resume::from_tlv(&sigma2_resume.payload).unwrap();
+        assert_ne!(sigma2_resume.resumption_id, record.resumption_id);
+        assert_eq!(sigma2_resume.responder_session_id, 20);
+
+        let mut tampered = Sigma2Resume::from_tlv(sigma2_resume.to_tlv().to_slice()).unwrap();
+        tampered.resumption_id = record.resumption_id;
+        assert_eq!(
+            initiator.sigma_finished(&tampered).err(),
+            Some(CASEError::InvalidResumeMic)
+        );
+        let (finished, initiator_session, initiator_record) =
+            initiator.sigma_finished(&sigma2_resume).unwrap();
+        assert_eq!(
+            StatusReport::from_payload(&finished.payload).unwrap(),
+            StatusReport::session_establishment_success()
+        );
+        let (responder_session, responder_record) = responder.resumed_session().unwrap();
+        assert_eq!(
+            responder.resumed_session().err(),
+            Some(CASEError::UnexpectedMessage)
+        );
+
+        // Both nodes replace their record, and derive the same session keys
+        assert_eq!(initiator_record.resumption_id, sigma2_resume.resumption_id);
+        assert_eq!(responder_record.resumption_id, sigma2_resume.resumption_id);
+        assert_eq!(responder_record.peer_node_id, 0x0201);
+        assert_eq!(
+            initiator_session.encryption_key,
+            responder_session.decryption_key
+        );
+        assert_eq!(
+            initiator_session.decryption_key,
+            responder_session.encryption_key
+        );
+        assert_eq!(
+            (
+                initiator_session.local_session_id,
+                initiator_session.peer_session_id
+            ),
+            (10, 20)
+        );
+        assert_eq!(
+            responder_session.peer_mrp_parameters.idle_retrans_timeout,
+            core::time::Duration::from_secs(10)
+        );
+    }
+}
@@ -1,15 +1,24 @@
+use core::fmt;
+
+use bytes::BytesMut;
 use num::FromPrimitive;
 
 use crate::{
     crypto::fill_random,
+    exchange::{ExchangeKey, ExchangeRole, MrpParameters, ProtocolContext, ProtocolHandler},
     message::{
-        status_report::StatusReport, ExchangeFlags, Message, MessageFlags, ProtocolID,
-        SecurityFlags,
+        status_report::StatusReport, ExchangeFlags, Message, MessageFlags, MessageHeader,
+        ProtocolHeader, ProtocolID, SecurityFlags,
+    },
+    secure_channel::{
+        case::{Sigma1, Sigma2Resume},
+        pake::{PBKDFParamRequest, PBKDFParams, Pake1, Pake3},
     },
-    secure_channel::pake::{PBKDFParamRequest, PBKDFParams, Pake1, Pake3},
     session_context::{
-        SecureChannelProtocolOpCode, SecureSessionContext, SessionContext, UnsecuredSessionContext,
+        ResumptionRecord, SecureChannelProtocolOpCode, SecureSessionContext, SessionContext,
+        SessionRole, UnsecuredSessionContext,
     },
+    tlv::serde_tlv,
 };
 
 use self::{case::CASEManager, pake::PASEManager};
@@ -20,22 +29,87 @@ pub mod unsecured;
 
 /// The message counter maximum window size
 pub const MSG_COUNTER_WINDOW_SIZE: u32 = 32;
+/// The most that a MsgCounterSyncReq is delayed by, in milliseconds (4.8.1.1)
 pub const MSG_COUNTER_SYNC_REQ_JITTER: usize = 500;
+/// How long a MsgCounterSyncRsp is waited for, in milliseconds (4.8.1.1)
 pub const MSG_COUNTER_SYNC_TIMEOUT: usize = 400;
 
+/// Why a secure channel message can't be handled
+#[derive(Debug)]
+pub enum SecureChannelError {
+    /// The opcode isn't one of the Secure Channel protocol
+    UnknownOpCode(u8),
+    /// The payload of the message is malformed
+    Malformed(serde_tlv::Error),
+}
+
+impl fmt::Display for SecureChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecureChannelError::UnknownOpCode(opcode) => {
+                write!(f, "unknown secure channel opcode {opcode:02x}")
+            }
+            SecureChannelError::Malformed(e) => write!(f, "malformed payload: {e}"),
+        }
+    }
+}
+
+impl From<serde_tlv::Error> for SecureChannelError {
+    fn from(e: serde_tlv::Error) -> Self {
+        SecureChannelError::Malformed(e)
+    }
+}
+
 pub struct SecureChannelManager {
-    case: Option<CASEManager>,
+    /// The CASE session being established, and the exchange it's
+    /// established on
+    case: Option<(ExchangeKey, CASEManager)>,
     pase: Option<PASEManager>,
+    /// The MRP parameters of this node, which are sent to peers
+    mrp_parameters: MrpParameters,
 }
 
 impl SecureChannelManager {
     pub fn new() -> Self {
+        Self::with_mrp_parameters(MrpParameters::default())
+    }
+
+    /// A manager for a node that uses different MRP parameters, such as a
+    /// sleepy end device
+    pub fn with_mrp_parameters(mrp_parameters: MrpParameters) -> Self {
         Self {
             case: None,
             pase: None,
+            mrp_parameters,
         }
     }
 
+    /// Ask the peer of a resumption record to resume the session, as the
+    /// CASE initiator on a new unsecured exchange. `local_session_id` is the
+    /// ID that the exchange manager reserved for the session. The session is
+    /// added once the peer's Sigma2_Resume is verified.
+    pub fn resume_session(
+        &mut self,
+        record: &ResumptionRecord,
+        exchange_id: u16,
+        local_session_id: u16,
+        ephemeral_initiator_node_id: u64,
+    ) -> Message {
+        let mut case = CASEManager::initiator(local_session_id, exchange_id);
+        case.set_session_parameters(self.mrp_parameters.session_parameters());
+        let mut sigma1 = case.sigma1_with_resumption(record);
+        sigma1.message_header.source_node_id = Some(ephemeral_initiator_node_id);
+        sigma1.message_header.message_flags |= MessageFlags::SOURCE_NODE_ID_PRESENT;
+        let exchange = ExchangeKey {
+            session_id: 0,
+            exchange_id,
+            role: ExchangeRole::Initiator,
+            initiator_node_id: Some(ephemeral_initiator_node_id),
+        };
+        self.case = Some((exchange, case));
+        sigma1
+    }
+
     /// Handle a new message and send a response with a response message
     ///
     /// TODO: This would have to handle MRP acks, unless we send specific messages here
@@ -44,21 +118,46 @@ impl SecureChannelManager {
     ///
     /// TODO: this is a hacky way of returning a maybe-session, don't want to take a ref to the handler
     /// though. We might have to merge UnsecuredSessionContext with SecureSessionContext.
+    ///
+    /// Returns an error if the opcode is unknown, or the message payload is
+    /// malformed.
     pub fn on_message(
         &mut self,
-        session_context: &mut SessionContext,
+        context: &mut ProtocolContext<'_>,
         message: &Message,
-    ) -> (Option<Message>, Option<SecureSessionContext>) {
+    ) -> Result<(Option<Message>, Option<SecureSessionContext>), SecureChannelError> {
         let payload_header = message.payload_header.as_ref().unwrap();
         assert_eq!(payload_header.protocol_id, ProtocolID::SecureChannel as u16);
         let opcode: SecureChannelProtocolOpCode =
-            SecureChannelProtocolOpCode::from_u8(payload_header.protocol_opcode).unwrap();
+            SecureChannelProtocolOpCode::from_u8(payload_header.protocol_opcode).ok_or(
+                SecureChannelError::UnknownOpCode(payload_header.protocol_opcode),
+            )?;
         // TODO: validate that the correct stage of session establishment is being used.
         match opcode {
             SecureChannelProtocolOpCode::PBKDFParamRequest => {
-                let SessionContext::Unsecured(session_context) = session_context else {
-                    panic!();
+                // PASE is established in the unsecured session of its initiator
+                if context.unsecured_session_mut().is_none() {
+                    println!("Dropping PBKDFParamRequest without an unsecured session");
+                    return Ok((None, None));
+                }
+                let request = PBKDFParamRequest::from_tlv(&message.payload)?;
+                // The request is part of the SPAKE2+ context, and is kept as it was sent
+                let Ok(pbkdf_param_request) = heapless::Vec::from_slice(&message.payload) else {
+                    println!("Rejecting oversized PBKDFParamRequest");
+                    return Ok((
+                        Some(status_report(message, &StatusReport::invalid_parameter())),
+                        None,
+                    ));
                 };
+                // Only the default passcode is supported
+                if request.passcode_id != 0 {
+                    return Ok((
+                        Some(status_report(message, &StatusReport::invalid_parameter())),
+                        None,
+                    ));
+                }
+                // Reserve the ID of the session that PASE establishes
+                let session_id = context.new_session(SessionRole::Responder);
                 let mut node_id = [0; 8];
                 fill_random(&mut node_id);
                 let node_id = u64::from_le_bytes(node_id);
@@ -74,8 +173,7 @@ impl SecureChannelManager {
                 let mut pase = PASEManager::responder(
                     passcode,
                     message.message_header.session_id,
-                    // message.message_header.session_id,
-                    1, // TODO: should not hardcode it
+                    session_id,
                     payload_header.exchange_id,
                     node_id,
                     message
@@ -85,12 +183,16 @@ impl SecureChannelManager {
                     message_counter,
                     params,
                 );
-                let request = PBKDFParamRequest::from_tlv(&message.payload);
-                pase.set_pbkdf_param_request(
-                    heapless::Vec::from_slice(message.payload.as_slice()).unwrap(),
-                );
-                self.pase = Some(pase);
-                return (
+                pase.set_session_parameters(self.mrp_parameters.session_parameters());
+                pase.set_pbkdf_param_request(pbkdf_param_request);
+                // A new establishment replaces the one in progress
+                if let Some(pase) = self.pase.replace(pase) {
+                    context.release_session(pase.responder_session_id);
+                }
+                let session_context = context
+                    .unsecured_session_mut()
+                    .expect("the unsecured session was checked");
+                Ok((
                     Some(
                         self.pase
                             .as_mut()
@@ -98,47 +200,123 @@ impl SecureChannelManager {
                             .pbkdf_param_response(session_context, &request),
                     ),
                     None,
-                );
+                ))
             }
-            SecureChannelProtocolOpCode::PBKDFParamResponse => todo!(),
+            // This node is only a PASE responder, and doesn't establish
+            // CASE sessions in full yet
+            SecureChannelProtocolOpCode::PBKDFParamResponse
+            | SecureChannelProtocolOpCode::PASEPake2
+            | SecureChannelProtocolOpCode::CASESigma2
+            | SecureChannelProtocolOpCode::CASESigma3 => Ok((
+                Some(status_report(message, &StatusReport::invalid_parameter())),
+                None,
+            )),
             SecureChannelProtocolOpCode::PASEPake1 => {
                 // TODO: send acks
-                let request = Pake1::from_tlv(&message.payload);
-                return (Some(self.pase.as_mut().unwrap().pake2(&request)), None);
+                let request = Pake1::from_tlv(&message.payload)?;
+                let Some(pase) = self.pase.as_mut() else {
+                    println!("Rejecting Pake1, no PASE session is being established");
+                    return Ok((
+                        Some(status_report(message, &StatusReport::invalid_parameter())),
+                        None,
+                    ));
+                };
+                Ok((Some(pase.pake2(&request)), None))
             }
-            SecureChannelProtocolOpCode::PASEPake2 => todo!(),
             SecureChannelProtocolOpCode::PASEPake3 => {
-                let request = Pake3::from_tlv(&message.payload);
-                let SessionContext::Unsecured(session_context) = session_context else {
-                    panic!();
+                let request = Pake3::from_tlv(&message.payload)?;
+                let Some(session_context) = context.unsecured_session_mut() else {
+                    println!("Dropping Pake3 without an unsecured session");
+                    return Ok((None, None));
+                };
+                // A Pake3 that doesn't confirm the key ends the establishment
+                let Some(pase) = self
+                    .pase
+                    .as_mut()
+                    .filter(|pase| pase.verify_pake3(&request))
+                else {
+                    if let Some(pase) = self.pase.take() {
+                        context.release_session(pase.responder_session_id);
+                    }
+                    println!("Rejecting Pake3, it doesn't confirm a PASE key");
+                    return Ok((
+                        Some(status_report(message, &StatusReport::invalid_parameter())),
+                        None,
+                    ));
                 };
 
                 // Create a secure session
-                let (k_e, c_a, c_b) = self.pase.as_ref().unwrap().get_secrets();
+                let (k_e, c_a, c_b) = pase.get_secrets();
                 let mut secured_session = SecureSessionContext::new_pase(
                     false,
                     false,
-                    self.pase.as_ref().unwrap().responder_session_id,
+                    pase.responder_session_id,
                     session_context.peer_session_id,
                     k_e,
                     &[],
                 );
+                secured_session.peer_mrp_parameters =
+                    MrpParameters::from_session_parameters(pase.peer_session_parameters());
+                let finished = pase.pake_finished(&request);
+                self.pase = None;
 
-                return (
-                    Some(self.pase.as_mut().unwrap().pake_finished(&request)),
-                    Some(secured_session),
-                );
+                Ok((Some(finished), Some(secured_session)))
             }
             SecureChannelProtocolOpCode::MRPStandaloneAck => {
                 // TODO: update for standard ack
-                (None, None)
+                Ok((None, None))
+            }
+            SecureChannelProtocolOpCode::MsgCounterSyncReq
+            | SecureChannelProtocolOpCode::MsgCounterSyncRsp => {
+                // MCSP is only used in group sessions, whose messages the
+                // exchange manager synchronizes before they get here
+                Ok((None, None))
+            }
+            SecureChannelProtocolOpCode::CASESigma1 => {
+                let request = Sigma1::from_tlv(&message.payload)?;
+                if request.resumption_id.is_none() && request.initiator_resume_mic.is_none() {
+                    // TODO: CASE needs operational certificates
+                    println!("Dropping Sigma1, only session resumption is supported");
+                    return Ok((None, None));
+                }
+                let session_id = context.new_session(SessionRole::Responder);
+                let mut case = CASEManager::responder(session_id, payload_header.exchange_id);
+                case.set_session_parameters(self.mrp_parameters.session_parameters());
+                match case.sigma2_resume(&request, context.resumption_records()) {
+                    Ok(response) => {
+                        self.case = Some((context.exchange, case));
+                        Ok((Some(response), None))
+                    }
+                    Err(e) => {
+                        // TODO: continue with Sigma2 once CASE is supported
+                        println!("Unable to resume CASE session: {e}");
+                        context.release_session(session_id);
+                        Ok((None, None))
+                    }
+                }
+            }
+            SecureChannelProtocolOpCode::CASESigma2Resume => {
+                let response = Sigma2Resume::from_tlv(&message.payload)?;
+                let Some((_, case)) = self
+                    .case
+                    .as_mut()
+                    .filter(|(exchange, _)| *exchange == context.exchange)
+                else {
+                    println!("Dropping Sigma2_Resume, no session is being resumed on its exchange");
+                    return Ok((None, None));
+                };
+                match case.sigma_finished(&response) {
+                    Ok((finished, session, record)) => {
+                        self.case = None;
+                        context.resumption_records().save(record);
+                        Ok((Some(finished), Some(session)))
+                    }
+                    Err(e) => {
+                        println!("Dropping Sigma2_Resume: {e}");
+                        Ok((None, None))
+                    }
+                }
             }
-            SecureChannelProtocolOpCode::MsgCounterSyncReq => todo!(),
-            SecureChannelProtocolOpCode::MsgCounterSyncRsp => todo!(),
-            SecureChannelProtocolOpCode::CASESigma1 => todo!(),
-            SecureChannelProtocolOpCode::CASESigma2 => todo!(),
-            SecureChannelProtocolOpCode::CASESigma3 => todo!(),
-            SecureChannelProtocolOpCode::CASESigma2Resume => todo!(),
             SecureChannelProtocolOpCode::StatusReport => {
                 /*
                 Receiving a status report at a random stage of interaction is going to be interesting,
@@ -147,9 +325,278 @@ impl SecureChannelManager {
                  */
                 // TODO: handle when tracking state per channel
                 let status_report = StatusReport::from_payload(&message.payload);
+                // SigmaFinished completes a resumed CASE session, on the
+                // exchange that it was resumed on
+                let case = self
+                    .case
+                    .as_mut()
+                    .filter(|(exchange, _)| *exchange == context.exchange);
+                if let (Ok(report), Some((_, case))) = (&status_report, case) {
+                    if *report == StatusReport::session_establishment_success() {
+                        if let Ok((session, record)) = case.resumed_session() {
+                            self.case = None;
+                            context.resumption_records().save(record);
+                            return Ok((None, Some(session)));
+                        }
+                    }
+                }
                 dbg!(status_report);
-                (None, None)
+                Ok((None, None))
             }
         }
     }
 }
+
+impl ProtocolHandler for SecureChannelManager {
+    fn on_message(
+        &mut self,
+        context: &mut ProtocolContext<'_>,
+        message: &Message,
+    ) -> Option<Message> {
+        let (response, session) = match SecureChannelManager::on_message(self, context, message) {
+            Ok(response) => response,
+            Err(e) => {
+                println!("Dropping secure channel message: {e}");
+                return None;
+            }
+        };
+        if let Some(session) = session {
+            if let Err(e) = context.add_session(SessionContext::Secure(session)) {
+                println!("Dropping established session: {e}");
+                return None;
+            }
+        }
+        response
+    }
+}
+
+/// Answer a message with a StatusReport on its exchange
+fn status_report(message: &Message, status_report: &StatusReport) -> Message {
+    let received = message.payload_header.as_ref().unwrap();
+    let mut payload_header = ProtocolHeader {
+        exchange_id: received.exchange_id,
+        protocol_opcode: SecureChannelProtocolOpCode::StatusReport as _,
+        ..Default::default()
+    };
+    payload_header.exchange_flags.set(
+        ExchangeFlags::INITIATOR,
+        !received.exchange_flags.contains(ExchangeFlags::INITIATOR),
+    );
+    payload_header
+        .exchange_flags
+        .set(ExchangeFlags::RELIABILITY, true);
+    let mut payload = BytesMut::with_capacity(status_report.len());
+    status_report.encode(&mut payload);
+    Message::new(
+        MessageHeader {
+            session_type: message.message_header.session_type.clone(),
+            ..MessageHeader::new(message.message_header.session_id)
+        },
+        Some(payload_header),
+        payload,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        exchange::{ExchangeManager, ExchangeMessageAction},
+        message::NodeID,
+        secure_channel::{
+            case::CASE_RESUMPTION_ID_LENGTH,
+            pake::{CRYPTO_GROUP_SIZE_BYTES, CRYPTO_PUBLIC_KEY_SIZE_BYTES},
+        },
+        session_context::{MemoryResumptionStore, ResumptionStore},
+    };
+
+    use super::*;
+
+    /// An unsolicited secure channel message from node 0x0102
+    fn request(opcode: u8, exchange_id: u16, message_counter: u32) -> Message {
+        let mut message_header = MessageHeader::new(0);
+        message_header.message_flags = MessageFlags::SOURCE_NODE_ID_PRESENT;
+        message_header.source_node_id = Some(0x0102);
+        message_header.message_counter = message_counter;
+        Message::new(
+            message_header,
+            Some(ProtocolHeader {
+                exchange_flags: ExchangeFlags::INITIATOR | ExchangeFlags::RELIABILITY,
+                protocol_opcode: opcode,
+                exchange_id,
+                protocol_id: ProtocolID::SecureChannel as _,
+                ..Default::default()
+            }),
+            BytesMut::new(),
+        )
+    }
+
+    /// Receive a message, and return what is sent in response
+    fn exchange(manager: &mut ExchangeManager, message: &Message) -> Option<Message> {
+        let mut out = BytesMut::new();
+        message.encode(&mut out, None);
+        let mut message = Message::decode(out).unwrap();
+        assert_eq!(
+            manager.receive_message(&mut message),
+            ExchangeMessageAction::Process
+        );
+        let mut out = BytesMut::new();
+        manager.dispatch(&message, &mut out).unwrap();
+        if out.is_empty() {
+            return None;
+        }
+        let mut response = Message::decode(out).unwrap();
+        response.decrypt(None).unwrap();
+        Some(response)
+    }
+
+    #[test]
+    fn test_unsupported_messages() {
+        let mut manager = ExchangeManager::new();
+        manager.register_handler(
+            0,
+            ProtocolID::SecureChannel as u16,
+            SecureChannelManager::new(),
+        );
+
+        // Messages that this node can't continue with end the session
+        // establishment
+        let response = exchange(
+            &mut manager,
+            &request(SecureChannelProtocolOpCode::CASESigma2 as _, 1, 100),
+        )
+        .unwrap();
+        let payload_header = response.payload_header.as_ref().unwrap();
+        assert_eq!(
+            payload_header.protocol_opcode,
+            SecureChannelProtocolOpCode::StatusReport as u8
+        );
+        assert_eq!(payload_header.exchange_id, 1);
+        assert!(!payload_header
+            .exchange_flags
+            .contains(ExchangeFlags::INITIATOR));
+        assert_eq!(
+            StatusReport::from_payload(&response.payload).unwrap(),
+            StatusReport::invalid_parameter()
+        );
+
+        // Unknown opcodes are dropped
+        assert!(exchange(&mut manager, &request(0x7f, 2, 101)).is_none());
+
+        // and so are PASE messages when no PASE session is being established
+        let mut pake1 = request(SecureChannelProtocolOpCode::PASEPake1 as _, 3, 102);
+        pake1.payload = BytesMut::from(
+            Pake1 {
+                p_a: [4; CRYPTO_PUBLIC_KEY_SIZE_BYTES],
+            }
+            .to_tlv()
+            .to_slice(),
+        );
+        let mut pake3 = request(SecureChannelProtocolOpCode::PASEPake3 as _, 4, 103);
+        pake3.payload = BytesMut::from(Pake3 { c_a: [0; 32] }.to_tlv().to_slice());
+        for message in [pake1, pake3] {
+            let response = exchange(&mut manager, &message).unwrap();
+            assert_eq!(
+                StatusReport::from_payload(&response.payload).unwrap(),
+                StatusReport::invalid_parameter()
+            );
+        }
+        assert!(manager.session_context(0).is_none());
+    }
+
+    #[test]
+    fn test_case_resumption() {
+        let record = |peer_node_id| ResumptionRecord {
+            fabric_index: 1,
+            peer_node_id,
+            resumption_id: [1; CASE_RESUMPTION_ID_LENGTH],
+            shared_secret: [2; CRYPTO_GROUP_SIZE_BYTES],
+        };
+        let store = |peer_node_id| {
+            let mut store = MemoryResumptionStore::new();
+            store.store(&record(peer_node_id));
+            store
+        };
+        // The controller is node 0x0a, and the device is node 0x0b
+        let mut controller = ExchangeManager::new();
+        controller.set_resumption_store(store(0x0b));
+        let mut device = ExchangeManager::new();
+        device.set_resumption_store(store(0x0a));
+        device.register_handler(
+            0,
+            ProtocolID::SecureChannel as u16,
+            SecureChannelManager::new(),
+        );
+
+        let (exchange_id, session_id) =
+            controller.new_initiator_exchange_unsecured(0x0102).unwrap();
+        let mut initiator = SecureChannelManager::new();
+        let mut sigma1 = initiator.resume_session(
+            controller.resumption_records().get(1, 0x0b).unwrap(),
+            exchange_id,
+            session_id,
+            0x0102,
+        );
+        controller.register_handler(0, ProtocolID::SecureChannel as u16, initiator);
+        let mut out = BytesMut::new();
+        controller.encode_message(&mut sigma1, &mut out).unwrap();
+        let mut sigma1 = Message::decode(out).unwrap();
+        sigma1.decrypt(None).unwrap();
+
+        // The device answers the controller's ephemeral node
+        let sigma2_resume = exchange(&mut device, &sigma1).unwrap();
+        assert!(matches!(
+            sigma2_resume.message_header.dest_node_id,
+            Some(NodeID::Unique(0x0102))
+        ));
+        let finished = exchange(&mut controller, &sigma2_resume).unwrap();
+        assert_eq!(
+            StatusReport::from_payload(&finished.payload).unwrap(),
+            StatusReport::session_establishment_success()
+        );
+        // A success report on another exchange doesn't complete the resumption
+        let mut forged = request(
+            SecureChannelProtocolOpCode::StatusReport as _,
+            exchange_id.wrapping_add(1),
+            finished.message_header.message_counter + 1,
+        );
+        let mut payload = BytesMut::new();
+        StatusReport::session_establishment_success().encode(&mut payload);
+        forged.payload = payload;
+        assert!(exchange(&mut device, &forged).is_none());
+        let Some(SessionContext::Secure(initiator_session)) =
+            controller.session_context(session_id)
+        else {
+            panic!("the controller's session wasn't resumed");
+        };
+        assert!(!matches!(
+            device.session_context(initiator_session.peer_session_id),
+            Some(SessionContext::Secure(_))
+        ));
+        assert!(exchange(&mut device, &finished).is_none());
+
+        // Both nodes have the resumed session, and replaced their record
+        let Some(SessionContext::Secure(responder_session)) =
+            device.session_context(initiator_session.peer_session_id)
+        else {
+            panic!("the device's session wasn't resumed");
+        };
+        assert_eq!(
+            initiator_session.encryption_key,
+            responder_session.decryption_key
+        );
+        assert_eq!(initiator_session.peer_node_id, 0x0b);
+        assert_eq!(responder_session.peer_node_id, 0x0a);
+        let resumption_id = initiator_session.resumption_id.unwrap();
+        assert_ne!(resumption_id, record(0).resumption_id);
+        assert_eq!(responder_session.resumption_id, Some(resumption_id));
+        for (manager, peer_node_id) in [(&controller, 0x0b), (&device, 0x0a)] {
+            let record = manager.resumption_records().get(1, peer_node_id).unwrap();
+            assert_eq!(record.resumption_id, resumption_id);
+        }
+
+        // Sigma2_Resume is dropped once no session is being resumed
+        let mut again = sigma2_resume.clone();
+        again.message_header.message_counter += 1;
+        assert!(exchange(&mut controller, &again).is_none());
+    }
+}
@@ -1,3 +1,6 @@
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
+
 use crate::{
     constants::*,
     crypto::{fill_random, pbkdf2_hmac, sha256 as crypto_sha256, spake2p::Spake2P},
@@ -6,7 +9,7 @@ use crate::{
         SecureChannelProtocolCode, SecureChannelProtocolOpCode, SecureSessionContext, SessionRole,
         UnsecuredSessionContext,
     },
-    tlv::*,
+    tlv::{serde_tlv, Encoder},
 };
 
 pub const CRYPTO_PBKDF_ITERATIONS_MIN: u32 = 1000;
@@ -29,6 +32,9 @@ pub struct PASEManager {
     pbkdf_param_response: heapless::Vec<u8, 512>,
     /// Respondent can set these at the beginning if known or when generated.
     pbkdf_params: Option<PBKDFParams>,
+    /// The parameters we send to the peer, and the ones it sent us
+    session_parameters: Option<SessionParameters>,
+    peer_session_parameters: Option<SessionParameters>,
     // This is also stored in the unsecured session context
     // at least as the peer. We can determine this with the
     // session_role there.
@@ -64,6 +70,8 @@ impl PASEManager {
             pbkdf_param_request: Default::default(),
             pbkdf_param_response: Default::default(),
             pbkdf_params: None,
+            session_parameters: None,
+            peer_session_parameters: None,
             c_a: Default::default(),
             c_b: Default::default(),
             k_e: Default::default(),
@@ -93,6 +101,8 @@ impl PASEManager {
             pbkdf_param_request: heapless::Vec::new(),
             pbkdf_param_response: heapless::Vec::new(),
             pbkdf_params,
+            session_parameters: None,
+            peer_session_parameters: None,
             c_a: Default::default(),
             c_b: Default::default(),
             k_e: Default::default(),
@@ -129,12 +139,16 @@ impl PASEManager {
             initiator_session_id: session_context.local_session_id,
             passcode_id,
             has_pbkdf_params: self.pbkdf_params.is_some(),
-            initiator_sed_params: None,
+            initiator_session_params: self.session_parameters.clone(),
         };
         // Encode the request struct
         let encoded = pbkdf_param_request.to_tlv();
         self.pbkdf_param_request = heapless::Vec::from_slice(encoded.to_slice()).unwrap();
-        Message::new(self.message_header(), Some(payload_header), encoded.inner())
+        Message::new(
+            self.message_header(),
+            Some(payload_header),
+            BytesMut::from(encoded.to_slice()),
+        )
     }
 
     pub fn pbkdf_param_response(
@@ -147,13 +161,14 @@ impl PASEManager {
         let mut responder_random = [0; 32];
         fill_random(&mut responder_random);
         session_context.peer_session_id = request.initiator_session_id;
+        self.peer_session_parameters = request.initiator_session_params.clone();
 
         let mut pbkdf_params_response = PBKDFParamResponse {
             initiator_random: request.initiator_random,
             responder_random,
             responder_session_id: self.responder_session_id,
             pbkdf_params: None,
-            responder_sed_params: None,
+            responder_session_params: self.session_parameters.clone(),
         };
         if self.pbkdf_params.is_none() {
             let mut salt = [0; 16];
@@ -180,12 +195,20 @@ impl PASEManager {
             .set(ExchangeFlags::RELIABILITY, true);
         payload_header.protocol_opcode = SecureChannelProtocolOpCode::PBKDFParamResponse as _;
 
-        Message::new(self.message_header(), Some(payload_header), encoded.inner())
+        Message::new(
+            self.message_header(),
+            Some(payload_header),
+            BytesMut::from(encoded.to_slice()),
+        )
     }
 
-    pub fn pake1(&mut self, session_context: &mut UnsecuredSessionContext) -> Message {
-        let request = PBKDFParamResponse::from_tlv(&self.pbkdf_param_response);
+    pub fn pake1(
+        &mut self,
+        session_context: &mut UnsecuredSessionContext,
+    ) -> serde_tlv::Result<Message> {
+        let request = PBKDFParamResponse::from_tlv(&self.pbkdf_param_response)?;
         session_context.peer_session_id = request.responder_session_id;
+        self.peer_session_parameters = request.responder_session_params.clone();
 
         if self.pbkdf_params.is_none() {
             self.pbkdf_params = request.pbkdf_params.clone();
@@ -214,7 +237,11 @@ impl PASEManager {
             .set(ExchangeFlags::RELIABILITY, true);
         payload_header.protocol_opcode = SecureChannelProtocolOpCode::PASEPake1 as _;
 
-        Message::new(self.message_header(), Some(payload_header), encoded.inner())
+        Ok(Message::new(
+            self.message_header(),
+            Some(payload_header),
+            BytesMut::from(encoded.to_slice()),
+        ))
     }
     pub fn pake2(&mut self, request: &Pake1) -> Message {
         let PBKDFParams { iterations, salt } = self.pbkdf_params.as_ref().unwrap();
@@ -256,7 +283,11 @@ impl PASEManager {
             .set(ExchangeFlags::RELIABILITY, true);
         payload_header.protocol_opcode = SecureChannelProtocolOpCode::PASEPake2 as _;
 
-        Message::new(self.message_header(), Some(payload_header), encoded.inner())
+        Message::new(
+            self.message_header(),
+            Some(payload_header),
+            BytesMut::from(encoded.to_slice()),
+        )
     }
     pub fn pake3(&mut self, request: &Pake2) -> Message {
         // TODO: can provide these to spake2p to avoid repetition
@@ -300,8 +331,18 @@ impl PASEManager {
 
         println!("PAKE Finished ***********");
 
-        Message::new(self.message_header(), Some(payload_header), encoded.inner())
+        Message::new(
+            self.message_header(),
+            Some(payload_header),
+            BytesMut::from(encoded.to_slice()),
+        )
     }
+    /// Whether Pake3 confirms the key that was agreed in Pake2, which the
+    /// responder has to have sent first
+    pub fn verify_pake3(&self, pake3: &Pake3) -> bool {
+        self.spake2p.is_some() && self.c_a == pake3.c_a
+    }
+
     pub fn pake_finished(&mut self, pake3: &Pake3) -> Message {
         // Verify Pake3.cA against cA
         assert_eq!(self.c_a, pake3.c_a);
@@ -317,24 +358,25 @@ impl PASEManager {
             .exchange_flags
             .set(ExchangeFlags::RELIABILITY, false);
         payload_header.protocol_opcode = SecureChannelProtocolOpCode::StatusReport as _;
-        let status_report = StatusReport {
-            general_code: status_report::GeneralCode::Success,
-            // TODO: there's a mismatch here, we're going from u16 to u32
-            protocol_id: ProtocolID::SecureChannel as u32,
-            protocol_code: SecureChannelProtocolCode::SessionEstablishmentSuccess as _,
-            protocol_data: vec![],
-        };
-
-        // TODO: too many allocations :/
-        let mut payload = [0u8; 8];
-        status_report.to_payload(&mut payload);
-        let payload = heapless::Vec::from_slice(&payload).unwrap();
+        let status_report = StatusReport::session_establishment_success();
+        let mut payload = BytesMut::with_capacity(status_report.len());
+        status_report.encode(&mut payload);
 
         println!("PAKE Finished ---------------");
 
         // TODO: is header different?
         Message::new(self.message_header(), Some(payload_header), payload)
     }
+    /// Send our MRP parameters to the peer
+    pub fn set_session_parameters(&mut self, parameters: SessionParameters) {
+        self.session_parameters = Some(parameters);
+    }
+
+    /// The MRP parameters that the peer sent, if any
+    pub fn peer_session_parameters(&self) -> Option<&SessionParameters> {
+        self.peer_session_parameters.as_ref()
+    }
+
     pub fn set_pbkdf_param_request(&mut self, value: heapless::Vec<u8, 512>) {
         self.pbkdf_param_request = value;
     }
@@ -378,525 +420,120 @@ In each of the above interactions, I'll mutate state, then return a response.
 So let's start by returning a message or an error.
  */
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PBKDFParams {
+    #[serde(rename = "1")]
     pub iterations: u32,
+    #[serde(rename = "2", with = "serde_bytes")]
     pub salt: Vec<u8>, // 16..32 length
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct PBKDFParamRequest {
+    #[serde(rename = "1", with = "serde_bytes")]
     pub initiator_random: [u8; 32],
+    #[serde(rename = "2")]
     pub initiator_session_id: u16,
+    #[serde(rename = "3")]
     pub passcode_id: u16,
+    #[serde(rename = "4")]
     pub has_pbkdf_params: bool,
-    pub initiator_sed_params: Option<SedParameters>,
+    #[serde(rename = "5")]
+    pub initiator_session_params: Option<SessionParameters>,
 }
 
 impl PBKDFParamRequest {
     pub fn to_tlv(&self) -> Encoder {
-        let mut encoder = Encoder::default();
-        encoder.write(
-            TlvType::Structure,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-        encoder.write(
-            TlvType::ByteString(ElementSize::Byte1, self.initiator_random.len()),
-            TagControl::ContextSpecific(1),
-            TagLengthValue::ByteString(heapless::Vec::from_slice(&self.initiator_random).unwrap()),
-        );
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte2),
-            TagControl::ContextSpecific(2),
-            TagLengthValue::Unsigned16(self.initiator_session_id),
-        );
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte2),
-            TagControl::ContextSpecific(3),
-            TagLengthValue::Unsigned16(self.passcode_id),
-        );
-        encoder.write(
-            TlvType::Boolean(self.has_pbkdf_params),
-            TagControl::ContextSpecific(4),
-            TagLengthValue::Boolean(self.has_pbkdf_params),
-        );
-        // TODO: sleepy vafiables
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-
-        encoder
+        serde_tlv::to_encoder(self).unwrap()
     }
 
-    pub fn from_tlv(data: &[u8]) -> Self {
-        let tlv = decode(data);
-        let mut initiator_random = None;
-        let mut initiator_session_id = None;
-        let mut passcode_id = None;
-        let mut has_pbkdf_params = None;
-        let mut initiator_sed_params: Option<SedParameters> = None;
-
-        let mut element = tlv;
-        // Track when we are inside structs
-        let mut in_sed_params = false;
-
-        loop {
-            match element.get_control() {
-                TagControl::Anonymous => {}
-                TagControl::ContextSpecific(1) if in_sed_params => {
-                    if let TagLengthValue::Unsigned32(value) = element.get_value() {
-                        let timeout = Some(value);
-                        match initiator_sed_params.as_mut() {
-                            Some(params) => {
-                                params.sleepy_idle_interval = timeout;
-                            }
-                            None => {
-                                initiator_sed_params = Some(SedParameters {
-                                    sleepy_idle_interval: timeout,
-                                    sleepy_active_interval: None,
-                                })
-                            }
-                        }
-                    }
-                }
-                TagControl::ContextSpecific(2) if in_sed_params => {
-                    if let TagLengthValue::Unsigned32(value) = element.get_value() {
-                        let timeout = Some(value);
-                        match initiator_sed_params.as_mut() {
-                            Some(params) => {
-                                params.sleepy_active_interval = timeout;
-                            }
-                            None => {
-                                initiator_sed_params = Some(SedParameters {
-                                    sleepy_idle_interval: None,
-                                    sleepy_active_interval: timeout,
-                                })
-                            }
-                        }
-                    }
-                }
-                TagControl::ContextSpecific(1) => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        initiator_random = Some(bytes.as_slice().try_into().unwrap());
-                    }
-                }
-                TagControl::ContextSpecific(2) => {
-                    if let TagLengthValue::Unsigned16(value) = element.get_value() {
-                        initiator_session_id = Some(value);
-                    }
-                }
-                TagControl::ContextSpecific(3) => match element.get_value() {
-                    TagLengthValue::Unsigned8(value) => passcode_id = Some(value as _),
-                    TagLengthValue::Unsigned16(value) => passcode_id = Some(value as _),
-                    _ => {
-                        panic!("Unexpected type for passcode_id")
-                    }
-                },
-                TagControl::ContextSpecific(4) => {
-                    if let TagLengthValue::Boolean(value) = element.get_value() {
-                        has_pbkdf_params = Some(value);
-                    }
-                }
-                TagControl::ContextSpecific(5) => {
-                    in_sed_params = true;
-                }
-                other => {
-                    panic!("Unexpected tag {other:?}");
-                }
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-
-        Self {
-            initiator_random: initiator_random.unwrap(),
-            initiator_session_id: initiator_session_id.unwrap(),
-            passcode_id: passcode_id.unwrap_or_default(), // TODO: fix
-            has_pbkdf_params: has_pbkdf_params.unwrap(),
-            initiator_sed_params,
-        }
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct PBKDFParamResponse {
+    #[serde(rename = "1", with = "serde_bytes")]
     pub initiator_random: [u8; 32],
+    #[serde(rename = "2", with = "serde_bytes")]
     pub responder_random: [u8; 32],
+    #[serde(rename = "3")]
     pub responder_session_id: u16,
+    #[serde(rename = "4")]
     pub pbkdf_params: Option<PBKDFParams>,
-    pub responder_sed_params: Option<SedParameters>,
+    #[serde(rename = "5")]
+    pub responder_session_params: Option<SessionParameters>,
 }
 
 impl PBKDFParamResponse {
     pub fn to_tlv(&self) -> Encoder {
-        let mut encoder = Encoder::default();
-        encoder.write(
-            TlvType::Structure,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-        encoder.write(
-            TlvType::ByteString(ElementSize::Byte1, self.initiator_random.len()),
-            TagControl::ContextSpecific(1),
-            TagLengthValue::ByteString(heapless::Vec::from_slice(&self.initiator_random).unwrap()),
-        );
-        encoder.write(
-            TlvType::ByteString(ElementSize::Byte1, self.responder_random.len()),
-            TagControl::ContextSpecific(2),
-            TagLengthValue::ByteString(heapless::Vec::from_slice(&self.responder_random).unwrap()),
-        );
-        encoder.write(
-            TlvType::UnsignedInt(ElementSize::Byte2),
-            TagControl::ContextSpecific(3),
-            TagLengthValue::Unsigned16(self.responder_session_id),
-        );
-        if let Some(params) = &self.pbkdf_params {
-            encoder.write(
-                TlvType::Structure,
-                TagControl::ContextSpecific(4),
-                TagLengthValue::Container,
-            );
-            encoder.write(
-                TlvType::UnsignedInt(ElementSize::Byte4),
-                TagControl::ContextSpecific(1),
-                TagLengthValue::Unsigned32(params.iterations),
-            );
-            encoder.write(
-                TlvType::ByteString(ElementSize::Byte1, params.salt.len()),
-                TagControl::ContextSpecific(2),
-                TagLengthValue::ByteString(heapless::Vec::from_slice(&params.salt).unwrap()),
-            );
-            encoder.write(
-                TlvType::EndOfContainer,
-                TagControl::Anonymous,
-                TagLengthValue::EndOfContainer,
-            );
-        }
-        // TODO: sleepy vafiables
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-        encoder
+        serde_tlv::to_encoder(self).unwrap()
     }
-    pub fn from_tlv(data: &[u8]) -> Self {
-        let tlv = decode(data);
-        let mut initiator_random = None;
-        let mut responder_random = None;
-        let mut responder_session_id = None;
-        let mut pbkdf_params: Option<PBKDFParams> = None;
-        let mut responder_sed_params: Option<SedParameters> = None;
-
-        let mut element = tlv;
-        let mut in_pbkdf_params = false;
-        let mut in_sed_params = false;
-
-        loop {
-            match element.get_control() {
-                TagControl::Anonymous => {}
-                TagControl::ContextSpecific(1) if in_pbkdf_params => {
-                    let value = if let TagLengthValue::Unsigned32(value) = element.get_value() {
-                        value
-                    } else if let TagLengthValue::Unsigned16(value) = element.get_value() {
-                        value as u32
-                    } else {
-                        panic!();
-                    };
-                    match pbkdf_params.as_mut() {
-                        Some(params) => {
-                            params.iterations = value;
-                        }
-                        None => {
-                            pbkdf_params = Some(PBKDFParams {
-                                iterations: value,
-                                // TODO: avoid this alloc
-                                salt: vec![],
-                            })
-                        }
-                    }
-                }
-                TagControl::ContextSpecific(2) if in_pbkdf_params => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        match pbkdf_params.as_mut() {
-                            Some(params) => {
-                                params.salt = bytes.to_vec();
-                            }
-                            None => {
-                                pbkdf_params = Some(PBKDFParams {
-                                    iterations: 0,
-                                    salt: bytes.to_vec(),
-                                })
-                            }
-                        }
-                    }
-                }
-                TagControl::ContextSpecific(1) if in_sed_params => {
-                    if let TagLengthValue::Unsigned32(value) = element.get_value() {
-                        let timeout = Some(value);
-                        match responder_sed_params.as_mut() {
-                            Some(params) => {
-                                params.sleepy_idle_interval = timeout;
-                            }
-                            None => {
-                                responder_sed_params = Some(SedParameters {
-                                    sleepy_idle_interval: timeout,
-                                    sleepy_active_interval: None,
-                                })
-                            }
-                        }
-                    }
-                }
-                TagControl::ContextSpecific(2) if in_sed_params => {
-                    if let TagLengthValue::Unsigned32(value) = element.get_value() {
-                        let timeout = Some(value);
-                        match responder_sed_params.as_mut() {
-                            Some(params) => {
-                                params.sleepy_active_interval = timeout;
-                            }
-                            None => {
-                                responder_sed_params = Some(SedParameters {
-                                    sleepy_idle_interval: None,
-                                    sleepy_active_interval: timeout,
-                                })
-                            }
-                        }
-                    }
-                }
-                TagControl::ContextSpecific(1) => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        initiator_random = Some(bytes.as_slice().try_into().unwrap());
-                    }
-                }
-                TagControl::ContextSpecific(2) => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        responder_random = Some(bytes.as_slice().try_into().unwrap());
-                    }
-                }
-                TagControl::ContextSpecific(3) => {
-                    if let TagLengthValue::Unsigned16(value) = element.get_value() {
-                        responder_session_id = Some(value);
-                    }
-                }
-                TagControl::ContextSpecific(4) => {
-                    in_pbkdf_params = true;
-                }
-                TagControl::ContextSpecific(5) => {
-                    in_sed_params = true;
-                }
-
-                other => {
-                    panic!("Unexpected tag {other:?}");
-                }
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-
-        Self {
-            initiator_random: initiator_random.unwrap(),
-            responder_random: responder_random.unwrap(),
-            responder_session_id: responder_session_id.unwrap_or_default(),
-            pbkdf_params,
-            responder_sed_params,
-        }
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
     }
 }
 
-#[derive(Debug)]
-pub struct SedParameters {
-    pub sleepy_idle_interval: Option<u32>,
-    pub sleepy_active_interval: Option<u32>,
+/// The MRP parameters that a node sends during session establishment, in
+/// milliseconds (4.12.8). Missing values take their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionParameters {
+    #[serde(rename = "1")]
+    pub session_idle_interval: Option<u32>,
+    #[serde(rename = "2")]
+    pub session_active_interval: Option<u32>,
+    #[serde(rename = "3")]
+    pub session_active_threshold: Option<u16>,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Pake1 {
+    #[serde(rename = "1", with = "serde_bytes")]
     pub p_a: [u8; CRYPTO_PUBLIC_KEY_SIZE_BYTES],
 }
 
 impl Pake1 {
     pub fn to_tlv(&self) -> Encoder {
-        let mut encoder = Encoder::default();
-
-        encoder.write(
-            TlvType::Structure,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-        encoder.write(
-            TlvType::ByteString(ElementSize::Byte1, CRYPTO_PUBLIC_KEY_SIZE_BYTES),
-            TagControl::ContextSpecific(1),
-            TagLengthValue::ByteString(heapless::Vec::from_slice(&self.p_a).unwrap()),
-        );
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-
-        encoder
+        serde_tlv::to_encoder(self).unwrap()
     }
 
-    pub fn from_tlv(data: &[u8]) -> Self {
-        let tlv = decode(data);
-        let mut p_a = None;
-
-        let mut element = tlv;
-
-        loop {
-            match element.get_control() {
-                TagControl::Anonymous => {}
-                TagControl::ContextSpecific(1) => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        p_a = Some(bytes.as_slice().try_into().unwrap());
-                    }
-                }
-                other => {
-                    panic!("Unexpected tag {other:?}");
-                }
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-
-        Self { p_a: p_a.unwrap() }
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Pake2 {
+    #[serde(rename = "1", with = "serde_bytes")]
     pub p_b: [u8; CRYPTO_PUBLIC_KEY_SIZE_BYTES],
+    #[serde(rename = "2", with = "serde_bytes")]
     pub c_b: [u8; CRYPTO_HASH_LEN_BYTES],
 }
 
 impl Pake2 {
     pub fn to_tlv(&self) -> Encoder {
-        let mut encoder = Encoder::default();
-
-        encoder.write(
-            TlvType::Structure,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-        encoder.write(
-            TlvType::ByteString(ElementSize::Byte1, CRYPTO_PUBLIC_KEY_SIZE_BYTES),
-            TagControl::ContextSpecific(1),
-            TagLengthValue::ByteString(heapless::Vec::from_slice(&self.p_b).unwrap()),
-        );
-        encoder.write(
-            TlvType::ByteString(ElementSize::Byte1, CRYPTO_HASH_LEN_BYTES),
-            TagControl::ContextSpecific(2),
-            TagLengthValue::ByteString(heapless::Vec::from_slice(&self.c_b).unwrap()),
-        );
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-
-        encoder
+        serde_tlv::to_encoder(self).unwrap()
     }
 
-    pub fn from_tlv(data: &[u8]) -> Self {
-        let tlv = decode(data);
-        let mut p_b = None;
-        let mut c_b = None;
-
-        let mut element = tlv;
-
-        loop {
-            match element.get_control() {
-                TagControl::Anonymous => {}
-                TagControl::ContextSpecific(1) => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        p_b = Some(bytes.as_slice().try_into().unwrap());
-                    }
-                }
-                TagControl::ContextSpecific(2) => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        c_b = Some(bytes.as_slice().try_into().unwrap());
-                    }
-                }
-                other => {
-                    panic!("Unexpected tag {other:?}");
-                }
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-
-        Self {
-            p_b: p_b.unwrap(),
-            c_b: c_b.unwrap(),
-        }
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Pake3 {
+    #[serde(rename = "1", with = "serde_bytes")]
     pub c_a: [u8; CRYPTO_HASH_LEN_BYTES],
 }
 
 impl Pake3 {
     pub fn to_tlv(&self) -> Encoder {
-        let mut encoder = Encoder::default();
-
-        encoder.write(
-            TlvType::Structure,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-        encoder.write(
-            TlvType::ByteString(ElementSize::Byte1, CRYPTO_HASH_LEN_BYTES),
-            TagControl::ContextSpecific(1),
-            TagLengthValue::ByteString(heapless::Vec::from_slice(&self.c_a).unwrap()),
-        );
-        encoder.write(
-            TlvType::EndOfContainer,
-            TagControl::Anonymous,
-            TagLengthValue::Container,
-        );
-
-        encoder
+        serde_tlv::to_encoder(self).unwrap()
     }
 
-    pub fn from_tlv(data: &[u8]) -> Self {
-        let tlv = decode(data);
-        let mut c_a = None;
-
-        let mut element = tlv;
-
-        loop {
-            match element.get_control() {
-                TagControl::Anonymous => {}
-                TagControl::ContextSpecific(1) => {
-                    if let TagLengthValue::ByteString(bytes) = element.get_value() {
-                        c_a = Some(bytes.as_slice().try_into().unwrap());
-                    }
-                }
-                other => {
-                    panic!("Unexpected tag {other:?}");
-                }
-            }
-
-            if element.is_last() {
-                break;
-            }
-            element = element.next_in_container();
-        }
-
-        Self { c_a: c_a.unwrap() }
+    pub fn from_tlv(data: &[u8]) -> serde_tlv::Result<Self> {
+        serde_tlv::from_slice(data)
     }
 }
 
@@ -905,13 +542,66 @@ mod tests {
     use super::*;
 
     #[test]
-    #[ignore = "fails, using it to investigate TLV differences in implementation"]
+    fn decode_tlv_pbkdf_param_request() {
+        let data = hex_literal::hex!("153001204715a406c6b0496ad52039e347db8528cb69a1cb2fce6f2318552ae65e103aca250233dc240300280435052501881325022c011818");
+        let request = PBKDFParamRequest::from_tlv(&data).unwrap();
+        assert_eq!(request.initiator_random[..2], [0x47, 0x15]);
+        assert_eq!(request.initiator_session_id, 56371);
+        assert_eq!(request.passcode_id, 0);
+        assert!(!request.has_pbkdf_params);
+        let session_params = request.initiator_session_params.unwrap();
+        assert_eq!(session_params.session_idle_interval, Some(5000));
+        assert_eq!(session_params.session_active_interval, Some(300));
+        assert_eq!(session_params.session_active_threshold, None);
+    }
+
+    #[test]
+    fn decode_tlv_truncated_pbkdf_param_request() {
+        // The initiator random is cut short
+        let data = hex_literal::hex!("153001204715a406");
+        assert!(matches!(
+            PBKDFParamRequest::from_tlv(&data),
+            Err(serde_tlv::Error::Tlv(crate::tlv::TlvError::Truncated))
+        ));
+    }
+
+    #[test]
     fn decode_tlv_pbkdf_param_response() {
         let data = hex_literal::hex!("15300120c3bf6a81dda5b85c626a582fdaf855cb7085ee308c8976954544afe814cca1a3300220cbcf9f1deebd2e12bac9ae12ef8573f6dfa8a80ef27a0de5529661652ddf315b24030135042501d00730022054dbdb1db37e40d5d57c9e1a84ffde9311a98a843cec2e75b526fa4f424def761818");
-        let response = PBKDFParamResponse::from_tlv(&data);
+        let response = PBKDFParamResponse::from_tlv(&data).unwrap();
         assert_eq!(response.pbkdf_params.as_ref().unwrap().iterations, 2000);
         let out = response.to_tlv();
         let out = out.to_slice();
         assert_eq!(hex::encode(data), hex::encode(out));
     }
+
+    #[test]
+    fn test_pbkdf_session_parameters() {
+        let session = |session_role| UnsecuredSessionContext {
+            session_role,
+            local_session_id: 1,
+            peer_session_id: 0,
+            ephemeral_initiator_node_id: 0,
+            message_reception_state: None,
+            peer_active_at: None,
+        };
+        let sleepy = SessionParameters {
+            session_idle_interval: Some(5000),
+            session_active_interval: Some(300),
+            session_active_threshold: Some(4000),
+        };
+        let mut initiator = PASEManager::initiator(123456, 1, 1, 0, 0);
+        initiator.set_session_parameters(sleepy.clone());
+        let request = initiator.pbkdf_param_request(&mut session(SessionRole::Initiator));
+        let request = PBKDFParamRequest::from_tlv(&request.payload).unwrap();
+        assert_eq!(request.initiator_session_params.as_ref(), Some(&sleepy));
+
+        // The responder keeps the initiator's parameters, and sends none of its own
+        let mut responder = PASEManager::responder(123456, 1, 2, 1, 0, 0, 0, None);
+        let response =
+            responder.pbkdf_param_response(&mut session(SessionRole::Responder), &request);
+        assert_eq!(responder.peer_session_parameters(), Some(&sleepy));
+        let response = PBKDFParamResponse::from_tlv(&response.payload).unwrap();
+        assert_eq!(response.responder_session_params, None);
+    }
 }
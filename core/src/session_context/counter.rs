@@ -0,0 +1,184 @@
+//! Message Counters (4.5.1)
+//!
+//! Unsecured and group messages use global counters, which are persisted so
+//! that peers don't drop messages after a reboot as duplicates. Each secure
+//! unicast session has its own counter, which ends the session when it runs
+//! out, as the encryption nonce would otherwise repeat.
+
+use core::fmt;
+use std::collections::HashMap;
+
+use crate::crypto::fill_random;
+
+/// How many counter values are reserved each time a global counter is
+/// persisted. After a reboot the counter continues from the end of the
+/// reservation, skipping values that may have been used.
+pub const COUNTER_PERSISTENCE_LOOKAHEAD: u32 = 1000;
+
+/// The counters that are shared by all sessions of a type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GlobalCounter {
+    /// Global Unencrypted Message Counter
+    Unencrypted,
+    /// Global Group Encrypted Message Counter
+    GroupEncrypted,
+}
+
+/// Where global counters are persisted
+pub trait CounterStore: fmt::Debug {
+    fn load(&self, counter: GlobalCounter) -> Option<u32>;
+    fn store(&mut self, counter: GlobalCounter, value: u32);
+}
+
+/// Keeps counters in memory, for nodes without storage and for tests
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCounterStore {
+    values: HashMap<GlobalCounter, u32>,
+}
+
+impl MemoryCounterStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CounterStore for MemoryCounterStore {
+    fn load(&self, counter: GlobalCounter) -> Option<u32> {
+        self.values.get(&counter).copied()
+    }
+
+    fn store(&mut self, counter: GlobalCounter, value: u32) {
+        self.values.insert(counter, value);
+    }
+}
+
+/// A new counter starts at a random value in `[1, 2^28]` (4.5.1.1)
+pub fn random_counter_start() -> u32 {
+    let mut start = [0; 4];
+    fill_random(&mut start);
+    (u32::from_le_bytes(start) & 0x0fff_ffff) + 1
+}
+
+/// A global counter, which rolls over when it runs out
+#[derive(Debug)]
+pub struct PersistentCounter {
+    counter: GlobalCounter,
+    next: u32,
+    /// The first value that isn't reserved in the store
+    reserved_until: u32,
+}
+
+impl PersistentCounter {
+    /// Continue a counter from the end of its last reservation, or start it
+    /// if it was never stored
+    pub fn load(counter: GlobalCounter, store: &mut dyn CounterStore) -> Self {
+        let next = store.load(counter).unwrap_or_else(random_counter_start);
+        let mut persistent = Self {
+            counter,
+            next,
+            reserved_until: next,
+        };
+        persistent.reserve(store);
+        persistent
+    }
+
+    pub fn next(&mut self, store: &mut dyn CounterStore) -> u32 {
+        if self.next == self.reserved_until {
+            self.reserve(store);
+        }
+        let value = self.next;
+        self.next = self.next.wrapping_add(1);
+        value
+    }
+
+    fn reserve(&mut self, store: &mut dyn CounterStore) {
+        self.reserved_until = self.next.wrapping_add(COUNTER_PERSISTENCE_LOOKAHEAD);
+        store.store(self.counter, self.reserved_until);
+    }
+}
+
+/// The Secure Session Message Counter of a unicast session, which never
+/// rolls over (4.5.1.2)
+#[derive(Debug)]
+pub struct SessionCounter {
+    /// `None` once every value was used
+    next: Option<u32>,
+}
+
+impl SessionCounter {
+    pub fn new() -> Self {
+        Self::starting_at(random_counter_start())
+    }
+
+    pub fn starting_at(start: u32) -> Self {
+        Self { next: Some(start) }
+    }
+
+    /// The next counter, or `None` if the counter is exhausted and the
+    /// session has to expire
+    pub fn next(&mut self) -> Option<u32> {
+        let value = self.next?;
+        self.next = value.checked_add(1);
+        Some(value)
+    }
+}
+
+impl Default for SessionCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persistent_counter() {
+        let mut store = MemoryCounterStore::new();
+        let mut counter = PersistentCounter::load(GlobalCounter::GroupEncrypted, &mut store);
+        let start = counter.next(&mut store);
+        assert!((1..=1 << 28).contains(&start));
+        assert_eq!(
+            store.load(GlobalCounter::GroupEncrypted),
+            Some(start + COUNTER_PERSISTENCE_LOOKAHEAD)
+        );
+
+        // The next reservation is made when the current one is used up
+        for _ in 1..COUNTER_PERSISTENCE_LOOKAHEAD {
+            counter.next(&mut store);
+        }
+        assert_eq!(
+            store.load(GlobalCounter::GroupEncrypted),
+            Some(start + COUNTER_PERSISTENCE_LOOKAHEAD)
+        );
+        assert_eq!(
+            counter.next(&mut store),
+            start + COUNTER_PERSISTENCE_LOOKAHEAD
+        );
+        assert_eq!(
+            store.load(GlobalCounter::GroupEncrypted),
+            Some(start + 2 * COUNTER_PERSISTENCE_LOOKAHEAD)
+        );
+
+        // After a reboot, the counter skips what was reserved
+        counter.next(&mut store);
+        let mut rebooted = PersistentCounter::load(GlobalCounter::GroupEncrypted, &mut store);
+        assert_eq!(
+            rebooted.next(&mut store),
+            start + 2 * COUNTER_PERSISTENCE_LOOKAHEAD
+        );
+        // Counters are stored separately
+        assert_eq!(store.load(GlobalCounter::Unencrypted), None);
+    }
+
+    #[test]
+    fn test_session_counter_exhaustion() {
+        let mut counter = SessionCounter::starting_at(u32::MAX - 1);
+        assert_eq!(counter.next(), Some(u32::MAX - 1));
+        assert_eq!(counter.next(), Some(u32::MAX));
+        // Rolling over would reuse a nonce
+        assert_eq!(counter.next(), None);
+        assert_eq!(counter.next(), None);
+    }
+}
@@ -0,0 +1,393 @@
+//! Group Session Context (4.15.3) and the group keys it is secured with (4.16)
+
+use std::collections::HashMap;
+
+use crate::{
+    constants::{CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES, GROUP_KEY_HASH_INFO, GROUP_KEY_INFO},
+    crypto::hkdf_sha256,
+    exchange::MessageCounter,
+    message::{Message, SecurityFlags, SessionID},
+};
+
+/// How many senders of group messages the reception state is kept for
+pub const GROUP_PEER_LIMIT: usize = 32;
+
+/// An operational group key, derived from one epoch key of a group key set
+#[derive(Debug, Clone)]
+pub struct GroupKey {
+    pub fabric_index: usize,
+    pub key_set_id: u16,
+    /// When the epoch key starts being used, in microseconds since the epoch
+    pub epoch_start_time: u64,
+    pub encryption_key: [u8; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES],
+    /// The group session ID, which is a hash of the encryption key
+    pub session_id: SessionID,
+}
+
+impl GroupKey {
+    /// Derive the operational group key and group session ID (4.16.2)
+    pub fn new(
+        fabric_index: usize,
+        key_set_id: u16,
+        epoch_start_time: u64,
+        epoch_key: &[u8],
+        compressed_fabric_id: &[u8; 8],
+    ) -> Self {
+        let mut encryption_key = [0; CRYPTO_SYMMETRIC_KEY_LENGTH_BYTES];
+        hkdf_sha256(
+            compressed_fabric_id,
+            epoch_key,
+            &GROUP_KEY_INFO,
+            &mut encryption_key,
+        );
+        let mut session_id = [0; 2];
+        hkdf_sha256(&[], &encryption_key, &GROUP_KEY_HASH_INFO, &mut session_id);
+
+        Self {
+            fabric_index,
+            key_set_id,
+            epoch_start_time,
+            encryption_key,
+            session_id: u16::from_be_bytes(session_id),
+        }
+    }
+}
+
+/// The operational group keys of all fabrics, and the groups that use them
+#[derive(Debug, Default)]
+pub struct GroupKeyStore {
+    keys: Vec<GroupKey>,
+    /// Group Key Map, from a fabric's group to its key set ID
+    groups: HashMap<(usize, u16), u16>,
+    /// The node ID of this node in each fabric, which its group messages
+    /// are sent from
+    node_ids: HashMap<usize, u64>,
+}
+
+impl GroupKeyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_key(&mut self, key: GroupKey) {
+        self.keys.push(key);
+    }
+
+    /// Remove all epoch keys of a key set
+    pub fn remove_key_set(&mut self, fabric_index: usize, key_set_id: u16) {
+        self.keys
+            .retain(|key| (key.fabric_index, key.key_set_id) != (fabric_index, key_set_id));
+    }
+
+    /// Use a key set for the messages sent to a group
+    pub fn map_group(&mut self, fabric_index: usize, group_id: u16, key_set_id: u16) {
+        self.groups.insert((fabric_index, group_id), key_set_id);
+    }
+
+    pub fn set_node_id(&mut self, fabric_index: usize, node_id: u64) {
+        self.node_ids.insert(fabric_index, node_id);
+    }
+
+    /// The node ID of this node in a fabric
+    pub fn node_id(&self, fabric_index: usize) -> Option<u64> {
+        self.node_ids.get(&fabric_index).copied()
+    }
+
+    /// The keys that a message of a group session could be encrypted with.
+    ///
+    /// Group session IDs are only 16 bits, so keys of unrelated groups or
+    /// fabrics can share them, and each candidate has to be tried.
+    pub fn candidates(&self, session_id: SessionID) -> impl Iterator<Item = &GroupKey> {
+        self.keys
+            .iter()
+            .filter(move |key| key.session_id == session_id)
+    }
+
+    /// The key to encrypt messages to a group with, which is the epoch key
+    /// of its key set that started last, at or before `now` (4.16.3).
+    pub fn current_key(&self, fabric_index: usize, group_id: u16, now: u64) -> Option<&GroupKey> {
+        let key_set_id = *self.groups.get(&(fabric_index, group_id))?;
+        self.keys
+            .iter()
+            .filter(|key| {
+                key.fabric_index == fabric_index
+                    && key.key_set_id == key_set_id
+                    && key.epoch_start_time <= now
+            })
+            .max_by_key(|key| key.epoch_start_time)
+    }
+
+    /// The key that this node sends messages to a group with, from
+    /// `source_node_id`. The fabric is the one that this node has the
+    /// node ID in.
+    pub fn sending_key(&self, source_node_id: u64, group_id: u16, now: u64) -> Option<&GroupKey> {
+        self.node_ids
+            .iter()
+            .filter(|(_, &node_id)| node_id == source_node_id)
+            .find_map(|(&fabric_index, _)| self.current_key(fabric_index, group_id, now))
+    }
+}
+
+/// A node that sends group messages, and the key set that it sends them with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GroupPeer {
+    pub fabric_index: usize,
+    pub key_set_id: u16,
+    pub source_node_id: u64,
+}
+
+/// Whether a group message is new, going by what its sender sent before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupCounterCheck {
+    Accept,
+    Duplicate,
+    /// The counter of the sender of a control message isn't known, and has
+    /// to be synchronized before the message can be accepted (4.5.6.2)
+    Unsynchronized,
+}
+
+/// The reception states of a peer's data and control messages, which use
+/// separate counters
+#[derive(Debug, Default)]
+struct GroupPeerCounters {
+    data: Option<MessageCounter>,
+    control: Option<MessageCounter>,
+    /// When the peer last sent a message, in checks of the table
+    used_at: u64,
+}
+
+/// Group Peer Message Counters (4.5.6), which reject replayed group
+/// messages.
+///
+/// Data messages from unknown peers are trusted, and their counter is
+/// tracked from then on (trust-first). The table is bounded, and the peer
+/// that sent a message the longest ago is forgotten to make room.
+#[derive(Debug)]
+pub struct GroupPeerTable {
+    peers: HashMap<GroupPeer, GroupPeerCounters>,
+    limit: usize,
+    checks: u64,
+}
+
+impl GroupPeerTable {
+    pub fn new() -> Self {
+        Self::with_limit(GROUP_PEER_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            peers: HashMap::with_capacity(limit),
+            limit,
+            checks: 0,
+        }
+    }
+
+    /// Check the counter of a decrypted group message from `peer`, and
+    /// record it if the message is accepted
+    pub fn check(&mut self, peer: GroupPeer, message: &Message) -> GroupCounterCheck {
+        let header = &message.message_header;
+        let control = header.security_flags.contains(SecurityFlags::CONTROL);
+        if control && !self.peers.contains_key(&peer) {
+            return GroupCounterCheck::Unsynchronized;
+        }
+        if !self.peers.contains_key(&peer) && self.peers.len() >= self.limit {
+            self.evict();
+        }
+        self.checks += 1;
+        let counters = self.peers.entry(peer).or_default();
+        counters.used_at = self.checks;
+        let state = if control {
+            &mut counters.control
+        } else {
+            &mut counters.data
+        };
+        match state {
+            Some(state) => {
+                if state.process_message_counter(header.message_counter, &header.session_type) {
+                    GroupCounterCheck::Duplicate
+                } else {
+                    GroupCounterCheck::Accept
+                }
+            }
+            None if control => GroupCounterCheck::Unsynchronized,
+            None => {
+                *state = Some(MessageCounter::new(header.message_counter));
+                GroupCounterCheck::Accept
+            }
+        }
+    }
+
+    /// Track a peer's control messages from the counter that it sent with
+    /// MCSP (4.8.1.2)
+    pub fn synchronize(&mut self, peer: GroupPeer, synchronized_counter: u32) {
+        if !self.peers.contains_key(&peer) && self.peers.len() >= self.limit {
+            self.evict();
+        }
+        self.checks += 1;
+        let counters = self.peers.entry(peer).or_default();
+        counters.used_at = self.checks;
+        counters.control = Some(MessageCounter::synchronized(synchronized_counter));
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    fn evict(&mut self) {
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, counters)| counters.used_at)
+            .map(|(peer, _)| *peer);
+        if let Some(peer) = oldest {
+            self.peers.remove(&peer);
+        }
+    }
+}
+
+impl Default for GroupPeerTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_key_derivation() {
+        let epoch_key = hex_literal::hex!("235bf7e62823d358dca4ba50b1535f4b");
+        let compressed_fabric_id = hex_literal::hex!("87e1b004e235a130");
+        let key = GroupKey::new(1, 0x01a1, 0, &epoch_key, &compressed_fabric_id);
+        assert_eq!(
+            key.encryption_key,
+            hex_literal::hex!("a6f5306baf6d050af23ba4bd6b9dd960")
+        );
+        assert_eq!(key.session_id, 0xb9f7);
+    }
+
+    #[test]
+    fn test_current_key() {
+        let compressed_fabric_id = [0; 8];
+        let mut store = GroupKeyStore::new();
+        for (start, epoch_key) in [(10, [1; 16]), (20, [2; 16]), (30, [3; 16])] {
+            store.add_key(GroupKey::new(
+                1,
+                7,
+                start,
+                &epoch_key,
+                &compressed_fabric_id,
+            ));
+        }
+        store.map_group(1, 0x0101, 7);
+
+        let key = |now| {
+            store
+                .current_key(1, 0x0101, now)
+                .map(|k| k.epoch_start_time)
+        };
+        assert_eq!(key(5), None);
+        assert_eq!(key(25), Some(20));
+        assert_eq!(key(100), Some(30));
+        assert!(store.current_key(2, 0x0101, 100).is_none());
+
+        let session_id = store.current_key(1, 0x0101, 100).unwrap().session_id;
+        assert_eq!(store.candidates(session_id).count(), 1);
+        store.remove_key_set(1, 7);
+        assert_eq!(store.candidates(session_id).count(), 0);
+    }
+
+    #[test]
+    fn test_group_peer_table() {
+        use crate::message::MessageHeader;
+
+        let message = |source_node_id, message_counter, control| {
+            let mut message_header = MessageHeader::group(0x1234, source_node_id, 0x0101);
+            message_header.message_counter = message_counter;
+            if control {
+                message_header.security_flags |= SecurityFlags::CONTROL;
+            }
+            Message::new(message_header, None, Default::default())
+        };
+        let peer = |source_node_id| GroupPeer {
+            fabric_index: 1,
+            key_set_id: 7,
+            source_node_id,
+        };
+        let mut table = GroupPeerTable::with_limit(2);
+
+        // The first data message of a peer is trusted
+        assert_eq!(
+            table.check(peer(1), &message(1, 100, false)),
+            GroupCounterCheck::Accept
+        );
+        assert_eq!(
+            table.check(peer(1), &message(1, 100, false)),
+            GroupCounterCheck::Duplicate
+        );
+        assert_eq!(
+            table.check(peer(1), &message(1, 105, false)),
+            GroupCounterCheck::Accept
+        );
+        // Out of order messages in the window are accepted once
+        assert_eq!(
+            table.check(peer(1), &message(1, 103, false)),
+            GroupCounterCheck::Accept
+        );
+        assert_eq!(
+            table.check(peer(1), &message(1, 103, false)),
+            GroupCounterCheck::Duplicate
+        );
+        // A lower counter doesn't restart the window
+        assert_eq!(
+            table.check(peer(1), &message(1, 50, false)),
+            GroupCounterCheck::Duplicate
+        );
+
+        // Group counters roll over
+        assert_eq!(
+            table.check(peer(2), &message(2, u32::MAX, false)),
+            GroupCounterCheck::Accept
+        );
+        assert_eq!(
+            table.check(peer(2), &message(2, 1, false)),
+            GroupCounterCheck::Accept
+        );
+        assert_eq!(
+            table.check(peer(2), &message(2, u32::MAX, false)),
+            GroupCounterCheck::Duplicate
+        );
+        assert_eq!(
+            table.check(peer(2), &message(2, 0, false)),
+            GroupCounterCheck::Accept
+        );
+
+        // Control messages aren't trusted first
+        assert_eq!(
+            table.check(peer(1), &message(1, 7, true)),
+            GroupCounterCheck::Unsynchronized
+        );
+        assert_eq!(
+            table.check(peer(3), &message(3, 7, true)),
+            GroupCounterCheck::Unsynchronized
+        );
+        assert_eq!(table.len(), 2);
+
+        // The peer that was heard from the longest ago makes room, and is
+        // trusted again
+        assert_eq!(
+            table.check(peer(3), &message(3, 7, false)),
+            GroupCounterCheck::Accept
+        );
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            table.check(peer(2), &message(2, 0, false)),
+            GroupCounterCheck::Accept
+        );
+        assert_eq!(
+            table.check(peer(3), &message(3, 7, false)),
+            GroupCounterCheck::Duplicate
+        );
+    }
+}
@@ -1 +1,282 @@
+//! Message Counter Synchronization Protocol (4.8)
+//!
+//! A node that receives a group control message from a peer whose counter it
+//! doesn't know keeps the message, and asks the peer for its counter with a
+//! random challenge. The peer answers with its counter and the challenge,
+//! encrypted with the group key, which proves that the answer is fresh.
 
+use core::time::Duration;
+use std::collections::HashMap;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::{
+    crypto::fill_random,
+    message::{Message, MessageError, SessionID},
+    secure_channel::{MSG_COUNTER_SYNC_REQ_JITTER, MSG_COUNTER_SYNC_TIMEOUT},
+    util::time::Instant,
+};
+
+use super::GroupPeer;
+
+pub const MCSP_CHALLENGE_LENGTH: usize = 8;
+/// The most messages that are kept for a peer while its counter is
+/// synchronized. Older messages make room for newer ones.
+pub const MCSP_QUEUE_LIMIT: usize = 4;
+
+/// MsgCounterSyncReq (4.8.1.1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsgCounterSyncRequest {
+    pub challenge: [u8; MCSP_CHALLENGE_LENGTH],
+}
+
+impl MsgCounterSyncRequest {
+    pub fn encode(&self, out: &mut BytesMut) {
+        out.put_slice(&self.challenge);
+    }
+
+    pub fn decode(mut payload: &[u8]) -> Result<Self, MessageError> {
+        if payload.len() < MCSP_CHALLENGE_LENGTH {
+            return Err(MessageError::Truncated);
+        }
+        let mut challenge = [0; MCSP_CHALLENGE_LENGTH];
+        payload.copy_to_slice(&mut challenge);
+        Ok(Self { challenge })
+    }
+}
+
+/// MsgCounterSyncRsp (4.8.1.2)
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsgCounterSyncResponse {
+    /// The group counter of the responder, which is the counter of this
+    /// message
+    pub synchronized_counter: u32,
+    /// The challenge of the request
+    pub response: [u8; MCSP_CHALLENGE_LENGTH],
+}
+
+impl MsgCounterSyncResponse {
+    pub fn encode(&self, out: &mut BytesMut) {
+        out.put_u32_le(self.synchronized_counter);
+        out.put_slice(&self.response);
+    }
+
+    pub fn decode(mut payload: &[u8]) -> Result<Self, MessageError> {
+        if payload.len() < 4 + MCSP_CHALLENGE_LENGTH {
+            return Err(MessageError::Truncated);
+        }
+        let synchronized_counter = payload.get_u32_le();
+        let mut response = [0; MCSP_CHALLENGE_LENGTH];
+        payload.copy_to_slice(&mut response);
+        Ok(Self {
+            synchronized_counter,
+            response,
+        })
+    }
+}
+
+/// A request to send to a peer, whose jitter has passed
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub peer: GroupPeer,
+    /// The group session of the key that the peer's message used
+    pub session_id: SessionID,
+    pub request: MsgCounterSyncRequest,
+}
+
+/// The synchronization with a peer that has messages waiting for it
+#[derive(Debug)]
+struct PendingSync {
+    challenge: [u8; MCSP_CHALLENGE_LENGTH],
+    session_id: SessionID,
+    /// The peer's messages, which are processed once its counter is known
+    queued: Vec<Message>,
+    /// When the request is sent. The jitter keeps the members of a group
+    /// from all asking at once.
+    send_at: Instant,
+    /// When the peer has to have answered, once the request is sent
+    timeout: Option<Instant>,
+}
+
+/// The requests this node made to synchronize the counters of group peers
+#[derive(Debug, Default)]
+pub struct MessageCounterSync {
+    pending: HashMap<GroupPeer, PendingSync>,
+}
+
+impl MessageCounterSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep a message until its sender's counter is synchronized, asking for
+    /// the counter if that hasn't been done yet
+    pub fn queue(&mut self, peer: GroupPeer, message: Message, now: Instant) {
+        let pending = self.pending.entry(peer).or_insert_with(|| {
+            let mut challenge = [0; MCSP_CHALLENGE_LENGTH];
+            fill_random(&mut challenge);
+            PendingSync {
+                challenge,
+                session_id: message.message_header.session_id,
+                queued: Vec::with_capacity(MCSP_QUEUE_LIMIT),
+                send_at: now + random_jitter(),
+                timeout: None,
+            }
+        });
+        if pending.queued.len() == MCSP_QUEUE_LIMIT {
+            pending.queued.remove(0);
+        }
+        pending.queued.push(message);
+    }
+
+    /// Whether a peer's counter is being synchronized
+    pub fn is_pending(&self, peer: &GroupPeer) -> bool {
+        self.pending.contains_key(peer)
+    }
+
+    /// The requests that have to be sent now
+    pub fn due_requests(&mut self, now: Instant) -> Vec<PendingRequest> {
+        let mut requests = vec![];
+        for (peer, pending) in self.pending.iter_mut() {
+            if pending.timeout.is_none() && pending.send_at <= now {
+                pending.timeout = Some(now + timeout());
+                requests.push(PendingRequest {
+                    peer: *peer,
+                    session_id: pending.session_id,
+                    request: MsgCounterSyncRequest {
+                        challenge: pending.challenge,
+                    },
+                });
+            }
+        }
+        requests
+    }
+
+    /// Give up on the peers that didn't answer in time, dropping their
+    /// messages
+    pub fn expire(&mut self, now: Instant) -> Vec<GroupPeer> {
+        let expired: Vec<GroupPeer> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.timeout.is_some_and(|timeout| timeout <= now))
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &expired {
+            self.pending.remove(peer);
+        }
+        expired
+    }
+
+    /// Complete the synchronization with a peer if the response answers its
+    /// challenge, returning the messages that waited for it
+    pub fn on_response(
+        &mut self,
+        peer: &GroupPeer,
+        response: &MsgCounterSyncResponse,
+    ) -> Option<Vec<Message>> {
+        let pending = self.pending.get(peer)?;
+        // A response before the request was sent can't be an answer to it
+        if pending.timeout.is_none() || pending.challenge != response.response {
+            return None;
+        }
+        self.pending.remove(peer).map(|pending| pending.queued)
+    }
+
+    /// When [MessageCounterSync::due_requests] or
+    /// [MessageCounterSync::expire] have something to do next
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| pending.timeout.unwrap_or(pending.send_at))
+            .min()
+    }
+}
+
+fn timeout() -> Duration {
+    Duration::from_millis(MSG_COUNTER_SYNC_TIMEOUT as u64)
+}
+
+/// A random delay of up to [MSG_COUNTER_SYNC_REQ_JITTER]
+fn random_jitter() -> Duration {
+    let mut jitter = [0; 2];
+    fill_random(&mut jitter);
+    let jitter = u16::from_le_bytes(jitter) as u64 % (MSG_COUNTER_SYNC_REQ_JITTER as u64 + 1);
+    Duration::from_millis(jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::MessageHeader;
+
+    use super::*;
+
+    #[test]
+    fn test_message_counter_sync() {
+        let peer = GroupPeer {
+            fabric_index: 1,
+            key_set_id: 7,
+            source_node_id: 0x0102,
+        };
+        let message = |message_counter| {
+            let mut message_header = MessageHeader::group(0x1234, 0x0102, 0x0101);
+            message_header.message_counter = message_counter;
+            Message::new(message_header, None, BytesMut::new())
+        };
+        let mut sync = MessageCounterSync::new();
+        let start = Instant::default();
+        for message_counter in 0..=MCSP_QUEUE_LIMIT as u32 {
+            sync.queue(peer, message(message_counter), start);
+        }
+        assert!(sync.is_pending(&peer));
+
+        // The request waits for its jitter
+        let send_at = sync.next_timeout().unwrap();
+        assert!(send_at <= start + Duration::from_millis(MSG_COUNTER_SYNC_REQ_JITTER as u64));
+        let [request] = &sync.due_requests(send_at)[..] else {
+            panic!("expected one request");
+        };
+        assert_eq!(request.session_id, 0x1234);
+        assert!(sync.due_requests(send_at).is_empty());
+        let mut payload = BytesMut::new();
+        request.request.encode(&mut payload);
+        assert_eq!(
+            MsgCounterSyncRequest::decode(&payload),
+            Ok(request.request.clone())
+        );
+
+        // Only the answer to the challenge completes the synchronization
+        let mut response = MsgCounterSyncResponse {
+            synchronized_counter: 100,
+            response: [0; MCSP_CHALLENGE_LENGTH],
+        };
+        assert!(sync.on_response(&peer, &response).is_none());
+        response.response = request.request.challenge;
+        let mut payload = BytesMut::new();
+        response.encode(&mut payload);
+        assert_eq!(
+            MsgCounterSyncResponse::decode(&payload),
+            Ok(response.clone())
+        );
+        assert_eq!(
+            MsgCounterSyncResponse::decode(&payload[..8]),
+            Err(MessageError::Truncated)
+        );
+        let queued = sync.on_response(&peer, &response).unwrap();
+        // The oldest message made room
+        let counters: Vec<u32> = queued
+            .iter()
+            .map(|message| message.message_header.message_counter)
+            .collect();
+        assert_eq!(counters, (1..=MCSP_QUEUE_LIMIT as u32).collect::<Vec<_>>());
+        assert!(!sync.is_pending(&peer));
+
+        // Peers that don't answer are given up on
+        sync.queue(peer, message(10), start);
+        let send_at = sync.next_timeout().unwrap();
+        sync.due_requests(send_at);
+        assert_eq!(sync.next_timeout(), Some(send_at + timeout()));
+        assert!(sync.expire(send_at).is_empty());
+        assert_eq!(sync.expire(send_at + timeout()), vec![peer]);
+        assert!(sync.next_timeout().is_none());
+    }
+}
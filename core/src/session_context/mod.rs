@@ -1,47 +1,170 @@
+use core::fmt;
 use std::collections::HashMap;
 
+/// Message Counters (4.5.1)
+pub mod counter;
+/// Group Session Context (4.15.3)
+pub mod group;
+/// Message Counter Synchronization Protocol (4.8)
 pub mod message_counter_sync;
+/// CASE Session Resumption (4.14.2.2)
+pub mod resumption;
 pub mod secure;
 /// Unsecured Session Context (4.12.1.1)
 pub mod unsecured;
 
+pub use counter::*;
+pub use group::*;
+pub use message_counter_sync::*;
+pub use resumption::*;
 pub use secure::*;
 pub use unsecured::*;
 
-use crate::{crypto::fill_random, message::SessionID};
+use crate::{
+    crypto::fill_random,
+    exchange::{ExchangeKey, MessageCounter},
+    message::{Message, MessageHeader, SessionID, SessionType},
+    util::time::{current_timestamp, Instant},
+};
+
+/// How many sessions are kept by default, before the least recently active
+/// one is evicted to make room
+pub const SESSION_LIMIT: usize = 16;
+
+/// Why a session can't be added
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionError {
+    /// A secure session with the local session ID already exists
+    InUse(SessionID),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::InUse(session_id) => write!(f, "session {session_id} is in use"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct SessionManager {
     // TODO: can I make this take only secure sessions?
-    //       what about MCSP? Insecure is prob fine as I shouldn't have > 1
+    //       Insecure is prob fine as I shouldn't have > 1
     sessions: HashMap<SessionID, SessionContext>,
-    pub last_session_id: SessionID,
-    resumption_records: HashMap<u64, ()>, // u64 -> node ID
+    /// When each session was last active, in activity of the manager
+    active_at: HashMap<SessionID, u64>,
+    activity: u64,
+    limit: usize,
+    /// Sessions that were evicted to make room, whose exchanges haven't been
+    /// closed yet
+    evicted: Vec<SessionID>,
+    /// The exchanges that reserved session IDs with
+    /// [SessionManager::new_session]. Reservations don't count towards the
+    /// limit, and are released when their exchange closes.
+    reservations: HashMap<SessionID, ExchangeKey>,
+    /// The unsecured sessions, one for each ephemeral initiator node ID,
+    /// from the least to the most recently used (4.12.1.1). They are kept
+    /// apart from the sessions with IDs, as they all have ID 0.
+    unsecured_sessions: Vec<UnsecuredSessionContext>,
+    /// The CASE sessions that peers can resume
+    pub resumption_records: ResumptionTable,
     random: [u8; 8],
+    /// Group sessions aren't established, they are identified by their keys
+    pub group_keys: GroupKeyStore,
+    /// The counters of the nodes that sent us group messages
+    pub group_peers: GroupPeerTable,
+    /// The group peers whose counters are being synchronized
+    pub counter_sync: MessageCounterSync,
+    counter_store: Box<dyn CounterStore + Send + Sync>,
+    unencrypted_counter: PersistentCounter,
+    group_counter: PersistentCounter,
 }
 
 impl SessionManager {
     pub fn new() -> Self {
+        Self::with_limit(SESSION_LIMIT)
+    }
+
+    pub fn with_limit(limit: usize) -> Self {
+        let mut counter_store = MemoryCounterStore::new();
         Self {
-            // these capacity values are arbitrary, not enforced
-            sessions: HashMap::with_capacity(10),
-            last_session_id: 0,
-            resumption_records: HashMap::with_capacity(4),
+            sessions: HashMap::with_capacity(limit),
+            active_at: HashMap::with_capacity(limit),
+            activity: 0,
+            limit,
+            evicted: vec![],
+            reservations: HashMap::new(),
+            unsecured_sessions: Vec::with_capacity(limit),
+            resumption_records: ResumptionTable::new(),
             random: rand::random(),
+            group_keys: GroupKeyStore::new(),
+            group_peers: GroupPeerTable::new(),
+            counter_sync: MessageCounterSync::new(),
+            unencrypted_counter: PersistentCounter::load(
+                GlobalCounter::Unencrypted,
+                &mut counter_store,
+            ),
+            group_counter: PersistentCounter::load(
+                GlobalCounter::GroupEncrypted,
+                &mut counter_store,
+            ),
+            counter_store: Box::new(counter_store),
+        }
+    }
+
+    /// Persist the global counters in `store`, continuing them from the
+    /// values it has
+    pub fn set_counter_store(&mut self, mut store: Box<dyn CounterStore + Send + Sync>) {
+        self.unencrypted_counter = PersistentCounter::load(GlobalCounter::Unencrypted, &mut *store);
+        self.group_counter = PersistentCounter::load(GlobalCounter::GroupEncrypted, &mut *store);
+        self.counter_store = store;
+    }
+
+    /// The counter of the next message that is sent in a session (4.5.1).
+    ///
+    /// Returns `None` if the counter of a secure session is exhausted, or
+    /// there's no such session. The session can't send any more messages.
+    pub fn next_message_counter(
+        &mut self,
+        session_id: SessionID,
+        session_type: &SessionType,
+    ) -> Option<u32> {
+        match session_type {
+            SessionType::UnsecuredSession => {
+                Some(self.unencrypted_counter.next(&mut *self.counter_store))
+            }
+            SessionType::SecureGroup(_) => Some(self.group_counter.next(&mut *self.counter_store)),
+            SessionType::SecureUnicast(_) => match self.sessions.get_mut(&session_id) {
+                Some(SessionContext::Secure(session)) => session.local_message_counter.next(),
+                _ => None,
+            },
         }
     }
 
-    /// Convenience to add a session that's manually created outside
-    pub fn add_session(&mut self, session_context: SessionContext, assign_id: bool) -> SessionID {
+    /// Change how many sessions are kept. Sessions over the limit are
+    /// evicted when the next one is added.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// Convenience to add a session that's manually created outside.
+    ///
+    /// A secure session replaces an unsecured session with its ID, which
+    /// reserved the ID while the session was established. When the manager
+    /// is full, the least recently active secure session is evicted
+    /// (4.13.2.1).
+    pub fn add_session(
+        &mut self,
+        session_context: SessionContext,
+        assign_id: bool,
+    ) -> Result<SessionID, SessionError> {
         let mut session_context = session_context;
         let session_id = match &mut session_context {
-            SessionContext::MCSP => todo!(),
             SessionContext::Secure(secure) => {
                 if !assign_id {
                     secure.local_session_id
                 } else {
                     let id = self.next_session_id();
-                    self.last_session_id = id;
                     secure.local_session_id = id;
                     id
                 }
@@ -53,23 +176,222 @@ impl SessionManager {
                 unsecured.local_session_id
             }
         };
-        if self.sessions.contains_key(&session_id) {
-            let current_session = self.sessions.get(&session_id).unwrap();
-            match current_session {
-                SessionContext::MCSP => todo!(),
-                SessionContext::Secure(_) => {
-                    panic!("Unable to insert session {:?}", session_context);
+        match self.sessions.get(&session_id) {
+            // 0 is the unsecured session
+            _ if session_id == 0 && matches!(session_context, SessionContext::Secure(_)) => {
+                return Err(SessionError::InUse(session_id))
+            }
+            Some(SessionContext::Secure(_)) => return Err(SessionError::InUse(session_id)),
+            Some(SessionContext::Unsecured(_)) | None => {}
+        }
+        if matches!(session_context, SessionContext::Secure(_)) {
+            self.reservations.remove(&session_id);
+            while self.secure_sessions() >= self.limit.max(1) {
+                if self.evict().is_none() {
+                    break;
                 }
-                SessionContext::Unsecured(_) => {}
             }
         }
         self.sessions.insert(session_id, session_context);
+        self.mark_active(session_id);
+        Ok(session_id)
+    }
+
+    /// Create an unsecured session with a new session ID, which reserves the
+    /// ID for the secure session that is established in it by `exchange`.
+    /// There are at most as many reservations as sessions, the oldest one
+    /// is released to make room.
+    pub fn new_session(&mut self, role: SessionRole, exchange: ExchangeKey) -> SessionID {
+        if self.reservations.len() >= self.limit.max(1) {
+            let oldest = self
+                .reservations
+                .keys()
+                .min_by_key(|id| self.active_at.get(id))
+                .copied();
+            if let Some(id) = oldest {
+                println!("Releasing the oldest session reservation {id}");
+                self.release_session(id);
+            }
+        }
+        let session_id = self.next_session_id();
+        let session_context = SessionContext::Unsecured(UnsecuredSessionContext {
+            session_role: role,
+            local_session_id: session_id,
+            peer_session_id: 0,
+            ephemeral_initiator_node_id: 0,
+            message_reception_state: None,
+            peer_active_at: None,
+        });
+        self.sessions.insert(session_id, session_context);
+        self.reservations.insert(session_id, exchange);
+        self.mark_active(session_id);
         session_id
     }
 
-    /// Create a new session and assign it a session ID
-    pub fn new_session(&mut self, role: SessionRole) -> SessionID {
-        panic!()
+    /// Release a session ID that was reserved with
+    /// [SessionManager::new_session], when its session can't be established.
+    /// Secure sessions aren't removed.
+    pub fn release_session(&mut self, id: SessionID) {
+        if let Some(SessionContext::Unsecured(_)) = self.sessions.get(&id) {
+            self.remove_session(id);
+        }
+    }
+
+    /// Release the session IDs that `exchange` reserved, as it closed
+    /// without establishing their sessions
+    pub fn release_reservations(&mut self, exchange: &ExchangeKey) {
+        let released = self
+            .reservations
+            .iter()
+            .filter(|(_, reserved_by)| *reserved_by == exchange)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in released {
+            self.release_session(id);
+        }
+    }
+
+    /// Record that a session sent or received a message, which keeps it from
+    /// being evicted
+    pub fn mark_active(&mut self, id: SessionID) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            self.activity += 1;
+            self.active_at.insert(id, self.activity);
+            if let SessionContext::Secure(session) = session {
+                session.active_timestamp = current_timestamp();
+            }
+        }
+    }
+
+    /// The unsecured session of the node that initiated it with
+    /// `ephemeral_initiator_node_id`, which is created if there's none.
+    /// The least recently used one makes room when there are too many.
+    pub fn unsecured_session_mut(
+        &mut self,
+        ephemeral_initiator_node_id: u64,
+        role: SessionRole,
+    ) -> &mut UnsecuredSessionContext {
+        let position = self
+            .unsecured_sessions
+            .iter()
+            .position(|session| session.ephemeral_initiator_node_id == ephemeral_initiator_node_id);
+        let session = match position {
+            Some(position) => self.unsecured_sessions.remove(position),
+            None => {
+                if self.unsecured_sessions.len() >= self.limit.max(1) {
+                    self.unsecured_sessions.remove(0);
+                }
+                UnsecuredSessionContext {
+                    session_role: role,
+                    local_session_id: 0,
+                    peer_session_id: 0,
+                    ephemeral_initiator_node_id,
+                    message_reception_state: None,
+                    peer_active_at: None,
+                }
+            }
+        };
+        self.unsecured_sessions.push(session);
+        self.unsecured_sessions.last_mut().unwrap()
+    }
+
+    /// The unsecured session of an ephemeral initiator node ID, if it has one
+    pub fn find_unsecured_session(
+        &self,
+        ephemeral_initiator_node_id: u64,
+    ) -> Option<&UnsecuredSessionContext> {
+        self.unsecured_sessions
+            .iter()
+            .find(|session| session.ephemeral_initiator_node_id == ephemeral_initiator_node_id)
+    }
+
+    /// The unsecured session of an ephemeral initiator node ID, if it has
+    /// one, to change
+    pub fn find_unsecured_session_mut(
+        &mut self,
+        ephemeral_initiator_node_id: u64,
+    ) -> Option<&mut UnsecuredSessionContext> {
+        self.unsecured_sessions
+            .iter_mut()
+            .find(|session| session.ephemeral_initiator_node_id == ephemeral_initiator_node_id)
+    }
+
+    /// Whether a received message is a duplicate, by the Message Reception
+    /// State of its session (4.5.5). Unsecured sessions are told apart by
+    /// `initiator_node_id`. Group messages are checked by [GroupPeerTable].
+    ///
+    /// The counter is only recorded once the message is accepted, with
+    /// [SessionManager::accept_message], so that a message that is
+    /// dropped can be retransmitted.
+    pub fn is_duplicate(
+        &mut self,
+        message: &Message,
+        initiator_node_id: Option<u64>,
+        role: SessionRole,
+    ) -> bool {
+        let header = &message.message_header;
+        match self.peer_state(header, initiator_node_id, role) {
+            Some((Some(state), _)) => state
+                .clone()
+                .process_message_counter(header.message_counter, &header.session_type),
+            _ => false,
+        }
+    }
+
+    /// Record a received message that was accepted, at `now`. The Message
+    /// Reception State starts with the first message of the session, and the
+    /// peer is active for a while after each one (4.12.2.1).
+    pub fn accept_message(
+        &mut self,
+        message: &Message,
+        initiator_node_id: Option<u64>,
+        role: SessionRole,
+        now: Instant,
+    ) {
+        let header = &message.message_header;
+        let Some((state, peer_active_at)) = self.peer_state(header, initiator_node_id, role) else {
+            return;
+        };
+        match state {
+            Some(state) => {
+                state.process_message_counter(header.message_counter, &header.session_type);
+            }
+            None => *state = Some(MessageCounter::new(header.message_counter)),
+        }
+        *peer_active_at = Some(now);
+    }
+
+    /// The Message Reception State of the peer that sent a message, and when
+    /// it was last active
+    fn peer_state(
+        &mut self,
+        header: &MessageHeader,
+        initiator_node_id: Option<u64>,
+        role: SessionRole,
+    ) -> Option<(&mut Option<MessageCounter>, &mut Option<Instant>)> {
+        match header.session_type {
+            SessionType::SecureUnicast(session_id) => match self.sessions.get_mut(&session_id) {
+                Some(SessionContext::Secure(session)) => Some((
+                    &mut session.message_reception_state,
+                    &mut session.peer_active_at,
+                )),
+                _ => None,
+            },
+            SessionType::UnsecuredSession => {
+                let session = self.unsecured_session_mut(initiator_node_id?, role);
+                Some((
+                    &mut session.message_reception_state,
+                    &mut session.peer_active_at,
+                ))
+            }
+            SessionType::SecureGroup(_) => None,
+        }
+    }
+
+    /// The sessions that were evicted since this was last called, whose
+    /// exchanges and subscriptions have to be removed
+    pub fn take_evicted(&mut self) -> Vec<SessionID> {
+        core::mem::take(&mut self.evicted)
     }
 
     pub fn get_session(&self, id: SessionID) -> Option<&SessionContext> {
@@ -80,22 +402,49 @@ impl SessionManager {
         self.sessions.get_mut(&id).unwrap()
     }
 
-    pub fn remove_session(&mut self, id: SessionID) {}
+    pub fn remove_session(&mut self, id: SessionID) -> Option<SessionContext> {
+        self.active_at.remove(&id);
+        self.reservations.remove(&id);
+        self.sessions.remove(&id)
+    }
 
+    /// A random session ID that isn't in use. 0 is the unsecured session, so
+    /// it's never picked.
     pub fn next_session_id(&self) -> SessionID {
-        // let mut buf = [0; 2];
-        // fill_random(&mut buf);
-        // let mut session_id = u16::from_le_bytes(buf);
-        // while self.pase_session_ids.contains(&session_id) {
-        //     session_id += 1;
-        // }
-        self.last_session_id + 1
+        loop {
+            let mut buf = [0; 2];
+            fill_random(&mut buf);
+            let session_id = u16::from_le_bytes(buf);
+            if session_id != 0 && !self.sessions.contains_key(&session_id) {
+                return session_id;
+            }
+        }
+    }
+
+    /// How many secure sessions there are, without the reservations
+    fn secure_sessions(&self) -> usize {
+        self.sessions
+            .values()
+            .filter(|session| matches!(session, SessionContext::Secure(_)))
+            .count()
+    }
+
+    /// Evict the least recently active secure session
+    fn evict(&mut self) -> Option<SessionID> {
+        let (&id, _) = self
+            .active_at
+            .iter()
+            .filter(|(id, _)| matches!(self.sessions.get(id), Some(SessionContext::Secure(_))))
+            .min_by_key(|(_, active_at)| **active_at)?;
+        println!("Evicting inactive session {id}");
+        self.remove_session(id);
+        self.evicted.push(id);
+        Some(id)
     }
 }
 
 #[derive(Debug)]
 pub enum SessionContext {
-    MCSP,
     Secure(SecureSessionContext),
     Unsecured(UnsecuredSessionContext),
 }
@@ -103,7 +452,6 @@ pub enum SessionContext {
 impl SessionContext {
     pub fn encryption_key(&self) -> Option<&[u8]> {
         match self {
-            SessionContext::MCSP => None,
             SessionContext::Secure(session) => match session.session_role {
                 // TODO: fix this at source
                 SessionRole::Initiator => Some(&session.encryption_key),
@@ -0,0 +1,161 @@
+//! CASE Session Resumption (4.14.2.2)
+//!
+//! A CASE session leaves a resumption record behind with the peer, which lets
+//! either node establish a new session with the shared secret of the last one
+//! instead of running CASE in full. Records are persisted, so that a sleepy
+//! device can be reconnected to cheaply after it restarts.
+
+use core::fmt;
+use std::collections::HashMap;
+
+use crate::secure_channel::{case::CASE_RESUMPTION_ID_LENGTH, pake::CRYPTO_GROUP_SIZE_BYTES};
+
+/// The state that a session can be resumed from, for a peer in a fabric
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumptionRecord {
+    pub fabric_index: usize,
+    pub peer_node_id: u64,
+    pub resumption_id: [u8; CASE_RESUMPTION_ID_LENGTH],
+    /// The shared secret of the session that was established in full
+    pub shared_secret: [u8; CRYPTO_GROUP_SIZE_BYTES],
+}
+
+/// Where resumption records are persisted
+pub trait ResumptionStore: fmt::Debug {
+    fn load(&self) -> Vec<ResumptionRecord>;
+    fn store(&mut self, record: &ResumptionRecord);
+    fn remove(&mut self, fabric_index: usize, peer_node_id: u64);
+}
+
+/// Keeps records in memory, for nodes without storage and for tests
+#[derive(Debug, Clone, Default)]
+pub struct MemoryResumptionStore {
+    records: HashMap<(usize, u64), ResumptionRecord>,
+}
+
+impl MemoryResumptionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ResumptionStore for MemoryResumptionStore {
+    fn load(&self) -> Vec<ResumptionRecord> {
+        self.records.values().cloned().collect()
+    }
+
+    fn store(&mut self, record: &ResumptionRecord) {
+        self.records
+            .insert((record.fabric_index, record.peer_node_id), record.clone());
+    }
+
+    fn remove(&mut self, fabric_index: usize, peer_node_id: u64) {
+        self.records.remove(&(fabric_index, peer_node_id));
+    }
+}
+
+/// The resumption records of this node, one for each peer in a fabric.
+/// Changes are written through to the store.
+#[derive(Debug)]
+pub struct ResumptionTable {
+    records: HashMap<(usize, u64), ResumptionRecord>,
+    store: Box<dyn ResumptionStore + Send + Sync>,
+}
+
+impl ResumptionTable {
+    pub fn new() -> Self {
+        Self {
+            records: HashMap::new(),
+            store: Box::new(MemoryResumptionStore::new()),
+        }
+    }
+
+    /// Persist the records in `store`, replacing the records with the ones
+    /// it has
+    pub fn set_store(&mut self, store: Box<dyn ResumptionStore + Send + Sync>) {
+        self.records = store
+            .load()
+            .into_iter()
+            .map(|record| ((record.fabric_index, record.peer_node_id), record))
+            .collect();
+        self.store = store;
+    }
+
+    /// The record to resume a session with a peer from
+    pub fn get(&self, fabric_index: usize, peer_node_id: u64) -> Option<&ResumptionRecord> {
+        self.records.get(&(fabric_index, peer_node_id))
+    }
+
+    /// The record that a peer asked to resume a session from
+    pub fn find(
+        &self,
+        resumption_id: &[u8; CASE_RESUMPTION_ID_LENGTH],
+    ) -> Option<&ResumptionRecord> {
+        self.records
+            .values()
+            .find(|record| &record.resumption_id == resumption_id)
+    }
+
+    /// Add a record, replacing the peer's previous one
+    pub fn save(&mut self, record: ResumptionRecord) {
+        self.store.store(&record);
+        self.records
+            .insert((record.fabric_index, record.peer_node_id), record);
+    }
+
+    pub fn remove(&mut self, fabric_index: usize, peer_node_id: u64) {
+        self.store.remove(fabric_index, peer_node_id);
+        self.records.remove(&(fabric_index, peer_node_id));
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+}
+
+impl Default for ResumptionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resumption_table() {
+        let record = |peer_node_id, resumption_id| ResumptionRecord {
+            fabric_index: 1,
+            peer_node_id,
+            resumption_id: [resumption_id; CASE_RESUMPTION_ID_LENGTH],
+            shared_secret: [peer_node_id as u8; CRYPTO_GROUP_SIZE_BYTES],
+        };
+        let mut table = ResumptionTable::new();
+        table.save(record(2, 1));
+        table.save(record(3, 2));
+        // A peer has one record, which a resumed session replaces
+        table.save(record(2, 3));
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(1, 2), Some(&record(2, 3)));
+        assert!(table.find(&[1; CASE_RESUMPTION_ID_LENGTH]).is_none());
+        assert_eq!(
+            table.find(&[2; CASE_RESUMPTION_ID_LENGTH]),
+            Some(&record(3, 2))
+        );
+        assert!(table.get(2, 2).is_none());
+
+        // Records are written through to the store, and survive a restart
+        let mut store = MemoryResumptionStore::new();
+        store.store(&record(4, 4));
+        table.set_store(Box::new(store));
+        assert_eq!(table.len(), 1);
+        table.save(record(5, 5));
+        table.remove(1, 4);
+        let store = core::mem::replace(&mut table.store, Box::new(MemoryResumptionStore::new()));
+        let mut restarted = ResumptionTable::new();
+        restarted.set_store(store);
+        assert_eq!(restarted.len(), 1);
+        assert_eq!(restarted.get(1, 5), Some(&record(5, 5)));
+    }
+}
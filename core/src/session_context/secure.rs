@@ -1,10 +1,12 @@
 use crate::{
     constants::{SESSION_KEYS_INFO, SESSION_RESUMPTION_KEYS_INFO},
     crypto::hkdf_sha256,
-    util::time::current_timestamp,
+    exchange::{MessageCounter, MrpParameters},
+    secure_channel::{case::CASE_RESUMPTION_ID_LENGTH, pake::CRYPTO_GROUP_SIZE_BYTES},
+    util::time::{current_timestamp, Instant},
 };
 
-use super::SessionRole;
+use super::{SessionCounter, SessionRole};
 
 // TODO: find a more appropriate place for this
 /// Session Context (4.12.2.1)
@@ -17,16 +19,25 @@ pub struct SecureSessionContext {
     pub encryption_key: [u8; 16],
     pub decryption_key: [u8; 16],
     pub attestation_key: [u8; 16],
-    pub shared_secret: [u8; 16],
-    pub local_message_counter: i64,
-    // TODO: (4.5.4)
-    pub message_reception_state: (),
+    /// The secret that the session keys are derived from, which is the PASE
+    /// key `Ke` or the CASE shared secret
+    pub shared_secret: heapless::Vec<u8, CRYPTO_GROUP_SIZE_BYTES>,
+    pub local_message_counter: SessionCounter,
+    /// Message Reception State (4.5.5) of the peer's messages, which starts
+    /// with the first one and outlives the exchanges they are sent on
+    pub message_reception_state: Option<MessageCounter>,
     pub local_fabric_index: usize,
     pub peer_node_id: u64,
-    pub resumption_id: i8, // TODO
+    /// The ID of the resumption record that a CASE session left with the
+    /// peer
+    pub resumption_id: Option<[u8; CASE_RESUMPTION_ID_LENGTH]>,
     pub session_timestamp: i64,
     pub active_timestamp: i64,
-    // TODO: sleepy parameters
+    /// When the peer last sent a message in the session, which tells whether
+    /// it's in its active mode (4.12.2.1)
+    pub peer_active_at: Option<Instant>,
+    /// The MRP parameters that the peer sent during session establishment
+    pub peer_mrp_parameters: MrpParameters,
     // TODO: CASE authenticated tags (max 3 can be stored)
 }
 
@@ -38,6 +49,45 @@ impl SecureSessionContext {
         peer_session_id: u16,
         shared_secret: &[u8],
         salt: &[u8],
+    ) -> Self {
+        Self::new(
+            SecureSessionType::Pase,
+            is_initiator,
+            is_resumption,
+            local_session_id,
+            peer_session_id,
+            shared_secret,
+            salt,
+        )
+    }
+
+    pub fn new_case(
+        is_initiator: bool,
+        is_resumption: bool,
+        local_session_id: u16,
+        peer_session_id: u16,
+        shared_secret: &[u8],
+        salt: &[u8],
+    ) -> Self {
+        Self::new(
+            SecureSessionType::Case,
+            is_initiator,
+            is_resumption,
+            local_session_id,
+            peer_session_id,
+            shared_secret,
+            salt,
+        )
+    }
+
+    fn new(
+        session_type: SecureSessionType,
+        is_initiator: bool,
+        is_resumption: bool,
+        local_session_id: u16,
+        peer_session_id: u16,
+        shared_secret: &[u8],
+        salt: &[u8],
     ) -> Self {
         let timestamp = current_timestamp();
         let info = if is_resumption {
@@ -54,7 +104,7 @@ impl SecureSessionContext {
         let (encryption_key, decryption_key) = if is_initiator { (a, b) } else { (b, a) };
 
         Self {
-            session_type: SecureSessionType::Pase,
+            session_type,
             session_role: if is_initiator {
                 SessionRole::Initiator
             } else {
@@ -65,14 +115,16 @@ impl SecureSessionContext {
             encryption_key: encryption_key.try_into().unwrap(),
             decryption_key: decryption_key.try_into().unwrap(),
             attestation_key: attestation_key.try_into().unwrap(),
-            shared_secret: shared_secret.try_into().unwrap(),
-            local_message_counter: 0,
-            message_reception_state: (),
+            shared_secret: heapless::Vec::from_slice(shared_secret).unwrap(),
+            local_message_counter: SessionCounter::new(),
+            message_reception_state: None,
             local_fabric_index: 0,
             peer_node_id: 0,
-            resumption_id: 0,
+            resumption_id: None,
             session_timestamp: timestamp,
             active_timestamp: timestamp,
+            peer_active_at: None,
+            peer_mrp_parameters: MrpParameters::default(),
         }
     }
 }
@@ -102,6 +154,7 @@ pub enum SecureChannelProtocolOpCode {
 }
 
 #[repr(u16)]
+#[derive(FromPrimitive, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum SecureChannelProtocolCode {
     SessionEstablishmentSuccess = 0x0000,
     NoSharedTrustRoots = 0x0001,
@@ -1,5 +1,7 @@
 //! Unsecured Session Context (4.12.1.1)
 
+use crate::{exchange::MessageCounter, util::time::Instant};
+
 use super::SessionRole;
 
 #[derive(Debug)]
@@ -8,10 +10,11 @@ pub struct UnsecuredSessionContext {
     pub local_session_id: u16,
     pub peer_session_id: u16,
     pub ephemeral_initiator_node_id: u64,
-    // TODO: (4.5.4)
-    // It sounds like this state should live outside of the context
-    // from 4.5.6 1.c
-    pub message_reception_state: (),
+    /// Message Reception State (4.5.5) of the peer, which starts with the
+    /// first message it sends
+    pub message_reception_state: Option<MessageCounter>,
+    /// When the peer last sent a message, which tells whether it's active
+    pub peer_active_at: Option<Instant>,
     /*
     The downside with storing interaction specific data here is that
     when there's a new interaction, we'd have to alter this context
@@ -0,0 +1,102 @@
+//! Buffers that an [Encoder](super::Encoder) writes into.
+
+use alloc::vec::Vec;
+
+use super::TlvError;
+
+/// Storage for encoded TLV, which fails instead of panicking when full
+pub trait TlvBuf {
+    /// Append `data`, returning [TlvError::Overflow] if it does not fit.
+    /// Nothing is written when the data does not fit.
+    fn put(&mut self, data: &[u8]) -> Result<(), TlvError>;
+
+    /// Discard everything written after the first `len` bytes
+    fn truncate(&mut self, len: usize);
+
+    fn as_slice(&self) -> &[u8];
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+}
+
+impl<const N: usize> TlvBuf for heapless::Vec<u8, N> {
+    fn put(&mut self, data: &[u8]) -> Result<(), TlvError> {
+        self.extend_from_slice(data).map_err(|_| TlvError::Overflow)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        heapless::Vec::truncate(self, len)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+}
+
+impl TlvBuf for Vec<u8> {
+    fn put(&mut self, data: &[u8]) -> Result<(), TlvError> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+}
+
+impl TlvBuf for bytes::BytesMut {
+    fn put(&mut self, data: &[u8]) -> Result<(), TlvError> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        bytes::BytesMut::truncate(self, len)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+}
+
+/// A caller-provided buffer, such as the payload area of a message buffer
+pub struct SliceBuf<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceBuf<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// The bytes written so far
+    pub fn into_slice(self) -> &'a mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl<'a> TlvBuf for SliceBuf<'a> {
+    fn put(&mut self, data: &[u8]) -> Result<(), TlvError> {
+        let end = self.len + data.len();
+        if end > self.buf.len() {
+            return Err(TlvError::Overflow);
+        }
+        self.buf[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
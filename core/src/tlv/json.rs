@@ -0,0 +1,344 @@
+//! Lossless conversion between TLV and JSON, for tools and test fixtures.
+//!
+//! Each element is an object member named `tag:TYPE`, or just `TYPE` for
+//! anonymous elements, with the tag and type names of the
+//! [pretty printer](super::pretty):
+//!
+//! ```json
+//! {"STRUCT": {"1:BYTES": "4715a406", "2:U16": 56371, "6:ARRAY": [{"BOOL": true}]}}
+//! ```
+//!
+//! - Structures are objects, in the order of their members.
+//! - Arrays and lists are arrays of single member objects, as list members
+//!   can share a tag.
+//! - Byte strings are hex.
+//! - Floats that JSON can't hold are the strings `NaN`, `Infinity` and `-Infinity`.
+//!
+//! Integers keep their width, so non-canonical TLV converts back unchanged.
+//! String lengths are always written at their smallest width.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+use serde_json::{Map, Number, Value};
+
+use super::{
+    decode, ElementSize, Encoder, TagControl, TagLengthValue, Tlv, TlvBuf, TlvData, TlvError,
+    TlvType,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The TLV is malformed, or doesn't fit in the encoder
+    Tlv(TlvError),
+    /// A member name that isn't a valid `tag:TYPE`
+    InvalidKey(String),
+    /// A value that doesn't match the type in its member name
+    InvalidValue(String),
+    /// Structure members with the same tag
+    DuplicateTag(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Tlv(e) => e.fmt(f),
+            Error::InvalidKey(key) => write!(f, "{key} is not a valid TLV element name"),
+            Error::InvalidValue(key) => write!(f, "invalid value for {key}"),
+            Error::DuplicateTag(key) => write!(f, "structure has more than one {key}"),
+        }
+    }
+}
+
+impl From<TlvError> for Error {
+    fn from(e: TlvError) -> Self {
+        Error::Tlv(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Convert the first element of `data` to JSON
+pub fn to_json(data: &[u8]) -> Result<Value> {
+    let (key, value) = element_to_json(&decode(data))?;
+    let mut map = Map::new();
+    map.insert(key, value);
+    Ok(Value::Object(map))
+}
+
+/// Convert JSON from [to_json] back to TLV
+pub fn from_json(value: &Value) -> Result<Vec<u8>> {
+    let mut encoder = Encoder::new(Vec::new());
+    write_json(&mut encoder, value)?;
+    Ok(encoder.inner())
+}
+
+/// Write JSON from [to_json] as a TLV element
+pub fn write_json<B: TlvBuf>(encoder: &mut Encoder<B>, value: &Value) -> Result<()> {
+    let (key, value) = single_member(value)?;
+    write_member(encoder, key, value)
+}
+
+fn element_to_json(element: &TlvData<'_>) -> Result<(String, Value)> {
+    let control = element.get_control()?;
+    let tlv_type = element.get_type()?;
+    let key = match control {
+        TagControl::Anonymous => tlv_type.name().to_string(),
+        control => format!("{control}:{}", tlv_type.name()),
+    };
+
+    let value = match tlv_type {
+        TlvType::Structure => {
+            let mut map = Map::new();
+            for child in element.children()? {
+                let (key, value) = element_to_json(&child?)?;
+                if map.contains_key(&key) {
+                    return Err(Error::DuplicateTag(key));
+                }
+                map.insert(key, value);
+            }
+            Value::Object(map)
+        }
+        TlvType::Array | TlvType::List => {
+            let mut values = Vec::new();
+            for child in element.children()? {
+                let (key, value) = element_to_json(&child?)?;
+                let mut map = Map::new();
+                map.insert(key, value);
+                values.push(Value::Object(map));
+            }
+            Value::Array(values)
+        }
+        TlvType::String(_, _) => Value::String(element.as_str()?.to_string()),
+        TlvType::ByteString(_, _) => Value::String(hex::encode(element.as_bytes()?)),
+        _ => match element.get_value()? {
+            TagLengthValue::Signed8(v) => v.into(),
+            TagLengthValue::Signed16(v) => v.into(),
+            TagLengthValue::Signed32(v) => v.into(),
+            TagLengthValue::Signed64(v) => v.into(),
+            TagLengthValue::Unsigned8(v) => v.into(),
+            TagLengthValue::Unsigned16(v) => v.into(),
+            TagLengthValue::Unsigned32(v) => v.into(),
+            TagLengthValue::Unsigned64(v) => v.into(),
+            TagLengthValue::Boolean(v) => v.into(),
+            TagLengthValue::Float(v) => float_to_json(v as f64),
+            TagLengthValue::Double(v) => float_to_json(v),
+            _ => Value::Null,
+        },
+    };
+    Ok((key, value))
+}
+
+fn float_to_json(value: f64) -> Value {
+    match Number::from_f64(value) {
+        Some(number) => Value::Number(number),
+        None if value.is_nan() => Value::String("NaN".to_string()),
+        None if value > 0.0 => Value::String("Infinity".to_string()),
+        None => Value::String("-Infinity".to_string()),
+    }
+}
+
+fn float_from_json(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(s) if s == "NaN" => Some(f64::NAN),
+        Value::String(s) if s == "Infinity" => Some(f64::INFINITY),
+        Value::String(s) if s == "-Infinity" => Some(f64::NEG_INFINITY),
+        _ => None,
+    }
+}
+
+fn single_member(value: &Value) -> Result<(&String, &Value)> {
+    match value {
+        Value::Object(map) if map.len() == 1 => Ok(map.iter().next().unwrap()),
+        _ => Err(Error::InvalidValue(value.to_string())),
+    }
+}
+
+fn write_member<B: TlvBuf>(encoder: &mut Encoder<B>, key: &str, value: &Value) -> Result<()> {
+    let (tag, type_name) = match key.rsplit_once(':') {
+        Some((tag, type_name)) => (parse_tag(tag).ok_or_else(|| invalid_key(key))?, type_name),
+        None => (TagControl::Anonymous, key),
+    };
+    let invalid = || Error::InvalidValue(key.to_string());
+
+    let unsigned = |size: ElementSize| -> Result<(TlvType, TagLengthValue)> {
+        let v = value.as_u64().ok_or_else(invalid)?;
+        let value = match size {
+            ElementSize::Byte1 => TagLengthValue::Unsigned8(v.try_into().map_err(|_| invalid())?),
+            ElementSize::Byte2 => TagLengthValue::Unsigned16(v.try_into().map_err(|_| invalid())?),
+            ElementSize::Byte4 => TagLengthValue::Unsigned32(v.try_into().map_err(|_| invalid())?),
+            ElementSize::Byte8 => TagLengthValue::Unsigned64(v),
+        };
+        Ok((TlvType::UnsignedInt(size), value))
+    };
+    let signed = |size: ElementSize| -> Result<(TlvType, TagLengthValue)> {
+        let v = value.as_i64().ok_or_else(invalid)?;
+        let value = match size {
+            ElementSize::Byte1 => TagLengthValue::Signed8(v.try_into().map_err(|_| invalid())?),
+            ElementSize::Byte2 => TagLengthValue::Signed16(v.try_into().map_err(|_| invalid())?),
+            ElementSize::Byte4 => TagLengthValue::Signed32(v.try_into().map_err(|_| invalid())?),
+            ElementSize::Byte8 => TagLengthValue::Signed64(v),
+        };
+        Ok((TlvType::SignedInt(size), value))
+    };
+
+    let (tlv_type, tlv_value) = match type_name {
+        "U8" => unsigned(ElementSize::Byte1)?,
+        "U16" => unsigned(ElementSize::Byte2)?,
+        "U32" => unsigned(ElementSize::Byte4)?,
+        "U64" => unsigned(ElementSize::Byte8)?,
+        "I8" => signed(ElementSize::Byte1)?,
+        "I16" => signed(ElementSize::Byte2)?,
+        "I32" => signed(ElementSize::Byte4)?,
+        "I64" => signed(ElementSize::Byte8)?,
+        "BOOL" => {
+            let v = value.as_bool().ok_or_else(invalid)?;
+            (TlvType::Boolean(v), TagLengthValue::Boolean(v))
+        }
+        "F32" => {
+            let v = float_from_json(value).ok_or_else(invalid)?;
+            (TlvType::Float, TagLengthValue::Float(v as f32))
+        }
+        "F64" => {
+            let v = float_from_json(value).ok_or_else(invalid)?;
+            (TlvType::Double, TagLengthValue::Double(v))
+        }
+        "NULL" if value.is_null() => (TlvType::Null, TagLengthValue::Null),
+        "UTF8" => return Ok(encoder.str(tag, value.as_str().ok_or_else(invalid)?)?),
+        "BYTES" => {
+            let bytes = hex::decode(value.as_str().ok_or_else(invalid)?).map_err(|_| invalid())?;
+            return Ok(encoder.bytes(tag, &bytes)?);
+        }
+        "STRUCT" => {
+            let map = value.as_object().ok_or_else(invalid)?;
+            encoder.start_struct(tag)?;
+            for (key, value) in map {
+                write_member(encoder, key, value)?;
+            }
+            return Ok(encoder.end_container()?);
+        }
+        "ARRAY" | "LIST" => {
+            let values = value.as_array().ok_or_else(invalid)?;
+            if type_name == "ARRAY" {
+                encoder.start_array(tag)?;
+            } else {
+                encoder.start_list(tag)?;
+            }
+            for value in values {
+                let (key, value) = single_member(value)?;
+                write_member(encoder, key, value)?;
+            }
+            return Ok(encoder.end_container()?);
+        }
+        "NULL" => return Err(invalid()),
+        _ => return Err(invalid_key(key)),
+    };
+    Ok(encoder.write(tlv_type, tag, tlv_value)?)
+}
+
+fn invalid_key(key: &str) -> Error {
+    Error::InvalidKey(key.to_string())
+}
+
+/// Parse a tag written by `TagControl`'s `Display`
+fn parse_tag(tag: &str) -> Option<TagControl> {
+    fn number<T: TryFrom<u64>>(s: &str) -> Option<T> {
+        let value = match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+            None => s.parse().ok()?,
+        };
+        value.try_into().ok()
+    }
+
+    let parts: Vec<&str> = tag.split('.').collect();
+    let fully_qualified = |tag: &[u8]| -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&number::<u16>(parts[1])?.to_le_bytes());
+        bytes.extend_from_slice(&number::<u16>(parts[2])?.to_le_bytes());
+        bytes.extend_from_slice(tag);
+        Some(bytes)
+    };
+    let tag = match parts.as_slice() {
+        [number_str] => TagControl::ContextSpecific(number(number_str)?),
+        ["CP2", n] => TagControl::CommonProfile2Bytes(number::<u16>(n)?.to_le_bytes()),
+        ["CP4", n] => TagControl::CommonProfile4Bytes(number::<u32>(n)?.to_le_bytes()),
+        ["IP2", n] => TagControl::ImplicitProfile2Bytes(number::<u16>(n)?.to_le_bytes()),
+        ["IP4", n] => TagControl::ImplicitProfile4Bytes(number::<u32>(n)?.to_le_bytes()),
+        ["FQ6", _, _, n] => TagControl::FullyQualified6Bytes(
+            fully_qualified(&number::<u16>(n)?.to_le_bytes())?
+                .try_into()
+                .ok()?,
+        ),
+        ["FQ8", _, _, n] => TagControl::FullyQualified8Bytes(
+            fully_qualified(&number::<u32>(n)?.to_le_bytes())?
+                .try_into()
+                .ok()?,
+        ),
+        _ => return None,
+    };
+    Some(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        // A structure with every element type, and a non-canonical U32 of 1
+        let data = hex_literal::hex!(
+            "15" "2001ff" "2602" "01000000" "2a03" "0000803f" "2c0402" "6869" "3005" "020102"
+            "3606" "09" "14" "18" "3707" "2400" "01" "2400" "02" "18"
+            "d5" "f1ff" "0100" "0500" "18" "18"
+        );
+        let json = to_json(&data).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"STRUCT": {
+                "1:I8": -1,
+                "2:U32": 1,
+                "3:F32": 1.0,
+                "4:UTF8": "hi",
+                "5:BYTES": "0102",
+                "6:ARRAY": [{"BOOL": true}, {"NULL": null}],
+                "7:LIST": [{"0:U8": 1}, {"0:U8": 2}],
+                "FQ6.0xfff1.0x0001.5:STRUCT": {},
+            }})
+        );
+        assert_eq!(from_json(&json).unwrap(), data);
+    }
+
+    #[test]
+    fn special_floats() {
+        let mut encoder = Encoder::default();
+        encoder.start_array(TagControl::Anonymous).unwrap();
+        encoder.f64(TagControl::Anonymous, f64::NAN).unwrap();
+        encoder
+            .f32(TagControl::Anonymous, f32::NEG_INFINITY)
+            .unwrap();
+        encoder.end_container().unwrap();
+        let json = to_json(encoder.to_slice()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"ARRAY": [{"F64": "NaN"}, {"F32": "-Infinity"}]})
+        );
+        assert_eq!(from_json(&json).unwrap(), encoder.to_slice());
+    }
+
+    #[test]
+    fn invalid_json() {
+        let json = serde_json::json!({"STRUCT": {"1:U8": 256}});
+        assert_eq!(from_json(&json), Err(Error::InvalidValue("1:U8".into())));
+        let json = serde_json::json!({"STRUCT": {"x:U8": 1}});
+        assert_eq!(from_json(&json), Err(Error::InvalidKey("x:U8".into())));
+        let json = serde_json::json!({"STRUCT": {"1:U128": 1}});
+        assert_eq!(from_json(&json), Err(Error::InvalidKey("1:U128".into())));
+        let json = serde_json::json!({"ARRAY": [1]});
+        assert!(matches!(from_json(&json), Err(Error::InvalidValue(_))));
+    }
+}
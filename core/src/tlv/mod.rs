@@ -0,0 +1,1289 @@
+//!
+//!
+//! ATTRIBUTION: [bare-matter](https://github.com/bjoernQ/bare-matter/blob/main/src/tlv_codec.rs)
+
+use core::fmt;
+
+use crate::TlvAnyData;
+
+mod buf;
+#[cfg(feature = "std")]
+pub mod json;
+mod pretty;
+pub mod serde_tlv;
+mod traits;
+
+pub use buf::{SliceBuf, TlvBuf};
+pub use matter_derive::{FromTlv, ToTlv};
+pub use traits::{from_slice, to_encoder, Children, FromTlv, RawTlv, TlvBytes, ToTlv};
+
+/// Errors from decoding TLV, usually because the input is malformed
+#[derive(Debug, Clone, PartialEq)]
+pub enum TlvError {
+    /// The input ended in the middle of an element
+    Truncated,
+    /// The control byte has a reserved element type
+    InvalidControl(u8),
+    /// The element type is valid, but not supported, e.g. 64-bit string lengths
+    UnsupportedType(&'static str),
+    /// The element has a different type than the one expected
+    UnexpectedType(TlvType),
+    /// A required field of a structure is missing
+    MissingField(&'static str),
+    /// The value doesn't fit in the type it's decoded into
+    OutOfRange,
+    /// A string isn't valid UTF-8
+    InvalidUtf8,
+    /// The encoder's buffer is full
+    Overflow,
+}
+
+impl fmt::Display for TlvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlvError::Truncated => f.write_str("TLV input is truncated"),
+            TlvError::InvalidControl(control) => {
+                write!(f, "invalid TLV control byte {control:02x}")
+            }
+            TlvError::UnsupportedType(what) => write!(f, "{what} is not supported"),
+            TlvError::UnexpectedType(t) => write!(f, "unexpected TLV type {t:?}"),
+            TlvError::MissingField(field) => write!(f, "missing field {field}"),
+            TlvError::OutOfRange => f.write_str("TLV value is out of range"),
+            TlvError::InvalidUtf8 => f.write_str("TLV string is not valid UTF-8"),
+            TlvError::Overflow => f.write_str("TLV buffer is full"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagLengthValue {
+    Signed8(i8),
+    Signed16(i16),
+    Signed32(i32),
+    Signed64(i64),
+
+    Unsigned8(u8),
+    Unsigned16(u16),
+    Unsigned32(u32),
+    Unsigned64(u64),
+
+    Boolean(bool),
+
+    Float(f32),
+
+    Double(f64),
+
+    String(heapless::Vec<u8, 256>),
+
+    ByteString(heapless::Vec<u8, 1024>),
+
+    Null,
+
+    Container,
+    EndOfContainer,
+}
+
+impl TagLengthValue {
+    pub fn to_simple_tlv(&self) -> TlvAnyData {
+        let mut encoder = Encoder::default();
+        encoder
+            .write(self.infer_tlv_type(), TagControl::Anonymous, self.clone())
+            .unwrap();
+        TlvAnyData::from_slice(encoder.to_slice()).unwrap()
+    }
+
+    pub fn to_bytes<const N: usize>(&self) -> heapless::Vec<u8, N> {
+        let mut res: heapless::Vec<u8, N> = heapless::Vec::new();
+
+        match self {
+            TagLengthValue::Signed8(v) => res.push(*v as u8).unwrap(),
+            TagLengthValue::Signed16(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Signed32(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Signed64(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Unsigned8(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Unsigned16(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Unsigned32(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Unsigned64(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Boolean(_v) => (),
+            TagLengthValue::Float(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::Double(v) => res.extend_from_slice(&(v.to_le_bytes())).unwrap(),
+            TagLengthValue::String(v) => res.extend_from_slice(v).unwrap(),
+            TagLengthValue::ByteString(v) => res.extend_from_slice(v).unwrap(),
+            TagLengthValue::Null => (),
+            TagLengthValue::Container => (),
+            TagLengthValue::EndOfContainer => (),
+        }
+
+        res
+    }
+
+    pub const fn unsigned_value(&self) -> u64 {
+        match self {
+            TagLengthValue::Unsigned8(v) => *v as u64,
+            TagLengthValue::Unsigned16(v) => *v as u64,
+            TagLengthValue::Unsigned32(v) => *v as u64,
+            TagLengthValue::Unsigned64(v) => *v,
+            _ => 0xffff_ffff_ffff_ffff,
+        }
+    }
+
+    pub fn vec<const N: usize>(&self) -> heapless::Vec<u8, N> {
+        match self {
+            TagLengthValue::ByteString(v) => heapless::Vec::from_slice(&v).unwrap(),
+            _ => heapless::Vec::new(),
+        }
+    }
+
+    /// The smallest unsigned integer that holds `value`
+    pub const fn unsigned(value: u64) -> Self {
+        if value <= u8::MAX as u64 {
+            TagLengthValue::Unsigned8(value as u8)
+        } else if value <= u16::MAX as u64 {
+            TagLengthValue::Unsigned16(value as u16)
+        } else if value <= u32::MAX as u64 {
+            TagLengthValue::Unsigned32(value as u32)
+        } else {
+            TagLengthValue::Unsigned64(value)
+        }
+    }
+
+    /// The smallest signed integer that holds `value`
+    pub const fn signed(value: i64) -> Self {
+        if value >= i8::MIN as i64 && value <= i8::MAX as i64 {
+            TagLengthValue::Signed8(value as i8)
+        } else if value >= i16::MIN as i64 && value <= i16::MAX as i64 {
+            TagLengthValue::Signed16(value as i16)
+        } else if value >= i32::MIN as i64 && value <= i32::MAX as i64 {
+            TagLengthValue::Signed32(value as i32)
+        } else {
+            TagLengthValue::Signed64(value)
+        }
+    }
+
+    pub(crate) fn infer_tlv_type(&self) -> TlvType {
+        match self {
+            TagLengthValue::Signed8(_) => TlvType::SignedInt(ElementSize::Byte1),
+            TagLengthValue::Signed16(_) => TlvType::SignedInt(ElementSize::Byte2),
+            TagLengthValue::Signed32(_) => TlvType::SignedInt(ElementSize::Byte4),
+            TagLengthValue::Signed64(_) => TlvType::SignedInt(ElementSize::Byte8),
+            TagLengthValue::Unsigned8(_) => TlvType::UnsignedInt(ElementSize::Byte1),
+            TagLengthValue::Unsigned16(_) => TlvType::UnsignedInt(ElementSize::Byte2),
+            TagLengthValue::Unsigned32(_) => TlvType::UnsignedInt(ElementSize::Byte4),
+            TagLengthValue::Unsigned64(_) => TlvType::UnsignedInt(ElementSize::Byte8),
+            TagLengthValue::Boolean(v) => TlvType::Boolean(*v),
+            TagLengthValue::Float(_) => TlvType::Float,
+            TagLengthValue::Double(_) => TlvType::Double,
+            TagLengthValue::String(c) => TlvType::String(ElementSize::Byte1, c.len()),
+            TagLengthValue::ByteString(c) => TlvType::String(ElementSize::Byte1, c.len()),
+            TagLengthValue::Null => TlvType::Null,
+            TagLengthValue::Container => panic!("Can't infer type of Value::Container"),
+            TagLengthValue::EndOfContainer => TlvType::EndOfContainer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElementSize {
+    Byte1 = 0x00,
+    Byte2 = 0x01,
+    Byte4 = 0x02,
+    Byte8 = 0x03,
+}
+
+impl ElementSize {
+    const fn to_byte(&self) -> u8 {
+        match self {
+            ElementSize::Byte1 => 0,
+            ElementSize::Byte2 => 1,
+            ElementSize::Byte4 => 2,
+            ElementSize::Byte8 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TlvType {
+    SignedInt(ElementSize),
+    UnsignedInt(ElementSize),
+    Boolean(bool),
+    Float,
+    Double,
+    String(ElementSize, usize),
+    ByteString(ElementSize, usize),
+    Null,
+    Structure,
+    Array,
+    List,
+    EndOfContainer,
+}
+
+impl TlvType {
+    fn from(data: &[u8], control: TagControl) -> Result<Self, TlvError> {
+        let skip = control.skip();
+        let control = *data.first().ok_or(TlvError::Truncated)?;
+        let length = |size: usize| -> Result<usize, TlvError> {
+            let bytes = data
+                .get(1 + skip..)
+                .and_then(|d| d.get(..size))
+                .ok_or(TlvError::Truncated)?;
+            let mut len = [0; 4];
+            len[..size].copy_from_slice(bytes);
+            Ok(u32::from_le_bytes(len) as usize)
+        };
+        let tlv_type = match control & 0x1f {
+            0x00 => TlvType::SignedInt(ElementSize::Byte1),
+            0x01 => TlvType::SignedInt(ElementSize::Byte2),
+            0x02 => TlvType::SignedInt(ElementSize::Byte4),
+            0x03 => TlvType::SignedInt(ElementSize::Byte8),
+            0x04 => TlvType::UnsignedInt(ElementSize::Byte1),
+            0x05 => TlvType::UnsignedInt(ElementSize::Byte2),
+            0x06 => TlvType::UnsignedInt(ElementSize::Byte4),
+            0x07 => TlvType::UnsignedInt(ElementSize::Byte8),
+            0x08 => TlvType::Boolean(false),
+            0x09 => TlvType::Boolean(true),
+            0x0a => TlvType::Float,
+            0x0b => TlvType::Double,
+            0x0c => TlvType::String(ElementSize::Byte1, length(1)?),
+            0x0d => TlvType::String(ElementSize::Byte2, length(2)?),
+            0x0e => TlvType::String(ElementSize::Byte4, length(4)?),
+            0x0f => return Err(TlvError::UnsupportedType("64-bit string length")),
+            0x10 => TlvType::ByteString(ElementSize::Byte1, length(1)?),
+            0x11 => TlvType::ByteString(ElementSize::Byte2, length(2)?),
+            0x12 => TlvType::ByteString(ElementSize::Byte4, length(4)?),
+            0x13 => return Err(TlvError::UnsupportedType("64-bit byte string length")),
+            0x14 => TlvType::Null,
+            0x15 => TlvType::Structure,
+            0x16 => TlvType::Array,
+            0x17 => TlvType::List,
+            0x18 => TlvType::EndOfContainer,
+            _ => return Err(TlvError::InvalidControl(control)),
+        };
+        Ok(tlv_type)
+    }
+
+    fn to_bytes(&self) -> heapless::Vec<u8, 5> {
+        let mut res = heapless::Vec::new();
+        match self {
+            TlvType::SignedInt(s) => res.push(s.to_byte()).unwrap(),
+            TlvType::UnsignedInt(s) => res.push(0x04 + s.to_byte()).unwrap(),
+            TlvType::Boolean(value) => res.push(0x08 + if *value { 1 } else { 0 }).unwrap(),
+            TlvType::Float => res.push(0x0a).unwrap(),
+            TlvType::Double => res.push(0x0b).unwrap(),
+            TlvType::String(s, len) => {
+                res.push(0x0c + s.to_byte()).unwrap();
+                match s {
+                    ElementSize::Byte1 => res.push(*len as u8).unwrap(),
+                    ElementSize::Byte2 => {
+                        res.extend_from_slice(&(*len as u16).to_le_bytes()).unwrap()
+                    }
+                    ElementSize::Byte4 => {
+                        res.extend_from_slice(&(*len as u32).to_le_bytes()).unwrap()
+                    }
+                    ElementSize::Byte8 => panic!(),
+                };
+            }
+            TlvType::ByteString(s, len) => {
+                res.push(0x10 + s.to_byte()).unwrap();
+                match s {
+                    ElementSize::Byte1 => res.push(*len as u8).unwrap(),
+                    ElementSize::Byte2 => {
+                        res.extend_from_slice(&(*len as u16).to_le_bytes()).unwrap()
+                    }
+                    ElementSize::Byte4 => {
+                        res.extend_from_slice(&(*len as u32).to_le_bytes()).unwrap()
+                    }
+                    ElementSize::Byte8 => panic!(),
+                };
+            }
+            TlvType::Null => res.push(0x14).unwrap(),
+            TlvType::Structure => res.push(0x15).unwrap(),
+            TlvType::Array => res.push(0x16).unwrap(),
+            TlvType::List => res.push(0x17).unwrap(),
+            TlvType::EndOfContainer => res.push(0x18).unwrap(),
+        };
+
+        res
+    }
+
+    fn content_len(&mut self) -> usize {
+        match self {
+            TlvType::SignedInt(ElementSize::Byte1) => 1,
+            TlvType::SignedInt(ElementSize::Byte2) => 2,
+            TlvType::SignedInt(ElementSize::Byte4) => 4,
+            TlvType::SignedInt(ElementSize::Byte8) => 8,
+            TlvType::UnsignedInt(ElementSize::Byte1) => 1,
+            TlvType::UnsignedInt(ElementSize::Byte2) => 2,
+            TlvType::UnsignedInt(ElementSize::Byte4) => 4,
+            TlvType::UnsignedInt(ElementSize::Byte8) => 8,
+            TlvType::Boolean(_) => 0,
+            TlvType::Float => 4,
+            TlvType::Double => 8,
+            TlvType::String(ElementSize::Byte1, len) => *len,
+            TlvType::String(ElementSize::Byte2, len) => *len,
+            TlvType::String(ElementSize::Byte4, len) => *len,
+            TlvType::String(ElementSize::Byte8, len) => *len,
+            TlvType::ByteString(ElementSize::Byte1, len) => *len,
+            TlvType::ByteString(ElementSize::Byte2, len) => *len,
+            TlvType::ByteString(ElementSize::Byte4, len) => *len,
+            TlvType::ByteString(ElementSize::Byte8, len) => *len,
+            TlvType::Null => 0,
+            TlvType::Structure => 0,
+            TlvType::Array => 0,
+            TlvType::List => 0,
+            TlvType::EndOfContainer => 0,
+        }
+    }
+
+    fn skip(&mut self) -> usize {
+        match self {
+            TlvType::SignedInt(ElementSize::Byte1) => 0,
+            TlvType::SignedInt(ElementSize::Byte2) => 0,
+            TlvType::SignedInt(ElementSize::Byte4) => 0,
+            TlvType::SignedInt(ElementSize::Byte8) => 0,
+            TlvType::UnsignedInt(ElementSize::Byte1) => 0,
+            TlvType::UnsignedInt(ElementSize::Byte2) => 0,
+            TlvType::UnsignedInt(ElementSize::Byte4) => 0,
+            TlvType::UnsignedInt(ElementSize::Byte8) => 0,
+            TlvType::Boolean(_) => 0,
+            TlvType::Float => 0,
+            TlvType::Double => 0,
+            TlvType::String(ElementSize::Byte1, _len) => 1,
+            TlvType::String(ElementSize::Byte2, _len) => 2,
+            TlvType::String(ElementSize::Byte4, _len) => 4,
+            TlvType::String(ElementSize::Byte8, _len) => 8,
+            TlvType::ByteString(ElementSize::Byte1, _len) => 1,
+            TlvType::ByteString(ElementSize::Byte2, _len) => 2,
+            TlvType::ByteString(ElementSize::Byte4, _len) => 4,
+            TlvType::ByteString(ElementSize::Byte8, _len) => 8,
+            TlvType::Null => 0,
+            TlvType::Structure => 0,
+            TlvType::Array => 0,
+            TlvType::List => 0,
+            TlvType::EndOfContainer => 0,
+        }
+    }
+
+    pub(crate) const fn is_container(&self) -> bool {
+        matches!(self, TlvType::Structure | TlvType::Array | TlvType::List)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagControl {
+    Anonymous,
+    ContextSpecific(u8),
+    CommonProfile2Bytes([u8; 2]),
+    CommonProfile4Bytes([u8; 4]),
+    ImplicitProfile2Bytes([u8; 2]),
+    ImplicitProfile4Bytes([u8; 4]),
+    FullyQualified6Bytes([u8; 6]),
+    FullyQualified8Bytes([u8; 8]),
+}
+
+impl TagControl {
+    fn skip(&self) -> usize {
+        match self {
+            TagControl::Anonymous => 0,
+            TagControl::ContextSpecific(_) => 1,
+            TagControl::CommonProfile2Bytes(_) => 2,
+            TagControl::CommonProfile4Bytes(_) => 4,
+            TagControl::ImplicitProfile2Bytes(_) => 2,
+            TagControl::ImplicitProfile4Bytes(_) => 4,
+            TagControl::FullyQualified6Bytes(_) => 6,
+            TagControl::FullyQualified8Bytes(_) => 8,
+        }
+    }
+
+    fn to_bytes(&self) -> heapless::Vec<u8, 9> {
+        let mut res = heapless::Vec::new();
+
+        let control = match self {
+            TagControl::Anonymous => 0x00,
+            TagControl::ContextSpecific(byte) => {
+                res.push(*byte).unwrap();
+                0x20
+            }
+            TagControl::CommonProfile2Bytes(bytes) => {
+                res.extend_from_slice(bytes).unwrap();
+                0x40
+            }
+            TagControl::CommonProfile4Bytes(bytes) => {
+                res.extend_from_slice(bytes).unwrap();
+                0x60
+            }
+            TagControl::ImplicitProfile2Bytes(bytes) => {
+                res.extend_from_slice(bytes).unwrap();
+                0x80
+            }
+            TagControl::ImplicitProfile4Bytes(bytes) => {
+                res.extend_from_slice(bytes).unwrap();
+                0xa0
+            }
+            TagControl::FullyQualified6Bytes(bytes) => {
+                res.extend_from_slice(bytes).unwrap();
+                0xc0
+            }
+            TagControl::FullyQualified8Bytes(bytes) => {
+                res.extend_from_slice(bytes).unwrap();
+                0xd0
+            }
+        };
+
+        res.insert(0, control).unwrap();
+
+        res
+    }
+}
+
+pub trait Tlv<'a> {
+    fn get_type(&self) -> Result<TlvType, TlvError>;
+
+    fn get_control(&self) -> Result<TagControl, TlvError>;
+
+    fn next(&self) -> Result<TlvData<'a>, TlvError>;
+
+    fn read_to_bytes(&self) -> Result<(TlvData<'a>, TlvAnyData), TlvError>;
+
+    fn is_container(&self) -> Result<bool, TlvError>;
+
+    fn next_in_container(&self) -> Result<TlvData<'a>, TlvError>;
+
+    fn get_value(&self) -> Result<TagLengthValue, TlvError>;
+
+    fn is_last(&self) -> Result<bool, TlvError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlvData<'a> {
+    data: &'a [u8],
+    index: usize,
+    in_container: bool,
+}
+
+impl<'a> Tlv<'a> for TlvData<'a> {
+    fn get_type(&self) -> Result<TlvType, TlvError> {
+        let data = self.data.get(self.index..).ok_or(TlvError::Truncated)?;
+        TlvType::from(data, self.get_control()?)
+    }
+
+    fn get_control(&self) -> Result<TagControl, TlvError> {
+        let control = *self.data.get(self.index).ok_or(TlvError::Truncated)?;
+        let control = match control & 0xe0 {
+            0x00 => TagControl::Anonymous,
+            0x20 => TagControl::ContextSpecific(self.bytes(1, 1)?[0]),
+            0x40 => TagControl::CommonProfile2Bytes(self.bytes(1, 2)?.try_into().unwrap()),
+            0x60 => TagControl::CommonProfile4Bytes(self.bytes(1, 4)?.try_into().unwrap()),
+            0x80 => TagControl::ImplicitProfile2Bytes(self.bytes(1, 2)?.try_into().unwrap()),
+            0xa0 => TagControl::ImplicitProfile4Bytes(self.bytes(1, 4)?.try_into().unwrap()),
+            0xc0 => TagControl::FullyQualified6Bytes(self.bytes(1, 6)?.try_into().unwrap()),
+            _ => TagControl::FullyQualified8Bytes(self.bytes(1, 8)?.try_into().unwrap()),
+        };
+        Ok(control)
+    }
+
+    fn is_container(&self) -> Result<bool, TlvError> {
+        Ok(self.get_type()?.is_container())
+    }
+
+    fn next(&self) -> Result<TlvData<'a>, TlvError> {
+        if !self.in_container {
+            return self.next_in_container();
+        }
+
+        loop {
+            let next = self.next_in_container()?;
+            if next.get_type()? == TlvType::EndOfContainer {
+                break self.next_in_container();
+            }
+        }
+    }
+
+    /// Read the type (maybe container) into bytes and return the next element.
+    fn read_to_bytes(&self) -> Result<(TlvData<'a>, TlvAnyData), TlvError> {
+        if !self.is_container()? {
+            let mut encoder = Encoder::default();
+            encoder.write(self.get_type()?, self.get_control()?, self.get_value()?)?;
+            return Ok((
+                self.next_in_container()?,
+                TlvAnyData::from_slice(encoder.to_slice()).map_err(|_| TlvError::Overflow)?,
+            ));
+        }
+
+        let mut outer_written = false;
+        let mut depth = 1;
+        let mut encoder = Encoder::default();
+        let mut next = self.clone();
+        loop {
+            encoder.write(next.get_type()?, next.get_control()?, next.get_value()?)?;
+            if outer_written && next.is_container()? {
+                depth += 1;
+            }
+
+            if next.get_type()? == TlvType::EndOfContainer {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Ok((
+                        next.next_in_container()?,
+                        TlvAnyData::from_slice(encoder.to_slice())
+                            .map_err(|_| TlvError::Overflow)?,
+                    ));
+                }
+            }
+
+            outer_written = true;
+            next = next.next_in_container()?;
+        }
+    }
+
+    fn next_in_container(&self) -> Result<TlvData<'a>, TlvError> {
+        let mut res = TlvData {
+            data: self.data,
+            index: self.index + self.element_len()?,
+            in_container: self.in_container,
+        };
+
+        let tlv_type = res.get_type()?;
+        if tlv_type.is_container() {
+            res.in_container = true;
+        }
+
+        if tlv_type == TlvType::EndOfContainer {
+            res.in_container = false;
+        }
+
+        Ok(res)
+    }
+
+    fn is_last(&self) -> Result<bool, TlvError> {
+        Ok(self.index + self.element_len()? >= self.data.len())
+    }
+
+    fn get_value(&self) -> Result<TagLengthValue, TlvError> {
+        let value = self.value_slice()?;
+        let value = match self.get_type()? {
+            TlvType::SignedInt(s) => match s {
+                ElementSize::Byte1 => TagLengthValue::Signed8(value[0] as i8),
+                ElementSize::Byte2 => {
+                    TagLengthValue::Signed16(i16::from_le_bytes(value.try_into().unwrap()))
+                }
+                ElementSize::Byte4 => {
+                    TagLengthValue::Signed32(i32::from_le_bytes(value.try_into().unwrap()))
+                }
+                ElementSize::Byte8 => {
+                    TagLengthValue::Signed64(i64::from_le_bytes(value.try_into().unwrap()))
+                }
+            },
+            TlvType::UnsignedInt(s) => match s {
+                ElementSize::Byte1 => TagLengthValue::Unsigned8(value[0]),
+                ElementSize::Byte2 => {
+                    TagLengthValue::Unsigned16(u16::from_le_bytes(value.try_into().unwrap()))
+                }
+                ElementSize::Byte4 => {
+                    TagLengthValue::Unsigned32(u32::from_le_bytes(value.try_into().unwrap()))
+                }
+                ElementSize::Byte8 => {
+                    TagLengthValue::Unsigned64(u64::from_le_bytes(value.try_into().unwrap()))
+                }
+            },
+            TlvType::Boolean(b) => TagLengthValue::Boolean(b),
+            TlvType::Float => TagLengthValue::Float(f32::from_le_bytes(value.try_into().unwrap())),
+            TlvType::Double => {
+                TagLengthValue::Double(f64::from_le_bytes(value.try_into().unwrap()))
+            }
+            TlvType::String(_, _) => TagLengthValue::String(
+                heapless::Vec::from_slice(value).map_err(|_| TlvError::OutOfRange)?,
+            ),
+            TlvType::ByteString(_, _) => TagLengthValue::ByteString(
+                heapless::Vec::from_slice(value).map_err(|_| TlvError::OutOfRange)?,
+            ),
+            TlvType::Null => TagLengthValue::Null,
+            TlvType::Structure => TagLengthValue::Container,
+            TlvType::Array => TagLengthValue::Container,
+            TlvType::List => TagLengthValue::Container,
+            TlvType::EndOfContainer => TagLengthValue::EndOfContainer,
+        };
+        Ok(value)
+    }
+}
+
+impl<'a> TlvData<'a> {
+    /// `len` bytes at `offset` from the start of the element
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], TlvError> {
+        self.data
+            .get(self.index + offset..)
+            .and_then(|data| data.get(..len))
+            .ok_or(TlvError::Truncated)
+    }
+
+    /// The length of the element's control byte, tag, length and value.
+    /// Containers only count their opening byte.
+    pub(crate) fn element_len(&self) -> Result<usize, TlvError> {
+        let mut tlv_type = self.get_type()?;
+        Ok(1 + tlv_type.skip() + self.get_control()?.skip() + tlv_type.content_len())
+    }
+
+    /// The element's value without copying it
+    pub(crate) fn value_slice(&self) -> Result<&'a [u8], TlvError> {
+        let mut tlv_type = self.get_type()?;
+        let offset = 1 + tlv_type.skip() + self.get_control()?.skip();
+        self.bytes(offset, tlv_type.content_len())
+    }
+
+    /// The length of the element, including the contents of a container
+    /// up to and including its end.
+    pub(crate) fn subtree_len(&self) -> Result<usize, TlvError> {
+        if !self.is_container()? {
+            return self.element_len();
+        }
+        let mut depth = 0;
+        let mut element = self.clone();
+        loop {
+            let tlv_type = element.get_type()?;
+            if tlv_type.is_container() {
+                depth += 1;
+            } else if tlv_type == TlvType::EndOfContainer {
+                depth -= 1;
+            }
+            element.index += element.element_len()?;
+            if depth == 0 {
+                break Ok(element.index - self.index);
+            }
+        }
+    }
+
+    /// Iterate over the direct children of a container, skipping nested
+    /// containers' contents.
+    pub fn children(&self) -> Result<Children<'a>, TlvError> {
+        Children::new(self)
+    }
+
+    /// Find the direct child of a container with the given tag
+    pub fn find(&self, tag: &TagControl) -> Result<Option<TlvData<'a>>, TlvError> {
+        for child in self.children()? {
+            let child = child?;
+            if child.get_control()? == *tag {
+                return Ok(Some(child));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the direct child of a structure or list with a context tag
+    pub fn find_context(&self, tag: u8) -> Result<Option<TlvData<'a>>, TlvError> {
+        self.find(&TagControl::ContextSpecific(tag))
+    }
+
+    /// The element after this one, skipping a container's contents.
+    /// Returns `None` at the end of the enclosing container or the input.
+    pub fn skip(&self) -> Result<Option<TlvData<'a>>, TlvError> {
+        let next = TlvData {
+            data: self.data,
+            index: self.index + self.subtree_len()?,
+            in_container: self.in_container,
+        };
+        if next.index >= self.data.len() || next.get_type()? == TlvType::EndOfContainer {
+            return Ok(None);
+        }
+        Ok(Some(next))
+    }
+
+    /// The encoded element, including a container's contents and its end
+    pub fn raw(&self) -> Result<&'a [u8], TlvError> {
+        self.bytes(0, self.subtree_len()?)
+    }
+
+    /// The value of a UTF-8 string, borrowed from the input
+    pub fn as_str(&self) -> Result<&'a str, TlvError> {
+        match self.get_type()? {
+            TlvType::String(_, _) => {
+                core::str::from_utf8(self.value_slice()?).map_err(|_| TlvError::InvalidUtf8)
+            }
+            tlv_type => Err(TlvError::UnexpectedType(tlv_type)),
+        }
+    }
+
+    /// The value of a byte string, borrowed from the input
+    pub fn as_bytes(&self) -> Result<&'a [u8], TlvError> {
+        match self.get_type()? {
+            TlvType::ByteString(_, _) => self.value_slice(),
+            tlv_type => Err(TlvError::UnexpectedType(tlv_type)),
+        }
+    }
+}
+
+/// The size of the length field needed for a string of `len` bytes
+pub(crate) const fn string_size(len: usize) -> ElementSize {
+    if len <= u8::MAX as usize {
+        ElementSize::Byte1
+    } else if len <= u16::MAX as usize {
+        ElementSize::Byte2
+    } else {
+        ElementSize::Byte4
+    }
+}
+
+pub fn decode(data: &[u8]) -> TlvData<'_> {
+    TlvData {
+        data,
+        index: 0,
+        in_container: false,
+    }
+}
+
+/// The position of an [Encoder] to roll back to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint(usize);
+
+/// Writes TLV elements into a [TlvBuf], by default a 1024 byte vector.
+///
+/// Writing an element that doesn't fit returns [TlvError::Overflow] and
+/// leaves the buffer as it was before the element.
+pub struct Encoder<B = heapless::Vec<u8, 1024>> {
+    data: B,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new(heapless::Vec::new())
+    }
+}
+
+impl<B: TlvBuf> Encoder<B> {
+    pub fn new(data: B) -> Self {
+        Self { data }
+    }
+
+    pub fn write(
+        &mut self,
+        tlv_type: TlvType,
+        control: TagControl,
+        value: TagLengthValue,
+    ) -> Result<(), TlvError> {
+        let checkpoint = self.checkpoint();
+        let result = self.write_element(tlv_type, control, value);
+        if result.is_err() {
+            self.rollback(checkpoint);
+        }
+        result
+    }
+
+    fn write_element(
+        &mut self,
+        tlv_type: TlvType,
+        control: TagControl,
+        value: TagLengthValue,
+    ) -> Result<(), TlvError> {
+        self.write_header(tlv_type, control)?;
+        self.data.put(&value.to_bytes::<1024>())
+    }
+
+    /// Write the control octet, tag and length of an element, but not its value
+    fn write_header(&mut self, tlv_type: TlvType, control: TagControl) -> Result<(), TlvError> {
+        let tlv_type_bytes = tlv_type.to_bytes();
+        let control_bytes = control.to_bytes();
+        self.data.put(&[tlv_type_bytes[0] | control_bytes[0]])?;
+        self.data.put(&control_bytes[1..])?;
+        self.data.put(&tlv_type_bytes[1..])
+    }
+
+    /// Write a string or byte string without copying it into a [TagLengthValue]
+    fn write_string(
+        &mut self,
+        tlv_type: TlvType,
+        control: TagControl,
+        value: &[u8],
+    ) -> Result<(), TlvError> {
+        let checkpoint = self.checkpoint();
+        let result = self
+            .write_header(tlv_type, control)
+            .and_then(|_| self.data.put(value));
+        if result.is_err() {
+            self.rollback(checkpoint);
+        }
+        result
+    }
+
+    /// Write an unsigned integer in as few bytes as it needs
+    pub fn u64(&mut self, tag: TagControl, value: u64) -> Result<(), TlvError> {
+        let value = TagLengthValue::unsigned(value);
+        self.write(value.infer_tlv_type(), tag, value)
+    }
+
+    pub fn u32(&mut self, tag: TagControl, value: u32) -> Result<(), TlvError> {
+        self.u64(tag, value as u64)
+    }
+
+    pub fn u16(&mut self, tag: TagControl, value: u16) -> Result<(), TlvError> {
+        self.u64(tag, value as u64)
+    }
+
+    pub fn u8(&mut self, tag: TagControl, value: u8) -> Result<(), TlvError> {
+        self.u64(tag, value as u64)
+    }
+
+    /// Write a signed integer in as few bytes as it needs
+    pub fn i64(&mut self, tag: TagControl, value: i64) -> Result<(), TlvError> {
+        let value = TagLengthValue::signed(value);
+        self.write(value.infer_tlv_type(), tag, value)
+    }
+
+    pub fn i32(&mut self, tag: TagControl, value: i32) -> Result<(), TlvError> {
+        self.i64(tag, value as i64)
+    }
+
+    pub fn i16(&mut self, tag: TagControl, value: i16) -> Result<(), TlvError> {
+        self.i64(tag, value as i64)
+    }
+
+    pub fn i8(&mut self, tag: TagControl, value: i8) -> Result<(), TlvError> {
+        self.i64(tag, value as i64)
+    }
+
+    pub fn bool(&mut self, tag: TagControl, value: bool) -> Result<(), TlvError> {
+        self.write(TlvType::Boolean(value), tag, TagLengthValue::Boolean(value))
+    }
+
+    pub fn f32(&mut self, tag: TagControl, value: f32) -> Result<(), TlvError> {
+        self.write(TlvType::Float, tag, TagLengthValue::Float(value))
+    }
+
+    pub fn f64(&mut self, tag: TagControl, value: f64) -> Result<(), TlvError> {
+        self.write(TlvType::Double, tag, TagLengthValue::Double(value))
+    }
+
+    pub fn null(&mut self, tag: TagControl) -> Result<(), TlvError> {
+        self.write(TlvType::Null, tag, TagLengthValue::Null)
+    }
+
+    /// Write a UTF-8 string
+    pub fn str(&mut self, tag: TagControl, value: &str) -> Result<(), TlvError> {
+        let len = value.len();
+        self.write_string(
+            TlvType::String(string_size(len), len),
+            tag,
+            value.as_bytes(),
+        )
+    }
+
+    /// Write a byte string
+    pub fn bytes(&mut self, tag: TagControl, value: &[u8]) -> Result<(), TlvError> {
+        let len = value.len();
+        self.write_string(TlvType::ByteString(string_size(len), len), tag, value)
+    }
+
+    pub fn start_struct(&mut self, tag: TagControl) -> Result<(), TlvError> {
+        self.write(TlvType::Structure, tag, TagLengthValue::Container)
+    }
+
+    pub fn start_array(&mut self, tag: TagControl) -> Result<(), TlvError> {
+        self.write(TlvType::Array, tag, TagLengthValue::Container)
+    }
+
+    pub fn start_list(&mut self, tag: TagControl) -> Result<(), TlvError> {
+        self.write(TlvType::List, tag, TagLengthValue::Container)
+    }
+
+    /// Close the innermost structure, array or list
+    pub fn end_container(&mut self) -> Result<(), TlvError> {
+        self.write(
+            TlvType::EndOfContainer,
+            TagControl::Anonymous,
+            TagLengthValue::EndOfContainer,
+        )
+    }
+
+    /// Copy an encoded element, replacing its tag with `control`
+    pub fn write_raw(&mut self, control: TagControl, data: &[u8]) -> Result<(), TlvError> {
+        let checkpoint = self.checkpoint();
+        let result = self.write_raw_elements(control, data);
+        if result.is_err() {
+            self.rollback(checkpoint);
+        }
+        result
+    }
+
+    fn write_raw_elements(&mut self, control: TagControl, data: &[u8]) -> Result<(), TlvError> {
+        let mut tlv = decode(data);
+        self.write(tlv.get_type()?, control, tlv.get_value()?)?;
+
+        if tlv.is_last()? {
+            return Ok(());
+        }
+
+        tlv = tlv.next_in_container()?;
+        loop {
+            self.write(tlv.get_type()?, tlv.get_control()?, tlv.get_value()?)?;
+            if tlv.is_last()? {
+                break Ok(());
+            }
+            tlv = tlv.next_in_container()?;
+        }
+    }
+
+    /// Mark the current position, e.g. before writing an attribute report
+    /// that might not fit.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint(self.data.len())
+    }
+
+    /// Discard everything written since `checkpoint`
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.data.truncate(checkpoint.0);
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_slice(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+
+    pub fn to_vec<const N: usize>(&self) -> heapless::Vec<u8, N> {
+        heapless::Vec::from_slice(self.to_slice()).unwrap()
+    }
+
+    pub fn inner(self) -> B {
+        self.data
+    }
+}
+
+#[cfg(test)]
+#[allow(unused_imports)] // Rust Analyzer complains without reason!
+mod test {
+    use std::{format, println};
+
+    extern crate std;
+
+    use crate::tlv::TlvType;
+
+    use super::{ElementSize, TagControl, TagLengthValue, Tlv, TlvData, TlvError};
+
+    #[test]
+    fn test_decode1() {
+        let encoded = hex_literal::hex!("153001204715a406c6b0496ad52039e347db8528cb69a1cb2fce6f2318552ae65e103aca250233dc240300280435052501881325022c011818");
+
+        let decoded = super::decode(&encoded);
+        assert_eq!(
+            format!("{decoded}"),
+            "\
+STRUCT {
+    1: BYTES = hex:4715a406c6b0496ad52039e347db8528cb69a1cb2fce6f2318552ae65e103aca
+    2: U16 = 56371
+    3: U8 = 0
+    4: BOOL = false
+    5: STRUCT {
+        1: U16 = 5000
+        2: U16 = 300
+    }
+}
+"
+        );
+
+        // Malformed input is rendered up to the error
+        let decoded = super::decode(&encoded[..45]);
+        assert!(format!("{decoded}").ends_with("    4: BOOL = false\n!! TLV input is truncated\n"));
+    }
+
+    #[test]
+    fn test_encode() {
+        let wanted = hex_literal::hex!("153001204715a406c6b0496ad52039e347db8528cb69a1cb2fce6f2318552ae65e103aca250233dc240300280435052501881325022c011818");
+        let mut encoder = super::Encoder::default();
+
+        encoder
+            .write(
+                TlvType::Structure,
+                TagControl::Anonymous,
+                TagLengthValue::Container,
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::ByteString(ElementSize::Byte1, 32),
+                TagControl::ContextSpecific(1),
+                TagLengthValue::ByteString(
+                    heapless::Vec::from_slice(&[
+                        71, 21, 164, 6, 198, 176, 73, 106, 213, 32, 57, 227, 71, 219, 133, 40, 203,
+                        105, 161, 203, 47, 206, 111, 35, 24, 85, 42, 230, 94, 16, 58, 202,
+                    ])
+                    .unwrap(),
+                ),
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::UnsignedInt(ElementSize::Byte2),
+                TagControl::ContextSpecific(2),
+                TagLengthValue::Unsigned16(56371),
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::UnsignedInt(ElementSize::Byte1),
+                TagControl::ContextSpecific(3),
+                TagLengthValue::Unsigned8(0),
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::Boolean(false),
+                TagControl::ContextSpecific(4),
+                TagLengthValue::Boolean(false),
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::Structure,
+                TagControl::ContextSpecific(5),
+                TagLengthValue::Container,
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::UnsignedInt(ElementSize::Byte2),
+                TagControl::ContextSpecific(1),
+                TagLengthValue::Unsigned16(5000),
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::UnsignedInt(ElementSize::Byte2),
+                TagControl::ContextSpecific(2),
+                TagLengthValue::Unsigned16(300),
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::EndOfContainer,
+                TagControl::Anonymous,
+                TagLengthValue::Container,
+            )
+            .unwrap();
+        encoder
+            .write(
+                TlvType::EndOfContainer,
+                TagControl::Anonymous,
+                TagLengthValue::Container,
+            )
+            .unwrap();
+
+        assert_eq!(&wanted, encoder.to_slice());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        // A context tag without its tag number
+        let decoded = super::decode(&[0x24]);
+        assert_eq!(decoded.get_control(), Err(TlvError::Truncated));
+        // A 4-byte integer with only 2 bytes
+        let decoded = super::decode(&hex_literal::hex!("060102"));
+        assert_eq!(decoded.get_value(), Err(TlvError::Truncated));
+        // A string whose length is longer than the input
+        let decoded = super::decode(&hex_literal::hex!("0c0561"));
+        assert_eq!(decoded.get_value(), Err(TlvError::Truncated));
+        // Reserved element type
+        let decoded = super::decode(&[0x1f]);
+        assert_eq!(decoded.get_type(), Err(TlvError::InvalidControl(0x1f)));
+        // 64-bit string lengths
+        let decoded = super::decode(&hex_literal::hex!("0f010000000000000061"));
+        assert!(matches!(
+            decoded.get_type(),
+            Err(TlvError::UnsupportedType(_))
+        ));
+        // An unterminated structure
+        let decoded = super::decode(&hex_literal::hex!("15240105"));
+        assert_eq!(
+            decoded.next_in_container().unwrap().next_in_container(),
+            Err(TlvError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_float_roundtrip() {
+        // Examples from Appendix A.12
+        let singles: [(f32, [u8; 5]); 5] = [
+            (0.0, hex_literal::hex!("0a00000000")),
+            (1.0 / 3.0, hex_literal::hex!("0aabaaaa3e")),
+            (17.9, hex_literal::hex!("0a33338f41")),
+            (f32::INFINITY, hex_literal::hex!("0a0000807f")),
+            (f32::NEG_INFINITY, hex_literal::hex!("0a000080ff")),
+        ];
+        for (value, encoded) in singles {
+            let mut encoder = super::Encoder::default();
+            encoder.write(
+                TlvType::Float,
+                TagControl::Anonymous,
+                TagLengthValue::Float(value),
+            );
+            assert_eq!(encoder.to_slice(), encoded);
+            assert_eq!(
+                super::decode(&encoded).get_value(),
+                Ok(TagLengthValue::Float(value))
+            );
+            assert_eq!(super::from_slice::<f32>(&encoded), Ok(value));
+        }
+
+        let doubles: [(f64, [u8; 9]); 5] = [
+            (0.0, hex_literal::hex!("0b0000000000000000")),
+            (1.0 / 3.0, hex_literal::hex!("0b555555555555d53f")),
+            (17.9, hex_literal::hex!("0b6666666666e63140")),
+            (f64::INFINITY, hex_literal::hex!("0b000000000000f07f")),
+            (f64::NEG_INFINITY, hex_literal::hex!("0b000000000000f0ff")),
+        ];
+        for (value, encoded) in doubles {
+            assert_eq!(super::to_encoder(&value).unwrap().to_slice(), encoded);
+            assert_eq!(super::from_slice::<f64>(&encoded), Ok(value));
+        }
+
+        // Singles can be read as doubles, but not the other way round
+        let encoded = hex_literal::hex!("0a33338f41");
+        assert_eq!(super::from_slice::<f64>(&encoded), Ok(17.9f32 as f64));
+        let encoded = hex_literal::hex!("0b6666666666e63140");
+        assert_eq!(
+            super::from_slice::<f32>(&encoded),
+            Err(TlvError::UnexpectedType(TlvType::Double))
+        );
+
+        // NaN doesn't compare equal, so check the bits survive
+        let encoded = super::to_encoder(&f32::NAN).unwrap();
+        let decoded = super::from_slice::<f32>(encoded.to_slice()).unwrap();
+        assert_eq!(decoded.to_bits(), f32::NAN.to_bits());
+    }
+
+    #[test]
+    fn test_borrowed_children() {
+        // {0: "abc", 1: {0: [1, 2]}, 2: h'0102'}
+        let data = hex_literal::hex!("152c000361626335013600040104021818300202010218");
+        let root = TlvData {
+            data: &data,
+            index: 0,
+            in_container: false,
+        };
+
+        let name = root.find_context(0).unwrap().unwrap();
+        let value = name.as_str().unwrap();
+        assert_eq!(value, "abc");
+        // The string is borrowed from the input rather than copied
+        assert_eq!(value.as_ptr(), data[4..].as_ptr());
+        assert!(name.as_bytes().is_err());
+
+        let nested = root.find_context(1).unwrap().unwrap();
+        assert_eq!(nested.raw().unwrap(), &data[7..17]);
+        let array = nested.find_context(0).unwrap().unwrap();
+        assert_eq!(array.children().unwrap().count(), 2);
+
+        // Skipping the nested structure lands on the byte string
+        let bytes = nested.skip().unwrap().unwrap();
+        assert_eq!(bytes.get_control().unwrap(), TagControl::ContextSpecific(2));
+        assert_eq!(bytes.as_bytes().unwrap(), &[1, 2]);
+        assert!(bytes.skip().unwrap().is_none());
+
+        assert!(root.find_context(3).unwrap().is_none());
+        assert!(root.skip().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_encode_overflow() {
+        let mut buf = [0u8; 8];
+        let mut encoder = super::Encoder::new(super::SliceBuf::new(&mut buf));
+        encoder
+            .write(
+                TlvType::Structure,
+                TagControl::Anonymous,
+                TagLengthValue::Container,
+            )
+            .unwrap();
+        let checkpoint = encoder.checkpoint();
+        encoder
+            .write(
+                TlvType::UnsignedInt(ElementSize::Byte2),
+                TagControl::ContextSpecific(1),
+                TagLengthValue::Unsigned16(5000),
+            )
+            .unwrap();
+        assert_eq!(encoder.to_slice(), &[0x15, 0x25, 0x01, 0x88, 0x13]);
+
+        // An element that doesn't fit leaves the buffer as it was
+        assert_eq!(
+            encoder.write(
+                TlvType::UnsignedInt(ElementSize::Byte4),
+                TagControl::ContextSpecific(2),
+                TagLengthValue::Unsigned32(5000),
+            ),
+            Err(TlvError::Overflow)
+        );
+        assert_eq!(encoder.len(), 5);
+
+        encoder.rollback(checkpoint);
+        encoder
+            .write(
+                TlvType::EndOfContainer,
+                TagControl::Anonymous,
+                TagLengthValue::EndOfContainer,
+            )
+            .unwrap();
+        assert_eq!(encoder.inner().into_slice(), &[0x15, 0x18]);
+
+        // The default encoder is bounded too
+        let value = heapless::Vec::<u16, 512>::from_slice(&[0; 512]).unwrap();
+        assert_eq!(super::to_encoder(&value).err(), Some(TlvError::Overflow));
+
+        // and copying an element that doesn't fit fails instead of truncating it
+        let mut data = vec![0x16];
+        data.extend([0x04, 0x00].repeat(600));
+        data.push(0x18);
+        assert_eq!(
+            super::decode(&data).read_to_bytes().err(),
+            Some(TlvError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_typed_writer() {
+        let mut encoder = super::Encoder::default();
+        encoder.start_struct(TagControl::Anonymous).unwrap();
+        encoder.u64(TagControl::ContextSpecific(0), 42).unwrap();
+        encoder.u16(TagControl::ContextSpecific(1), 0x1234).unwrap();
+        encoder
+            .u32(TagControl::ContextSpecific(2), 0x10000)
+            .unwrap();
+        encoder.i64(TagControl::ContextSpecific(3), -1).unwrap();
+        encoder.i32(TagControl::ContextSpecific(4), -129).unwrap();
+        encoder.start_array(TagControl::ContextSpecific(5)).unwrap();
+        encoder.bool(TagControl::Anonymous, true).unwrap();
+        encoder.null(TagControl::Anonymous).unwrap();
+        encoder.end_container().unwrap();
+        encoder.start_list(TagControl::ContextSpecific(6)).unwrap();
+        encoder
+            .str(TagControl::ContextSpecific(0), "Hello!")
+            .unwrap();
+        encoder
+            .bytes(TagControl::ContextSpecific(1), &[0, 1, 2])
+            .unwrap();
+        encoder.end_container().unwrap();
+        encoder.end_container().unwrap();
+
+        // Integers use the smallest width that holds their value
+        assert_eq!(
+            encoder.to_slice(),
+            hex_literal::hex!(
+                "15" "24002a" "25013412" "260200000100" "2003ff" "21047fff"
+                "3605" "09" "14" "18"
+                "3706" "2c000648656c6c6f21" "300103000102" "18"
+                "18"
+            )
+        );
+
+        // Strings aren't limited to the size of a TagLengthValue
+        let long = alloc::vec![b'a'; 2000];
+        let mut encoder = super::Encoder::new(alloc::vec::Vec::new());
+        encoder.bytes(TagControl::Anonymous, &long).unwrap();
+        assert_eq!(encoder.to_slice()[..3], [0x11, 0xd0, 0x07]);
+        assert_eq!(encoder.len(), 2003);
+    }
+}
@@ -0,0 +1,161 @@
+//! Rendering TLV for debugging, e.g. `println!("{}", tlv::decode(&payload))`.
+//!
+//! Each element is written on its own line as `tag: TYPE = value`, leaving
+//! out the tag of anonymous elements:
+//!
+//! ```text
+//! STRUCT {
+//!     1: BYTES = hex:4715a406
+//!     2: U16 = 56371
+//!     5: STRUCT {
+//!         1: U16 = 5000
+//!     }
+//!     6: ARRAY [
+//!         BOOL = true
+//!     ]
+//! }
+//! ```
+//!
+//! Malformed input is rendered up to the first error, which is written as
+//! `!! error`.
+
+use core::fmt;
+
+use super::{ElementSize, TagControl, TagLengthValue, Tlv, TlvData, TlvError, TlvType};
+
+const INDENT: usize = 4;
+
+impl TlvType {
+    /// The name of the type, with the width of integers, e.g. `U16`
+    pub fn name(&self) -> &'static str {
+        match self {
+            TlvType::SignedInt(ElementSize::Byte1) => "I8",
+            TlvType::SignedInt(ElementSize::Byte2) => "I16",
+            TlvType::SignedInt(ElementSize::Byte4) => "I32",
+            TlvType::SignedInt(ElementSize::Byte8) => "I64",
+            TlvType::UnsignedInt(ElementSize::Byte1) => "U8",
+            TlvType::UnsignedInt(ElementSize::Byte2) => "U16",
+            TlvType::UnsignedInt(ElementSize::Byte4) => "U32",
+            TlvType::UnsignedInt(ElementSize::Byte8) => "U64",
+            TlvType::Boolean(_) => "BOOL",
+            TlvType::Float => "F32",
+            TlvType::Double => "F64",
+            TlvType::String(_, _) => "UTF8",
+            TlvType::ByteString(_, _) => "BYTES",
+            TlvType::Null => "NULL",
+            TlvType::Structure => "STRUCT",
+            TlvType::Array => "ARRAY",
+            TlvType::List => "LIST",
+            TlvType::EndOfContainer => "END",
+        }
+    }
+}
+
+/// Tags are written as:
+/// - `N` for context-specific tags
+/// - `CP2.N`, `CP4.N` for common profile tags, by their width
+/// - `IP2.N`, `IP4.N` for implicit profile tags
+/// - `FQ6.0xVVVV.0xPPPP.N`, `FQ8.0xVVVV.0xPPPP.N` for fully qualified tags
+impl fmt::Display for TagControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let u16_at = |bytes: &[u8], i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        match self {
+            TagControl::Anonymous => f.write_str("Anonymous"),
+            TagControl::ContextSpecific(tag) => write!(f, "{tag}"),
+            TagControl::CommonProfile2Bytes(b) => write!(f, "CP2.{}", u16::from_le_bytes(*b)),
+            TagControl::CommonProfile4Bytes(b) => write!(f, "CP4.{}", u32::from_le_bytes(*b)),
+            TagControl::ImplicitProfile2Bytes(b) => write!(f, "IP2.{}", u16::from_le_bytes(*b)),
+            TagControl::ImplicitProfile4Bytes(b) => write!(f, "IP4.{}", u32::from_le_bytes(*b)),
+            TagControl::FullyQualified6Bytes(b) => write!(
+                f,
+                "FQ6.0x{:04x}.0x{:04x}.{}",
+                u16_at(b, 0),
+                u16_at(b, 2),
+                u16_at(b, 4)
+            ),
+            TagControl::FullyQualified8Bytes(b) => write!(
+                f,
+                "FQ8.0x{:04x}.0x{:04x}.{}",
+                u16_at(b, 0),
+                u16_at(b, 2),
+                u32::from_le_bytes([b[4], b[5], b[6], b[7]])
+            ),
+        }
+    }
+}
+
+/// Renders the element and, for containers, everything in it
+impl<'a> fmt::Display for TlvData<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match write_element(f, self, 0) {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => writeln!(f, "!! {e}"),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// The outer result is the formatter's, the inner one is from decoding
+fn write_element(
+    f: &mut fmt::Formatter<'_>,
+    element: &TlvData<'_>,
+    depth: usize,
+) -> Result<Result<(), TlvError>, fmt::Error> {
+    macro_rules! tlv {
+        ($e:expr) => {
+            match $e {
+                Ok(value) => value,
+                Err(e) => return Ok(Err(e)),
+            }
+        };
+    }
+
+    let control = tlv!(element.get_control());
+    let tlv_type = tlv!(element.get_type());
+    write!(f, "{:indent$}", "", indent = depth * INDENT)?;
+    if control != TagControl::Anonymous {
+        write!(f, "{control}: ")?;
+    }
+    f.write_str(tlv_type.name())?;
+
+    let (open, close) = match tlv_type {
+        TlvType::Structure => ("{", "}"),
+        TlvType::Array | TlvType::List => ("[", "]"),
+        TlvType::Null => return writeln!(f).map(Ok),
+        TlvType::String(_, _) => return writeln!(f, " = {:?}", tlv!(element.as_str())).map(Ok),
+        TlvType::ByteString(_, _) => {
+            f.write_str(" = hex:")?;
+            for byte in tlv!(element.as_bytes()) {
+                write!(f, "{byte:02x}")?;
+            }
+            return writeln!(f).map(Ok);
+        }
+        _ => {
+            f.write_str(" = ")?;
+            match tlv!(element.get_value()) {
+                TagLengthValue::Signed8(v) => write!(f, "{v}")?,
+                TagLengthValue::Signed16(v) => write!(f, "{v}")?,
+                TagLengthValue::Signed32(v) => write!(f, "{v}")?,
+                TagLengthValue::Signed64(v) => write!(f, "{v}")?,
+                TagLengthValue::Unsigned8(v) => write!(f, "{v}")?,
+                TagLengthValue::Unsigned16(v) => write!(f, "{v}")?,
+                TagLengthValue::Unsigned32(v) => write!(f, "{v}")?,
+                TagLengthValue::Unsigned64(v) => write!(f, "{v}")?,
+                TagLengthValue::Boolean(v) => write!(f, "{v}")?,
+                TagLengthValue::Float(v) => write!(f, "{v}")?,
+                TagLengthValue::Double(v) => write!(f, "{v}")?,
+                _ => {}
+            }
+            return writeln!(f).map(Ok);
+        }
+    };
+
+    writeln!(f, " {open}")?;
+    for child in tlv!(element.children()) {
+        let child = tlv!(child);
+        if let Err(e) = write_element(f, &child, depth + 1)? {
+            return Ok(Err(e));
+        }
+    }
+    writeln!(f, "{:indent$}{close}", "", indent = depth * INDENT).map(Ok)
+}
@@ -0,0 +1,888 @@
+//! A `serde` compatible TLV serializer and deserializer.
+//!
+//! Structs are encoded as TLV structures, with each field written under a
+//! context-specific tag. The tag number comes from the field's name, so fields
+//! are renamed to their tag:
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Pake1 {
+//!     #[serde(rename = "1", with = "serde_bytes")]
+//!     p_a: [u8; 65],
+//! }
+//! ```
+//!
+//! Other mappings:
+//! - `Option` fields that are `None` are omitted, and missing fields decode as `None`.
+//!   Outside of a struct, `None` and `()` are encoded as `Null`.
+//! - Integers are encoded in as few bytes as their value needs.
+//! - Sequences (`Vec`, slices, tuples) are encoded as arrays.
+//! - Byte strings need `serde_bytes`, otherwise they are encoded as arrays of `u8`.
+//! - Unit enum variants are encoded as their variant index (enum8).
+//! - Structs renamed to [`LIST`] are encoded as TLV lists instead of structures,
+//!   which is what most paths in the interaction model use.
+
+use alloc::string::{String, ToString};
+use core::fmt::{self, Display, Write};
+
+use serde::{
+    de::{self, DeserializeSeed, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+};
+
+use super::{
+    string_size, ElementSize, Encoder, TagControl, TagLengthValue, Tlv, TlvBuf, TlvData, TlvError,
+    TlvType,
+};
+
+/// Struct name that encodes a struct as a TLV list instead of a structure.
+pub const LIST: &str = "$tlv::list";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A custom error raised by a `Serialize` or `Deserialize` implementation
+    Custom(String),
+    /// The value can't be represented in TLV, or isn't supported yet
+    Unsupported(&'static str),
+    /// A struct field or map key that isn't a context tag number
+    InvalidTag(String),
+    /// The input isn't valid TLV, or has a different type than the one requested
+    Tlv(TlvError),
+    /// There are elements after the decoded value
+    TrailingData,
+    /// A string or byte string is longer than the encoder supports
+    TooLong(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Custom(msg) => f.write_str(msg),
+            Error::Unsupported(what) => write!(f, "{what} is not supported in TLV"),
+            Error::InvalidTag(tag) => write!(f, "{tag} is not a valid context tag"),
+            Error::Tlv(e) => e.fmt(f),
+            Error::TrailingData => f.write_str("trailing TLV data"),
+            Error::TooLong(len) => write!(f, "value of length {len} is too long"),
+        }
+    }
+}
+
+impl ser::StdError for Error {}
+
+impl From<TlvError> for Error {
+    fn from(e: TlvError) -> Self {
+        Error::Tlv(e)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Serialize a value into a new encoder
+pub fn to_encoder<T: Serialize + ?Sized>(value: &T) -> Result<Encoder> {
+    let mut encoder = Encoder::default();
+    value.serialize(&mut Serializer::new(&mut encoder))?;
+    Ok(encoder)
+}
+
+/// Serialize a value into TLV bytes
+pub fn to_vec<T: Serialize + ?Sized, const N: usize>(value: &T) -> Result<heapless::Vec<u8, N>> {
+    let encoder = to_encoder(value)?;
+    heapless::Vec::from_slice(encoder.to_slice())
+        .map_err(|_| Error::TooLong(encoder.to_slice().len()))
+}
+
+/// Deserialize a value from TLV bytes, borrowing strings and byte strings where possible
+pub fn from_slice<'de, T: de::Deserialize<'de>>(data: &'de [u8]) -> Result<T> {
+    let mut deserializer = Deserializer::new(data);
+    let value = T::deserialize(&mut deserializer)?;
+    if deserializer.index < data.len() {
+        return Err(Error::TrailingData);
+    }
+    Ok(value)
+}
+
+/// Parse a struct field name or map key into a context tag
+fn context_tag(key: &str) -> Result<TagControl> {
+    key.parse::<u8>()
+        .map(TagControl::ContextSpecific)
+        .map_err(|_| Error::InvalidTag(key.to_string()))
+}
+
+pub struct Serializer<'e, B: TlvBuf = heapless::Vec<u8, 1024>> {
+    encoder: &'e mut Encoder<B>,
+    /// The tag of the next element to be written
+    tag: TagControl,
+}
+
+impl<'e, B: TlvBuf> Serializer<'e, B> {
+    pub fn new(encoder: &'e mut Encoder<B>) -> Self {
+        Self {
+            encoder,
+            tag: TagControl::Anonymous,
+        }
+    }
+
+    fn write(&mut self, value: TagLengthValue) -> Result<()> {
+        let tlv_type = value.infer_tlv_type();
+        self.write_with_type(tlv_type, value)
+    }
+
+    fn write_with_type(&mut self, tlv_type: TlvType, value: TagLengthValue) -> Result<()> {
+        // Elements are anonymous unless a struct field sets a tag
+        let tag = core::mem::replace(&mut self.tag, TagControl::Anonymous);
+        self.encoder.write(tlv_type, tag, value)?;
+        Ok(())
+    }
+
+    fn start_container(&mut self, tlv_type: TlvType) -> Result<()> {
+        self.write_with_type(tlv_type, TagLengthValue::Container)
+    }
+
+    fn end_container(&mut self) -> Result<()> {
+        self.write_with_type(TlvType::EndOfContainer, TagLengthValue::EndOfContainer)
+    }
+}
+
+impl<'a, 'e, B: TlvBuf> ser::Serializer for &'a mut Serializer<'e, B> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = ser::Impossible<(), Error>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = ser::Impossible<(), Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.write(TagLengthValue::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.write(TagLengthValue::signed(v as i64))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.write(TagLengthValue::signed(v as i64))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.write(TagLengthValue::signed(v as i64))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.write(TagLengthValue::signed(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.write(TagLengthValue::unsigned(v as u64))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.write(TagLengthValue::unsigned(v as u64))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.write(TagLengthValue::unsigned(v as u64))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.write(TagLengthValue::unsigned(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.write(TagLengthValue::Float(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.write(TagLengthValue::Double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        let mut buf = [0; 4];
+        self.serialize_str(v.encode_utf8(&mut buf))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        let value = heapless::Vec::from_slice(v.as_bytes()).map_err(|_| Error::TooLong(v.len()))?;
+        self.write_with_type(
+            TlvType::String(string_size(v.len()), v.len()),
+            TagLengthValue::String(value),
+        )
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        let value = heapless::Vec::from_slice(v).map_err(|_| Error::TooLong(v.len()))?;
+        self.write_with_type(
+            TlvType::ByteString(string_size(v.len()), v.len()),
+            TagLengthValue::ByteString(value),
+        )
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        match self.tag {
+            // Optional fields are omitted from structures
+            TagControl::ContextSpecific(_) => {
+                self.tag = TagControl::Anonymous;
+                Ok(())
+            }
+            _ => self.write(TagLengthValue::Null),
+        }
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.write(TagLengthValue::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        let value = u8::try_from(variant_index).map_err(|_| Error::Unsupported("enum16"))?;
+        self.serialize_u8(value)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<()> {
+        Err(Error::Unsupported("newtype variant"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        self.start_container(TlvType::Array)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::Unsupported("tuple variant"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        self.start_container(TlvType::Structure)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        if name == LIST {
+            self.start_container(TlvType::List)?;
+        } else {
+            self.start_container(TlvType::Structure)?;
+        }
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::Unsupported("struct variant"))
+    }
+}
+
+impl<'a, 'e, B: TlvBuf> ser::SerializeSeq for &'a mut Serializer<'e, B> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_container()
+    }
+}
+
+impl<'a, 'e, B: TlvBuf> ser::SerializeTuple for &'a mut Serializer<'e, B> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_container()
+    }
+}
+
+impl<'a, 'e, B: TlvBuf> ser::SerializeTupleStruct for &'a mut Serializer<'e, B> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_container()
+    }
+}
+
+impl<'a, 'e, B: TlvBuf> ser::SerializeMap for &'a mut Serializer<'e, B> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.tag = key.serialize(KeySerializer)?;
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_container()
+    }
+}
+
+impl<'a, 'e, B: TlvBuf> ser::SerializeStruct for &'a mut Serializer<'e, B> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.tag = context_tag(key)?;
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.end_container()
+    }
+}
+
+/// Converts map keys into context tags. Keys can be integers or numeric strings.
+struct KeySerializer;
+
+impl KeySerializer {
+    fn tag<T: TryInto<u8> + Display + Copy>(v: T) -> Result<TagControl> {
+        v.try_into()
+            .map(TagControl::ContextSpecific)
+            .map_err(|_| Error::InvalidTag(v.to_string()))
+    }
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = TagControl;
+    type Error = Error;
+    type SerializeSeq = ser::Impossible<TagControl, Error>;
+    type SerializeTuple = ser::Impossible<TagControl, Error>;
+    type SerializeTupleStruct = ser::Impossible<TagControl, Error>;
+    type SerializeTupleVariant = ser::Impossible<TagControl, Error>;
+    type SerializeMap = ser::Impossible<TagControl, Error>;
+    type SerializeStruct = ser::Impossible<TagControl, Error>;
+    type SerializeStructVariant = ser::Impossible<TagControl, Error>;
+
+    fn serialize_bool(self, _v: bool) -> Result<TagControl> {
+        Err(Error::Unsupported("bool key"))
+    }
+    fn serialize_i8(self, v: i8) -> Result<TagControl> {
+        Self::tag(v)
+    }
+    fn serialize_i16(self, v: i16) -> Result<TagControl> {
+        Self::tag(v)
+    }
+    fn serialize_i32(self, v: i32) -> Result<TagControl> {
+        Self::tag(v)
+    }
+    fn serialize_i64(self, v: i64) -> Result<TagControl> {
+        Self::tag(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<TagControl> {
+        Ok(TagControl::ContextSpecific(v))
+    }
+    fn serialize_u16(self, v: u16) -> Result<TagControl> {
+        Self::tag(v)
+    }
+    fn serialize_u32(self, v: u32) -> Result<TagControl> {
+        Self::tag(v)
+    }
+    fn serialize_u64(self, v: u64) -> Result<TagControl> {
+        Self::tag(v)
+    }
+    fn serialize_f32(self, _v: f32) -> Result<TagControl> {
+        Err(Error::Unsupported("f32 key"))
+    }
+    fn serialize_f64(self, _v: f64) -> Result<TagControl> {
+        Err(Error::Unsupported("f64 key"))
+    }
+    fn serialize_char(self, _v: char) -> Result<TagControl> {
+        Err(Error::Unsupported("char key"))
+    }
+    fn serialize_str(self, v: &str) -> Result<TagControl> {
+        context_tag(v)
+    }
+    fn serialize_bytes(self, _v: &[u8]) -> Result<TagControl> {
+        Err(Error::Unsupported("bytes key"))
+    }
+    fn serialize_none(self) -> Result<TagControl> {
+        Err(Error::Unsupported("optional key"))
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<TagControl> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<TagControl> {
+        Err(Error::Unsupported("unit key"))
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<TagControl> {
+        Err(Error::Unsupported("unit struct key"))
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<TagControl> {
+        Self::tag(variant_index)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<TagControl> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<TagControl> {
+        Err(Error::Unsupported("newtype variant key"))
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(Error::Unsupported("sequence key"))
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(Error::Unsupported("tuple key"))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(Error::Unsupported("tuple struct key"))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(Error::Unsupported("tuple variant key"))
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(Error::Unsupported("map key"))
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(Error::Unsupported("struct key"))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(Error::Unsupported("struct variant key"))
+    }
+}
+
+pub struct Deserializer<'de> {
+    data: &'de [u8],
+    /// Position of the current element
+    index: usize,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn new(data: &'de [u8]) -> Self {
+        Self { data, index: 0 }
+    }
+
+    /// The element at the current position
+    fn element(&self) -> Result<TlvData<'de>> {
+        if self.index >= self.data.len() {
+            return Err(Error::Tlv(TlvError::Truncated));
+        }
+        Ok(TlvData {
+            data: self.data,
+            index: self.index,
+            in_container: false,
+        })
+    }
+
+    /// Move past the current element, without entering it if it's a container
+    fn advance(&mut self) -> Result<TlvData<'de>> {
+        let element = self.element()?;
+        self.index += element.element_len()?;
+        Ok(element)
+    }
+
+    /// Skip the current element, including the contents of containers
+    fn skip(&mut self) -> Result<()> {
+        let element = self.element()?;
+        self.index += element.raw()?.len();
+        Ok(())
+    }
+
+    /// Check whether the current element closes the container, consuming it if so
+    fn end_of_container(&mut self) -> Result<bool> {
+        if self.element()?.get_type()? == TlvType::EndOfContainer {
+            self.index += 1;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn start_container(&mut self) -> Result<TlvType> {
+        let element = self.advance()?;
+        match element.get_type()? {
+            t if t.is_container() => Ok(t),
+            t => Err(Error::Tlv(TlvError::UnexpectedType(t))),
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let element = self.element()?;
+        match element.get_type()? {
+            TlvType::Structure => self.deserialize_map(visitor),
+            TlvType::Array | TlvType::List => self.deserialize_seq(visitor),
+            TlvType::EndOfContainer => Err(Error::Tlv(TlvError::UnexpectedType(
+                TlvType::EndOfContainer,
+            ))),
+            _ => {
+                self.advance()?;
+                match element.get_value()? {
+                    TagLengthValue::Signed8(v) => visitor.visit_i8(v),
+                    TagLengthValue::Signed16(v) => visitor.visit_i16(v),
+                    TagLengthValue::Signed32(v) => visitor.visit_i32(v),
+                    TagLengthValue::Signed64(v) => visitor.visit_i64(v),
+                    TagLengthValue::Unsigned8(v) => visitor.visit_u8(v),
+                    TagLengthValue::Unsigned16(v) => visitor.visit_u16(v),
+                    TagLengthValue::Unsigned32(v) => visitor.visit_u32(v),
+                    TagLengthValue::Unsigned64(v) => visitor.visit_u64(v),
+                    TagLengthValue::Boolean(v) => visitor.visit_bool(v),
+                    TagLengthValue::Float(v) => visitor.visit_f32(v),
+                    TagLengthValue::Double(v) => visitor.visit_f64(v),
+                    TagLengthValue::String(_) => {
+                        let value = core::str::from_utf8(element.value_slice()?)
+                            .map_err(|_| TlvError::InvalidUtf8)?;
+                        visitor.visit_borrowed_str(value)
+                    }
+                    TagLengthValue::ByteString(_) => {
+                        visitor.visit_borrowed_bytes(element.value_slice()?)
+                    }
+                    TagLengthValue::Null => visitor.visit_unit(),
+                    TagLengthValue::Container | TagLengthValue::EndOfContainer => unreachable!(),
+                }
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.element()?.get_type()? == TlvType::Null {
+            self.advance()?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.advance()?.get_type()? {
+            TlvType::Null => visitor.visit_unit(),
+            t => Err(Error::Tlv(TlvError::UnexpectedType(t))),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.start_container()? {
+            TlvType::Array | TlvType::List => visitor.visit_seq(ContainerAccess { de: self }),
+            t => Err(Error::Tlv(TlvError::UnexpectedType(t))),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        // Lists are also tagged, so they can be read into structs and maps
+        match self.start_container()? {
+            TlvType::Structure | TlvType::List => visitor.visit_map(ContainerAccess { de: self }),
+            t => Err(Error::Tlv(TlvError::UnexpectedType(t))),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        let index = match self.advance()?.get_value()? {
+            TagLengthValue::Unsigned8(v) => v as u32,
+            TagLengthValue::Unsigned16(v) => v as u32,
+            TagLengthValue::Unsigned32(v) => v,
+            other => return Err(Error::Tlv(TlvError::UnexpectedType(other.infer_tlv_type()))),
+        };
+        visitor.visit_enum(index.into_deserializer())
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.skip()?;
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf identifier
+    }
+}
+
+/// Gives access to the children of a container, which are terminated by
+/// an end of container element.
+struct ContainerAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for ContainerAccess<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.de.end_of_container()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for ContainerAccess<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.de.end_of_container()? {
+            return Ok(None);
+        }
+        let tag = match self.de.element()?.get_control()? {
+            TagControl::ContextSpecific(tag) => tag,
+            other => return Err(Error::InvalidTag(alloc::format!("{other:?}"))),
+        };
+        // Struct fields are named after their tags, so present the key as a string
+        let mut key = heapless::String::<3>::new();
+        write!(key, "{tag}").unwrap();
+        seed.deserialize(key.as_str().into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct SessionParams {
+        #[serde(rename = "1")]
+        idle: Option<u32>,
+        #[serde(rename = "2")]
+        active: Option<u32>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Request<'a> {
+        #[serde(rename = "1", with = "serde_bytes")]
+        random: [u8; 4],
+        #[serde(rename = "2")]
+        session_id: u16,
+        #[serde(rename = "3")]
+        name: &'a str,
+        #[serde(rename = "4")]
+        has_params: bool,
+        #[serde(rename = "5")]
+        params: Option<SessionParams>,
+        #[serde(rename = "6")]
+        values: Vec<u8>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename = "$tlv::list")]
+    struct Path {
+        #[serde(rename = "2")]
+        endpoint: Option<u16>,
+        #[serde(rename = "3")]
+        cluster: Option<u32>,
+    }
+
+    #[test]
+    fn test_roundtrip_struct() {
+        let request = Request {
+            random: [1, 2, 3, 4],
+            session_id: 56371,
+            name: "light",
+            has_params: true,
+            params: Some(SessionParams {
+                idle: Some(5000),
+                active: None,
+            }),
+            values: vec![7, 8],
+        };
+        let encoded = to_encoder(&request).unwrap();
+        assert_eq!(
+            hex::encode(encoded.to_slice()),
+            "1530010401020304250233dc2c03056c696768742904350525018813183606040704081818"
+        );
+        let decoded: Request = from_slice(encoded.to_slice()).unwrap();
+        assert_eq!(request, decoded);
+    }
+
+    #[test]
+    fn test_roundtrip_list() {
+        let path = Path {
+            endpoint: Some(1),
+            cluster: Some(6),
+        };
+        let encoded = to_encoder(&path).unwrap();
+        assert_eq!(hex::encode(encoded.to_slice()), "1724020124030618");
+        let decoded: Path = from_slice(encoded.to_slice()).unwrap();
+        assert_eq!(path, decoded);
+    }
+
+    #[test]
+    fn test_decode_skips_unknown_tags() {
+        // Tag 3 is a nested structure that SessionParams doesn't know about
+        let data = hex_literal::hex!("1524010535032401011818");
+        let decoded: SessionParams = from_slice(&data).unwrap();
+        assert_eq!(
+            decoded,
+            SessionParams {
+                idle: Some(5),
+                active: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_floats() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Color {
+            #[serde(rename = "1")]
+            hue: f32,
+            #[serde(rename = "2")]
+            level: f64,
+        }
+
+        let color = Color {
+            hue: 17.9,
+            level: 17.9,
+        };
+        let encoded: heapless::Vec<u8, 32> = to_vec(&color).unwrap();
+        assert_eq!(
+            encoded.as_slice(),
+            hex_literal::hex!("15" "2a0133338f41" "2b026666666666e63140" "18")
+        );
+        assert_eq!(from_slice::<Color>(&encoded).unwrap(), color);
+    }
+}
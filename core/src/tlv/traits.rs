@@ -0,0 +1,553 @@
+//! Encoding and decoding Rust types as TLV elements.
+//!
+//! Protocol structures normally derive these traits:
+//!
+//! ```ignore
+//! #[derive(ToTlv, FromTlv)]
+//! #[tlv(list)]
+//! pub struct ClusterPathIB {
+//!     #[tlv(tag = 0)]
+//!     pub node: Option<u64>,
+//!     #[tlv(tag = 1)]
+//!     pub endpoint: Option<u16>,
+//!     #[tlv(tag = 2)]
+//!     pub cluster: Option<u32>,
+//! }
+//! ```
+//!
+//! Structs are encoded as a TLV structure, or a list with `#[tlv(list)]`.
+//! Every field needs a tag, one of:
+//! - `#[tlv(tag = N)]` for a context-specific tag
+//! - `#[tlv(anonymous)]`
+//! - `#[tlv(fully_qualified(vendor = V, profile = P, tag = T))]`
+//!
+//! `Option<T>` fields are optional, and are omitted when `None`.
+//! Fields marked `#[tlv(nullable)]` are encoded as `null` when `None`,
+//! and can be made optional too with `Option<Option<T>>`.
+//! Byte strings are marked with `#[tlv(bytes)]`, as `Vec<u8>` is otherwise
+//! an array of integers.
+//!
+//! Enums with unit variants are encoded as their discriminant, using the
+//! integer type of their `#[repr]`.
+//!
+//! Decoding skips unknown tags, and returns [TlvError::MissingField] if a
+//! required field is missing.
+
+use alloc::{string::String, vec::Vec};
+
+use super::{decode, Encoder, TagControl, TagLengthValue, Tlv, TlvBuf, TlvData, TlvError, TlvType};
+use crate::TlvAnyData;
+
+/// A type that can be written as a TLV element.
+///
+/// A container that overflows the encoder is left half written, callers
+/// that carry on after [TlvError::Overflow] should roll back to an
+/// [Encoder::checkpoint] taken before writing it.
+pub trait ToTlv {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError>;
+}
+
+/// A type that can be read from a TLV element
+pub trait FromTlv<'a>: Sized {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError>;
+}
+
+/// A type that is written and read as a TLV byte string
+pub trait TlvBytes<'a>: Sized {
+    fn to_tlv_bytes<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError>;
+
+    fn from_tlv_bytes(element: &TlvData<'a>) -> Result<Self, TlvError>;
+}
+
+/// Encode a value as an anonymous element
+pub fn to_encoder<T: ToTlv + ?Sized>(value: &T) -> Result<Encoder, TlvError> {
+    let mut encoder = Encoder::default();
+    value.to_tlv(&mut encoder, TagControl::Anonymous)?;
+    Ok(encoder)
+}
+
+/// Decode a value from the first element of `data`
+pub fn from_slice<'a, T: FromTlv<'a>>(data: &'a [u8]) -> Result<T, TlvError> {
+    T::from_tlv(&decode(data))
+}
+
+/// An already encoded element, such as attribute data or command fields,
+/// that is written with the tag of the field holding it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RawTlv(pub TlvAnyData);
+
+impl ToTlv for RawTlv {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        encoder.write_raw(tag, &self.0)
+    }
+}
+
+impl<'a> FromTlv<'a> for RawTlv {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        let mut encoder = Encoder::default();
+        encoder.write_raw(TagControl::Anonymous, element.raw()?)?;
+        Ok(Self(encoder.inner()))
+    }
+}
+
+/// An iterator over the direct children of a container.
+///
+/// Iteration stops after the first error.
+pub struct Children<'a> {
+    data: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Children<'a> {
+    pub(super) fn new(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        let tlv_type = element.get_type()?;
+        if !tlv_type.is_container() {
+            return Err(TlvError::UnexpectedType(tlv_type));
+        }
+        Ok(Self {
+            data: element.data,
+            index: element.index + element.element_len()?,
+        })
+    }
+
+    fn next_child(&mut self) -> Result<Option<TlvData<'a>>, TlvError> {
+        let element = TlvData {
+            data: self.data,
+            index: self.index,
+            in_container: true,
+        };
+        if element.get_type()? == TlvType::EndOfContainer {
+            return Ok(None);
+        }
+        self.index += element.subtree_len()?;
+        Ok(Some(element))
+    }
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = Result<TlvData<'a>, TlvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index > self.data.len() {
+            return None;
+        }
+        match self.next_child() {
+            Ok(Some(element)) => Some(Ok(element)),
+            Ok(None) => {
+                self.index = usize::MAX;
+                None
+            }
+            Err(e) => {
+                self.index = usize::MAX;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Integers are written in as few bytes as their value needs
+macro_rules! impl_unsigned {
+    ($($ty:ty),*) => {
+        $(
+            impl ToTlv for $ty {
+                fn to_tlv<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError> {
+                    encoder.u64(tag, *self as u64)
+                }
+            }
+
+            impl<'a> FromTlv<'a> for $ty {
+                fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+                    let value = match element.get_value()? {
+                        TagLengthValue::Unsigned8(v) => v as u64,
+                        TagLengthValue::Unsigned16(v) => v as u64,
+                        TagLengthValue::Unsigned32(v) => v as u64,
+                        TagLengthValue::Unsigned64(v) => v,
+                        _ => return Err(TlvError::UnexpectedType(element.get_type()?)),
+                    };
+                    value.try_into().map_err(|_| TlvError::OutOfRange)
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_signed {
+    ($($ty:ty),*) => {
+        $(
+            impl ToTlv for $ty {
+                fn to_tlv<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError> {
+                    encoder.i64(tag, *self as i64)
+                }
+            }
+
+            impl<'a> FromTlv<'a> for $ty {
+                fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+                    let value = match element.get_value()? {
+                        TagLengthValue::Signed8(v) => v as i64,
+                        TagLengthValue::Signed16(v) => v as i64,
+                        TagLengthValue::Signed32(v) => v as i64,
+                        TagLengthValue::Signed64(v) => v,
+                        _ => return Err(TlvError::UnexpectedType(element.get_type()?)),
+                    };
+                    value.try_into().map_err(|_| TlvError::OutOfRange)
+                }
+            }
+        )*
+    };
+}
+
+impl_unsigned!(u8, u16, u32, u64);
+impl_signed!(i8, i16, i32, i64);
+
+impl ToTlv for f32 {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        encoder.f32(tag, *self)
+    }
+}
+
+impl<'a> FromTlv<'a> for f32 {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        match element.get_value()? {
+            TagLengthValue::Float(v) => Ok(v),
+            _ => Err(TlvError::UnexpectedType(element.get_type()?)),
+        }
+    }
+}
+
+impl ToTlv for f64 {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        encoder.f64(tag, *self)
+    }
+}
+
+/// Single precision values are widened, as that's lossless
+impl<'a> FromTlv<'a> for f64 {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        match element.get_value()? {
+            TagLengthValue::Float(v) => Ok(v as f64),
+            TagLengthValue::Double(v) => Ok(v),
+            _ => Err(TlvError::UnexpectedType(element.get_type()?)),
+        }
+    }
+}
+
+impl ToTlv for bool {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        encoder.bool(tag, *self)
+    }
+}
+
+impl<'a> FromTlv<'a> for bool {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        match element.get_type()? {
+            TlvType::Boolean(value) => Ok(value),
+            tlv_type => Err(TlvError::UnexpectedType(tlv_type)),
+        }
+    }
+}
+
+impl ToTlv for str {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        encoder.str(tag, self)
+    }
+}
+
+impl<'a> FromTlv<'a> for &'a str {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        element.as_str()
+    }
+}
+
+impl ToTlv for String {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        self.as_str().to_tlv(encoder, tag)
+    }
+}
+
+impl<'a> FromTlv<'a> for String {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        <&str>::from_tlv(element).map(Into::into)
+    }
+}
+
+impl<const N: usize> ToTlv for heapless::String<N> {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        self.as_str().to_tlv(encoder, tag)
+    }
+}
+
+impl<'a, const N: usize> FromTlv<'a> for heapless::String<N> {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        let value = <&str>::from_tlv(element)?;
+        let mut string = heapless::String::new();
+        string.push_str(value).map_err(|_| TlvError::OutOfRange)?;
+        Ok(string)
+    }
+}
+
+impl<T: ToTlv + ?Sized> ToTlv for &T {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        (**self).to_tlv(encoder, tag)
+    }
+}
+
+/// `None` is written as `null`, optional fields are handled by the derive
+impl<T: ToTlv> ToTlv for Option<T> {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        match self {
+            Some(value) => value.to_tlv(encoder, tag),
+            None => encoder.null(tag),
+        }
+    }
+}
+
+impl<'a, T: FromTlv<'a>> FromTlv<'a> for Option<T> {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        match element.get_type()? {
+            TlvType::Null => Ok(None),
+            _ => T::from_tlv(element).map(Some),
+        }
+    }
+}
+
+fn write_array<'t, T: ToTlv + 't, B: TlvBuf>(
+    encoder: &mut Encoder<B>,
+    tag: TagControl,
+    values: impl Iterator<Item = &'t T>,
+) -> Result<(), TlvError> {
+    encoder.start_array(tag)?;
+    for value in values {
+        value.to_tlv(encoder, TagControl::Anonymous)?;
+    }
+    encoder.end_container()
+}
+
+impl<T: ToTlv> ToTlv for [T] {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        write_array(encoder, tag, self.iter())
+    }
+}
+
+impl<T: ToTlv> ToTlv for Vec<T> {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        write_array(encoder, tag, self.iter())
+    }
+}
+
+impl<'a, T: FromTlv<'a>> FromTlv<'a> for Vec<T> {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        element
+            .children()?
+            .map(|child| T::from_tlv(&child?))
+            .collect()
+    }
+}
+
+impl<T: ToTlv, const N: usize> ToTlv for heapless::Vec<T, N> {
+    fn to_tlv<B: TlvBuf>(&self, encoder: &mut Encoder<B>, tag: TagControl) -> Result<(), TlvError> {
+        write_array(encoder, tag, self.iter())
+    }
+}
+
+impl<'a, T: FromTlv<'a>, const N: usize> FromTlv<'a> for heapless::Vec<T, N> {
+    fn from_tlv(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        let mut values = heapless::Vec::new();
+        for child in element.children()? {
+            values
+                .push(T::from_tlv(&child?)?)
+                .map_err(|_| TlvError::OutOfRange)?;
+        }
+        Ok(values)
+    }
+}
+
+impl<'a> TlvBytes<'a> for &'a [u8] {
+    fn to_tlv_bytes<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError> {
+        encoder.bytes(tag, self)
+    }
+
+    fn from_tlv_bytes(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        element.as_bytes()
+    }
+}
+
+impl<'a> TlvBytes<'a> for Vec<u8> {
+    fn to_tlv_bytes<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError> {
+        encoder.bytes(tag, self)
+    }
+
+    fn from_tlv_bytes(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        element.as_bytes().map(<[u8]>::to_vec)
+    }
+}
+
+impl<'a, const N: usize> TlvBytes<'a> for heapless::Vec<u8, N> {
+    fn to_tlv_bytes<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError> {
+        encoder.bytes(tag, self)
+    }
+
+    fn from_tlv_bytes(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        heapless::Vec::from_slice(element.as_bytes()?).map_err(|_| TlvError::OutOfRange)
+    }
+}
+
+impl<'a, const N: usize> TlvBytes<'a> for [u8; N] {
+    fn to_tlv_bytes<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError> {
+        encoder.bytes(tag, self)
+    }
+
+    fn from_tlv_bytes(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        element
+            .as_bytes()?
+            .try_into()
+            .map_err(|_| TlvError::OutOfRange)
+    }
+}
+
+impl<'a, T: TlvBytes<'a>> TlvBytes<'a> for Option<T> {
+    fn to_tlv_bytes<B: TlvBuf>(
+        &self,
+        encoder: &mut Encoder<B>,
+        tag: TagControl,
+    ) -> Result<(), TlvError> {
+        match self {
+            Some(value) => value.to_tlv_bytes(encoder, tag),
+            None => encoder.null(tag),
+        }
+    }
+
+    fn from_tlv_bytes(element: &TlvData<'a>) -> Result<Self, TlvError> {
+        match element.get_type()? {
+            TlvType::Null => Ok(None),
+            _ => T::from_tlv_bytes(element).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tlv::{FromTlv, ToTlv};
+
+    #[repr(u16)]
+    #[derive(Debug, PartialEq, ToTlv, FromTlv)]
+    enum Mode {
+        Off = 0,
+        On = 0x1234,
+    }
+
+    #[derive(Debug, PartialEq, ToTlv, FromTlv)]
+    #[tlv(list)]
+    struct Sample<'a> {
+        #[tlv(tag = 1)]
+        name: &'a str,
+        #[tlv(tag = 2, bytes)]
+        key: Vec<u8>,
+        #[tlv(tag = 3, nullable)]
+        label: Option<u8>,
+        #[tlv(tag = 4, nullable)]
+        index: Option<Option<u8>>,
+        #[tlv(tag = 5)]
+        mode: Option<Mode>,
+        #[tlv(fully_qualified(vendor = 0xfff1, profile = 0xdeed, tag = 1))]
+        extra: Vec<u8>,
+    }
+
+    #[test]
+    fn derive_roundtrip() {
+        let sample = Sample {
+            name: "light",
+            key: vec![1, 2],
+            label: None,
+            index: Some(None),
+            mode: Some(Mode::On),
+            extra: vec![7],
+        };
+        let encoded = to_encoder(&sample).unwrap();
+        assert_eq!(
+            encoded.to_slice(),
+            hex_literal::hex!(
+                "17" "2c01056c69676874" "30020201 02" "3403" "3404" "25053412"
+                "d6f1ffedde0100" "0407" "18" "18"
+            )
+        );
+        assert_eq!(from_slice::<Sample>(encoded.to_slice()), Ok(sample));
+    }
+
+    #[test]
+    fn decode_skips_unknown_tags() {
+        // An unknown structure, with a nested structure and a field that
+        // reuses a known tag, between the known fields
+        let data = hex_literal::hex!("17240105" "3509240101" "1518" "18" "300201aa" "18");
+        let decoded: Partial = from_slice(&data).unwrap();
+        assert_eq!(
+            decoded,
+            Partial {
+                a: 5,
+                b: vec![0xaa],
+                c: None
+            }
+        );
+    }
+
+    #[derive(Debug, PartialEq, ToTlv, FromTlv)]
+    #[tlv(list)]
+    struct Partial {
+        #[tlv(tag = 1)]
+        a: u8,
+        #[tlv(tag = 2, bytes)]
+        b: Vec<u8>,
+        #[tlv(tag = 5)]
+        c: Option<Mode>,
+    }
+
+    #[test]
+    fn decode_errors() {
+        // Missing the required field 1
+        let data = hex_literal::hex!("17300201aa18");
+        assert_eq!(
+            from_slice::<Partial>(&data),
+            Err(TlvError::MissingField("a"))
+        );
+        // Field 1 is a string instead of an integer
+        let data = hex_literal::hex!("172c01016118");
+        assert!(matches!(
+            from_slice::<Partial>(&data),
+            Err(TlvError::UnexpectedType(TlvType::String(_, 1)))
+        ));
+        // Unknown enum value
+        let data = hex_literal::hex!("17240105" "300200" "25050500" "18");
+        assert_eq!(from_slice::<Partial>(&data), Err(TlvError::OutOfRange));
+        // Unterminated list
+        let data = hex_literal::hex!("17240105");
+        assert_eq!(from_slice::<Partial>(&data), Err(TlvError::Truncated));
+    }
+}
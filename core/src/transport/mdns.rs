@@ -7,7 +7,7 @@ use std::{
 use libmdns::{Responder, Service};
 use once_cell::sync::Lazy;
 
-use crate::cluster::utility::basic_information::DeviceInformation;
+use crate::{cluster::utility::basic_information::DeviceInformation, exchange::MrpParameters};
 
 pub const MDNS_BROADCAST_IPV4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251); // "224.0.0.251"
 pub const MDNS_BROADCAST_IPV6: Ipv6Addr = Ipv6Addr::new(0xFF02, 0, 0, 0, 0, 0, 0, 0x00FA); // "ff02::fb"
@@ -33,7 +33,12 @@ impl MdnsHandler {
         name: &str,
         mode: DnsServiceMode,
         device_info: &DeviceInformation,
+        mrp_parameters: &MrpParameters,
     ) -> MdnsService {
+        // The same values that are sent during session establishment (4.3.1.3)
+        let idle = mrp_parameters.idle_retrans_timeout.as_millis().to_string();
+        let active = mrp_parameters.active_retrans_timeout.as_millis().to_string();
+        let threshold = mrp_parameters.active_threshold.as_millis().to_string();
         match mode {
             DnsServiceMode::Commissionable(mode) => {
                 let discriminator = 0xFFu16.to_string(); // TODO
@@ -53,15 +58,17 @@ impl MdnsHandler {
                     ["CM", mode.as_str()],
                     ["DN", "Test Device"],
                     ["VP", vp.as_str()],
-                    ["SII", "10000"],
-                    ["SAI", "500"],
+                    ["SII", &idle],
+                    ["SAI", &active],
+                    ["SAT", &threshold],
                     ["PI", ""], // ...
                 ];
 
                 MdnsService::new(name, "_matterc", "_udp", DNS_MATTER_PORT, &txt_values)
             }
             DnsServiceMode::Commissioned => {
-                MdnsService::new(name, "_matter", "_tcp", DNS_MATTER_PORT, &[])
+                let txt_values = [["SII", &idle], ["SAI", &active], ["SAT", &threshold]];
+                MdnsService::new(name, "_matter", "_tcp", DNS_MATTER_PORT, &txt_values)
             }
             DnsServiceMode::Commisioner(port) => {
                 MdnsService::new(name, "_matterd", "_udp", port, &[])
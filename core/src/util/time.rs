@@ -1,3 +1,286 @@
+//! Clocks and timers that protocol state machines measure time with.
+//!
+//! Timeouts are driven by a [Clock] instead of the system time, so that
+//! devices can use their own time source, and tests can use a [MockClock]
+//! that only moves when it is advanced.
+
+use core::{
+    future::Future,
+    ops::{Add, AddAssign, Sub},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use std::sync::{Arc, Mutex};
+
+/// A point in time of a [Clock], as the time since the clock started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub const fn from_duration(since_start: Duration) -> Self {
+        Self(since_start)
+    }
+
+    /// The time since the clock started
+    pub const fn as_duration(&self) -> Duration {
+        self.0
+    }
+
+    /// The time since `earlier`, or zero if `earlier` is later
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.saturating_duration_since(rhs)
+    }
+}
+
+/// A source of time
+pub trait Clock {
+    /// Monotonic time, which never goes backwards
+    fn now(&self) -> Instant;
+
+    /// Milliseconds since the Unix epoch, for timestamps that are stored or
+    /// sent to peers
+    fn timestamp(&self) -> i64;
+}
+
+/// A clock that can wait for a point in time
+pub trait Timer: Clock {
+    /// Wait until `deadline`, finishing straight away if it has passed
+    fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()> + Send;
+}
+
+/// The system clock, which runs its timers on threads so that they work with
+/// any executor
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy)]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdClock {
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    fn now(&self) -> Instant {
+        Instant(self.start.elapsed())
+    }
+
+    fn timestamp(&self) -> i64 {
+        current_timestamp()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Timer for StdClock {
+    fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()> + Send {
+        ThreadSleep {
+            deadline: self.start + deadline.0,
+            waker: None,
+        }
+    }
+}
+
+/// Sleeps on a thread, which wakes the task when the deadline passes
+#[cfg(feature = "std")]
+struct ThreadSleep {
+    deadline: std::time::Instant,
+    waker: Option<Arc<Mutex<Option<Waker>>>>,
+}
+
+#[cfg(feature = "std")]
+impl Future for ThreadSleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if std::time::Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        let waker = self.waker.get_or_insert_with(|| {
+            let waker = Arc::new(Mutex::new(None::<Waker>));
+            let thread_waker = waker.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(deadline.saturating_duration_since(std::time::Instant::now()));
+                if let Some(waker) = thread_waker.lock().unwrap().take() {
+                    waker.wake();
+                }
+            });
+            waker
+        });
+        *waker.lock().unwrap() = Some(cx.waker().clone());
+        // The thread only takes the waker once the deadline has passed
+        if std::time::Instant::now() >= deadline {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// The clock of the tokio runtime, which can be paused and advanced in tests
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy)]
+pub struct TokioClock {
+    start: tokio::time::Instant,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioClock {
+    pub fn new() -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant(self.start.elapsed())
+    }
+
+    fn timestamp(&self) -> i64 {
+        current_timestamp()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioClock {
+    fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()> + Send {
+        tokio::time::sleep_until(self.start + deadline.0)
+    }
+}
+
+/// A clock that only moves when it is advanced, for testing timeouts.
+///
+/// Clones share the same time, so a test can keep one and give the other to
+/// the code under test.
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    now: Duration,
+    /// Milliseconds since the Unix epoch when the clock started
+    start_timestamp: i64,
+    /// The tasks that are waiting for the clock to move
+    sleepers: Vec<Waker>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A clock whose timestamps start at `start_timestamp`
+    pub fn with_timestamp(start_timestamp: i64) -> Self {
+        let clock = Self::new();
+        clock.state.lock().unwrap().start_timestamp = start_timestamp;
+        clock
+    }
+
+    /// Move the clock forward, waking the timers that are due
+    pub fn advance(&self, by: Duration) {
+        let sleepers = {
+            let mut state = self.state.lock().unwrap();
+            state.now += by;
+            core::mem::take(&mut state.sleepers)
+        };
+        // The timers that aren't due register themselves again
+        sleepers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Move the clock forward to `instant`, if it is later
+    pub fn advance_to(&self, instant: Instant) {
+        let now = self.now();
+        self.advance(instant.saturating_duration_since(now));
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        Instant(self.state.lock().unwrap().now)
+    }
+
+    fn timestamp(&self) -> i64 {
+        let state = self.state.lock().unwrap();
+        state.start_timestamp + state.now.as_millis() as i64
+    }
+}
+
+impl Timer for MockClock {
+    fn sleep_until(&self, deadline: Instant) -> impl Future<Output = ()> + Send {
+        MockSleep {
+            clock: self.clone(),
+            deadline,
+        }
+    }
+}
+
+struct MockSleep {
+    clock: MockClock,
+    deadline: Instant,
+}
+
+impl Future for MockSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.clock.state.lock().unwrap();
+        if Instant(state.now) >= self.deadline {
+            Poll::Ready(())
+        } else {
+            state.sleepers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
 #[cfg(not(feature = "end_device"))]
 pub fn current_timestamp() -> i64 {
     use std::time::SystemTime;
@@ -12,3 +295,29 @@ pub fn current_timestamp() -> i64 {
 pub fn current_timestamp() -> i64 {
     unimplemented!("A clock source is not yet implemented")
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::with_timestamp(1_000);
+        let start = clock.now();
+        let mut sleep = Box::pin(clock.sleep_until(start + Duration::from_millis(300)));
+        assert!(sleep.as_mut().now_or_never().is_none());
+
+        // Clones share the time
+        clock.clone().advance(Duration::from_millis(299));
+        assert!(sleep.as_mut().now_or_never().is_none());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(sleep.as_mut().now_or_never(), Some(()));
+
+        assert_eq!(clock.now() - start, Duration::from_millis(300));
+        assert_eq!(clock.timestamp(), 1_300);
+        // Deadlines that have passed don't wait
+        assert_eq!(clock.sleep_until(start).now_or_never(), Some(()));
+    }
+}
@@ -0,0 +1,386 @@
+//! Derive macros for encoding and decoding Matter TLV.
+//!
+//! The generated code uses `matter_controller::tlv::{ToTlv, FromTlv}`, see the
+//! documentation of those traits for the supported attributes.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Field, Fields, GenericArgument,
+    Generics, Ident, Lifetime, LitInt, PathArguments, Result, Type,
+};
+
+#[proc_macro_derive(ToTlv, attributes(tlv))]
+pub fn derive_to_tlv(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_to_tlv(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(FromTlv, attributes(tlv))]
+pub fn derive_from_tlv(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_from_tlv(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// The TLV container that a struct is encoded as
+enum ContainerKind {
+    Structure,
+    List,
+}
+
+struct ContainerAttrs {
+    kind: ContainerKind,
+}
+
+impl ContainerAttrs {
+    fn parse(input: &DeriveInput) -> Result<Self> {
+        let mut attrs = Self {
+            kind: ContainerKind::Structure,
+        };
+        for attr in input.attrs.iter().filter(|a| a.path().is_ident("tlv")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("list") {
+                    attrs.kind = ContainerKind::List;
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported tlv container attribute"))
+                }
+            })?;
+        }
+        Ok(attrs)
+    }
+
+    /// The encoder method that opens the container
+    fn start(&self) -> TokenStream2 {
+        match self.kind {
+            ContainerKind::Structure => quote!(start_struct),
+            ContainerKind::List => quote!(start_list),
+        }
+    }
+}
+
+struct FieldAttrs {
+    tag: TokenStream2,
+    nullable: bool,
+    bytes: bool,
+}
+
+impl FieldAttrs {
+    fn parse(field: &Field) -> Result<Self> {
+        let mut tag = None;
+        let mut nullable = false;
+        let mut bytes = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("tlv")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("tag") {
+                    let value: LitInt = meta.value()?.parse()?;
+                    let value: u8 = value.base10_parse()?;
+                    tag =
+                        Some(quote!(::matter_controller::tlv::TagControl::ContextSpecific(#value)));
+                } else if meta.path.is_ident("anonymous") {
+                    tag = Some(quote!(::matter_controller::tlv::TagControl::Anonymous));
+                } else if meta.path.is_ident("fully_qualified") {
+                    let mut vendor = None;
+                    let mut profile = None;
+                    let mut number = None;
+                    meta.parse_nested_meta(|inner| {
+                        let value: LitInt = inner.value()?.parse()?;
+                        if inner.path.is_ident("vendor") {
+                            vendor = Some(value.base10_parse::<u16>()?);
+                        } else if inner.path.is_ident("profile") {
+                            profile = Some(value.base10_parse::<u16>()?);
+                        } else if inner.path.is_ident("tag") {
+                            number = Some(value.base10_parse::<u32>()?);
+                        } else {
+                            return Err(inner.error("expected vendor, profile or tag"));
+                        }
+                        Ok(())
+                    })?;
+                    let (Some(vendor), Some(profile), Some(number)) = (vendor, profile, number)
+                    else {
+                        return Err(meta.error("fully_qualified requires vendor, profile and tag"));
+                    };
+                    tag = Some(fully_qualified_tag(vendor, profile, number));
+                } else if meta.path.is_ident("nullable") {
+                    nullable = true;
+                } else if meta.path.is_ident("bytes") {
+                    bytes = true;
+                } else {
+                    return Err(meta.error("unsupported tlv field attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        let Some(tag) = tag else {
+            return Err(Error::new_spanned(
+                field,
+                "fields need a tag, e.g. #[tlv(tag = 1)]",
+            ));
+        };
+        Ok(Self {
+            tag,
+            nullable,
+            bytes,
+        })
+    }
+}
+
+/// Fully-qualified tags are the vendor ID, profile number and tag number (A.7.2)
+fn fully_qualified_tag(vendor: u16, profile: u16, number: u32) -> TokenStream2 {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&vendor.to_le_bytes());
+    bytes.extend_from_slice(&profile.to_le_bytes());
+    if let Ok(number) = u16::try_from(number) {
+        bytes.extend_from_slice(&number.to_le_bytes());
+        quote!(::matter_controller::tlv::TagControl::FullyQualified6Bytes([#(#bytes),*]))
+    } else {
+        bytes.extend_from_slice(&number.to_le_bytes());
+        quote!(::matter_controller::tlv::TagControl::FullyQualified8Bytes([#(#bytes),*]))
+    }
+}
+
+/// Returns the inner type if this is an `Option<T>`
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    }
+}
+
+/// How a field is represented in its container
+enum Presence<'a> {
+    /// Always encoded
+    Required(&'a Type),
+    /// Omitted when `None`, the inner type is encoded when present
+    Optional(&'a Type),
+}
+
+fn presence<'a>(ty: &'a Type, attrs: &FieldAttrs) -> Presence<'a> {
+    match option_inner(ty) {
+        // Nullable fields are `Option<T>`, and only optional if wrapped again
+        Some(inner) if attrs.nullable => match option_inner(inner) {
+            Some(_) => Presence::Optional(inner),
+            None => Presence::Required(ty),
+        },
+        Some(inner) => Presence::Optional(inner),
+        None => Presence::Required(ty),
+    }
+}
+
+fn named_fields(input: &DeriveInput) -> Result<Vec<&Field>> {
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(fields.named.iter().collect()),
+            _ => Err(Error::new_spanned(
+                input,
+                "only structs with named fields are supported",
+            )),
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// The integer type of an enum's discriminant, from its `#[repr]`
+fn enum_repr(input: &DeriveInput) -> Result<Ident> {
+    let mut repr = Ident::new("u8", Span::call_site());
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        attr.parse_nested_meta(|meta| {
+            if let Some(ident) = meta.path.get_ident() {
+                repr = ident.clone();
+            }
+            Ok(())
+        })?;
+    }
+    Ok(repr)
+}
+
+fn unit_variants(input: &DeriveInput) -> Result<Vec<&Ident>> {
+    let Data::Enum(data) = &input.data else {
+        unreachable!()
+    };
+    data.variants
+        .iter()
+        .map(|variant| match variant.fields {
+            Fields::Unit => Ok(&variant.ident),
+            _ => Err(Error::new_spanned(
+                variant,
+                "only enums with unit variants are supported",
+            )),
+        })
+        .collect()
+}
+
+fn expand_to_tlv(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::matter_controller::tlv::ToTlv));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(_) => {
+            let container = ContainerAttrs::parse(&input)?;
+            let start = container.start();
+            let mut writes = vec![];
+            for field in named_fields(&input)? {
+                let ident = &field.ident;
+                let attrs = FieldAttrs::parse(field)?;
+                let tag = &attrs.tag;
+                let write = |value: TokenStream2| {
+                    if attrs.bytes {
+                        quote!(::matter_controller::tlv::TlvBytes::to_tlv_bytes(#value, encoder, #tag)?;)
+                    } else {
+                        quote!(::matter_controller::tlv::ToTlv::to_tlv(#value, encoder, #tag)?;)
+                    }
+                };
+                writes.push(match presence(&field.ty, &attrs) {
+                    Presence::Required(_) => write(quote!(&self.#ident)),
+                    Presence::Optional(_) => {
+                        let write = write(quote!(value));
+                        quote! {
+                            if let Some(value) = &self.#ident {
+                                #write
+                            }
+                        }
+                    }
+                });
+            }
+            quote! {
+                encoder.#start(tag)?;
+                #(#writes)*
+                encoder.end_container()
+            }
+        }
+        Data::Enum(_) => {
+            let repr = enum_repr(&input)?;
+            let variants = unit_variants(&input)?;
+            quote! {
+                let value: #repr = match self {
+                    #(Self::#variants => Self::#variants as #repr,)*
+                };
+                ::matter_controller::tlv::ToTlv::to_tlv(&value, encoder, tag)
+            }
+        }
+        Data::Union(_) => return Err(Error::new_spanned(input, "unions are not supported")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::matter_controller::tlv::ToTlv for #name #ty_generics #where_clause {
+            fn to_tlv<__B: ::matter_controller::tlv::TlvBuf>(
+                &self,
+                encoder: &mut ::matter_controller::tlv::Encoder<__B>,
+                tag: ::matter_controller::tlv::TagControl,
+            ) -> ::core::result::Result<(), ::matter_controller::tlv::TlvError> {
+                #body
+            }
+        }
+    })
+}
+
+/// Picks the lifetime that decoded values borrow from, adding one if needed
+fn decode_lifetime(generics: &mut Generics) -> Lifetime {
+    if let Some(param) = generics.lifetimes().next() {
+        return param.lifetime.clone();
+    }
+    let lifetime = Lifetime::new("'__tlv", Span::call_site());
+    generics.params.insert(0, parse_quote!(#lifetime));
+    lifetime
+}
+
+fn expand_from_tlv(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let mut generics = input.generics.clone();
+    let lifetime = decode_lifetime(&mut generics);
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::matter_controller::tlv::FromTlv<#lifetime>));
+    }
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(_) => {
+            let mut vars = vec![];
+            let mut reads = vec![];
+            let mut inits = vec![];
+            for field in named_fields(&input)? {
+                let ident = field.ident.as_ref().unwrap();
+                let attrs = FieldAttrs::parse(field)?;
+                let tag = &attrs.tag;
+                let (value_type, init) = match presence(&field.ty, &attrs) {
+                    Presence::Required(ty) => {
+                        let missing = ident.to_string();
+                        (
+                            ty,
+                            quote!(#ident.ok_or(::matter_controller::tlv::TlvError::MissingField(#missing))?),
+                        )
+                    }
+                    Presence::Optional(ty) => (ty, quote!(#ident)),
+                };
+                let read = if attrs.bytes {
+                    quote!(<#value_type as ::matter_controller::tlv::TlvBytes>::from_tlv_bytes(&child))
+                } else {
+                    quote!(<#value_type as ::matter_controller::tlv::FromTlv>::from_tlv(&child))
+                };
+                vars.push(quote!(let mut #ident: Option<#value_type> = None;));
+                reads.push(quote!(if control == #tag {
+                    #ident = Some(#read?);
+                }));
+                inits.push(quote!(#ident: #init));
+            }
+            quote! {
+                #(#vars)*
+                for child in element.children()? {
+                    let child = child?;
+                    let control = ::matter_controller::tlv::Tlv::get_control(&child)?;
+                    // Unknown tags are skipped for forward compatibility
+                    #(#reads else)* {}
+                }
+                Ok(Self {
+                    #(#inits,)*
+                })
+            }
+        }
+        Data::Enum(_) => {
+            let repr = enum_repr(&input)?;
+            let variants = unit_variants(&input)?;
+            quote! {
+                let value = <#repr as ::matter_controller::tlv::FromTlv>::from_tlv(element)?;
+                match value {
+                    #(value if value == Self::#variants as #repr => Ok(Self::#variants),)*
+                    _ => Err(::matter_controller::tlv::TlvError::OutOfRange),
+                }
+            }
+        }
+        Data::Union(_) => return Err(Error::new_spanned(input, "unions are not supported")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::matter_controller::tlv::FromTlv<#lifetime> for #name #ty_generics #where_clause {
+            fn from_tlv(
+                element: &::matter_controller::tlv::TlvData<#lifetime>,
+            ) -> ::core::result::Result<Self, ::matter_controller::tlv::TlvError> {
+                #body
+            }
+        }
+    })
+}